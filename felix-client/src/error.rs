@@ -0,0 +1,40 @@
+use std::fmt;
+
+/// Errors returned by `FelixClient`.
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or its response not read.
+    Http(reqwest::Error),
+    /// The API answered with a non-2xx status, e.g. 401 for an unknown
+    /// token or 403 for a read-only one.
+    Api { status: u16, message: String },
+    /// The base URL given to `FelixClient::with_base_url` is not usable.
+    InvalidUrl(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "management API request failed: {}", e),
+            Error::Api { status, message } => write!(f, "management API returned {}: {}", status, message),
+            Error::InvalidUrl(url) => write!(f, "invalid management API URL {:?}", url),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Api { .. } | Error::InvalidUrl(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
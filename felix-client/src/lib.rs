@@ -0,0 +1,598 @@
+//! Typed async client for the management API of a running felix instance.
+//!
+//! ```ignore
+//! let felix = FelixClient::new("127.0.0.1:5380".parse()?, token);
+//! felix.add_domain("app.dev", Ipv4Addr::LOCALHOST).await?;
+//! for d in felix.list_domains().await? {
+//!     println!("{} -> {}", d.domain, d.ip);
+//! }
+//! felix.flush_cache(Some("api.example.com")).await?;
+//! println!("{} domains", felix.stats().await?.domains);
+//! ```
+//!
+//! Reads need a read-only token, changes an admin one.
+
+pub mod error;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{Method, RequestBuilder, Url};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+pub use error::{Error, Result};
+
+/// A local name mapping.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Domain {
+    pub domain: String,
+    pub ip: Ipv4Addr,
+}
+
+/// A mapping that takes precedence over the wider `wildcard` for the
+/// names it covers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingConflict {
+    pub domain: String,
+    pub ip: Ipv4Addr,
+    pub wildcard: String,
+    pub wildcard_ip: Ipv4Addr,
+}
+
+/// Order of a domain listing; ties are broken by domain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListSort {
+    #[default]
+    #[serde(rename = "domain")]
+    DomainAsc,
+    #[serde(rename = "-domain")]
+    DomainDesc,
+    #[serde(rename = "ip")]
+    IpAsc,
+    #[serde(rename = "-ip")]
+    IpDesc,
+}
+
+/// Which page of the domain list to fetch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ListQuery {
+    pub offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Only domains containing this text, ignoring case.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    pub sort: ListSort,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainPage {
+    pub domains: Vec<Domain>,
+    /// Domains matching the filter across all pages.
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of mapped domains.
+    pub domains: usize,
+    /// Whether local answers are on; off, every query is forwarded.
+    pub enabled: bool,
+    pub upstream: SocketAddr,
+}
+
+/// Queries handled within a window, busiest first in every list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryReport {
+    /// Seconds covered, rounded up to whole minutes.
+    pub window: u64,
+    pub queries: u64,
+    pub qtypes: Vec<TypeCount>,
+    pub sources: Vec<SourceCount>,
+    pub names: Vec<NameCount>,
+    /// Queries for names the server stopped tracking.
+    pub untracked: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeCount {
+    pub qtype: String,
+    pub count: u64,
+}
+
+/// Queries by answer source, e.g. `local-wildcard` or `upstream-1.1.1.1:53`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameCount {
+    pub name: String,
+    pub count: u64,
+    /// How the last query for it was answered.
+    pub source: String,
+}
+
+/// One client's query counts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub client: IpAddr,
+    pub queries_today: u64,
+    /// Queries refused today for being over quota.
+    pub refused_today: u64,
+    pub queries_total: u64,
+    /// Daily quota, if any.
+    pub quota: Option<u64>,
+    /// Seconds since the last query.
+    pub idle: u64,
+}
+
+/// One cached upstream answer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub name: String,
+    pub qtype: String,
+    pub upstream: SocketAddr,
+    /// Seconds until the entry expires.
+    pub ttl: u32,
+    /// Hits since the entry was stored or last refreshed.
+    pub hits: u32,
+    pub rcode: String,
+    /// Answer records as `TYPE RDATA`.
+    pub answers: Vec<String>,
+}
+
+/// One check made while resolving a name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceStep {
+    /// `stage` (the subject names it), `bypass`, `policy`, `type-block`,
+    /// `exact`, `wildcard`, `upstream-selection`, `cache` or `upstream`.
+    pub check: String,
+    pub subject: String,
+    pub matched: bool,
+    pub detail: Option<String>,
+    /// Wildcard level, counted out from the name.
+    pub level: Option<usize>,
+    pub elapsed_us: u64,
+}
+
+/// How a name is answered, check by check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolveTrace {
+    pub name: String,
+    pub steps: Vec<TraceStep>,
+    /// The pipeline stage that answered.
+    pub stage: Option<String>,
+    pub decided_by: Option<String>,
+    pub answer: String,
+    pub elapsed_us: u64,
+}
+
+/// An artificial delay on the local answers for a domain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainDelay {
+    pub domain: String,
+    pub ms: u64,
+}
+
+/// How the addresses answered for a domain with several are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnswerOrder {
+    Fixed,
+    Shuffle,
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainOrder {
+    pub domain: String,
+    pub order: AnswerOrder,
+}
+
+/// A named mapping set served to queries on the listeners bound to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct View {
+    pub name: String,
+    /// Number of mapped domains.
+    pub domains: usize,
+    pub listeners: Vec<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+struct Projected {
+    domains: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogFilter {
+    filter: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct FelixClient {
+    http: reqwest::Client,
+    base: Url,
+    token: String,
+}
+
+impl FelixClient {
+    /// A client for the plain-HTTP API at `addr`, e.g. `127.0.0.1:5380`.
+    pub fn new(addr: SocketAddr, token: impl Into<String>) -> Self {
+        let base = Url::parse(&format!("http://{}/", addr)).expect("socket address forms a valid URL");
+        Self { http: reqwest::Client::new(), base, token: token.into() }
+    }
+
+    /// A client for an API behind a reverse proxy, e.g.
+    /// `https://dns.internal/felix/`.
+    pub fn with_base_url(base: &str, token: impl Into<String>) -> Result<Self> {
+        let mut url = Url::parse(base).map_err(|_| Error::InvalidUrl(base.to_string()))?;
+        if url.cannot_be_a_base() {
+            return Err(Error::InvalidUrl(base.to_string()));
+        }
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(Self { http: reqwest::Client::new(), base: url, token: token.into() })
+    }
+
+    /// Uses `http` for requests, to share a connection pool or set
+    /// timeouts.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub async fn list_domains(&self) -> Result<Vec<Domain>> {
+        self.json(self.request(Method::GET, &["domains"])).await
+    }
+
+    pub async fn list_domains_paged(&self, query: &ListQuery) -> Result<DomainPage> {
+        let resp = self.send(self.request(Method::GET, &["domains"]).query(query)).await?;
+        let total = resp
+            .headers()
+            .get("x-total-count")
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        Ok(DomainPage { domains: resp.json().await?, total })
+    }
+
+    /// Up to `limit` domains containing `term`, best match first.
+    pub async fn search_domains(&self, term: &str, limit: usize) -> Result<Vec<Domain>> {
+        let params = [("q", term.to_string()), ("limit", limit.to_string())];
+        self.json(self.request(Method::GET, &["search"]).query(&params)).await
+    }
+
+    /// Adds `domain`, or points it at `ip` if already mapped.
+    /// Returns warnings about wildcards the mapping overlaps with.
+    pub async fn add_domain(&self, domain: &str, ip: Ipv4Addr) -> Result<Vec<String>> {
+        self.put_domain(domain, ip, false).await
+    }
+
+    /// `add_domain` for a public address on an instance that only accepts
+    /// loopback and private ones by default (`serve --safe-ips`).
+    pub async fn add_domain_allow_public(&self, domain: &str, ip: Ipv4Addr) -> Result<Vec<String>> {
+        self.put_domain(domain, ip, true).await
+    }
+
+    async fn put_domain(&self, domain: &str, ip: Ipv4Addr, allow_public: bool) -> Result<Vec<String>> {
+        #[derive(Serialize)]
+        struct Target {
+            ip: Ipv4Addr,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            allow_public: bool,
+        }
+        #[derive(Deserialize)]
+        struct Added {
+            warnings: Vec<String>,
+        }
+        let added: Added = self
+            .json(self.request(Method::PUT, &["domains", domain]).json(&Target { ip, allow_public }))
+            .await?;
+        Ok(added.warnings)
+    }
+
+    /// Maps the domains of the instance's project template, e.g.
+    /// `myapp.test` and `*.myapp.test`, returning them.
+    pub async fn add_project(&self, project: &str, ip: Ipv4Addr) -> Result<Vec<String>> {
+        #[derive(Serialize)]
+        struct Target {
+            ip: Ipv4Addr,
+        }
+        let projected: Projected = self.json(self.request(Method::PUT, &["projects", project]).json(&Target { ip })).await?;
+        Ok(projected.domains)
+    }
+
+    /// Removes the domains `add_project` mapped, returning them.
+    pub async fn remove_project(&self, project: &str) -> Result<Vec<String>> {
+        let projected: Projected = self.json(self.request(Method::DELETE, &["projects", project])).await?;
+        Ok(projected.domains)
+    }
+
+    /// Every exact entry or wildcard that overrides a wider wildcard.
+    pub async fn conflicts(&self) -> Result<Vec<MappingConflict>> {
+        self.json(self.request(Method::GET, &["conflicts"])).await
+    }
+
+    /// Removing a domain that is not mapped succeeds.
+    pub async fn remove_domain(&self, domain: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["domains", domain])).await?;
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        self.json(self.request(Method::GET, &["stats"])).await
+    }
+
+    /// Queries of the last `window`, with the `top` most queried names.
+    pub async fn query_report(&self, window: Duration, top: usize) -> Result<QueryReport> {
+        let params = [("window", window.as_secs().to_string()), ("top", top.to_string())];
+        self.json(self.request(Method::GET, &["stats", "queries"]).query(&params)).await
+    }
+
+    /// Turns local resolution on or off.
+    pub async fn set_enabled(&self, enabled: bool) -> Result<()> {
+        #[derive(Serialize)]
+        struct Enabled {
+            enabled: bool,
+        }
+        self.send(self.request(Method::PUT, &["enabled"]).json(&Enabled { enabled })).await?;
+        Ok(())
+    }
+
+    /// The running log filter in `RUST_LOG` syntax; `None` when the host
+    /// application installed its own logger.
+    pub async fn log_filter(&self) -> Result<Option<String>> {
+        Ok(self.json::<LogFilter>(self.request(Method::GET, &["log-filter"])).await?.filter)
+    }
+
+    /// Replaces the log filter, e.g. `info,felix_dns::server_handler=debug`.
+    pub async fn set_log_filter(&self, filter: &str) -> Result<()> {
+        let body = LogFilter { filter: Some(filter.to_string()) };
+        self.send(self.request(Method::PUT, &["log-filter"]).json(&body)).await?;
+        Ok(())
+    }
+
+    /// Query counts by client, busiest today first.
+    pub async fn clients(&self) -> Result<Vec<ClientStats>> {
+        self.json(self.request(Method::GET, &["clients"])).await
+    }
+
+    /// Explains how an A query for `name` would be answered. The upstream
+    /// is queried if nothing local answers.
+    pub async fn resolve_trace(&self, name: &str) -> Result<ResolveTrace> {
+        self.json(self.request(Method::GET, &["resolve"]).query(&[("name", name)])).await
+    }
+
+    pub async fn cache_entries(&self) -> Result<Vec<CacheEntry>> {
+        self.json(self.request(Method::GET, &["cache"])).await
+    }
+
+    /// Purges the cached answers for `name`, or all of them with `None`.
+    /// Returns how many entries were dropped.
+    pub async fn flush_cache(&self, name: Option<&str>) -> Result<usize> {
+        #[derive(Deserialize)]
+        struct Flushed {
+            flushed: usize,
+        }
+        let mut req = self.request(Method::DELETE, &["cache"]);
+        if let Some(name) = name {
+            req = req.query(&[("name", name)]);
+        }
+        Ok(self.json::<Flushed>(req).await?.flushed)
+    }
+
+    pub async fn domain_delays(&self) -> Result<Vec<DomainDelay>> {
+        self.json(self.request(Method::GET, &["delays"])).await
+    }
+
+    /// Holds local answers for `domain` (or `*.suffix`) back by `delay`.
+    pub async fn set_domain_delay(&self, domain: &str, delay: Duration) -> Result<()> {
+        #[derive(Serialize)]
+        struct Delay {
+            ms: u64,
+        }
+        let ms = delay.as_millis() as u64;
+        self.send(self.request(Method::PUT, &["domains", domain, "delay"]).json(&Delay { ms })).await?;
+        Ok(())
+    }
+
+    pub async fn remove_domain_delay(&self, domain: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["domains", domain, "delay"])).await?;
+        Ok(())
+    }
+
+    pub async fn answer_orders(&self) -> Result<Vec<DomainOrder>> {
+        self.json(self.request(Method::GET, &["orders"])).await
+    }
+
+    /// Orders the addresses answered for `domain` (or `*.suffix`).
+    pub async fn set_answer_order(&self, domain: &str, order: AnswerOrder) -> Result<()> {
+        #[derive(Serialize)]
+        struct Order {
+            order: AnswerOrder,
+        }
+        self.send(self.request(Method::PUT, &["domains", domain, "order"]).json(&Order { order })).await?;
+        Ok(())
+    }
+
+    pub async fn remove_answer_order(&self, domain: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["domains", domain, "order"])).await?;
+        Ok(())
+    }
+
+    pub async fn views(&self) -> Result<Vec<View>> {
+        self.json(self.request(Method::GET, &["views"])).await
+    }
+
+    pub async fn view_domains(&self, view: &str) -> Result<Vec<Domain>> {
+        self.json(self.request(Method::GET, &["views", view, "domains"])).await
+    }
+
+    /// Maps `domain` in `view`, creating the view if needed.
+    pub async fn add_view_domain(&self, view: &str, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        #[derive(Serialize)]
+        struct Target {
+            ip: Ipv4Addr,
+        }
+        self.send(self.request(Method::PUT, &["views", view, "domains", domain]).json(&Target { ip })).await?;
+        Ok(())
+    }
+
+    pub async fn remove_view_domain(&self, view: &str, domain: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["views", view, "domains", domain])).await?;
+        Ok(())
+    }
+
+    /// Drops `view`; its listeners go back to the default mappings.
+    pub async fn remove_view(&self, view: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["views", view])).await?;
+        Ok(())
+    }
+
+    /// Answers queries arriving on the DNS listener bound to `listener`
+    /// from `view`, or from the default mappings with `None`.
+    pub async fn bind_view(&self, listener: SocketAddr, view: Option<&str>) -> Result<()> {
+        #[derive(Serialize)]
+        struct ListenerView<'a> {
+            view: Option<&'a str>,
+        }
+        let req = self.request(Method::PUT, &["listeners", &listener.to_string(), "view"]);
+        self.send(req.json(&ListenerView { view })).await?;
+        Ok(())
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> RequestBuilder {
+        let mut url = self.base.clone();
+        // the base is checked to be a base URL on construction
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        self.http.request(method, url).bearer_auth(&self.token)
+    }
+
+    async fn send(&self, req: RequestBuilder) -> Result<reqwest::Response> {
+        let resp = req.send().await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let message = resp.text().await.unwrap_or_default();
+            return Err(Error::Api { status: status.as_u16(), message });
+        }
+        Ok(resp)
+    }
+
+    async fn json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T> {
+        Ok(self.send(req).await?.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use felix_dns::{FelixServer, Role};
+
+    #[tokio::test]
+    async fn test_client_round_trip() {
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .management("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .token("admin-token", Role::Admin)
+            .token("viewer-token", Role::ReadOnly)
+            .safe_ips(true)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let addr = server.management_addr().unwrap();
+
+        let admin = FelixClient::new(addr, "admin-token");
+        admin.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        admin.add_domain("*.api.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        let warnings = admin.add_domain("v1.api.dev", Ipv4Addr::new(10, 0, 0, 3)).await.unwrap();
+        assert_eq!(warnings, vec!["exact entry v1.api.dev (10.0.0.3) overrides *.api.dev (10.0.0.2)"]);
+        assert_eq!(admin.conflicts().await.unwrap()[0].wildcard, "*.api.dev");
+        admin.remove_domain("v1.api.dev").await.unwrap();
+
+        let viewer = FelixClient::with_base_url(&format!("http://{}", addr), "viewer-token").unwrap();
+        let mut domains = viewer.list_domains().await.unwrap();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
+        assert_eq!(
+            domains,
+            vec![
+                Domain { domain: "*.api.dev".into(), ip: Ipv4Addr::new(10, 0, 0, 2) },
+                Domain { domain: "app.dev".into(), ip: Ipv4Addr::new(10, 0, 0, 1) },
+            ]
+        );
+        let page = viewer
+            .list_domains_paged(&ListQuery { limit: Some(1), filter: Some("API".into()), ..Default::default() })
+            .await
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.domains[0].domain, "*.api.dev");
+        let page = viewer
+            .list_domains_paged(&ListQuery { offset: 1, sort: ListSort::IpDesc, ..Default::default() })
+            .await
+            .unwrap();
+        assert_eq!((page.total, page.domains[0].domain.as_str()), (2, "app.dev"));
+        let found = viewer.search_domains("AP", 10).await.unwrap();
+        assert_eq!(found.iter().map(|d| d.domain.as_str()).collect::<Vec<_>>(), ["app.dev", "*.api.dev"]);
+        let stats = viewer.stats().await.unwrap();
+        assert_eq!(stats, Stats { domains: 2, enabled: true, upstream: "127.0.0.1:9".parse().unwrap() });
+        assert!(viewer.cache_entries().await.unwrap().is_empty());
+        assert!(viewer.clients().await.unwrap().is_empty());
+        let report = viewer.query_report(Duration::from_secs(300), 20).await.unwrap();
+        assert_eq!((report.window, report.queries), (300, 0));
+
+        // read-only tokens cannot change anything
+        match viewer.remove_domain("app.dev").await {
+            Err(Error::Api { status: 403, .. }) => {}
+            other => panic!("expected 403, got {:?}", other),
+        }
+
+        admin.remove_domain("app.dev").await.unwrap();
+        admin.set_enabled(false).await.unwrap();
+        assert_eq!(admin.flush_cache(None).await.unwrap(), 0);
+        let stats = admin.stats().await.unwrap();
+        assert_eq!((stats.domains, stats.enabled), (1, false));
+
+        match admin.add_domain("cdn.dev", Ipv4Addr::new(1, 1, 1, 1)).await {
+            Err(Error::Api { status: 400, .. }) => {}
+            other => panic!("expected 400, got {:?}", other),
+        }
+        admin.add_domain_allow_public("cdn.dev", Ipv4Addr::new(1, 1, 1, 1)).await.unwrap();
+
+        let listener = server.local_addrs()[0];
+        admin.add_view_domain("lan", "app.dev", Ipv4Addr::new(192, 168, 1, 20)).await.unwrap();
+        admin.bind_view(listener, Some("lan")).await.unwrap();
+        assert_eq!(viewer.views().await.unwrap(), vec![View { name: "lan".into(), domains: 1, listeners: vec![listener] }]);
+        assert_eq!(viewer.view_domains("lan").await.unwrap()[0].ip, Ipv4Addr::new(192, 168, 1, 20));
+        assert!(matches!(viewer.view_domains("guest").await, Err(Error::Api { status: 404, .. })));
+        admin.remove_view("lan").await.unwrap();
+        assert!(viewer.views().await.unwrap().is_empty());
+
+        admin.set_domain_delay("*.slow.dev", Duration::from_millis(1500)).await.unwrap();
+        assert_eq!(viewer.domain_delays().await.unwrap(), vec![DomainDelay { domain: "*.slow.dev".into(), ms: 1500 }]);
+        admin.remove_domain_delay("*.slow.dev").await.unwrap();
+        assert!(viewer.domain_delays().await.unwrap().is_empty());
+
+        let domains = admin.add_project("shop", Ipv4Addr::new(10, 0, 0, 9)).await.unwrap();
+        assert_eq!(domains, vec!["shop.test", "*.shop.test"]);
+        assert_eq!(admin.remove_project("shop").await.unwrap(), domains);
+
+        admin.set_answer_order("app.dev", AnswerOrder::RoundRobin).await.unwrap();
+        assert_eq!(
+            viewer.answer_orders().await.unwrap(),
+            vec![DomainOrder { domain: "app.dev".into(), order: AnswerOrder::RoundRobin }]
+        );
+        admin.remove_answer_order("app.dev").await.unwrap();
+        assert!(viewer.answer_orders().await.unwrap().is_empty());
+
+        server.shutdown().await;
+    }
+}
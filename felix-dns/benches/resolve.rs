@@ -0,0 +1,68 @@
+//! `cargo bench -p felix-dns`: the workloads of `felix_dns::bench` at a
+//! few table sizes.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use felix_dns::bench;
+
+const SIZES: [usize; 3] = [100, 10_000, 100_000];
+const QUERIES: usize = 1024;
+
+fn domain_map(c: &mut Criterion) {
+    let mut group = c.benchmark_group("domain_map");
+    for n in SIZES {
+        let map = bench::domain_map(n);
+        let queries = bench::queries(n, QUERIES);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter(|| {
+                i = (i + 1) % queries.len();
+                map.resolve(&queries[i])
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_store(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("sqlite_store");
+    for n in SIZES {
+        let path = std::env::temp_dir().join(format!("felix-bench-{}-{}.db", std::process::id(), n));
+        let path = path.to_str().unwrap().to_string();
+        let store = runtime.block_on(bench::sqlite_store(&path, n)).unwrap();
+        let queries = bench::queries(n, QUERIES);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.to_async(&runtime).iter(|| {
+                i = (i + 1) % queries.len();
+                let (store, name) = (&store, &queries[i]);
+                async move { store.resolve(name).await.unwrap() }
+            })
+        });
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_store(_: &mut Criterion) {}
+
+fn udp_round_trip(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let n = 10_000;
+    let mut server = runtime.block_on(bench::UdpBench::start(n)).unwrap();
+    let queries = bench::queries(n, QUERIES);
+    let mut i = 0;
+    c.bench_function("udp_round_trip", |b| {
+        b.iter(|| {
+            i = (i + 1) % queries.len();
+            runtime.block_on(server.query(&queries[i])).unwrap()
+        })
+    });
+    runtime.block_on(server.shutdown());
+}
+
+criterion_group!(benches, domain_map, sqlite_store, udp_round_trip);
+criterion_main!(benches);
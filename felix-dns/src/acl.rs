@@ -0,0 +1,93 @@
+//! Which clients may query at all, for listeners bound beyond loopback.
+//!
+//! The `acl` stage runs first and refuses any client outside the allowed
+//! networks. An empty list allows everyone, which is the default.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    Result,
+    events::QueryOutcome,
+    pipeline::{Answer, QueryContext, Stage},
+    proto,
+};
+
+/// An address block such as `192.168.1.0/24`; a bare address is a
+/// single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl ClientNet {
+    /// `None` when `prefix` is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix <= bits).then_some(Self { addr: addr.to_canonical(), prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for ClientNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address {:?}", addr))?;
+        let prefix = match prefix {
+            Some(p) => p.parse().map_err(|_| format!("invalid prefix length {:?}", p))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix).ok_or_else(|| format!("prefix /{} is too long for {}", prefix, addr))
+    }
+}
+
+impl fmt::Display for ClientNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Refuses queries from clients outside `ResolverState::allowed_clients`.
+pub struct AclStage;
+
+#[async_trait]
+impl Stage for AclStage {
+    fn name(&self) -> &'static str {
+        "acl"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let allowed = ctx.state.allowed_clients();
+        let client = ctx.client.ip();
+        if allowed.is_empty() || allowed.iter().any(|net| net.contains(client)) {
+            return Ok(None);
+        }
+        log::debug!("Refused {} from {}: not an allowed client", ctx.qname, client);
+        Ok(Some(Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused)))
+    }
+}
@@ -0,0 +1,176 @@
+//! ACME DNS-01 support: challenge TXT records at `_acme-challenge.<domain>`,
+//! set through the management API or, when a TSIG key is configured,
+//! RFC 2136 dynamic updates, so local ACME servers (pebble, step-ca) can
+//! validate against felix.
+
+use std::{fmt, str::FromStr};
+
+use async_trait::async_trait;
+use data_encoding::BASE64;
+use hickory_proto::{
+    dnssec::{
+        rdata::tsig::{TSIG, TsigAlgorithm, make_tsig_record, message_tbs},
+        tsig::TSigner,
+    },
+    op::{Message, OpCode, ResponseCode},
+    rr::{DNSClass, Name, RData, Record, RecordType},
+};
+
+use crate::{
+    ResolverState, Result,
+    events::QueryOutcome,
+    pipeline::{Answer, QueryContext, Response, Stage},
+    proto,
+};
+
+/// Seconds a signed update's clock may be off from ours.
+const TSIG_FUDGE: u16 = 300;
+
+/// TTL of challenge records, short so validators never see stale values.
+pub const CHALLENGE_TTL: u32 = 10;
+
+/// Where the challenge for a certificate name lives. `*.example.dev` shares
+/// `_acme-challenge.example.dev` with `example.dev`; challenge names are
+/// returned as they are.
+pub fn challenge_name(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let domain = domain.strip_prefix("*.").unwrap_or(&domain);
+    if is_challenge_name(domain) {
+        domain.to_string()
+    } else {
+        format!("_acme-challenge.{}", domain)
+    }
+}
+
+fn is_challenge_name(name: &str) -> bool {
+    name.to_ascii_lowercase().starts_with("_acme-challenge.")
+}
+
+/// The TSIG key RFC 2136 updates must be signed with, HMAC-SHA256 as
+/// certbot's rfc2136 plugin uses by default. Written `NAME:BASE64SECRET`.
+#[derive(Clone)]
+pub struct UpdateKey {
+    signer: TSigner,
+}
+
+impl UpdateKey {
+    pub fn new(name: &str, secret: Vec<u8>) -> std::result::Result<Self, String> {
+        if secret.is_empty() {
+            return Err("empty TSIG secret".into());
+        }
+        let name = Name::from_utf8(name).map_err(|e| format!("invalid key name {:?}: {}", name, e))?;
+        let signer = TSigner::new(secret, TsigAlgorithm::HmacSha256, name, TSIG_FUDGE).map_err(|e| e.to_string())?;
+        Ok(Self { signer })
+    }
+
+    pub fn name(&self) -> String {
+        self.signer.signer_name().to_string()
+    }
+
+    /// Checks the TSIG record closing `packet` and returns its MAC, which
+    /// the signed reply covers.
+    fn verify(&self, packet: &[u8], now: u64) -> std::result::Result<Vec<u8>, String> {
+        let (mac, valid, _) = self.signer.verify_message_byte(None, packet, true).map_err(|e| e.to_string())?;
+        if !valid.contains(&now) {
+            return Err("signature time outside the allowed window".into());
+        }
+        Ok(mac)
+    }
+
+    /// Encodes `resp` with a TSIG record answering the request signed with
+    /// `request_mac` (RFC 8945 5.3).
+    fn sign(&self, mut resp: Message, request_mac: &[u8], now: u64) -> Result<Vec<u8>> {
+        let pre_tsig = TSIG::new(TsigAlgorithm::HmacSha256, now, TSIG_FUDGE, Vec::new(), resp.id(), 0, Vec::new());
+        let tbs = message_tbs(Some(request_mac), &resp, &pre_tsig, self.signer.signer_name())?;
+        let mac = self.signer.sign(&tbs).map_err(|e| hickory_proto::ProtoError::from(e.to_string()))?;
+        resp.add_tsig(make_tsig_record(self.signer.signer_name().clone(), pre_tsig.set_mac(mac)));
+        proto::encode(&resp)
+    }
+}
+
+impl FromStr for UpdateKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, secret) = s.split_once(':').ok_or("expected NAME:BASE64SECRET")?;
+        let secret = BASE64.decode(secret.as_bytes()).map_err(|e| format!("invalid base64 secret: {}", e))?;
+        Self::new(name, secret)
+    }
+}
+
+impl fmt::Debug for UpdateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpdateKey").field("name", &self.name()).finish_non_exhaustive()
+    }
+}
+
+/// Applies RFC 2136 updates that only touch `_acme-challenge` TXT records,
+/// e.g. from certbot's rfc2136 plugin. Only runs once a key is set with
+/// `ResolverState::set_acme_update_key`; unsigned or badly signed updates
+/// get NOTAUTH and change nothing. Prerequisites are ignored.
+pub struct AcmeUpdateStage;
+
+#[async_trait]
+impl Stage for AcmeUpdateStage {
+    fn name(&self) -> &'static str {
+        "acme-update"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        if ctx.request.op_code() != OpCode::Update {
+            return Ok(None);
+        }
+        let Some(key) = ctx.state.acme_update_key() else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let request_mac = match key.verify(&ctx.raw, now) {
+            Ok(mac) => mac,
+            Err(e) => {
+                log::warn!("Rejected DNS update from {}: {}", ctx.client, e);
+                let mut resp = ctx.reply_with_code(ResponseCode::NotAuth);
+                resp.set_op_code(OpCode::Update);
+                return Ok(Some(Answer::new(resp, QueryOutcome::Refused)));
+            }
+        };
+
+        let code = apply_update(&ctx.state, ctx.request.name_servers()).err().unwrap_or(ResponseCode::NoError);
+        log::info!("DNS update from {}: {}", ctx.client, code);
+
+        let mut resp = ctx.reply_with_code(code);
+        resp.set_op_code(OpCode::Update);
+        let outcome = if code == ResponseCode::NoError { QueryOutcome::Local } else { QueryOutcome::Refused };
+        Ok(Some(Answer {
+            response: Response::Raw(key.sign(resp, &request_mac, now)?),
+            outcome,
+            source: crate::events::AnswerSource::Synthesized,
+        }))
+    }
+}
+
+fn apply_update(state: &ResolverState, updates: &[Record]) -> std::result::Result<(), ResponseCode> {
+    // check everything first so a rejected update changes nothing
+    for record in updates {
+        let rtype = record.record_type();
+        let deletes_all = record.dns_class() == DNSClass::ANY && rtype == RecordType::ANY;
+        if !is_challenge_name(&record.name().to_utf8()) || !(rtype == RecordType::TXT || deletes_all) {
+            return Err(ResponseCode::Refused);
+        }
+    }
+
+    for record in updates {
+        let name = record.name().to_utf8();
+        let result = match (record.dns_class(), record.data()) {
+            (DNSClass::IN, RData::TXT(txt)) => state.set_acme_challenge(&name, &txt.to_string()),
+            (DNSClass::NONE, RData::TXT(txt)) => state.clear_acme_challenge(&name, Some(&txt.to_string())),
+            (DNSClass::ANY, _) => state.clear_acme_challenge(&name, None),
+            _ => return Err(ResponseCode::FormErr),
+        };
+        result.map_err(|e| {
+            log::warn!("Applying update for {} failed: {}", name, e);
+            ResponseCode::ServFail
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Alerts for a felix losing its upstream: an upstream is marked down
+//! after `AlertConfig::down_after` forwards to it in a row fail, and up
+//! again with its first answer; separately, the share of clients getting
+//! SERVFAIL is checked once per `AlertConfig::window`.
+//!
+//! Windows are counted from when felix started, on atomic counters, so
+//! recording a query takes no lock: the first query of a new window
+//! closes the one before and judges its rate.
+//!
+//! Every transition is an `UpstreamEvent`, streamed on the management
+//! API's `/alerts` and, with the `webhooks` feature, POSTed to the
+//! configured URLs (see `webhooks`).
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast, time::Instant};
+
+use crate::events::{AnswerSource, QueryOutcome};
+
+const ALERT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertConfig {
+    /// Failed forwards in a row after which an upstream is down.
+    pub down_after: u32,
+    /// Share of SERVFAIL answers, 0.0 to 1.0, at which the rate is high.
+    pub servfail_rate: f64,
+    /// Length of the windows the SERVFAIL rate is checked over, each
+    /// judged by the first query after it.
+    pub window: Duration,
+    /// Fewer queries in a window leave the rate state as it was.
+    pub min_queries: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self { down_after: 3, servfail_rate: 0.5, window: Duration::from_secs(60), min_queries: 20 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamEvent {
+    /// The last `failures` forwards to `upstream` failed.
+    UpstreamDown { upstream: SocketAddr, failures: u32 },
+    /// The first answer from `upstream` since it went down.
+    UpstreamUp { upstream: SocketAddr },
+    /// `rate` of the `queries` of the last window got SERVFAIL, at or
+    /// over the threshold.
+    ServfailRateHigh { rate: f64, queries: u64 },
+    /// The rate is below the threshold again.
+    ServfailRateNormal { rate: f64, queries: u64 },
+}
+
+impl UpstreamEvent {
+    /// One line for logs and chat notifications.
+    pub fn summary(&self) -> String {
+        match self {
+            UpstreamEvent::UpstreamDown { upstream, failures } => {
+                format!("Upstream {} is down after {} failed queries", upstream, failures)
+            }
+            UpstreamEvent::UpstreamUp { upstream } => format!("Upstream {} is answering again", upstream),
+            UpstreamEvent::ServfailRateHigh { rate, queries } => {
+                format!("{:.0}% of the last {} queries got SERVFAIL", rate * 100.0, queries)
+            }
+            UpstreamEvent::ServfailRateNormal { rate, queries } => {
+                format!("SERVFAIL rate is back to {:.0}% of {} queries", rate * 100.0, queries)
+            }
+        }
+    }
+}
+
+/// Query and SERVFAIL counts of the window being counted and the one
+/// before, in the slot of the window's number modulo two. The slot of the
+/// next window is emptied when the previous one is closed.
+struct Windows {
+    started: Instant,
+    /// Number of the window being counted.
+    current: AtomicU64,
+    queries: [AtomicU64; 2],
+    servfails: [AtomicU64; 2],
+    high: AtomicBool,
+}
+
+impl Windows {
+    fn number(&self, window: Duration) -> u64 {
+        (self.started.elapsed().as_millis() / window.as_millis().max(1)) as u64
+    }
+}
+
+pub(crate) struct UpstreamMonitor {
+    config: RwLock<Arc<AlertConfig>>,
+    /// Failed forwards in a row, by upstream; down once at `down_after`.
+    failures: RwLock<HashMap<SocketAddr, u32>>,
+    windows: Windows,
+    events: broadcast::Sender<UpstreamEvent>,
+}
+
+impl Default for UpstreamMonitor {
+    fn default() -> Self {
+        Self {
+            config: RwLock::new(Arc::new(AlertConfig::default())),
+            failures: RwLock::new(HashMap::new()),
+            windows: Windows {
+                started: Instant::now(),
+                current: AtomicU64::new(0),
+                queries: Default::default(),
+                servfails: Default::default(),
+                high: AtomicBool::new(false),
+            },
+            events: broadcast::channel(ALERT_CAPACITY).0,
+        }
+    }
+}
+
+impl UpstreamMonitor {
+    pub fn set_config(&self, config: AlertConfig) {
+        *self.config.write() = Arc::new(config);
+    }
+
+    pub fn config(&self) -> AlertConfig {
+        AlertConfig::clone(&self.config.read())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UpstreamEvent> {
+        self.events.subscribe()
+    }
+
+    /// Upstreams currently down, sorted.
+    pub fn down(&self) -> Vec<SocketAddr> {
+        let down_after = self.config.read().down_after.max(1);
+        let mut down: Vec<SocketAddr> =
+            self.failures.read().iter().filter(|(_, n)| **n >= down_after).map(|(u, _)| *u).collect();
+        down.sort();
+        down
+    }
+
+    /// Counts one answered query; `servfail` is whether the client got
+    /// SERVFAIL, from felix or relayed from the upstream.
+    pub fn record(&self, outcome: QueryOutcome, source: AnswerSource, servfail: bool) {
+        let config = self.config.read().clone();
+        if let AnswerSource::Upstream(upstream) = source {
+            match outcome {
+                QueryOutcome::Forwarded => self.forwarded(upstream, &config),
+                QueryOutcome::ServFail => self.failed(upstream, &config),
+                _ => {}
+            }
+        }
+
+        let windows = &self.windows;
+        let number = windows.number(config.window);
+        let current = windows.current.load(Ordering::Acquire);
+        // one query closes the window; the others count on
+        if number != current
+            && windows.current.compare_exchange(current, number, Ordering::AcqRel, Ordering::Acquire).is_ok()
+        {
+            self.close_window(current, &config);
+        }
+        let slot = (number % 2) as usize;
+        windows.queries[slot].fetch_add(1, Ordering::Relaxed);
+        windows.servfails[slot].fetch_add(u64::from(servfail), Ordering::Relaxed);
+    }
+
+    /// Judges the SERVFAIL rate of window `number` and empties its slot
+    /// for the window after next.
+    fn close_window(&self, number: u64, config: &AlertConfig) {
+        let windows = &self.windows;
+        let slot = (number % 2) as usize;
+        let queries = windows.queries[slot].swap(0, Ordering::Relaxed);
+        let servfails = windows.servfails[slot].swap(0, Ordering::Relaxed);
+        if queries < config.min_queries.max(1) {
+            return;
+        }
+        let rate = servfails as f64 / queries as f64;
+        let high = rate >= config.servfail_rate;
+        if windows.high.swap(high, Ordering::Relaxed) != high {
+            self.publish(if high {
+                UpstreamEvent::ServfailRateHigh { rate, queries }
+            } else {
+                UpstreamEvent::ServfailRateNormal { rate, queries }
+            });
+        }
+    }
+
+    fn forwarded(&self, upstream: SocketAddr, config: &AlertConfig) {
+        // nearly always nothing to clear
+        if !self.failures.read().contains_key(&upstream) {
+            return;
+        }
+        let was_down = self.failures.write().remove(&upstream).is_some_and(|n| n >= config.down_after.max(1));
+        if was_down {
+            self.publish(UpstreamEvent::UpstreamUp { upstream });
+        }
+    }
+
+    fn failed(&self, upstream: SocketAddr, config: &AlertConfig) {
+        let failures = {
+            let mut all = self.failures.write();
+            let n = all.entry(upstream).or_default();
+            *n = n.saturating_add(1);
+            *n
+        };
+        if failures == config.down_after.max(1) {
+            self.publish(UpstreamEvent::UpstreamDown { upstream, failures });
+        }
+    }
+
+    fn publish(&self, event: UpstreamEvent) {
+        match event {
+            UpstreamEvent::UpstreamDown { .. } | UpstreamEvent::ServfailRateHigh { .. } => log::warn!("{}", event.summary()),
+            _ => log::info!("{}", event.summary()),
+        }
+        let _ = self.events.send(event);
+    }
+}
@@ -0,0 +1,114 @@
+//! Order of the addresses in local answers for names with several of them,
+//! e.g. a mapping with extra addresses from
+//! `ResolverState::add_domain_address`, or several AAAA records.
+//!
+//! Addresses are answered in the order they were added unless an
+//! `AnswerOrder` is set for the mapping with
+//! `ResolverState::set_answer_order`. A and AAAA records are reordered
+//! separately; other records keep their place.
+
+use std::{collections::HashMap, sync::Arc};
+
+use hickory_proto::rr::{Record, RecordType};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{cow_map::CowMap, rng::XorShift};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnswerOrder {
+    /// The order the addresses were added in, mapping first, so tests see
+    /// the same answer every time.
+    #[default]
+    Fixed,
+    /// A random permutation per answer.
+    Shuffle,
+    /// Rotate by one address per answer, spreading clients over all of
+    /// them.
+    RoundRobin,
+}
+
+#[derive(Clone)]
+pub(crate) struct AnswerOrders {
+    /// Normalized mapping key (or `*.suffix`) to its strategy.
+    orders: CowMap<String, AnswerOrder>,
+    /// Answers given so far per round-robin key.
+    turns: Arc<Mutex<HashMap<String, usize>>>,
+    rng: Arc<XorShift>,
+}
+
+impl Default for AnswerOrders {
+    fn default() -> Self {
+        Self { orders: CowMap::default(), turns: Default::default(), rng: Arc::new(XorShift::random()) }
+    }
+}
+
+impl AnswerOrders {
+    pub fn set(&self, key: String, order: AnswerOrder) {
+        self.orders.set(key, order);
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.turns.lock().remove(key);
+        self.orders.remove(key);
+    }
+
+    /// The first of `keys` with a strategy, and the strategy.
+    pub fn find(&self, keys: &[String]) -> Option<(String, AnswerOrder)> {
+        let orders = self.orders.snapshot();
+        if orders.is_empty() {
+            return None;
+        }
+        keys.iter().find_map(|k| orders.get(k).map(|order| (k.clone(), *order)))
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn all(&self) -> Arc<HashMap<String, AnswerOrder>> {
+        self.orders.snapshot()
+    }
+
+    /// Replaces every strategy, starting round-robin over.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_all(&self, orders: Arc<HashMap<String, AnswerOrder>>) {
+        self.turns.lock().clear();
+        self.orders.restore(orders);
+    }
+
+    /// Sorted by key.
+    pub fn list(&self) -> Vec<(String, AnswerOrder)> {
+        self.orders.sorted()
+    }
+
+    /// Reorders the addresses among `answers` for the mapping `key`.
+    pub fn apply(&self, key: &str, order: AnswerOrder, answers: &mut [Record]) {
+        let turn = match order {
+            AnswerOrder::Fixed => return,
+            AnswerOrder::Shuffle => 0,
+            AnswerOrder::RoundRobin => {
+                let mut turns = self.turns.lock();
+                let turn = turns.entry(key.to_string()).or_default();
+                *turn = turn.wrapping_add(1);
+                *turn - 1
+            }
+        };
+        for rtype in [RecordType::A, RecordType::AAAA] {
+            let slots: Vec<usize> = (0..answers.len()).filter(|&i| answers[i].record_type() == rtype).collect();
+            if slots.len() < 2 {
+                continue;
+            }
+            let mut records: Vec<Record> = slots.iter().map(|&i| answers[i].clone()).collect();
+            match order {
+                AnswerOrder::Shuffle => {
+                    for i in (1..records.len()).rev() {
+                        records.swap(i, (self.rng.next() % (i as u64 + 1)) as usize);
+                    }
+                }
+                _ => records.rotate_left(turn % slots.len()),
+            }
+            for (slot, record) in slots.into_iter().zip(records) {
+                answers[slot] = record;
+            }
+        }
+    }
+}
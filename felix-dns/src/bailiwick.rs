@@ -0,0 +1,100 @@
+//! Bailiwick filtering of upstream answers, a basic defence against cache
+//! poisoning: records an upstream has no business sending for the
+//! question are dropped before the answer is cached or relayed.
+//!
+//! Upstreams are recursive resolvers, so answers may legitimately follow
+//! CNAMEs into other zones. What is kept:
+//!
+//! - answers owned by the question name or a CNAME target reached from it,
+//!   and DNAMEs owned by a zone enclosing one of those names;
+//! - authority records owned by a zone enclosing one of those names (the
+//!   SOA of a negative answer, delegation NS records);
+//! - DNSSEC denial records (NSEC, NSEC3 and their RRSIGs) in the authority
+//!   section that lie under the zone of a kept SOA or NS record, or whose
+//!   parent encloses one of those names;
+//! - additional records for those names or the targets of kept NS, MX and
+//!   SRV records.
+
+use hickory_proto::{
+    op::Message,
+    rr::{Name, RData, Record, RecordType},
+};
+
+/// hickory has no type of its own for DNAME (RFC 6672).
+const DNAME: RecordType = RecordType::Unknown(39);
+
+/// Drops out-of-bailiwick records from `msg`, returning how many. Messages
+/// without a question are left alone.
+pub(crate) fn strip_out_of_bailiwick(msg: &mut Message) -> usize {
+    let Some(query) = msg.queries().first() else { return 0 };
+    let mut chain = vec![query.name().clone()];
+
+    let mut answers = msg.take_answers();
+    // CNAMEs may come in any order; follow them until the chain stops growing
+    let mut kept = vec![false; answers.len()];
+    loop {
+        let mut grew = false;
+        for (record, keep) in answers.iter().zip(kept.iter_mut()) {
+            let owned = chain.contains(record.name())
+                || (record.record_type() == DNAME && chain.iter().any(|name| record.name().zone_of(name)));
+            if *keep || !owned {
+                continue;
+            }
+            *keep = true;
+            if let RData::CNAME(target) = record.data()
+                && !chain.contains(&target.0)
+            {
+                chain.push(target.0.clone());
+                grew = true;
+            }
+        }
+        if !grew {
+            break;
+        }
+    }
+    let mut stripped = kept.iter().filter(|k| !**k).count();
+    let mut keep = kept.into_iter();
+    answers.retain(|_| keep.next().unwrap_or(false));
+
+    let mut name_servers = msg.take_name_servers();
+    let before = name_servers.len();
+    let encloses = |owner: &Name| chain.iter().any(|name| owner.zone_of(name));
+    let zones: Vec<Name> = name_servers
+        .iter()
+        .filter(|r| matches!(r.record_type(), RecordType::SOA | RecordType::NS) && encloses(r.name()))
+        .map(|r| r.name().clone())
+        .collect();
+    name_servers.retain(|r| {
+        encloses(r.name())
+            || (is_denial(r)
+                && (zones.iter().any(|zone| zone.zone_of(r.name())) || encloses(&r.name().base_name())))
+    });
+    stripped += before - name_servers.len();
+
+    let mut targets = chain;
+    targets.extend(answers.iter().chain(&name_servers).filter_map(target));
+    let mut additionals = msg.take_additionals();
+    let before = additionals.len();
+    additionals.retain(|r| targets.contains(r.name()));
+    stripped += before - additionals.len();
+
+    msg.insert_answers(answers);
+    msg.insert_name_servers(name_servers);
+    msg.insert_additionals(additionals);
+    stripped
+}
+
+/// Proof of non-existence, or a signature that may cover one.
+fn is_denial(record: &Record) -> bool {
+    matches!(record.record_type(), RecordType::NSEC | RecordType::NSEC3 | RecordType::RRSIG)
+}
+
+/// The host a record points at, whose addresses may follow as glue.
+fn target(record: &Record) -> Option<Name> {
+    match record.data() {
+        RData::NS(ns) => Some(ns.0.clone()),
+        RData::MX(mx) => Some(mx.exchange().clone()),
+        RData::SRV(srv) => Some(srv.target().clone()),
+        _ => None,
+    }
+}
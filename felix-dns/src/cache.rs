@@ -0,0 +1,276 @@
+//! Cache of forwarded answers, keyed by name and query type.
+//!
+//! Entries live for the smallest TTL in the answer (for negative answers,
+//! the SOA minimum). Popular entries are refreshed from upstream during the
+//! last tenth of their lifetime, so clients asking for them keep hitting
+//! the cache instead of waiting on a miss.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+// tokio's clock, so a paused test runtime controls expiry
+use tokio::time::Instant;
+
+use crate::name::NormalizedName;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Most entries kept; 0 disables the cache.
+    pub max_entries: usize,
+    /// Hits within one TTL after which an entry is prefetched before it
+    /// expires; 0 disables prefetching.
+    pub prefetch_hits: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { max_entries: 10_000, prefetch_hits: 3 }
+    }
+}
+
+/// One cached answer, as listed by the management API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub name: String,
+    pub qtype: String,
+    pub upstream: SocketAddr,
+    /// Seconds until the entry expires.
+    pub ttl: u32,
+    /// Hits since the entry was stored or last refreshed.
+    pub hits: u32,
+    pub rcode: String,
+    /// Answer records as `TYPE RDATA`.
+    pub answers: Vec<String>,
+}
+
+type Key = (String, RecordType);
+
+struct Entry {
+    response: Message,
+    upstream: SocketAddr,
+    stored: Instant,
+    expires: Instant,
+    ttl: u32,
+    hits: u32,
+    prefetching: bool,
+}
+
+impl Entry {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        Duration::from_secs(self.ttl.into()).checked_sub(now.duration_since(self.stored))
+    }
+}
+
+/// A cache hit: the stored answer with TTLs counted down.
+pub(crate) struct Hit {
+    pub response: Message,
+    /// The caller should refresh the entry from upstream.
+    pub prefetch: bool,
+}
+
+/// Entries plus an index of them by expiry, so a full cache evicts the
+/// entry closest to expiring without scanning the rest.
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    expiry: BTreeSet<(Instant, Key)>,
+}
+
+impl Entries {
+    fn insert(&mut self, key: Key, entry: Entry) {
+        self.expiry.insert((entry.expires, key.clone()));
+        if let Some(old) = self.map.insert(key.clone(), entry) {
+            self.expiry.remove(&(old.expires, key));
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.expiry.remove(&(entry.expires, key.clone()));
+        Some(entry)
+    }
+
+    /// Drops the entry that expires first; already expired ones go before
+    /// any live entry.
+    fn evict_one(&mut self) {
+        if let Some((_, key)) = self.expiry.pop_first() {
+            self.map.remove(&key);
+        }
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Key) -> bool) {
+        self.map.retain(|key, _| keep(key));
+        self.expiry.retain(|(_, key)| keep(key));
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.expiry.clear();
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ResponseCache {
+    entries: Arc<Mutex<Entries>>,
+    config: Arc<RwLock<Arc<CacheConfig>>>,
+}
+
+impl ResponseCache {
+    pub(crate) fn set_config(&self, config: CacheConfig) {
+        if config.max_entries == 0 {
+            self.entries.lock().clear();
+        }
+        *self.config.write() = Arc::new(config);
+    }
+
+    pub(crate) fn config(&self) -> Arc<CacheConfig> {
+        self.config.read().clone()
+    }
+
+    /// The answer cached for `name`/`qtype` from `upstream`, if still fresh.
+    pub(crate) fn get(&self, name: &str, qtype: RecordType, upstream: SocketAddr) -> Option<Hit> {
+        let prefetch_hits = self.config().prefetch_hits;
+        let now = Instant::now();
+        let key = (cache_key(name), qtype);
+
+        let mut entries = self.entries.lock();
+        let entry = entries.map.get_mut(&key)?;
+        let Some(remaining) = entry.remaining(now) else {
+            entries.remove(&key);
+            return None;
+        };
+        if entry.upstream != upstream {
+            return None;
+        }
+
+        entry.hits += 1;
+        let prefetch = prefetch_hits > 0
+            && entry.hits >= prefetch_hits
+            && !entry.prefetching
+            && remaining.as_secs() * 10 <= u64::from(entry.ttl);
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        let elapsed = entry.ttl - remaining.as_secs() as u32;
+        Some(Hit { response: age(&entry.response, elapsed), prefetch })
+    }
+
+    /// Stores an upstream answer, replacing any earlier one. Answers that are
+    /// not NOERROR/NXDOMAIN, truncated, or have nothing to take a TTL from
+    /// are not cached. Returns whether the answer was stored.
+    pub(crate) fn insert(&self, name: &str, qtype: RecordType, upstream: SocketAddr, response: Message) -> bool {
+        let max_entries = self.config().max_entries;
+        if max_entries == 0 {
+            return false;
+        }
+        let Some(ttl) = cacheable_ttl(&response) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let key = (cache_key(name), qtype);
+        if !entries.map.contains_key(&key) {
+            // a lowered limit can leave more than one entry to drop
+            while entries.map.len() >= max_entries {
+                entries.evict_one();
+            }
+        }
+        let expires = now + Duration::from_secs(ttl.into());
+        entries.insert(key, Entry { response, upstream, stored: now, expires, ttl, hits: 0, prefetching: false });
+        true
+    }
+
+    /// Unexpired entries, sorted by name and type.
+    pub(crate) fn entries(&self) -> Vec<CacheEntry> {
+        let now = Instant::now();
+        let mut listed: Vec<CacheEntry> = self
+            .entries
+            .lock()
+            .map
+            .iter()
+            .filter_map(|((name, qtype), e)| {
+                Some(CacheEntry {
+                    name: name.clone(),
+                    qtype: qtype.to_string(),
+                    upstream: e.upstream,
+                    ttl: e.remaining(now)?.as_secs() as u32,
+                    hits: e.hits,
+                    rcode: e.response.response_code().to_string(),
+                    answers: e.response.answers().iter().map(|r| format!("{} {}", r.record_type(), r.data())).collect(),
+                })
+            })
+            .collect();
+        listed.sort_by(|a, b| (&a.name, &a.qtype).cmp(&(&b.name, &b.qtype)));
+        listed
+    }
+
+    /// Drops every entry for `name` (all types), or everything with `None`.
+    /// Returns how many entries were dropped.
+    pub(crate) fn flush(&self, name: Option<&str>) -> usize {
+        let mut entries = self.entries.lock();
+        let before = entries.map.len();
+        match name {
+            Some(name) => {
+                let name = cache_key(name);
+                entries.retain(|(n, _)| *n != name);
+            }
+            None => entries.clear(),
+        }
+        before - entries.map.len()
+    }
+
+    /// Lets a later hit retry a prefetch that failed.
+    pub(crate) fn prefetch_failed(&self, name: &str, qtype: RecordType) {
+        if let Some(entry) = self.entries.lock().map.get_mut(&(cache_key(name), qtype)) {
+            entry.prefetching = false;
+        }
+    }
+}
+
+fn cache_key(name: &str) -> String {
+    NormalizedName::new(name).into_string()
+}
+
+fn cacheable_ttl(response: &Message) -> Option<u32> {
+    if response.truncated() {
+        return None;
+    }
+    match response.response_code() {
+        ResponseCode::NoError if !response.answers().is_empty() => {
+            response.answers().iter().chain(response.name_servers()).map(Record::ttl).min()
+        }
+        // negative answers live as long as the SOA says (RFC 2308 section 5)
+        ResponseCode::NoError | ResponseCode::NXDomain => response.name_servers().iter().find_map(|r| match r.data() {
+            RData::SOA(soa) => Some(r.ttl().min(soa.minimum())),
+            _ => None,
+        }),
+        _ => None,
+    }
+    .filter(|ttl| *ttl > 0)
+}
+
+fn age(response: &Message, elapsed: u32) -> Message {
+    let mut msg = response.clone();
+    let mut answers = msg.take_answers();
+    let mut authority = msg.take_name_servers();
+    let mut additionals = msg.take_additionals();
+    for record in answers.iter_mut().chain(authority.iter_mut()).chain(additionals.iter_mut()) {
+        record.set_ttl(record.ttl().saturating_sub(elapsed));
+    }
+    msg.insert_answers(answers);
+    msg.insert_name_servers(authority);
+    msg.insert_additionals(additionals);
+    msg
+}
@@ -0,0 +1,165 @@
+//! Per-client query counts and daily quotas, for small shared networks
+//! where one chatty device should not use up everyone's upstream.
+//!
+//! The `quota` stage counts every query by client address. A client over
+//! its quota gets REFUSED until the day rolls over; days are counted in
+//! the state's `PolicyTimeZone`.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    Result,
+    events::QueryOutcome,
+    pipeline::{Answer, QueryContext, Stage},
+    proto,
+};
+
+/// Clients tracked at once. Usage is split over `SHARDS` independently
+/// locked maps so busy clients do not queue behind each other.
+const MAX_CLIENTS: usize = 4096;
+const SHARDS: usize = 16;
+const SHARD_CAPACITY: usize = MAX_CLIENTS / SHARDS;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Queries a client may make per day; `None` is unlimited.
+    pub daily: Option<u64>,
+    /// Overrides `daily` for single clients.
+    pub per_client: HashMap<IpAddr, u64>,
+}
+
+impl QuotaConfig {
+    pub fn limit(&self, client: IpAddr) -> Option<u64> {
+        self.per_client.get(&client).copied().or(self.daily)
+    }
+}
+
+/// One client's usage, as listed by the management API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub client: IpAddr,
+    pub queries_today: u64,
+    /// Queries refused today for being over quota.
+    pub refused_today: u64,
+    /// Since the client was first seen or felix started.
+    pub queries_total: u64,
+    pub quota: Option<u64>,
+    /// Seconds since the last query.
+    pub idle: u64,
+}
+
+struct Usage {
+    day: NaiveDate,
+    today: u64,
+    refused: u64,
+    total: u64,
+    last_seen: Instant,
+}
+
+type Shard = Mutex<HashMap<IpAddr, Usage>>;
+
+#[derive(Clone, Default)]
+pub(crate) struct ClientTracker {
+    shards: Arc<[Shard; SHARDS]>,
+    hasher: RandomState,
+    quotas: Arc<RwLock<Arc<QuotaConfig>>>,
+}
+
+impl ClientTracker {
+    pub fn set_quotas(&self, quotas: QuotaConfig) {
+        *self.quotas.write() = Arc::new(quotas);
+    }
+
+    pub fn quotas(&self) -> Arc<QuotaConfig> {
+        self.quotas.read().clone()
+    }
+
+    fn shard(&self, client: IpAddr) -> &Shard {
+        &self.shards[self.hasher.hash_one(client) as usize % SHARDS]
+    }
+
+    /// Counts a query from `client` on `day`; false when it is over quota.
+    pub fn admit(&self, client: IpAddr, day: NaiveDate) -> bool {
+        let limit = self.quotas.read().limit(client);
+        let now = Instant::now();
+        let mut usage = self.shard(client).lock();
+        if usage.len() >= SHARD_CAPACITY && !usage.contains_key(&client) {
+            evict_idlest(&mut usage);
+        }
+
+        let u = usage.entry(client).or_insert(Usage { day, today: 0, refused: 0, total: 0, last_seen: now });
+        if u.day != day {
+            (u.day, u.today, u.refused) = (day, 0, 0);
+        }
+        u.last_seen = now;
+        u.total += 1;
+        u.today += 1;
+        let admitted = limit.is_none_or(|limit| u.today <= limit);
+        if !admitted {
+            u.refused += 1;
+        }
+        admitted
+    }
+
+    /// Usage by client, busiest first. Counts from an earlier day read as
+    /// zero.
+    pub fn stats(&self, day: NaiveDate) -> Vec<ClientStats> {
+        let quotas = self.quotas();
+        let mut stats = Vec::new();
+        for shard in self.shards.iter() {
+            stats.extend(shard.lock().iter().map(|(client, u)| {
+                let current = u.day == day;
+                ClientStats {
+                    client: *client,
+                    queries_today: if current { u.today } else { 0 },
+                    refused_today: if current { u.refused } else { 0 },
+                    queries_total: u.total,
+                    quota: quotas.limit(*client),
+                    idle: u.last_seen.elapsed().as_secs(),
+                }
+            }));
+        }
+        stats.sort_by(|a, b| b.queries_today.cmp(&a.queries_today).then(a.client.cmp(&b.client)));
+        stats
+    }
+}
+
+/// Drops the quarter of a full shard seen least recently, so the scan is
+/// paid once per `SHARD_CAPACITY / 4` new clients rather than for each.
+fn evict_idlest(usage: &mut HashMap<IpAddr, Usage>) {
+    let mut seen: Vec<Instant> = usage.values().map(|u| u.last_seen).collect();
+    let cut = seen.len() / 4;
+    let (_, &mut cutoff, _) = seen.select_nth_unstable(cut);
+    usage.retain(|_, u| u.last_seen > cutoff);
+}
+
+/// Counts the query against its client and refuses it once the client is
+/// over quota.
+pub struct QuotaStage;
+
+#[async_trait]
+impl Stage for QuotaStage {
+    fn name(&self) -> &'static str {
+        "quota"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let client = ctx.client.ip().to_canonical();
+        if ctx.state.admit_client(client) {
+            return Ok(None);
+        }
+        log::debug!("Refused {} from {}: over daily quota", ctx.qname, client);
+        Ok(Some(Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused)))
+    }
+}
@@ -0,0 +1,69 @@
+//! Overlapping mappings: an exact entry or a narrower wildcard under a
+//! wider wildcard. Both are legal (the more specific entry wins) but often
+//! a leftover, so they are reported when a mapping is added and by
+//! `felix doctor`.
+
+use std::{collections::HashMap, fmt, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Wildcards, candidate_keys};
+
+/// `domain` takes precedence over the wider `wildcard` for the names it
+/// covers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingConflict {
+    pub domain: String,
+    pub ip: Ipv4Addr,
+    pub wildcard: String,
+    pub wildcard_ip: Ipv4Addr,
+}
+
+impl fmt::Display for MappingConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.domain.starts_with('*') { "nested wildcard" } else { "exact entry" };
+        if self.ip == self.wildcard_ip {
+            write!(f, "{} {} repeats {} ({}) and can be removed", kind, self.domain, self.wildcard, self.ip)
+        } else {
+            write!(
+                f,
+                "{} {} ({}) overrides {} ({})",
+                kind, self.domain, self.ip, self.wildcard, self.wildcard_ip
+            )
+        }
+    }
+}
+
+/// The closest wildcard strictly wider than `domain`: the first existing
+/// key after it among the keys a name it covers is looked up by. For
+/// `api.app.dev` that is `*.app.dev`, `**.app.dev`, `**.dev`; for
+/// `*.api.app.dev` it is `**.api.app.dev`, `**.app.dev`, `**.dev`.
+fn enclosing_wildcard<'a>(domain: &str, mappings: &'a HashMap<String, Ipv4Addr>) -> Option<(&'a String, Ipv4Addr)> {
+    // the apex is not "wider" than an exact entry for it
+    let keys = candidate_keys(domain, Wildcards { apex: false });
+    let wider = keys[1..].iter().position(|k| k == domain).map_or(1, |i| i + 2);
+    keys[wider..].iter().find_map(|key| mappings.get_key_value(key)).map(|(key, ip)| (key, *ip))
+}
+
+/// Every overlap in `mappings` (normalized keys), sorted by domain.
+pub fn find_conflicts(mappings: &[(String, Ipv4Addr)]) -> Vec<MappingConflict> {
+    let index: HashMap<String, Ipv4Addr> = mappings.iter().cloned().collect();
+    let mut conflicts: Vec<MappingConflict> = mappings
+        .iter()
+        .filter_map(|(domain, ip)| {
+            let (wildcard, wildcard_ip) = enclosing_wildcard(domain, &index)?;
+            Some(MappingConflict { domain: domain.clone(), ip: *ip, wildcard: wildcard.clone(), wildcard_ip })
+        })
+        .collect();
+    conflicts.sort_by(|a, b| a.domain.cmp(&b.domain));
+    conflicts
+}
+
+/// The overlaps `domain` is part of: the wildcard it sits under and, for a
+/// wildcard, the entries directly under it.
+pub fn conflicts_with(domain: &str, mappings: &[(String, Ipv4Addr)]) -> Vec<MappingConflict> {
+    find_conflicts(mappings)
+        .into_iter()
+        .filter(|c| c.domain == domain || c.wildcard == domain)
+        .collect()
+}
@@ -0,0 +1,89 @@
+//! A map read on every query and written rarely: readers take a cheap
+//! `Arc` of the current version, writers copy it and swap the copy in, so
+//! a lookup never holds the lock while it works.
+
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    sync::Arc,
+};
+
+use parking_lot::RwLock;
+
+pub(crate) struct CowMap<K, V> {
+    current: Arc<RwLock<Arc<HashMap<K, V>>>>,
+}
+
+// derived impls would require `K: Clone` and `V: Clone` for sharing an `Arc`
+impl<K, V> Clone for CowMap<K, V> {
+    fn clone(&self) -> Self {
+        Self { current: self.current.clone() }
+    }
+}
+
+impl<K, V> Default for CowMap<K, V> {
+    fn default() -> Self {
+        Self { current: Arc::new(RwLock::new(Arc::new(HashMap::new()))) }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> CowMap<K, V> {
+    /// The current contents; later writes do not show up in it.
+    pub fn snapshot(&self) -> Arc<HashMap<K, V>> {
+        self.current.read().clone()
+    }
+
+    /// Swaps in `map` wholesale, e.g. one loaded from storage or taken
+    /// with `snapshot`.
+    pub fn restore(&self, map: Arc<HashMap<K, V>>) {
+        *self.current.write() = map;
+    }
+
+    pub fn replace(&self, map: HashMap<K, V>) {
+        self.restore(Arc::new(map));
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.current.read().contains_key(key)
+    }
+
+    /// Returns the value `key` had before.
+    pub fn set(&self, key: K, value: V) -> Option<V> {
+        let mut current = self.current.write();
+        let mut updated = HashMap::clone(&current);
+        let old = updated.insert(key, value);
+        *current = Arc::new(updated);
+        old
+    }
+
+    /// Returns the removed value; the map is left alone when there was none.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut current = self.current.write();
+        if !current.contains_key(key) {
+            return None;
+        }
+        let mut updated = HashMap::clone(&current);
+        let old = updated.remove(key);
+        *current = Arc::new(updated);
+        old
+    }
+
+    /// Every entry, sorted by key.
+    pub fn sorted(&self) -> Vec<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<(K, V)> = self.current.read().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+}
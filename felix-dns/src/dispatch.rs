@@ -0,0 +1,69 @@
+//! The first look at a message, before the query pipeline: which opcodes
+//! felix handles, and what malformed or unsupported messages get back
+//! (RFC 1035 4.1.1, RFC 9619) instead of silence. Only EDNS version 0 is
+//! spoken; anything newer gets BADVERS (RFC 6891 6.1.3).
+
+use hickory_proto::op::{Message, MessageType, OpCode, ResponseCode};
+
+use crate::{ResolverState, proto};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dispatch {
+    /// Run the query pipeline.
+    Pipeline,
+    /// Answer with this code without running the pipeline.
+    Reject(ResponseCode),
+    /// Not a request, e.g. a stray response; dropped without a reply.
+    Ignore,
+}
+
+pub(crate) fn dispatch(msg: &Message, state: &ResolverState) -> Dispatch {
+    if msg.message_type() == MessageType::Response {
+        return Dispatch::Ignore;
+    }
+    if msg.extensions().as_ref().is_some_and(|edns| edns.version() > 0) {
+        return Dispatch::Reject(ResponseCode::BADVERS);
+    }
+    match msg.op_code() {
+        // a query has exactly one question, an update exactly one zone
+        OpCode::Query | OpCode::Update if msg.queries().len() != 1 => Dispatch::Reject(ResponseCode::FormErr),
+        OpCode::Query => Dispatch::Pipeline,
+        // the only updates handled so far are ACME challenges, see `acme`
+        OpCode::Update if state.acme_update_key().is_some() => Dispatch::Pipeline,
+        OpCode::Update | OpCode::Notify | OpCode::Status | OpCode::Unknown(_) => {
+            Dispatch::Reject(ResponseCode::NotImp)
+        }
+    }
+}
+
+/// A reply to `request` carrying only `code`, echoing its id, opcode, RD
+/// flag and question when there is exactly one, plus an OPT record when
+/// the request had one.
+pub(crate) fn reject(request: &Message, code: ResponseCode) -> Message {
+    let mut resp = Message::new();
+    resp.set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_response_code(code);
+    if let [query] = request.queries() {
+        resp.add_query(query.clone());
+    }
+    proto::echo_edns(request, &mut resp);
+    resp
+}
+
+/// FORMERR for a message that did not parse, built from its header alone.
+/// `None` when not even a request header is there to answer.
+pub(crate) fn format_error(packet: &[u8]) -> Option<Vec<u8>> {
+    let header = packet.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let mut reply = vec![0u8; 12];
+    reply[..2].copy_from_slice(&header[..2]);
+    // QR set, opcode and RD kept
+    reply[2] = 0x80 | (header[2] & 0x79);
+    reply[3] = ResponseCode::FormErr.low();
+    Some(reply)
+}
@@ -1,63 +1,70 @@
 use std::{collections::HashMap, net::Ipv4Addr};
 
+use crate::{
+    name::NormalizedName,
+    store::{Wildcards, candidate_keys},
+};
+
+#[derive(Clone)]
 pub struct DomainMap {
     map: HashMap<String, Ipv4Addr>,
+    wildcards: Wildcards,
+}
+
+impl Default for DomainMap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DomainMap {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            wildcards: Wildcards::default(),
         }
     }
 
-    pub fn set(&mut self, domain: impl Into<String>, ip: impl Into<Ipv4Addr>) {
-        let mut k = domain.into();
-        k.make_ascii_lowercase();
-
-        if k.ends_with('.') {
-            k.pop();
-        }
+    /// See `Wildcards::apex`.
+    pub fn set_wildcard_apex(&mut self, enabled: bool) {
+        self.wildcards.apex = enabled;
+    }
 
-        self.map.insert(k, ip.into());
+    pub fn set_wildcards(&mut self, wildcards: Wildcards) {
+        self.wildcards = wildcards;
     }
 
-    pub fn remove(&mut self, domain: &str) {
-        let mut k = domain.to_ascii_lowercase();
-        k.make_ascii_lowercase();
+    pub fn set(&mut self, domain: impl Into<String>, ip: impl Into<Ipv4Addr>) {
+        self.map.insert(NormalizedName::new(&domain.into()).into_string(), ip.into());
+    }
 
-        if k.ends_with('.') {
-            k.pop();
-        }
+    /// Returns the address `domain` was mapped to, if it was.
+    pub fn remove(&mut self, domain: &str) -> Option<Ipv4Addr> {
+        self.map.remove(NormalizedName::new(domain).as_str())
+    }
 
-        self.map.remove(&domain.to_ascii_lowercase());
+    /// The address `domain` itself is mapped to, ignoring wildcards.
+    pub fn get(&self, domain: &str) -> Option<Ipv4Addr> {
+        self.map.get(NormalizedName::new(domain).as_str()).copied()
     }
 
     pub fn resolve(&self, qname: &str) -> Option<Ipv4Addr> {
-        let mut lc = qname.to_ascii_lowercase();
-
-        if lc.ends_with('.') {
-            lc.pop();
-        }
-
-        if let Some(ip) = self.map.get(&lc) {
-            return Some(*ip);
-        }
-
-        let labels: Vec<&str> = qname.split('.').collect();
-        for i in 0..labels.len().saturating_sub(1) {
-            let suffix = labels[i + 1..].join(".");
-            let wildcard = format!("*.{}", suffix);
-
-            if let Some(ip) = self.map.get(&wildcard) {
-                return Some(*ip);
-            }
-        }
+        self.resolve_match(qname).map(|(_, ip)| ip)
+    }
 
-        None
+    /// Like `resolve`, also returning the key that matched (`*.app.dev`
+    /// for a wildcard). Precedence is as in `store::candidate_keys`.
+    pub fn resolve_match(&self, qname: &str) -> Option<(String, Ipv4Addr)> {
+        candidate_keys(&NormalizedName::new(qname), self.wildcards)
+            .into_iter()
+            .find_map(|key| self.map.get(&key).map(|ip| (key, *ip)))
     }
 
     pub fn list(&self) -> Vec<(String, Ipv4Addr)> {
         self.map.iter().map(|(k, v)| (k.clone(), *v)).collect()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
 }
@@ -0,0 +1,212 @@
+use std::{fmt, io, net::{Ipv4Addr, SocketAddr}};
+
+use crate::{management::AccessError, name::NameError, resolver_state::BuildError};
+
+/// Errors returned by felix's public API.
+#[derive(Debug)]
+pub enum Error {
+    /// The domain store failed (connection, query or schema).
+    #[cfg(feature = "sqlite")]
+    Storage(sqlx::Error),
+    /// The redb store failed.
+    #[cfg(feature = "redb")]
+    Redb(Box<redb::Error>),
+    /// A DNS message could not be parsed or encoded.
+    Protocol(hickory_proto::ProtoError),
+    /// The upstream resolver did not answer in time.
+    UpstreamTimeout(SocketAddr),
+    /// The DNS-over-HTTPS fallback failed.
+    #[cfg(feature = "doh")]
+    Doh(reqwest::Error),
+    /// The upstream is this instance's own listener.
+    UpstreamLoop(SocketAddr),
+    /// A CNAME would make local aliases loop or chain too deep; holds the
+    /// chain from the new record's owner.
+    AliasLoop(Vec<String>),
+    /// A domain name was rejected before it reached the store.
+    InvalidDomain(String, NameError),
+    /// A mapping to a public address was refused by the safe-IP policy.
+    PublicAddress(Ipv4Addr),
+    /// A mapping under a real public suffix was refused; holds the domain
+    /// and the suffix.
+    PublicSuffix(String, String),
+    /// A listener could not be bound.
+    Bind(SocketAddr, io::Error),
+    Io(io::Error),
+    /// A management token was missing or lacked the required role.
+    Access(AccessError),
+    /// Invalid builder configuration.
+    Config(BuildError),
+    /// A hook script failed to compile.
+    Script(String),
+    /// A WebAssembly plugin failed to load.
+    Plugin(String),
+    /// The OTLP exporter could not be set up.
+    Telemetry(String),
+    /// A log filter did not parse.
+    LogFilter(String),
+    /// The Raft cluster did not commit a mutation: no leader, a timeout,
+    /// or the leader's error.
+    Cluster(String),
+    /// A view name is empty or not lowercase letters, digits and hyphens.
+    InvalidView(String),
+    /// Log filters cannot be changed because the host application
+    /// installed its own logger.
+    LoggerNotInstalled,
+    /// The local CA or a certificate could not be created or loaded.
+    #[cfg(feature = "tls")]
+    Tls(String),
+    /// The webhook client could not be set up.
+    #[cfg(feature = "webhooks")]
+    Webhook(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+            #[cfg(feature = "redb")]
+            Error::Redb(e) => write!(f, "storage error: {}", e),
+            Error::Protocol(e) => write!(f, "DNS protocol error: {}", e),
+            Error::UpstreamTimeout(addr) => write!(f, "upstream {} timed out", addr),
+            #[cfg(feature = "doh")]
+            Error::Doh(e) => write!(f, "DoH request failed: {}", e),
+            Error::UpstreamLoop(addr) => {
+                write!(f, "upstream {} is this felix instance; forwarding to it would loop", addr)
+            }
+            Error::AliasLoop(chain) if chain[..chain.len() - 1].contains(chain.last().unwrap()) => {
+                write!(f, "CNAME chain loops: {}", chain.join(" -> "))
+            }
+            Error::AliasLoop(chain) => write!(
+                f,
+                "CNAME chain longer than {} links: {}",
+                crate::records::MAX_ALIAS_CHAIN,
+                chain.join(" -> ")
+            ),
+            Error::InvalidDomain(domain, reason) => write!(f, "invalid domain {:?}: {}", domain, reason),
+            Error::PublicAddress(ip) => {
+                write!(f, "{} is a public address; only loopback and private addresses are allowed", ip)
+            }
+            Error::PublicSuffix(domain, suffix) => write!(
+                f,
+                "{} is under the public suffix .{}; use .test, .localhost or .internal, or allow it explicitly",
+                domain, suffix
+            ),
+            Error::Bind(addr, e) => write!(f, "binding {}: {}", addr, e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Access(e) => e.fmt(f),
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            Error::Script(e) => write!(f, "script error: {}", e),
+            Error::Plugin(e) => write!(f, "plugin error: {}", e),
+            Error::Telemetry(e) => write!(f, "telemetry error: {}", e),
+            Error::LogFilter(e) => write!(f, "invalid log filter {}", e),
+            Error::Cluster(e) => write!(f, "cluster error: {}", e),
+            Error::InvalidView(name) => write!(f, "invalid view name {:?}", name),
+            Error::LoggerNotInstalled => write!(f, "log filters can only be changed when felix installed the logger"),
+            #[cfg(feature = "tls")]
+            Error::Tls(e) => write!(f, "TLS error: {}", e),
+            #[cfg(feature = "webhooks")]
+            Error::Webhook(e) => write!(f, "webhook error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Error::Storage(e) => Some(e),
+            #[cfg(feature = "redb")]
+            Error::Redb(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            #[cfg(feature = "doh")]
+            Error::Doh(e) => Some(e),
+            Error::Bind(_, e) | Error::Io(e) => Some(e),
+            Error::Access(e) => Some(e),
+            Error::Config(e) => Some(e),
+            Error::InvalidDomain(_, e) => Some(e),
+            Error::UpstreamTimeout(_) | Error::UpstreamLoop(_) | Error::AliasLoop(_) | Error::PublicAddress(_) | Error::Script(_)
+            | Error::PublicSuffix(..)
+            | Error::Plugin(_)
+            | Error::Telemetry(_)
+            | Error::LogFilter(_)
+            | Error::Cluster(_)
+            | Error::InvalidView(_)
+            | Error::LoggerNotInstalled => None,
+            #[cfg(feature = "tls")]
+            Error::Tls(_) => None,
+            #[cfg(feature = "webhooks")]
+            Error::Webhook(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "doh")]
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Doh(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<hickory_proto::ProtoError> for Error {
+    fn from(e: hickory_proto::ProtoError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<AccessError> for Error {
+    fn from(e: AccessError) -> Self {
+        Error::Access(e)
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(e: BuildError) -> Self {
+        Error::Config(e)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rcgen::Error> for Error {
+    fn from(e: rcgen::Error) -> Self {
+        Error::Tls(e.to_string())
+    }
+}
+
+/// redb reports each operation with its own error type.
+#[cfg(feature = "redb")]
+macro_rules! from_redb {
+    ($($ty:ty),*) => {$(
+        impl From<$ty> for Error {
+            fn from(e: $ty) -> Self {
+                Error::Redb(Box::new(e.into()))
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "redb")]
+from_redb!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
@@ -0,0 +1,102 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryOutcome {
+    /// Answered from the local domain store.
+    Local,
+    /// Relayed to the upstream resolver.
+    Forwarded,
+    /// Answered from the cache of forwarded answers.
+    Cached,
+    /// Upstream failed, client got SERVFAIL.
+    ServFail,
+    /// Refused by policy, client got REFUSED.
+    Refused,
+    /// Blocked by policy, client got an empty answer.
+    Blocked,
+    /// No local answer in local-only mode, client got NXDOMAIN or REFUSED.
+    NotForwarded,
+}
+
+impl QueryOutcome {
+    /// The snake_case name used in JSON and metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QueryOutcome::Local => "local",
+            QueryOutcome::Forwarded => "forwarded",
+            QueryOutcome::Cached => "cached",
+            QueryOutcome::ServFail => "serv_fail",
+            QueryOutcome::Refused => "refused",
+            QueryOutcome::Blocked => "blocked",
+            QueryOutcome::NotForwarded => "not_forwarded",
+        }
+    }
+}
+
+/// Where an answer came from: finer than `QueryOutcome`, for telling a
+/// wildcard hit from an exact one or which upstream was asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnswerSource {
+    /// An exact domain mapping.
+    LocalExact,
+    /// A wildcard domain mapping.
+    LocalWildcard,
+    /// Typed records or ACME challenges, without a domain mapping.
+    LocalRecord,
+    /// A script or WASM plugin decision.
+    Script,
+    /// A cached upstream answer.
+    Cache,
+    /// This upstream, including when it failed and the client got SERVFAIL.
+    Upstream(SocketAddr),
+    /// A policy or type-block rule.
+    Blocklist,
+    /// Made up by felix itself or a custom stage: CHAOS answers, quota and
+    /// local-only refusals, ACME update replies.
+    #[default]
+    Synthesized,
+}
+
+impl fmt::Display for AnswerSource {
+    /// The label used in logs and metrics, e.g. `local-wildcard` or
+    /// `upstream-1.1.1.1:53`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            AnswerSource::LocalExact => "local-exact",
+            AnswerSource::LocalWildcard => "local-wildcard",
+            AnswerSource::LocalRecord => "local-record",
+            AnswerSource::Script => "script",
+            AnswerSource::Cache => "cache",
+            AnswerSource::Upstream(addr) => return write!(f, "upstream-{}", addr),
+            AnswerSource::Blocklist => "blocklist",
+            AnswerSource::Synthesized => "synthesized",
+        };
+        f.write_str(label)
+    }
+}
+
+/// One handled query, as published to `/events` subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryEvent {
+    pub name: String,
+    pub qtype: String,
+    pub client: SocketAddr,
+    pub outcome: QueryOutcome,
+    #[serde(default)]
+    pub source: AnswerSource,
+}
+
+/// A mutation of the domain store, published to `/changes` subscribers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainChange {
+    Set { domain: String, ip: Ipv4Addr },
+    Removed { domain: String },
+}
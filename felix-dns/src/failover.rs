@@ -0,0 +1,226 @@
+//! Escalation for networks that mangle plain DNS: when the UDP answer
+//! from the upstream is truncated or never arrives, the query is retried
+//! over TCP, then with each further upstream in turn, then over
+//! DNS-over-HTTPS if an endpoint is configured, before the client gets
+//! SERVFAIL.
+//!
+//! A truncated UDP answer is still relayed if every fallback fails, so the
+//! client can retry itself.
+//!
+//! A DoH endpoint named by hostname has to be resolved first. When the
+//! system resolver is felix itself, that lookup would go through the very
+//! upstream that just failed, so `DohBootstrap` can resolve it with a
+//! plain resolver of its own or skip resolution with pinned addresses.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use crate::{
+    Error, Result,
+    server_handler::{answers_query, forward_udp},
+    tcp_pipeline::TcpPipeline,
+};
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(feature = "doh")]
+const DOH_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FailoverConfig {
+    /// Retry over TCP to the same upstream.
+    pub tcp: bool,
+    /// Further upstreams asked over UDP, in order, when the upstream fails.
+    pub upstreams: Vec<SocketAddr>,
+    /// Last resort: a DoH endpoint (RFC 8484), e.g.
+    /// `https://1.1.1.1/dns-query`. Ignored without the `doh` feature.
+    pub doh: Option<String>,
+    /// How the DoH endpoint's hostname is resolved. Ignored without the
+    /// `doh` feature, like `doh`.
+    pub doh_bootstrap: DohBootstrap,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DohBootstrap {
+    /// The system resolver, which must not send the query to felix.
+    #[default]
+    System,
+    /// A plain DNS query to this resolver, e.g. `1.1.1.1:53`.
+    Resolver(SocketAddr),
+    /// Connect to these addresses without resolving; the URL's port is
+    /// used.
+    Pinned(Vec<IpAddr>),
+}
+
+impl FailoverConfig {
+    fn is_enabled(&self) -> bool {
+        self.tcp || !self.upstreams.is_empty() || (cfg!(feature = "doh") && self.doh.is_some())
+    }
+}
+
+/// Sends `packet` to `upstream` over UDP, escalating as configured.
+pub(crate) async fn forward(packet: &[u8], upstream: SocketAddr, config: &FailoverConfig) -> Result<Vec<u8>> {
+    let udp = forward_udp(packet, upstream).await;
+    if !config.is_enabled() {
+        return udp;
+    }
+    let reason = match &udp {
+        Ok(reply) if !is_truncated(reply) => return udp,
+        Ok(_) => "truncated".to_string(),
+        Err(e) => e.to_string(),
+    };
+
+    if config.tcp {
+        log::debug!("UDP to {}: {}; retrying over TCP", upstream, reason);
+        match forward_tcp(packet, upstream).await.and_then(|reply| matching(packet, reply)) {
+            Ok(reply) => return Ok(reply),
+            Err(e) => log::debug!("TCP to {} failed: {}", upstream, e),
+        }
+    }
+    for next in config.upstreams.iter().filter(|u| **u != upstream) {
+        log::debug!("{} failed ({}); trying {}", upstream, reason, next);
+        match forward_udp(packet, *next).await {
+            Ok(reply) if !is_truncated(&reply) => return Ok(reply),
+            Ok(_) => log::debug!("UDP answer from {} is truncated too", next),
+            Err(e) => log::debug!("UDP to {} failed: {}", next, e),
+        }
+    }
+    #[cfg(feature = "doh")]
+    if let Some(url) = &config.doh {
+        log::debug!("Plain DNS to {} failed ({}); retrying over DoH at {}", upstream, reason, url);
+        match forward_doh(packet, url, &config.doh_bootstrap).await.and_then(|reply| matching(packet, reply)) {
+            Ok(reply) => return Ok(reply),
+            Err(e) => log::debug!("DoH to {} failed: {}", url, e),
+        }
+    }
+    udp
+}
+
+/// The DoH endpoint's hostname, when the system resolver has to find it.
+#[cfg(feature = "doh")]
+pub(crate) fn system_bootstrapped_host(config: &FailoverConfig) -> Option<String> {
+    if config.doh_bootstrap != DohBootstrap::System {
+        return None;
+    }
+    let url = reqwest::Url::parse(config.doh.as_deref()?).ok()?;
+    let host = url.host_str()?;
+    // IPv6 hosts keep their brackets
+    host.trim_matches(['[', ']']).parse::<IpAddr>().is_err().then(|| host.to_string())
+}
+
+/// `reply` if it answers `query`, as `forward_udp` checks for UDP.
+fn matching(query: &[u8], reply: Vec<u8>) -> Result<Vec<u8>> {
+    if !answers_query(query, &reply) {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "answer has another id or question than the query",
+        )));
+    }
+    Ok(reply)
+}
+
+/// The TC bit of an encoded message.
+fn is_truncated(reply: &[u8]) -> bool {
+    reply.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// One query over the upstream's shared TCP connection, pipelined with
+/// any others in flight.
+async fn forward_tcp(packet: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    static CONNECTIONS: LazyLock<TcpPipeline> = LazyLock::new(TcpPipeline::default);
+    CONNECTIONS.query(packet, upstream, TCP_TIMEOUT).await
+}
+
+/// One query POSTed to a DoH endpoint.
+#[cfg(feature = "doh")]
+async fn forward_doh(packet: &[u8], url: &str, bootstrap: &DohBootstrap) -> Result<Vec<u8>> {
+    let reply = doh_client(url, bootstrap)
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/dns-message")
+        .header(reqwest::header::ACCEPT, "application/dns-message")
+        .body(packet.to_vec())
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(reply.to_vec())
+}
+
+/// The client for `url`, kept while the endpoint and bootstrap stay the
+/// same so its connections are reused.
+#[cfg(feature = "doh")]
+fn doh_client(url: &str, bootstrap: &DohBootstrap) -> reqwest::Client {
+    type Cached = (String, DohBootstrap, reqwest::Client);
+    static CLIENT: parking_lot::Mutex<Option<Cached>> = parking_lot::Mutex::new(None);
+
+    let mut cached = CLIENT.lock();
+    if let Some((u, b, client)) = cached.as_ref()
+        && u == url
+        && b == bootstrap
+    {
+        return client.clone();
+    }
+    let mut builder = reqwest::Client::builder().timeout(DOH_TIMEOUT);
+    match bootstrap {
+        DohBootstrap::System => {}
+        DohBootstrap::Resolver(resolver) => {
+            builder = builder.dns_resolver(std::sync::Arc::new(BootstrapResolver(*resolver)));
+        }
+        DohBootstrap::Pinned(ips) => {
+            if let Some(host) = reqwest::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string)) {
+                let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+                builder = builder.resolve_to_addrs(&host, &addrs);
+            }
+        }
+    }
+    let client = builder.build().unwrap_or_default();
+    *cached = Some((url.to_string(), bootstrap.clone(), client.clone()));
+    client
+}
+
+/// Resolves DoH hostnames with plain A and AAAA queries to one resolver.
+#[cfg(feature = "doh")]
+struct BootstrapResolver(SocketAddr);
+
+#[cfg(feature = "doh")]
+impl reqwest::dns::Resolve for BootstrapResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        use hickory_proto::rr::{RData, RecordType};
+
+        use crate::proto;
+
+        // each query gets a random id from `build_query`, and
+        // `forward_udp` skips replies that do not carry it
+        async fn lookup(name: &str, rtype: RecordType, resolver: SocketAddr) -> Result<Vec<SocketAddr>> {
+            let query = proto::encode(&proto::build_query(name, rtype)?)?;
+            let reply = proto::decode(&forward_udp(&query, resolver).await?)?;
+            Ok(reply
+                .answers()
+                .iter()
+                .filter_map(|r| match r.data() {
+                    RData::A(a) => Some(SocketAddr::new(a.0.into(), 0)),
+                    RData::AAAA(aaaa) => Some(SocketAddr::new(aaaa.0.into(), 0)),
+                    _ => None,
+                })
+                .collect())
+        }
+
+        let (resolver, name) = (self.0, name.as_str().to_string());
+        Box::pin(async move {
+            // an IPv4-only endpoint is still reachable when the AAAA
+            // query times out, and the other way round
+            let (v4, v6) = tokio::join!(lookup(&name, RecordType::A, resolver), lookup(&name, RecordType::AAAA, resolver));
+            let addrs: Vec<SocketAddr> = match (v4, v6) {
+                (Err(e), Err(_)) => return Err(e.into()),
+                (v4, v6) => v4.into_iter().chain(v6).flatten().collect(),
+            };
+            if addrs.is_empty() {
+                return Err(format!("{} has no address at bootstrap resolver {}", name, resolver).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
@@ -0,0 +1,467 @@
+//! Mapping sync between felix instances on a LAN. Peers find each other
+//! with mDNS/DNS-SD (RFC 6762, RFC 6763): every instance advertises a
+//! `_felix-gossip._udp.local.` service whose SRV record carries its gossip
+//! port, and browses for the others'. A peer is reached at the address
+//! its mDNS response came from. Peers can also be configured directly;
+//! mapping entries themselves always travel unicast.
+//!
+//! Every packet carries an HMAC-SHA256 over its body keyed with the
+//! shared `GossipConfig::secret`; packets from instances without the
+//! secret are dropped before they are parsed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{PTR, SRV, TXT},
+    },
+};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast::error::RecvError, oneshot},
+    task::JoinHandle,
+    time::interval,
+};
+
+use sha2::Sha256;
+
+use crate::{BuildError, Error, ResolverState, Result, events::DomainChange};
+
+/// Entries per datagram, keeps full-state announcements well under typical MTUs.
+const ENTRIES_PER_PACKET: usize = 16;
+/// HMAC-SHA256 tag in front of every packet.
+const TAG_LEN: usize = 32;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The DNS-SD service type instances advertise and browse for.
+const SERVICE: &str = "_felix-gossip._udp.local.";
+/// RFC 6762 section 10 recommends 120 seconds for SRV and PTR records.
+const MDNS_TTL: u32 = 120;
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    pub bind: SocketAddr,
+    /// mDNS group to advertise the gossip service on and browse for peers;
+    /// `None` to gossip with `peers` only.
+    pub mdns: Option<SocketAddrV4>,
+    /// Peers to contact without discovering them first.
+    pub peers: Vec<SocketAddr>,
+    /// How often the full table is re-announced (anti-entropy) and peers
+    /// are browsed for.
+    pub interval: Duration,
+    /// Shared by every instance of the group; required, since anyone who
+    /// can send to the group could otherwise rewrite its mappings.
+    pub secret: String,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:5388".parse().unwrap(),
+            mdns: Some(SocketAddrV4::new(MDNS_GROUP, 5353)),
+            peers: Vec::new(),
+            interval: Duration::from_secs(5),
+            secret: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    domain: String,
+    /// `None` is a tombstone.
+    ip: Option<Ipv4Addr>,
+    /// Milliseconds since the Unix epoch; 0 for mappings that predate gossip.
+    ts: u64,
+    node: u64,
+}
+
+impl Entry {
+    fn newer_than(&self, other: &Entry) -> bool {
+        (self.ts, self.node) > (other.ts, other.node)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    node: u64,
+    entries: Vec<Entry>,
+}
+
+struct Shared {
+    node: u64,
+    secret: Vec<u8>,
+    table: Mutex<HashMap<String, Entry>>,
+    peers: Mutex<HashSet<SocketAddr>>,
+}
+
+pub struct GossipHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl GossipHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// Shares `state`'s mappings with other felix instances on the LAN.
+///
+/// Every instance keeps a last-write-wins table keyed by domain; removals
+/// are kept as tombstones so they propagate too. Local changes are sent
+/// immediately, and the whole table is re-announced every
+/// `config.interval` so late joiners converge.
+pub async fn start_gossip(config: GossipConfig, state: ResolverState) -> Result<GossipHandle> {
+    if config.secret.is_empty() {
+        return Err(Error::Config(BuildError::MissingGossipSecret));
+    }
+    let socket = bind_socket(&config)?;
+    let local_addr = socket.local_addr()?;
+    let socket = Arc::new(socket);
+    // another responder without SO_REUSEADDR, e.g. felix's own DNS listener
+    // on 127.0.0.1:5353, can hold the mDNS port
+    let mdns = match config.mdns.map(bind_mdns).transpose() {
+        Ok(mdns) => mdns,
+        Err(e) => {
+            log::warn!("mDNS discovery unavailable, gossiping with configured peers only: {}", e);
+            None
+        }
+    };
+
+    let mut table = HashMap::new();
+    for (domain, ip) in state.list_domains().await? {
+        table.insert(domain.clone(), Entry { domain, ip: Some(ip), ts: 0, node: 0 });
+    }
+
+    let shared = Arc::new(Shared {
+        node: node_id(),
+        secret: config.secret.as_bytes().to_vec(),
+        table: Mutex::new(table),
+        peers: Mutex::new(config.peers.iter().copied().collect()),
+    });
+
+    log::info!("Gossip node {:016x} listening on {}", shared.node, local_addr);
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let mut changes = state.subscribe_changes();
+
+    let task = tokio::spawn(async move {
+        let mut tick = interval(config.interval);
+        let mut buf = vec![0u8; 65536];
+        let mut mdns_buf = [0u8; 1500];
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                _ = tick.tick() => {
+                    if let (Some(mdns), Some(group)) = (&mdns, config.mdns) {
+                        send_mdns(mdns, group, browse_query()).await;
+                    }
+                    let entries: Vec<Entry> = shared.table.lock().values().cloned().collect();
+                    announce(&socket, &shared, entries).await;
+                }
+                change = changes.recv() => {
+                    let change = match change {
+                        Ok(c) => c,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
+                    if let Some(entry) = record_local(&shared, change) {
+                        announce(&socket, &shared, vec![entry]).await;
+                    }
+                }
+                recv = recv_mdns(mdns.as_ref(), &mut mdns_buf) => {
+                    let (n, from) = match recv {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!("mDNS recv error: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let Ok(msg) = Message::from_vec(&mdns_buf[..n]) else {
+                        continue;
+                    };
+                    match msg.message_type() {
+                        MessageType::Query if is_browse(&msg) => {
+                            if let (Some(mdns), Some(group)) = (&mdns, config.mdns) {
+                                send_mdns(mdns, group, advertisement(shared.node, local_addr.port())).await;
+                            }
+                        }
+                        MessageType::Query => {}
+                        MessageType::Response => {
+                            for peer in discovered(shared.node, &msg, from) {
+                                if shared.peers.lock().insert(peer) {
+                                    log::info!("Discovered gossip peer {} via mDNS", peer);
+                                }
+                            }
+                        }
+                    }
+                }
+                recv = socket.recv_from(&mut buf) => {
+                    let (n, peer) = match recv {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!("Gossip recv error: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let Some(body) = verify(&shared.secret, &buf[..n]) else {
+                        log::debug!("Ignoring unauthenticated gossip from {}", peer);
+                        continue;
+                    };
+                    let packet: Packet = match serde_json::from_slice(body) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            log::debug!("Ignoring malformed gossip from {}: {}", peer, e);
+                            continue;
+                        }
+                    };
+                    if packet.node == shared.node {
+                        // our own multicast looped back
+                        continue;
+                    }
+                    shared.peers.lock().insert(peer);
+                    merge(&shared, &state, packet.entries).await;
+                }
+            }
+        }
+    });
+
+    Ok(GossipHandle {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        task,
+    })
+}
+
+fn bind_socket(config: &GossipConfig) -> Result<UdpSocket> {
+    let bind = |e| Error::Bind(config.bind, e);
+    let socket = Socket::new(Domain::for_address(config.bind), Type::DGRAM, Some(Protocol::UDP)).map_err(bind)?;
+    // several instances on one host share the multicast port
+    socket.set_reuse_address(true).map_err(bind)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).map_err(bind)?;
+    socket.set_nonblocking(true).map_err(bind)?;
+    socket.bind(&config.bind.into()).map_err(bind)?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn bind_mdns(group: SocketAddrV4) -> Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port()));
+    let bind = |e| Error::Bind(addr, e);
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(bind)?;
+    // shared with the system's mDNS responder and other instances on the host
+    socket.set_reuse_address(true).map_err(bind)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).map_err(bind)?;
+    socket.set_nonblocking(true).map_err(bind)?;
+    socket.bind(&addr.into()).map_err(bind)?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(*group.ip(), Ipv4Addr::UNSPECIFIED)?;
+    // instances on the same host hear each other
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket)
+}
+
+async fn recv_mdns(socket: Option<&UdpSocket>, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send_mdns(socket: &UdpSocket, group: SocketAddrV4, msg: Message) {
+    let bytes = match msg.to_vec() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::warn!("Failed to encode mDNS message: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.send_to(&bytes, group).await {
+        log::debug!("mDNS send to {} failed: {:?}", group, e);
+    }
+}
+
+fn service() -> Name {
+    Name::from_str(SERVICE).expect("valid service name")
+}
+
+/// `<node>._felix-gossip._udp.local.`, unique per instance.
+fn instance(node: u64) -> Name {
+    Name::from_str(&format!("{:016x}.{}", node, SERVICE)).expect("valid instance name")
+}
+
+/// A one-shot DNS-SD browse for the gossip service (RFC 6763 section 4).
+fn browse_query() -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(service(), RecordType::PTR));
+    msg
+}
+
+fn is_browse(msg: &Message) -> bool {
+    let service = service();
+    msg.queries()
+        .iter()
+        .any(|q| matches!(q.query_type(), RecordType::PTR | RecordType::ANY) && q.name().eq_case(&service))
+}
+
+/// The PTR, SRV and TXT records advertising this instance's gossip port.
+fn advertisement(node: u64, port: u16) -> Message {
+    let instance = instance(node);
+    let target = Name::from_str(&format!("{:016x}.local.", node)).expect("valid host name");
+    let mut msg = Message::new();
+    // mDNS responses carry no questions and are always authoritative
+    msg.set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .add_answer(Record::from_rdata(service(), MDNS_TTL, RData::PTR(PTR(instance.clone()))))
+        .add_additional(Record::from_rdata(instance.clone(), MDNS_TTL, RData::SRV(SRV::new(0, 0, port, target))))
+        // section 6: every service has a TXT record, even an empty one
+        .add_additional(Record::from_rdata(instance, MDNS_TTL, RData::TXT(TXT::new(vec![String::new()]))));
+    msg
+}
+
+/// Gossip peers advertised in `msg`, other than this node itself.
+fn discovered(node: u64, msg: &Message, from: SocketAddr) -> Vec<SocketAddr> {
+    let service = service();
+    let own = instance(node);
+    msg.answers()
+        .iter()
+        .chain(msg.additionals())
+        .filter(|r| service.zone_of(r.name()) && !r.name().eq_case(&own))
+        .filter_map(|r| match r.data() {
+            RData::SRV(srv) => Some(SocketAddr::new(from.ip(), srv.port())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Turns a change made through the local `ResolverState` into a fresh
+/// entry. Returns `None` for changes that merely echo a merged remote entry.
+fn record_local(shared: &Shared, change: DomainChange) -> Option<Entry> {
+    let (domain, ip) = match change {
+        DomainChange::Set { domain, ip } => (domain, Some(ip)),
+        DomainChange::Removed { domain } => (domain, None),
+    };
+
+    let mut table = shared.table.lock();
+    if table.get(&domain).is_some_and(|e| e.ip == ip) {
+        return None;
+    }
+
+    let entry = Entry { domain: domain.clone(), ip, ts: now_millis(), node: shared.node };
+    table.insert(domain, entry.clone());
+    Some(entry)
+}
+
+/// Applies the entries newer than this node's to `state`. An entry only
+/// enters the table once `state` took it, so one this node rejects is
+/// neither announced onwards nor shadows a later retry.
+async fn merge(shared: &Shared, state: &ResolverState, entries: Vec<Entry>) {
+    for entry in entries {
+        let newer = |table: &HashMap<String, Entry>| table.get(&entry.domain).is_none_or(|cur| entry.newer_than(cur));
+        if !newer(&shared.table.lock()) {
+            continue;
+        }
+
+        let res = match entry.ip {
+            // peers are not trusted to bypass this node's safe-IP policy
+            Some(ip) => state.add_domain(&entry.domain, ip).await,
+            None => state.remove_domain(&entry.domain).await,
+        };
+        if let Err(e) = res {
+            log::warn!("Failed to apply gossiped entry for {}: {}", entry.domain, e);
+            continue;
+        }
+        let mut table = shared.table.lock();
+        if newer(&table) {
+            table.insert(entry.domain.clone(), entry);
+        }
+    }
+}
+
+async fn announce(socket: &UdpSocket, shared: &Shared, entries: Vec<Entry>) {
+    let targets: Vec<SocketAddr> = shared.peers.lock().iter().copied().collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    // an empty table still sends one packet so peers learn about us
+    let chunks: Vec<&[Entry]> = if entries.is_empty() {
+        vec![&[]]
+    } else {
+        entries.chunks(ENTRIES_PER_PACKET).collect()
+    };
+
+    for chunk in chunks {
+        let packet = Packet { node: shared.node, entries: chunk.to_vec() };
+        let bytes = match serde_json::to_vec(&packet) {
+            Ok(body) => sign(&shared.secret, body),
+            Err(e) => {
+                log::warn!("Failed to encode gossip packet: {}", e);
+                return;
+            }
+        };
+        for target in &targets {
+            if let Err(e) = socket.send_to(&bytes, target).await {
+                log::debug!("Gossip send to {} failed: {:?}", target, e);
+            }
+        }
+    }
+}
+
+fn mac(secret: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(secret).expect("HMAC takes keys of any length")
+}
+
+/// `body` with its tag in front.
+pub(crate) fn sign(secret: &[u8], body: Vec<u8>) -> Vec<u8> {
+    let mut mac = mac(secret);
+    mac.update(&body);
+    let mut packet = mac.finalize().into_bytes().to_vec();
+    packet.extend(body);
+    packet
+}
+
+/// The body of `packet` if its tag is valid, compared in constant time.
+pub(crate) fn verify<'a>(secret: &[u8], packet: &'a [u8]) -> Option<&'a [u8]> {
+    let (tag, body) = packet.split_at_checked(TAG_LEN)?;
+    let mut mac = mac(secret);
+    mac.update(body);
+    mac.verify_slice(tag).ok()?;
+    Some(body)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn node_id() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
@@ -0,0 +1,274 @@
+//! Zero-downtime restarts: a running felix starts its successor, usually
+//! a freshly installed binary, and passes it every listening socket
+//! instead of closing them, so no query finds the port closed while the
+//! new process comes up.
+//!
+//! `FelixServer::hand_over` execs the binary at the current path with the
+//! same arguments. The sockets stay open across exec and are named in
+//! `FELIX_INHERITED_SOCKETS` as `udp:ADDR=FD,tcp:ADDR=FD,...`; listeners
+//! of the successor adopt the ones bound to their address instead of
+//! binding anew. Once its `start` succeeds the successor says so on the
+//! socket named in `FELIX_HANDOVER_READY`, and the old process shuts down,
+//! answering the queries it already received. Until then both processes
+//! share the sockets and the kernel hands each query to one of them.
+//!
+//! Listeners on port 0 are never adopted, as their address is only known
+//! after binding. Unix only; elsewhere every listener binds as usual.
+//!
+//! Only sockets are passed on. The successor builds its state from its
+//! arguments and the database like any fresh start, so whatever lived in
+//! memory alone is lost: ephemeral mappings added at runtime, views,
+//! domain delays, answer orders and other settings changed through the
+//! management API, the cache and the counters. Mirrors such as the hosts
+//! file sync fill theirs in again as they start.
+//!
+//! The successor is a child of the old process. Under systemd with
+//! `Type=simple`, the unit stops when its main process exits and the
+//! successor is killed along with the rest of its cgroup; restart such a
+//! unit with `systemctl restart` instead.
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+
+use crate::{Error, Result};
+
+/// Sockets passed to a successor.
+pub const SOCKETS_ENV: &str = "FELIX_INHERITED_SOCKETS";
+/// The successor's end of the readiness socket.
+pub const READY_ENV: &str = "FELIX_HANDOVER_READY";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SocketKind {
+    Udp,
+    Tcp,
+}
+
+impl SocketKind {
+    #[cfg(unix)]
+    fn name(self) -> &'static str {
+        match self {
+            SocketKind::Udp => "udp",
+            SocketKind::Tcp => "tcp",
+        }
+    }
+}
+
+/// Binds a TCP listener, or adopts the one the predecessor passed on for
+/// `addr`. Also returns a duplicate of it for the listener's handle, to be
+/// passed on in turn.
+pub(crate) async fn listen_tcp(addr: SocketAddr) -> Result<(TcpListener, std::net::TcpListener)> {
+    let listener = match take_tcp(addr) {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => TcpListener::bind(addr).await.map_err(|e| Error::Bind(addr, e))?,
+    };
+    let std = listener.into_std()?;
+    let kept = std.try_clone()?;
+    Ok((TcpListener::from_std(std)?, kept))
+}
+
+#[cfg(unix)]
+pub(crate) use unix::{notify_ready, spawn_successor, take_tcp, take_udp};
+#[cfg(all(test, unix))]
+pub(crate) use unix::{Inherited, adopt, parse_entry};
+
+#[cfg(not(unix))]
+pub(crate) fn take_udp(_addr: SocketAddr) -> Vec<std::net::UdpSocket> {
+    Vec::new()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn take_tcp(_addr: SocketAddr) -> Option<std::net::TcpListener> {
+    None
+}
+
+#[cfg(not(unix))]
+pub(crate) fn notify_ready() {}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io::Write,
+        net::SocketAddr,
+        os::{
+            fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+            unix::net::UnixStream,
+        },
+        path::PathBuf,
+        sync::LazyLock,
+        time::Duration,
+    };
+
+    use parking_lot::Mutex;
+    use socket2::SockRef;
+    use tokio::{io::AsyncReadExt, process::Command, time::timeout};
+
+    use super::{READY_ENV, SOCKETS_ENV, SocketKind};
+    use crate::{Error, Result};
+
+    /// How long the successor gets to start before it is killed and this
+    /// process carries on.
+    const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// What the predecessor passed on and no listener has adopted yet.
+    #[derive(Default)]
+    pub(crate) struct Inherited {
+        pub sockets: Vec<(SocketKind, SocketAddr, OwnedFd)>,
+        ready: Option<OwnedFd>,
+    }
+
+    static INHERITED: LazyLock<Mutex<Inherited>> = LazyLock::new(|| Mutex::new(Inherited::from_env()));
+
+    impl Inherited {
+        fn from_env() -> Self {
+            let mut inherited = Inherited::default();
+            if let Ok(sockets) = std::env::var(SOCKETS_ENV) {
+                for entry in sockets.split(',').filter(|e| !e.is_empty()) {
+                    match parse_entry(entry).and_then(|(kind, addr, fd)| Some((kind, addr, adopt(fd, Some(addr))?))) {
+                        Some(socket) => inherited.sockets.push(socket),
+                        None => log::warn!("Ignoring inherited socket {:?}", entry),
+                    }
+                }
+            }
+            inherited.ready = std::env::var(READY_ENV).ok().and_then(|fd| adopt(fd.parse().ok()?, None));
+            inherited
+        }
+
+        /// Removes and returns the sockets of `kind` bound to `addr`.
+        pub fn take(&mut self, kind: SocketKind, addr: SocketAddr) -> Vec<OwnedFd> {
+            if addr.port() == 0 {
+                return Vec::new();
+            }
+            let (taken, kept) =
+                std::mem::take(&mut self.sockets).into_iter().partition(|(k, a, _)| *k == kind && *a == addr);
+            self.sockets = kept;
+            taken.into_iter().map(|(_, _, fd)| fd).collect()
+        }
+    }
+
+    pub(crate) fn parse_entry(entry: &str) -> Option<(SocketKind, SocketAddr, RawFd)> {
+        let (kind, rest) = entry.split_once(':')?;
+        let (addr, fd) = rest.rsplit_once('=')?;
+        let kind = match kind {
+            "udp" => SocketKind::Udp,
+            "tcp" => SocketKind::Tcp,
+            _ => return None,
+        };
+        Some((kind, addr.parse().ok()?, fd.parse().ok()?))
+    }
+
+    /// Takes ownership of an inherited descriptor if it is an open socket,
+    /// bound to `addr` when given, and keeps it from leaking into
+    /// processes felix runs.
+    pub(crate) fn adopt(fd: RawFd, addr: Option<SocketAddr>) -> Option<OwnedFd> {
+        if fd < 0 {
+            return None;
+        }
+        // SAFETY: only borrowed for the getsockname below, which fails
+        // cleanly with EBADF or ENOTSOCK for anything but an open socket
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let bound = SockRef::from(&borrowed).local_addr().ok()?.as_socket();
+        if addr.is_some() && bound != addr {
+            return None;
+        }
+        SockRef::from(&borrowed).set_cloexec(true).ok()?;
+        // SAFETY: the predecessor passed this socket to this process alone,
+        // and it is adopted at most once as the environment is read once
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn take(kind: SocketKind, addr: SocketAddr) -> Vec<OwnedFd> {
+        INHERITED.lock().take(kind, addr)
+    }
+
+    /// The UDP sockets passed on for `addr`, one per worker it had.
+    pub(crate) fn take_udp(addr: SocketAddr) -> Vec<std::net::UdpSocket> {
+        take(SocketKind::Udp, addr).into_iter().map(std::net::UdpSocket::from).collect()
+    }
+
+    pub(crate) fn take_tcp(addr: SocketAddr) -> Option<std::net::TcpListener> {
+        take(SocketKind::Tcp, addr).into_iter().next().map(std::net::TcpListener::from)
+    }
+
+    /// Tells the predecessor this process is serving, so it can go. Sockets
+    /// it passed on that no listener adopted, e.g. of a listener dropped
+    /// from the configuration, are closed.
+    pub(crate) fn notify_ready() {
+        let mut inherited = INHERITED.lock();
+        for (kind, addr, _) in inherited.sockets.drain(..) {
+            log::info!("Closing inherited {} socket {}, no listener uses it", kind.name(), addr);
+        }
+        if let Some(ready) = inherited.ready.take() {
+            let _ = UnixStream::from(ready).write_all(b"1");
+        }
+    }
+
+    /// Starts the successor with `sockets`, and waits until it serves.
+    /// Returns its pid.
+    pub(crate) async fn spawn_successor(sockets: &[(SocketKind, BorrowedFd<'_>)]) -> Result<u32> {
+        let mut passed = Vec::new();
+        for (kind, fd) in sockets {
+            let addr = SockRef::from(fd)
+                .local_addr()?
+                .as_socket()
+                .ok_or_else(|| Error::Io(std::io::Error::other("listener is not an IP socket")))?;
+            passed.push(format!("{}:{}={}", kind.name(), addr, fd.as_raw_fd()));
+        }
+        let (ready, notify) = UnixStream::pair()?;
+        let mut inherit: Vec<RawFd> = sockets.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+        inherit.push(notify.as_raw_fd());
+
+        let exe = executable()?;
+        let mut command = Command::new(&exe);
+        command
+            .args(std::env::args_os().skip(1))
+            .env(SOCKETS_ENV, passed.join(","))
+            .env(READY_ENV, notify.as_raw_fd().to_string());
+        // SAFETY: runs in the forked child before exec, and only clears
+        // FD_CLOEXEC with fcntl, which is async-signal-safe. Doing it there
+        // keeps other processes felix spawns meanwhile from inheriting them
+        unsafe {
+            command.pre_exec(move || {
+                for &fd in &inherit {
+                    SockRef::from(&BorrowedFd::borrow_raw(fd)).set_cloexec(false)?;
+                }
+                Ok(())
+            });
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| Error::Io(std::io::Error::other(format!("running {}: {}", exe.display(), e))))?;
+        drop(notify);
+        let pid = child.id().unwrap_or_default();
+        log::info!("Handing the listeners over to {} (pid {})", exe.display(), pid);
+
+        ready.set_nonblocking(true)?;
+        let mut ready = tokio::net::UnixStream::from_std(ready)?;
+        let mut byte = [0u8];
+        match timeout(READY_TIMEOUT, ready.read(&mut byte)).await {
+            Ok(Ok(1)) => Ok(pid),
+            Ok(_) => {
+                let status = child.wait().await?;
+                Err(Error::Io(std::io::Error::other(format!("new process exited before serving: {}", status))))
+            }
+            Err(_) => {
+                let _ = child.kill().await;
+                Err(Error::Io(std::io::Error::other(format!("new process did not serve within {:?}", READY_TIMEOUT))))
+            }
+        }
+    }
+
+    /// The binary at this process's path, which is the new one after an
+    /// upgrade replaced the file.
+    fn executable() -> Result<PathBuf> {
+        let exe = std::env::current_exe()?;
+        // Linux names a replaced binary "/path (deleted)"
+        Ok(match exe.to_str().and_then(|p| p.strip_suffix(" (deleted)")) {
+            Some(path) => PathBuf::from(path),
+            None => exe,
+        })
+    }
+}
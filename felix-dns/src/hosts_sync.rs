@@ -0,0 +1,152 @@
+//! Mirrors a hosts file (`/etc/hosts` by default) into the resolver, so
+//! teams that still maintain one get the same names over DNS.
+//!
+//! The file is polled for changes; every IPv4 entry becomes an ephemeral
+//! mapping (see `ResolverState::add_ephemeral_domain`) and is removed again
+//! once it leaves the file or the sync stops. IPv6 entries and names that
+//! are not valid domains are skipped; when a name is listed twice, the
+//! first entry wins, as with the system resolver. Names already mapped in
+//! memory by someone else keep their mapping, and the sync never removes
+//! one it did not make.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{ResolverState, Result, name::NormalizedName};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostsSyncConfig {
+    pub path: PathBuf,
+    /// How often the file is checked for changes.
+    pub interval: Duration,
+}
+
+impl Default for HostsSyncConfig {
+    fn default() -> Self {
+        Self { path: PathBuf::from("/etc/hosts"), interval: Duration::from_secs(2) }
+    }
+}
+
+pub struct HostsSyncHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl HostsSyncHandle {
+    /// Stops watching and removes the mirrored mappings.
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// Mirrors the file now and keeps doing so in the background. Fails if the
+/// file cannot be read at start; later read errors are logged and retried.
+pub async fn start_hosts_sync(config: HostsSyncConfig, state: ResolverState) -> Result<HostsSyncHandle> {
+    let mut mirror = Mirror { state, entries: HashMap::new() };
+    let mut seen = modified(&config).await;
+    mirror.sync(&parse_hosts(&tokio::fs::read_to_string(&config.path).await?));
+    log::info!("Mirroring {} ({} names)", config.path.display(), mirror.entries.len());
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut tick = tokio::time::interval(config.interval);
+        tick.tick().await;
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                _ = tick.tick() => {}
+            }
+            let current = modified(&config).await;
+            if current == seen {
+                continue;
+            }
+            match tokio::fs::read_to_string(&config.path).await {
+                Ok(text) => {
+                    mirror.sync(&parse_hosts(&text));
+                    log::debug!("Reloaded {} ({} names)", config.path.display(), mirror.entries.len());
+                    seen = current;
+                }
+                Err(e) => log::warn!("Reading {} failed, will retry: {}", config.path.display(), e),
+            }
+        }
+        mirror.sync(&[]);
+    });
+
+    Ok(HostsSyncHandle { shutdown_tx: Some(shutdown_tx), task })
+}
+
+/// Modification time and size, enough to notice edits and replacements.
+async fn modified(config: &HostsSyncConfig) -> Option<(SystemTime, u64)> {
+    let meta = tokio::fs::metadata(&config.path).await.ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// The mappings currently mirrored from the file.
+struct Mirror {
+    state: ResolverState,
+    entries: HashMap<String, Ipv4Addr>,
+}
+
+impl Mirror {
+    fn sync(&mut self, wanted: &[(String, Ipv4Addr)]) {
+        let wanted: HashMap<&str, Ipv4Addr> = wanted.iter().map(|(name, ip)| (name.as_str(), *ip)).collect();
+        self.entries.retain(|name, ip| {
+            if wanted.contains_key(name.as_str()) {
+                return true;
+            }
+            self.state.remove_ephemeral_domain_if(name, *ip);
+            false
+        });
+        for (name, ip) in wanted {
+            let current = self.entries.get(name).copied();
+            if current == Some(ip) {
+                continue;
+            }
+            match self.state.replace_ephemeral_domain(name, current, ip) {
+                Ok(true) => {
+                    self.entries.insert(name.to_string(), ip);
+                }
+                Ok(false) => {
+                    log::debug!("Not mirroring hosts entry {} {}: already mapped", ip, name);
+                    self.entries.remove(name);
+                }
+                Err(e) => log::warn!("Not mirroring hosts entry {} {}: {}", ip, name, e),
+            }
+        }
+    }
+}
+
+/// IPv4 entries of a hosts file as normalized names, first entry per name.
+pub(crate) fn parse_hosts(text: &str) -> Vec<(String, Ipv4Addr)> {
+    let mut entries: Vec<(String, Ipv4Addr)> = Vec::new();
+    let mut seen = HashSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(ip)) = fields.next().map(str::parse::<Ipv4Addr>) else {
+            continue;
+        };
+        for name in fields {
+            let name = match NormalizedName::checked(name) {
+                Ok(name) if !name.starts_with('*') => name.into_string(),
+                _ => {
+                    log::debug!("Skipping hosts entry {} {}", ip, name);
+                    continue;
+                }
+            };
+            if seen.insert(name.clone()) {
+                entries.push((name, ip));
+            }
+        }
+    }
+    entries
+}
@@ -1,12 +1,116 @@
 pub mod domain_map;
+pub mod acl;
+pub mod acme;
+pub mod alerts;
+pub mod answer_order;
+mod bailiwick;
+mod cow_map;
+pub mod bench;
+pub mod cache;
+pub mod clients;
+pub mod conflicts;
+mod dispatch;
+pub mod error;
+pub mod events;
+pub mod failover;
+pub mod gossip;
+pub mod handover;
+pub mod hosts_sync;
+pub mod llmnr;
+pub mod log_filter;
+#[cfg(feature = "sqlite")]
+pub mod maintenance;
+pub mod management;
+pub mod management_server;
+pub mod metrics;
+pub mod name;
+#[cfg(target_os = "linux")]
+mod mmsg;
+pub mod pipeline;
+pub mod policy;
+pub mod projects;
+pub mod proto;
+pub mod proxy;
+pub mod public_suffix;
+pub mod query_stats;
+mod records;
+pub mod replication;
 pub mod resolver_state;
+mod rng;
+pub mod scripting;
+pub mod server;
 pub mod server_handler;
+pub mod statsd;
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm_plugin;
+#[cfg(feature = "redb")]
+pub mod redb_domain_store;
+#[cfg(feature = "sqlite")]
 pub mod sqlite_domain_store;
+pub mod store;
+pub mod syslog;
+pub mod system_dns;
+pub mod systemd_resolved;
+mod tcp_pipeline;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod write_behind;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+pub mod raft;
+pub mod trace;
+pub mod views;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
 
+pub use acl::ClientNet;
+pub use alerts::{AlertConfig, UpstreamEvent};
+pub use acme::UpdateKey;
+pub use answer_order::AnswerOrder;
+pub use cache::{CacheConfig, CacheEntry};
+pub use clients::{ClientStats, QuotaConfig};
+pub use conflicts::MappingConflict;
 pub use domain_map::DomainMap;
-pub use resolver_state::ResolverState;
-pub use server_handler::run_udp_server;
-pub use sqlite_domain_store::SqliteDomainStore;
+pub use error::{Error, Result};
+pub use events::{AnswerSource, DomainChange, QueryEvent, QueryOutcome};
+pub use failover::{DohBootstrap, FailoverConfig};
+pub use gossip::{GossipConfig, start_gossip};
+pub use hosts_sync::{HostsSyncConfig, start_hosts_sync};
+pub use llmnr::{LlmnrConfig, start_llmnr};
+pub use management::{Management, Role};
+pub use management_server::run_management_server;
+pub use metrics::{LatencyHistogram, MetricsSnapshot, QueryMetrics};
+pub use proxy::run_http_proxy;
+pub use public_suffix::PublicSuffixGuard;
+pub use query_stats::QueryReport;
+pub use raft::{RaftConfig, RaftHandle, RaftNode, start_raft};
+#[cfg(feature = "tls")]
+pub use proxy::run_https_proxy;
+pub use pipeline::{BlockResponse, ChaosAnswer, ChaosConfig, ForwardRule, LocalOnly, NegativeSoa, Pipeline, ReservedTlds, RewriteRule, SingleLabel, Stage, TtlClamp, TypeBlockRule, UpstreamSource};
+pub use policy::{PolicyAction, PolicyRule, PolicyTimeZone, Schedule};
+pub use projects::ProjectTemplate;
+pub use replication::follow_primary;
+pub use resolver_state::{BuildError, ResolverState};
+pub use scripting::ScriptHooks;
+pub use server::FelixServer;
+pub use server_handler::{ListenOptions, run_udp_server, run_udp_server_with};
+#[cfg(feature = "redb")]
+pub use redb_domain_store::RedbDomainStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_domain_store::{DatabaseKey, SqliteDomainStore};
+pub use statsd::{StatsdConfig, start_statsd};
+pub use store::{DomainPage, ListQuery, ListSort, Wildcards};
+pub use syslog::SyslogTarget;
+pub use systemd_resolved::ResolvedMode;
+#[cfg(feature = "tls")]
+pub use tls::LocalCa;
+pub use trace::{ResolveTrace, TraceCheck, TraceStep};
+pub use views::ViewSummary;
+#[cfg(feature = "webhooks")]
+pub use webhooks::{WebhookConfig, start_webhooks};
+pub use hickory_proto::rr::{RData as RecordData, RecordType, rdata};
 
 
 #[cfg(test)]
@@ -23,9 +127,9 @@ mod tests {
         // exact match
         assert_eq!(dm.resolve("foo.dev"), Some(Ipv4Addr::new(127, 0, 0, 1)));
 
-        // wildcard match
+        // wildcard match: `*` covers one label
         assert_eq!(dm.resolve("api.example.com"), Some(Ipv4Addr::new(10, 0, 0, 42)));
-        assert_eq!(dm.resolve("deep.sub.example.com"), Some(Ipv4Addr::new(10, 0, 0, 42)));
+        assert_eq!(dm.resolve("deep.sub.example.com"), None);
 
         // not found
         assert_eq!(dm.resolve("unknown.test"), None);
@@ -64,6 +168,7 @@ mod tests {
         assert!(dm.resolve("foo.dev").is_some());
     }
 
+    #[cfg(feature = "sqlite")]
     #[tokio::test]
     async fn test_sqlite_domain_store() {
         // Sử dụng in-memory SQLite database cho tests
@@ -89,6 +194,19 @@ mod tests {
         assert_eq!(result, None);
     }
 
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_single_label_wildcards() {
+        let store = SqliteDomainStore::new(":memory:").await.unwrap();
+        store.set("*.test.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        assert_eq!(store.resolve("api.test.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(store.resolve("v1.api.test.dev").await.unwrap(), None);
+        store.set("**.test.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        assert_eq!(store.resolve("v1.api.test.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(store.resolve("api.test.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[cfg(feature = "sqlite")]
     #[tokio::test]
     async fn test_resolver_state_with_sqlite() {
         // Sử dụng in-memory SQLite database cho tests
@@ -104,17 +222,928 @@ mod tests {
         assert_eq!(domains.len(), 1);
         assert_eq!(domains[0], ("test.local".to_string(), Ipv4Addr::new(127, 0, 0, 1)));
     }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_resolver_state_builder_validation() {
+        let state = ResolverState::builder()
+            .upstream("1.1.1.1:53".parse().unwrap())
+            .sqlite(":memory:")
+            .default_ttl(300)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(state.default_ttl(), 300);
+
+        assert!(matches!(ResolverState::builder().build().await, Err(Error::Config(BuildError::MissingUpstream))));
+        assert!(matches!(
+            ResolverState::builder().upstream("0.0.0.0:53".parse().unwrap()).build().await,
+            Err(Error::Config(BuildError::InvalidUpstream(_)))
+        ));
+        assert!(matches!(
+            ResolverState::builder().upstream("1.1.1.1:53".parse().unwrap()).default_ttl(0).build().await,
+            Err(Error::Config(BuildError::InvalidTtl(0)))
+        ));
+        assert!(matches!(
+            ResolverState::builder().upstream("1.1.1.1:53".parse().unwrap()).sqlite("").build().await,
+            Err(Error::Config(BuildError::EmptyDatabasePath))
+        ));
+    }
+
+    #[test]
+    fn test_script_hooks_decisions() {
+        let hooks = ScriptHooks::from_source(r#"
+            fn on_query(q) {
+                if q.name == "blocked.dev" { return #{ refuse: true }; }
+                if q.name.ends_with(".corp") { return #{ upstream: "10.0.0.2:53", name: q.name + ".internal" }; }
+                if q.qtype == "A" && q.name == "pinned.dev" { return #{ answer: "10.9.8.7" }; }
+            }
+            fn on_answer(a) { if a.client.starts_with("127.") { "127.0.0.2" } }
+        "#).unwrap();
+        let client: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        assert!(hooks.on_query("blocked.dev", "A", client).refuse);
+        let corp = hooks.on_query("git.corp", "A", client);
+        assert_eq!(corp.upstream, Some("10.0.0.2:53".parse().unwrap()));
+        assert_eq!(corp.name.as_deref(), Some("git.corp.internal"));
+        assert_eq!(hooks.on_query("pinned.dev", "A", client).answer, Some(Ipv4Addr::new(10, 9, 8, 7)));
+        assert_eq!(hooks.on_query("other.dev", "A", client), scripting::QueryDecision::default());
+
+        assert_eq!(hooks.on_answer("x.dev", "A", client, Ipv4Addr::new(1, 2, 3, 4)), Ipv4Addr::new(127, 0, 0, 2));
+        let remote: std::net::SocketAddr = "10.0.0.5:5000".parse().unwrap();
+        assert_eq!(hooks.on_answer("x.dev", "A", remote, Ipv4Addr::new(1, 2, 3, 4)), Ipv4Addr::new(1, 2, 3, 4));
+
+        assert!(matches!(ScriptHooks::from_source("fn on_query(q) {"), Err(Error::Script(_))));
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    #[test]
+    fn test_wasm_plugin_decisions() {
+        let refuse_all = wasm_plugin::WasmPlugin::from_bytes("refuse", br#"
+            (module
+              (memory (export "memory") 1)
+              (data (i32.const 0) "{\"refuse\":true}")
+              (func (export "felix_alloc") (param i32) (result i32) (i32.const 1024))
+              (func (export "felix_on_query") (param i32 i32) (result i64) (i64.const 15)))
+        "#).unwrap();
+        let client: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        assert!(refuse_all.on_query("any.dev", "A", client).refuse);
+
+        // endless loop runs out of fuel and is treated as no decision
+        let spin = wasm_plugin::WasmPlugin::from_bytes("spin", br#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "felix_alloc") (param i32) (result i32) (i32.const 1024))
+              (func (export "felix_on_query") (param i32 i32) (result i64) (loop (br 0)) (i64.const 0)))
+        "#).unwrap();
+        assert_eq!(spin.on_query("any.dev", "A", client), scripting::QueryDecision::default());
+
+        assert!(matches!(wasm_plugin::WasmPlugin::from_bytes("bad", b"not wasm"), Err(Error::Plugin(_))));
+    }
+
+    #[test]
+    fn test_single_label_wildcards() {
+        let mut dm = DomainMap::new();
+        dm.set("*.example.com", Ipv4Addr::new(10, 0, 0, 42));
+
+        // `*` covers one label, `**` any depth
+        assert_eq!(dm.resolve("api.example.com"), Some(Ipv4Addr::new(10, 0, 0, 42)));
+        assert_eq!(dm.resolve("deep.sub.example.com"), None);
+        dm.set("**.example.com", Ipv4Addr::new(10, 0, 0, 43));
+        assert_eq!(dm.resolve("deep.sub.example.com"), Some(Ipv4Addr::new(10, 0, 0, 43)));
+        assert_eq!(dm.resolve("api.example.com"), Some(Ipv4Addr::new(10, 0, 0, 42)));
+        dm.set("**.sub.example.com", Ipv4Addr::new(10, 0, 0, 44));
+        assert_eq!(dm.resolve_match("deep.sub.example.com"), Some(("**.sub.example.com".into(), Ipv4Addr::new(10, 0, 0, 44))));
+
+        // `*` wins over `**` at the same level, the closest `**` over wider ones
+        dm.set("*.sub.example.com", Ipv4Addr::new(10, 0, 0, 45));
+        assert_eq!(dm.resolve_match("deep.sub.example.com"), Some(("*.sub.example.com".into(), Ipv4Addr::new(10, 0, 0, 45))));
+        assert_eq!(dm.resolve_match("a.deep.sub.example.com"), Some(("**.sub.example.com".into(), Ipv4Addr::new(10, 0, 0, 44))));
+    }
+
+    #[test]
+    fn test_wildcard_apex() {
+        let mut dm = DomainMap::new();
+        dm.set("*.example.dev", Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(dm.resolve("example.dev"), None);
+
+        dm.set_wildcard_apex(true);
+        assert_eq!(dm.resolve("example.dev"), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(dm.resolve("api.example.dev"), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // an explicit apex entry still wins
+        dm.set("example.dev", Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(dm.resolve("example.dev"), Some(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_list_paged_matches_in_memory() {
+        let store = SqliteDomainStore::new(":memory:").await.unwrap();
+        let mut map = DomainMap::new();
+        for (i, domain) in ["b.dev", "A_1.dev", "api.dev", "*.api.dev", "c.test"].iter().enumerate() {
+            let ip = Ipv4Addr::new(10, 0, (i % 2) as u8, 5 - i as u8);
+            store.set(domain, ip).await.unwrap();
+            map.set(*domain, ip);
+        }
+
+        for query in [
+            ListQuery::default(),
+            ListQuery { offset: 2, limit: Some(2), ..Default::default() },
+            ListQuery { filter: Some("API".into()), sort: ListSort::DomainDesc, ..Default::default() },
+            // LIKE wildcards in the filter are literal
+            ListQuery { filter: Some("a_".into()), ..Default::default() },
+            ListQuery { filter: Some("%".into()), ..Default::default() },
+            ListQuery { sort: ListSort::IpAsc, limit: Some(3), ..Default::default() },
+            ListQuery { sort: ListSort::IpDesc, offset: 10, ..Default::default() },
+        ] {
+            assert_eq!(store.list_paged(&query).await.unwrap(), query.apply(map.list()), "{:?}", query);
+        }
+        let page = store.list_paged(&ListQuery { filter: Some("a_".into()), ..Default::default() }).await.unwrap();
+        assert_eq!(page.domains, vec![("a_1.dev".to_string(), Ipv4Addr::new(10, 0, 1, 4))]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_search_matches_in_memory() {
+        let store = SqliteDomainStore::new(":memory:").await.unwrap();
+        let mut map = DomainMap::new();
+        let domains = ["api.dev", "*.api.dev", "rapid.dev", "v1.api.internal.dev", "app.dev", "a_pi.test", "web.dev"];
+        for (i, domain) in domains.iter().enumerate() {
+            let ip = Ipv4Addr::new(10, 0, 0, i as u8 + 1);
+            store.set(domain, ip).await.unwrap();
+            map.set(*domain, ip);
+        }
+        // updated in place, so still indexed once
+        store.set("rapid.dev", Ipv4Addr::new(10, 0, 1, 1)).await.unwrap();
+        map.set("rapid.dev", Ipv4Addr::new(10, 0, 1, 1));
+        store.remove("web.dev").await.unwrap();
+        map.remove("web.dev");
+
+        // indexed and scanned terms, LIKE wildcards and FTS syntax
+        for term in ["API", "api.dev.", "ap", "a", "_", "pi.", "\"api", "nothing", "web", ""] {
+            for limit in [2, 10] {
+                assert_eq!(store.search(term, limit).await.unwrap(), store::search_list(map.list(), term, limit), "{:?}", term);
+            }
+        }
+        let found: Vec<String> = store.search("api", 10).await.unwrap().into_iter().map(|(d, _)| d).collect();
+        assert_eq!(found, ["api.dev", "*.api.dev", "v1.api.internal.dev", "rapid.dev"]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_search_merges_ephemeral_domains() {
+        let state = ResolverState::new_with_sqlite("127.0.0.1:9".parse().unwrap(), ":memory:").await.unwrap();
+        for (i, domain) in ["api.dev", "v1.api.dev", "rapid.dev", "web.dev"].iter().enumerate() {
+            state.add_domain(domain, Ipv4Addr::new(10, 0, 0, i as u8 + 1)).await.unwrap();
+        }
+        // one shadowing a stored mapping, one of its own
+        state.add_ephemeral_domain("api.dev", Ipv4Addr::new(10, 9, 0, 1)).unwrap();
+        state.add_ephemeral_domain("api.test", Ipv4Addr::new(10, 9, 0, 2)).unwrap();
+
+        for limit in [1, 2, 10] {
+            let merged = store::search_list(state.list_domains().await.unwrap(), "api", limit);
+            assert_eq!(state.search_domains("api", limit).await.unwrap(), merged, "{}", limit);
+        }
+        assert_eq!(state.search_domains("API", 2).await.unwrap(), vec![
+            ("api.dev".to_string(), Ipv4Addr::new(10, 9, 0, 1)),
+            ("api.test".to_string(), Ipv4Addr::new(10, 9, 0, 2)),
+        ]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_wildcard_apex() {
+        let store = SqliteDomainStore::new(":memory:").await.unwrap();
+        store.set("*.example.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        assert_eq!(store.resolve("example.dev").await.unwrap(), None);
+
+        let store = store.with_wildcard_apex(true);
+        assert_eq!(store.resolve("example.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_migrates_ip_columns() {
+        let path = std::env::temp_dir().join(format!("felix-migrate-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        // the schema before record types
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite:{}?mode=rwc", path)).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE domain_mappings (domain TEXT PRIMARY KEY, ip_a INTEGER NOT NULL, ip_b INTEGER NOT NULL,
+                ip_c INTEGER NOT NULL, ip_d INTEGER NOT NULL, created_at INTEGER, updated_at INTEGER)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO domain_mappings VALUES ('app.dev', 10, 0, 0, 9, 1, 2), ('*.web.dev', 192, 168, 1, 20, 1, 2)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let store = SqliteDomainStore::new(&path).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec![
+            ("*.web.dev".to_string(), Ipv4Addr::new(192, 168, 1, 20)),
+            ("app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 9)),
+        ]);
+        assert_eq!(store.resolve("x.web.dev.").await.unwrap(), Some(Ipv4Addr::new(192, 168, 1, 20)));
+        store.set("app.dev", Ipv4Addr::new(10, 0, 0, 10)).await.unwrap();
+        let page = store.list_paged(&ListQuery { sort: ListSort::IpDesc, ..Default::default() }).await.unwrap();
+        assert_eq!(page.domains[0].0, "*.web.dev");
+        assert_eq!(store.count().await.unwrap(), 2);
+        // rows from before the search index are indexed too
+        assert_eq!(store.search("web", 10).await.unwrap(), vec![("*.web.dev".to_string(), Ipv4Addr::new(192, 168, 1, 20))]);
+        drop(store);
+
+        // reopening leaves the migrated table alone
+        let store = SqliteDomainStore::new(&path).await.unwrap();
+        assert_eq!(store.resolve("app.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 10)));
+
+        drop(store);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite_maintenance() {
+        let path = std::env::temp_dir().join(format!("felix-maintenance-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let state = ResolverState::builder()
+            .upstream("8.8.8.8:53".parse().unwrap())
+            .sqlite(&path)
+            .sqlite_maintenance(std::time::Duration::from_secs(3600))
+            .build()
+            .await
+            .unwrap();
+        for i in 0..2000u32 {
+            state.add_domain(&format!("host-{}.maintenance.dev", i), Ipv4Addr::from(0x0a00_0000 + i)).await.unwrap();
+        }
+        let size = std::fs::metadata(&path).unwrap().len();
+        for i in 0..2000u32 {
+            state.remove_domain(&format!("host-{}.maintenance.dev", i)).await.unwrap();
+        }
+
+        assert!(state.run_maintenance().await.unwrap().unwrap() > 0);
+        assert_eq!(state.run_maintenance().await.unwrap(), Some(0));
+        assert!(std::fs::metadata(&path).unwrap().len() < size);
+
+        assert!(matches!(
+            ResolverState::builder().sqlite(&path).sqlite_maintenance(std::time::Duration::ZERO).build().await,
+            Err(Error::Config(BuildError::ZeroMaintenanceInterval))
+        ));
+        let state = ResolverState::builder().sqlite(&path).build().await.unwrap();
+        assert_eq!(state.run_maintenance().await.unwrap(), None);
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn test_sqlcipher_store() {
+        let path = std::env::temp_dir().join(format!("felix-sqlcipher-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let key = DatabaseKey::new("correct ho'rse");
+
+        let store = SqliteDomainStore::encrypted(&path, 2, &key).await.unwrap();
+        store.set("secret-internal.corp.dev", Ipv4Addr::new(10, 1, 2, 3)).await.unwrap();
+        drop(store);
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(15).any(|w| w == b"secret-internal"));
+        assert!(SqliteDomainStore::new(&path).await.is_err());
+        assert!(SqliteDomainStore::encrypted(&path, 2, &DatabaseKey::new("wrong")).await.is_err());
+        assert_eq!(format!("{:?}", key), "DatabaseKey(..)");
+
+        let state = ResolverState::builder()
+            .upstream("8.8.8.8:53".parse().unwrap())
+            .sqlite(&path)
+            .sqlite_key(key)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(state.resolve("secret-internal.corp.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 1, 2, 3)));
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_domain_validation() {
+        use name::{NameError, validate_domain};
+
+        for ok in ["app.dev", "APP.dev.", "*.app.dev", "**.app.dev", "_acme-challenge.app.dev", "xn--bcher-kva.dev", "*.Bücher.dev.", "localhost"] {
+            assert_eq!(validate_domain(ok), Ok(()), "{}", ok);
+        }
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(60); 5].join(".");
+        for (bad, reason) in [
+            ("", NameError::Empty),
+            (".", NameError::Empty),
+            ("app..dev", NameError::EmptyLabel),
+            (".app.dev", NameError::EmptyLabel),
+            ("app dev", NameError::InvalidCharacter(' ')),
+            ("app.dev/x", NameError::InvalidCharacter('/')),
+            ("-app.dev", NameError::Hyphen("-app".into())),
+            ("app.*.dev", NameError::MisplacedWildcard),
+            ("app.**.dev", NameError::MisplacedWildcard),
+            ("***.app.dev", NameError::MisplacedWildcard),
+            ("*app.dev", NameError::MisplacedWildcard),
+            (long_label.as_str(), NameError::LabelTooLong(long_label.clone())),
+            (long_name.as_str(), NameError::TooLong(304)),
+        ] {
+            assert_eq!(validate_domain(bad), Err(reason), "{:?}", bad);
+        }
+
+        let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        match state.add_domain("app..dev", Ipv4Addr::LOCALHOST).await {
+            Err(Error::InvalidDomain(domain, NameError::EmptyLabel)) => assert_eq!(domain, "app..dev"),
+            other => panic!("expected InvalidDomain, got {:?}", other),
+        }
+        assert!(state.add_ephemeral_domain("a b.dev", Ipv4Addr::LOCALHOST).is_err());
+        assert!(state.list_domains().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_normalized_names() {
+        use name::NormalizedName;
+
+        for (name, normalized) in [
+            ("App.Dev.", "app.dev"),
+            ("Bücher.dev", "xn--bcher-kva.dev"),
+            ("*.BÜCHER.dev.", "*.xn--bcher-kva.dev"),
+            ("xn--BCHER-kva.dev", "xn--bcher-kva.dev"),
+            ("_acme-challenge.bücher.dev", "_acme-challenge.xn--bcher-kva.dev"),
+        ] {
+            assert_eq!(NormalizedName::new(name).as_str(), normalized, "{}", name);
+        }
+        assert_eq!(NormalizedName::checked("a b.dev"), Err(name::NameError::InvalidCharacter(' ')));
+        // the handler's form of a question name
+        let wire = hickory_proto::rr::Name::from_utf8("Shop.Bücher.dev.").unwrap();
+        assert_eq!(NormalizedName::from_wire(&wire).fqdn(), "shop.xn--bcher-kva.dev.");
+        assert_eq!(NormalizedName::from_wire(&hickory_proto::rr::Name::root()).fqdn(), ".");
+
+        // a mapping and a query in either form meet, for exact names and
+        // wildcards alike; removal normalizes like insertion
+        let mut map = DomainMap::new();
+        map.set("*.Bücher.dev.", Ipv4Addr::new(10, 0, 0, 1));
+        map.set("xn--caf-dma.dev", Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(map.resolve("shop.xn--bcher-kva.dev."), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(map.resolve("SHOP.bücher.dev"), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(map.resolve("café.dev."), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        map.remove("*.xn--BCHER-kva.dev.");
+        map.remove("CAFÉ.dev.");
+        assert!(map.is_empty());
+
+        let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        state.add_domain("**.Bücher.dev", Ipv4Addr::new(10, 0, 0, 3)).await.unwrap();
+        assert_eq!(state.list_domains().await.unwrap(), vec![("**.xn--bcher-kva.dev".to_string(), Ipv4Addr::new(10, 0, 0, 3))]);
+        // as given and as the handler passes it on
+        let qname = hickory_proto::rr::Name::from_ascii("a.b.xn--bcher-kva.dev.").unwrap().to_utf8();
+        assert_eq!(state.resolve(&qname).await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+        assert_eq!(state.resolve("a.B.xn--bcher-kva.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+        assert_eq!(state.search_domains("bücher", 10).await.unwrap().len(), 1);
+        state.remove_domain("**.bücher.dev.").await.unwrap();
+        assert!(state.list_domains().await.unwrap().is_empty());
+
+        #[cfg(feature = "sqlite")]
+        {
+            let store = SqliteDomainStore::new(":memory:").await.unwrap();
+            store.set("*.Bücher.dev.", Ipv4Addr::new(10, 0, 0, 4)).await.unwrap();
+            assert_eq!(store.resolve(&qname).await.unwrap(), None);
+            assert_eq!(store.resolve("b.xn--bcher-kva.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 4)));
+            assert_eq!(store.search("Bücher", 10).await.unwrap().len(), 1);
+            store.remove("*.XN--BCHER-KVA.DEV.").await.unwrap();
+            assert_eq!(store.count().await.unwrap(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_safe_ip_policy() {
+        let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        state.add_domain("public.dev", Ipv4Addr::new(93, 184, 216, 34)).await.unwrap();
+
+        state.set_safe_ips(true);
+        for ip in [Ipv4Addr::LOCALHOST, Ipv4Addr::new(10, 1, 2, 3), Ipv4Addr::new(172, 20, 0, 2), Ipv4Addr::new(192, 168, 1, 9)] {
+            state.add_domain("app.dev", ip).await.unwrap();
+        }
+        for ip in [Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(172, 32, 0, 1)] {
+            assert!(matches!(state.add_domain("typo.dev", ip).await, Err(Error::PublicAddress(a)) if a == ip));
+        }
+        assert!(state.add_ephemeral_domain("typo.dev", Ipv4Addr::new(1, 1, 1, 1)).is_err());
+        state.add_domain_allow_public("cdn.dev", Ipv4Addr::new(1, 1, 1, 1)).await.unwrap();
+
+        // mappings made before the policy was turned on still resolve
+        assert_eq!(state.resolve("public.dev").await.unwrap(), Some(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(state.resolve("typo.dev").await.unwrap(), None);
+        assert_eq!(state.resolve("cdn.dev").await.unwrap(), Some(Ipv4Addr::new(1, 1, 1, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_public_suffix_guard() {
+        use public_suffix::public_suffix;
+
+        assert_eq!(public_suffix("github.com").as_deref(), Some("com"));
+        assert_eq!(public_suffix("*.api.example.co.uk").as_deref(), Some("co.uk"));
+        assert_eq!(public_suffix("x.foo.ck").as_deref(), Some("foo.ck"));
+        assert_eq!(public_suffix("www.ck").as_deref(), Some("ck"));
+        for name in ["app.test", "api.localhost", "db.internal", "nas.home.arpa", "devbox", "app.notatld"] {
+            assert_eq!(public_suffix(name), None, "{}", name);
+        }
+
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
+        let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        state.set_public_suffix_guard(Some(PublicSuffixGuard::Warn));
+        state.add_domain("github.com", ip).await.unwrap();
+
+        state.set_public_suffix_guard(Some(PublicSuffixGuard::Refuse));
+        assert!(matches!(state.add_domain("google.com", ip).await, Err(Error::PublicSuffix(d, s)) if d == "google.com" && s == "com"));
+        assert!(state.add_ephemeral_domain("**.bbc.co.uk", ip).is_err());
+        assert!(state.add_view_domain("lan", "gitlab.com", ip).is_err());
+        state.add_domain("app.test", ip).await.unwrap();
+        state.add_domain_allow_public("google.com", ip).await.unwrap();
+        assert_eq!(state.resolve("github.com").await.unwrap(), Some(ip));
+    }
+
+    #[test]
+    fn test_mapping_conflicts() {
+        use conflicts::{conflicts_with, find_conflicts};
+
+        let ip = |d| Ipv4Addr::new(10, 0, 0, d);
+        let mappings: Vec<(String, Ipv4Addr)> = [
+            ("**.app.dev", ip(1)),
+            ("api.app.dev", ip(2)),
+            ("*.eu.app.dev", ip(1)),
+            ("db.eu.app.dev", ip(3)),
+            ("app.dev", ip(4)),
+            ("other.dev", ip(5)),
+        ]
+        .into_iter()
+        .map(|(d, ip)| (d.to_string(), ip))
+        .collect();
+
+        let found: Vec<String> = find_conflicts(&mappings).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            found,
+            vec![
+                "nested wildcard *.eu.app.dev repeats **.app.dev (10.0.0.1) and can be removed",
+                "exact entry api.app.dev (10.0.0.2) overrides **.app.dev (10.0.0.1)",
+                "exact entry db.eu.app.dev (10.0.0.3) overrides *.eu.app.dev (10.0.0.1)",
+            ]
+        );
+
+        // the apex is not covered by its wildcard
+        assert!(conflicts_with("app.dev", &mappings).is_empty());
+        let nested: Vec<String> = conflicts_with("*.eu.app.dev", &mappings).into_iter().map(|c| c.domain).collect();
+        assert_eq!(nested, vec!["*.eu.app.dev", "db.eu.app.dev"]);
+
+        // one-label `*.app.dev` does not reach into eu.app.dev
+        let mut single = mappings.clone();
+        single[0].0 = "*.app.dev".into();
+        let found: Vec<String> = find_conflicts(&single).into_iter().map(|c| c.domain).collect();
+        assert_eq!(found, vec!["api.app.dev", "db.eu.app.dev"]);
+    }
+
+    #[test]
+    fn test_type_block_rule_matching() {
+        let rule = TypeBlockRule { qtype: RecordType::AAAA, suffix: Some("corp.dev".into()), response: BlockResponse::NoData };
+        assert!(rule.matches("corp.dev.", RecordType::AAAA));
+        assert!(rule.matches("API.Corp.dev", RecordType::AAAA));
+        assert!(!rule.matches("notcorp.dev", RecordType::AAAA));
+        assert!(!rule.matches("api.corp.dev", RecordType::A));
+
+        let any = TypeBlockRule { qtype: RecordType::ANY, suffix: None, response: BlockResponse::Refused };
+        assert!(any.matches("anything.example", RecordType::ANY));
+    }
+
+    #[test]
+    fn test_policy_schedules() {
+        use policy::{NaiveTime, Weekday};
+
+        let at = |day: u32, hour: u32, min: u32| {
+            // 2024-01-01 was a Monday
+            chrono::NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, min, 0).unwrap()
+        };
+        let hm = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let weekdays = vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri];
+
+        let office = Schedule { days: weekdays.clone(), start: hm(9, 0), end: hm(17, 0) };
+        assert!(office.contains(at(1, 9, 0)));
+        assert!(office.contains(at(5, 16, 59)));
+        assert!(!office.contains(at(1, 17, 0)));
+        assert!(!office.contains(at(1, 8, 59)));
+        assert!(!office.contains(at(6, 12, 0)));
+
+        // Friday night runs into Saturday, Sunday night does not start
+        let night = Schedule { days: weekdays, start: hm(22, 0), end: hm(6, 0) };
+        assert!(night.contains(at(5, 23, 0)));
+        assert!(night.contains(at(6, 5, 59)));
+        assert!(!night.contains(at(6, 23, 0)));
+        assert!(!night.contains(at(1, 3, 0)));
+        assert!(night.contains(at(2, 3, 0)));
+
+        let weekend = Schedule::days(vec![Weekday::Sat, Weekday::Sun]);
+        assert!(weekend.contains(at(6, 0, 0)));
+        assert!(weekend.contains(at(7, 23, 59)));
+        assert!(!weekend.contains(at(8, 0, 0)));
+
+        let rule = PolicyRule { suffix: "social.dev".into(), action: PolicyAction::Allow, schedule: Some(office) };
+        assert!(rule.applies("www.social.dev.", at(1, 10, 0)));
+        assert!(!rule.applies("www.social.dev.", at(6, 10, 0)));
+        assert!(!rule.applies("social.example.", at(1, 10, 0)));
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = "# generated\nsearch corp.example\nnameserver 10.0.0.2\nnameserver fe80::1%eth0 # link-local\nnameserver 10.0.0.2\n;nameserver 1.1.1.1\noptions edns0\n";
+        let upstreams = system_dns::parse_resolv_conf(conf);
+        assert_eq!(upstreams, vec!["10.0.0.2:53".parse().unwrap(), "[fe80::1]:53".parse().unwrap()]);
+        assert_eq!(system_dns::parse_resolv_conf_search(conf), vec!["corp.example"]);
+
+        // the last of search and domain wins
+        let conf = "domain Office.example.\nsearch corp.example mycompany.dev # vpn\n";
+        assert_eq!(system_dns::parse_resolv_conf_search(conf), vec!["corp.example", "mycompany.dev"]);
+        let conf = "search corp.example\ndomain office.example extra\n";
+        assert_eq!(system_dns::parse_resolv_conf_search(conf), vec!["office.example"]);
+    }
+
+    #[test]
+    fn test_syslog_rfc5424_over_udp() {
+        use log::Log;
+
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target: SyslogTarget = format!("udp://{}", collector.local_addr().unwrap()).parse().unwrap();
+        let logger = syslog::SyslogLogger::new(&target, log::LevelFilter::Info).unwrap();
+
+        logger.log(&log::Record::builder()
+            .level(log::Level::Info)
+            .target("felix_dns::server_handler")
+            .args(format_args!("Answered app.dev"))
+            .build());
+        // below the level, dropped
+        logger.log(&log::Record::builder().level(log::Level::Debug).args(format_args!("noise")).build());
+
+        let mut buf = [0u8; 512];
+        let n = collector.recv(&mut buf).unwrap();
+        let line = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(line.starts_with("<30>1 "), "{}", line);
+        assert!(line.ends_with(" felix_dns::server_handler - Answered app.dev"), "{}", line);
+
+        assert!("tcp://x".parse::<SyslogTarget>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bench_workloads() {
+        let queries = bench::queries(1000, 400);
+        let map = bench::domain_map(1000);
+        let hits = bench::resolve_all(&map, &queries);
+        assert_eq!(queries.iter().filter(|q| q.starts_with("missing-")).count(), 100);
+        assert_eq!(hits, 300);
+
+        let mut udp = bench::UdpBench::start(1000).await.unwrap();
+        assert_eq!(udp.query("host-1.bench.dev").await.unwrap(), 1);
+        assert_eq!(udp.query("api.svc-10.bench.dev").await.unwrap(), 1);
+        assert_eq!(udp.query("missing-1.bench.dev").await.unwrap(), 0);
+        udp.shutdown().await;
+    }
+
+    #[test]
+    fn test_runtime_log_filter() {
+        use std::sync::{Arc, Mutex};
+
+        struct Capture(Arc<Mutex<Vec<String>>>);
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                // other tests log concurrently through the same global logger
+                if record.target().starts_with("filter_test") {
+                    self.0.lock().unwrap().push(format!("{} {}", record.target(), record.args()));
+                }
+            }
+            fn flush(&self) {}
+        }
+
+        let mgmt = Management::new(ResolverState::new("8.8.8.8:53".parse().unwrap()));
+        mgmt.add_token("viewer", Role::ReadOnly);
+        mgmt.add_token("root", Role::Admin);
+        assert!(matches!(mgmt.set_log_filter("root", "info,x=loud"), Err(Error::LogFilter(_))));
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        log_filter::install(Box::new(Capture(lines.clone())), "info").unwrap();
+        log::info!(target: "filter_test::handler", "one");
+        log::debug!(target: "filter_test::handler", "hidden");
+
+        assert!(mgmt.set_log_filter("viewer", "debug").is_err());
+        mgmt.set_log_filter("root", "info,filter_test::handler=debug").unwrap();
+        assert_eq!(mgmt.log_filter("viewer").unwrap().as_deref(), Some("info,filter_test::handler=debug"));
+        log::debug!(target: "filter_test::handler", "two");
+        log::debug!(target: "filter_test::other", "hidden");
+
+        assert_eq!(*lines.lock().unwrap(), vec!["filter_test::handler one", "filter_test::handler two"]);
+        assert!(log_filter::install(Box::new(Capture(lines.clone())), "info").is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_layered_storage() {
+        let state = ResolverState::builder()
+            .upstream("8.8.8.8:53".parse().unwrap())
+            .sqlite(":memory:")
+            .build()
+            .await
+            .unwrap();
+        state.add_domain("*.app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("db.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.add_ephemeral_domain("api.app.dev", Ipv4Addr::new(172, 17, 0, 5)).unwrap();
+        state.add_ephemeral_domain("db.dev", Ipv4Addr::new(172, 17, 0, 6)).unwrap();
+
+        // the memory layer is checked first, then the persistent one
+        assert_eq!(state.resolve("api.app.dev.").await.unwrap(), Some(Ipv4Addr::new(172, 17, 0, 5)));
+        assert_eq!(state.resolve("web.app.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.resolve("db.dev.").await.unwrap(), Some(Ipv4Addr::new(172, 17, 0, 6)));
+
+        let mut domains = state.list_domains().await.unwrap();
+        domains.sort();
+        assert_eq!(domains, vec![
+            ("*.app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 1)),
+            ("api.app.dev".to_string(), Ipv4Addr::new(172, 17, 0, 5)),
+            ("db.dev".to_string(), Ipv4Addr::new(172, 17, 0, 6)),
+        ]);
+
+        state.remove_domain("db.dev").await.unwrap();
+        assert_eq!(state.resolve("db.dev.").await.unwrap(), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_write_behind_batches_sqlite_writes() {
+        let path = std::env::temp_dir().join(format!("felix-write-behind-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let direct = SqliteDomainStore::new(&path).await.unwrap();
+        direct.set("old.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+
+        let state = ResolverState::builder()
+            .upstream("8.8.8.8:53".parse().unwrap())
+            .sqlite(&path)
+            .write_behind(std::time::Duration::from_secs(3600))
+            .build()
+            .await
+            .unwrap();
+        state.add_domain("*.App.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.remove_domain("old.dev").await.unwrap();
+
+        // staged changes are visible right away but not yet in the database
+        assert_eq!(state.resolve("web.app.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(state.resolve("old.dev.").await.unwrap(), None);
+        assert_eq!(state.list_domains().await.unwrap(), vec![("*.app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 2))]);
+        assert_eq!(direct.list().await.unwrap(), vec![("old.dev".to_string(), Ipv4Addr::new(10, 0, 0, 1))]);
+
+        state.flush().await.unwrap();
+        assert_eq!(direct.list().await.unwrap(), vec![("*.app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 2))]);
+
+        assert!(matches!(
+            ResolverState::builder().sqlite(&path).write_behind(std::time::Duration::ZERO).build().await,
+            Err(Error::Config(BuildError::ZeroFlushInterval))
+        ));
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_forwarding_config_survives_restart() {
+        let path = std::env::temp_dir().join(format!("felix-forward-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let corp: std::net::SocketAddr = "10.8.0.1:53".parse().unwrap();
+        let lab: std::net::SocketAddr = "10.8.0.2:53".parse().unwrap();
+
+        let state = ResolverState::builder()
+            .upstream("1.1.1.1:53".parse().unwrap())
+            .sqlite(&path)
+            .forward_rule(ForwardRule { suffix: "Corp.Example.".into(), upstream: corp })
+            .build()
+            .await
+            .unwrap();
+        state.add_forward_rule(ForwardRule { suffix: "lab.corp.example".into(), upstream: lab }).await.unwrap();
+        state.save_upstream("9.9.9.9:53".parse().unwrap()).await.unwrap();
+        drop(state);
+
+        // no upstream given: everything comes back from the database
+        let state = ResolverState::builder().sqlite(&path).build().await.unwrap();
+        assert_eq!(state.upstream(), "9.9.9.9:53".parse().unwrap());
+        assert_eq!(state.forward_rules().len(), 2);
+        assert_eq!(state.upstream_for("db.corp.example."), corp);
+        assert_eq!(state.upstream_for("x.lab.corp.example."), lab);
+        assert_eq!(state.upstream_for("example.com."), state.upstream());
+
+        state.remove_forward_rule("lab.corp.example").await.unwrap();
+        drop(state);
+        let state = ResolverState::builder().sqlite(&path).build().await.unwrap();
+        assert_eq!(state.upstream_for("x.lab.corp.example."), corp);
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_upstream_mappings() {
+        let path = std::env::temp_dir().join(format!("felix-upstream-map-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let internal: std::net::SocketAddr = "10.0.0.2:53".parse().unwrap();
+        let db: std::net::SocketAddr = "10.0.0.3:53".parse().unwrap();
+        let corp: std::net::SocketAddr = "10.8.0.1:53".parse().unwrap();
+
+        let state = ResolverState::builder()
+            .upstream("1.1.1.1:53".parse().unwrap())
+            .sqlite(&path)
+            .upstream_mapping("*.Internal.", internal)
+            .forward_rule(ForwardRule { suffix: "internal".into(), upstream: corp })
+            .build()
+            .await
+            .unwrap();
+        state.add_upstream_mapping("db.internal", db).await.unwrap();
+        assert!(state.add_upstream_mapping("x.internal", "0.0.0.0:53".parse().unwrap()).await.is_err());
+        drop(state);
+
+        let state = ResolverState::builder().sqlite(&path).build().await.unwrap();
+        assert_eq!(state.upstream_mappings(), vec![("*.internal".to_string(), internal), ("db.internal".to_string(), db)]);
+        // exact beats wildcard, and a mapping beats the broader forward rule
+        assert_eq!(state.upstream_for("db.internal."), db);
+        assert_eq!(state.upstream_route("api.internal."), (internal, UpstreamSource::Mapping("*.internal".into())));
+        // like an A wildcard, `*.internal` does not cover the apex
+        assert_eq!(state.upstream_route("internal."), (corp, UpstreamSource::ForwardRule("internal".into())));
+        // one lookup for both: the most specific entry wins either way
+        let lab: std::net::SocketAddr = "10.8.0.2:53".parse().unwrap();
+        state.add_forward_rule(ForwardRule { suffix: "lab.internal".into(), upstream: lab }).await.unwrap();
+        assert_eq!(state.upstream_for("lab.internal."), lab);
+        assert_eq!(state.upstream_for("db.lab.internal."), lab);
+        assert_eq!(state.upstream_route("example.com."), (state.upstream(), UpstreamSource::Default));
+
+        state.remove_upstream_mapping("*.internal").await.unwrap();
+        assert_eq!(state.upstream_for("api.internal."), corp);
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "redb")]
+    #[tokio::test]
+    async fn test_redb_backend() {
+        let path = std::env::temp_dir().join(format!("felix-{}.redb", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let state = ResolverState::builder()
+            .upstream("9.9.9.9:53".parse().unwrap())
+            .redb(&path)
+            .wildcard_apex(true)
+            .build()
+            .await
+            .unwrap();
+        state.add_domain("*.App.dev.", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("gone.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.remove_domain("gone.dev").await.unwrap();
+        state
+            .add_forward_rule(ForwardRule { suffix: "corp.example".into(), upstream: "10.8.0.1:53".parse().unwrap() })
+            .await
+            .unwrap();
+        drop(state);
+
+        let state = ResolverState::builder().redb(&path).wildcard_apex(true).build().await.unwrap();
+        assert_eq!(state.upstream(), "9.9.9.9:53".parse().unwrap());
+        assert_eq!(state.upstream_for("db.corp.example."), "10.8.0.1:53".parse().unwrap());
+        assert_eq!(state.resolve("web.app.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.resolve("app.dev.").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.resolve("gone.dev.").await.unwrap(), None);
+        assert_eq!(state.list_domains().await.unwrap(), vec![("*.app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 1))]);
+
+        drop(state);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_proto_helpers() {
+        use hickory_proto::op::ResponseCode;
+
+        let mut query = proto::build_query("app.dev.", RecordType::A).unwrap();
+        query.set_id(42);
+        let query = proto::decode(&proto::encode(&query).unwrap()).unwrap();
+        assert!(query.recursion_desired());
+
+        let resp = proto::build_a_response(&query, Ipv4Addr::new(10, 0, 0, 1), 30);
+        assert_eq!(resp.id(), 42);
+        assert!(resp.authoritative());
+        assert_eq!(resp.queries(), query.queries());
+        assert_eq!(resp.answers()[0].name().to_utf8(), "app.dev.");
+        assert_eq!(resp.answers()[0].ttl(), 30);
+
+        assert_eq!(proto::build_nxdomain(&query).response_code(), ResponseCode::NXDomain);
+        assert_eq!(proto::build_servfail(&query).response_code(), ResponseCode::ServFail);
+        assert_eq!(proto::build_refused(&query).response_code(), ResponseCode::Refused);
+        assert!(proto::decode(&[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_cache_inspect_and_flush() {
+        let upstream: std::net::SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let state = ResolverState::new(upstream);
+        let mgmt = Management::new(state.clone());
+        mgmt.add_token("viewer", Role::ReadOnly);
+        mgmt.add_token("root", Role::Admin);
+
+        for (name, qtype) in [("a.example.", RecordType::A), ("A.example", RecordType::TXT), ("b.example.", RecordType::A)] {
+            let query = proto::build_query(name, qtype).unwrap();
+            let reply = proto::build_a_response(&query, Ipv4Addr::new(192, 0, 2, 1), 300);
+            state.cache().insert(name, qtype, upstream, reply);
+        }
+
+        let entries = mgmt.cache_entries("viewer").unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].name.as_str(), entries[0].qtype.as_str()), ("a.example", "A"));
+        assert!(entries[0].ttl > 290 && entries[0].ttl <= 300);
+        assert_eq!(entries[0].answers, vec!["A 192.0.2.1"]);
+
+        assert!(mgmt.flush_cache("viewer", None).is_err());
+        // a name flushes every type cached for it
+        assert_eq!(mgmt.flush_cache("root", Some("A.EXAMPLE.")).unwrap(), 2);
+        assert_eq!(mgmt.flush_cache("root", None).unwrap(), 1);
+        assert!(state.cache_entries().is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_soonest_expiry() {
+        let upstream: std::net::SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let state = ResolverState::new(upstream);
+        state.set_cache_config(CacheConfig { max_entries: 2, prefetch_hits: 0 });
+
+        let insert = |name: &str, ttl: u32| {
+            let query = proto::build_query(name, RecordType::A).unwrap();
+            state.cache().insert(name, RecordType::A, upstream, proto::build_a_response(&query, Ipv4Addr::new(192, 0, 2, 1), ttl))
+        };
+        insert("long.example.", 300);
+        insert("short.example.", 60);
+        // refreshing a stored name never evicts another one
+        insert("long.example.", 600);
+        assert_eq!(state.cache_entries().len(), 2);
+
+        insert("new.example.", 120);
+        let names: Vec<_> = state.cache_entries().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["long.example", "new.example"]);
+    }
+
+    #[tokio::test]
+    async fn test_management_roles() {
+        let mgmt = Management::new(ResolverState::new("8.8.8.8:53".parse().unwrap()));
+        mgmt.add_token("viewer", Role::ReadOnly);
+        mgmt.add_token("root", Role::Admin);
+
+        // admin can mutate, viewer can only read
+        mgmt.add_domain("root", "app.dev", Ipv4Addr::new(127, 0, 0, 1)).await.unwrap();
+        assert!(mgmt.add_domain("viewer", "evil.dev", Ipv4Addr::new(6, 6, 6, 6)).await.is_err());
+        assert!(mgmt.set_upstream("viewer", "1.1.1.1:53".parse().unwrap()).await.is_err());
+        assert_eq!(mgmt.list_domains("viewer").await.unwrap().len(), 1);
+        assert_eq!(mgmt.stats("viewer").await.unwrap().domains, 1);
+
+        // unknown and revoked tokens are rejected, prefixes of real ones too
+        assert!(matches!(
+            mgmt.list_domains("nobody").await,
+            Err(Error::Access(management::AccessError::Unauthenticated))
+        ));
+        for guess in ["roo", "roots", "Root", ""] {
+            assert_eq!(mgmt.authorize(guess, Role::ReadOnly), Err(management::AccessError::Unauthenticated), "{:?}", guess);
+        }
+        mgmt.revoke_token("viewer");
+        assert_eq!(
+            mgmt.authorize("viewer", Role::ReadOnly),
+            Err(management::AccessError::Unauthenticated)
+        );
+    }
 }
 
 #[cfg(test)]
 mod integration_tests {
     use super::*;
+    use testing::{MockReply, MockUpstream};
     use tokio::runtime::Runtime;
     use std::net::{SocketAddr, Ipv4Addr};
     use hickory_resolver::{
         config::{NameServerConfig, ResolverConfig}, name_server::GenericConnector, proto::{runtime::TokioRuntimeProvider, xfer::Protocol}, TokioResolver 
     };
 
+    /// Upstream stub answering every query with one A record.
+    async fn spawn_static_upstream(ip: Ipv4Addr, ttl: u32) -> MockUpstream {
+        MockUpstream::start(MockReply::a(ip).ttl(ttl)).await.unwrap()
+    }
+
     #[test]
     fn test_server_resolves_custom_domain() {
         let rt = Runtime::new().unwrap();
@@ -125,11 +1154,9 @@ mod integration_tests {
             let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
             state.add_domain_sync("local.dev", Ipv4Addr::new(127,0,0,1));
 
-            let socket = tokio::net::UdpSocket::bind(listen).await.unwrap();
-            let local_addr = socket.local_addr().unwrap();
-            drop(socket); 
-
-            let handle = run_udp_server(local_addr, state.clone()).await.unwrap();
+            let handle = run_udp_server(listen, state.clone()).await.unwrap();
+            let local_addr = handle.local_addr();
+            assert_ne!(local_addr.port(), 0);
 
             let mut cfg = ResolverConfig::new();
             cfg.add_name_server(NameServerConfig {
@@ -160,4 +1187,2766 @@ mod integration_tests {
             handle.shutdown().await;
         });
     }
+
+    #[test]
+    fn test_events_websocket_streams_queries() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            state.add_domain_sync("events.dev", Ipv4Addr::new(127,0,0,1));
+
+            let dns = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+            let dns_addr = dns.local_addr();
+
+            let mgmt = Management::new(state.clone());
+            mgmt.add_token("viewer", Role::ReadOnly);
+            let api = run_management_server("127.0.0.1:0".parse().unwrap(), mgmt).await.unwrap();
+
+            // bad token is refused before the upgrade
+            let url = format!("ws://{}/events?token=nope", api.local_addr());
+            assert!(tokio_tungstenite::connect_async(url).await.is_err());
+
+            let url = format!("ws://{}/events?token=viewer", api.local_addr());
+            let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+            let mut cfg = ResolverConfig::new();
+            cfg.add_name_server(NameServerConfig::new(dns_addr, Protocol::Udp));
+            let resolver = TokioResolver::builder_with_config(cfg, GenericConnector::new(TokioRuntimeProvider::new())).build();
+            resolver.ipv4_lookup("events.dev").await.unwrap();
+
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), ws.next()).await.unwrap().unwrap().unwrap();
+            let Message::Text(text) = msg else { panic!("expected text frame, got {:?}", msg) };
+            let ev: QueryEvent = serde_json::from_str(&text).unwrap();
+            assert_eq!(ev.name, "events.dev");
+            assert_eq!(ev.qtype, "A");
+            assert_eq!(ev.outcome, QueryOutcome::Local);
+            assert_eq!(ev.source, AnswerSource::LocalExact);
+
+            api.shutdown().await;
+            dns.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_felix_server_builder() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut server = FelixServer::builder()
+                .listen("127.0.0.1:0".parse().unwrap())
+                .management("127.0.0.1:0".parse().unwrap())
+                .domain("embedded.dev", Ipv4Addr::new(10, 1, 2, 3))
+                .build()
+                .await
+                .unwrap();
+            assert!(server.local_addrs().is_empty());
+
+            server.start().await.unwrap();
+            let addrs = server.local_addrs();
+            assert_eq!(addrs.len(), 1);
+            assert_ne!(addrs[0].port(), 0);
+            assert!(server.management_addr().is_some());
+
+            let mut cfg = ResolverConfig::new();
+            cfg.add_name_server(NameServerConfig::new(addrs[0], Protocol::Udp));
+            let resolver = TokioResolver::builder_with_config(cfg, GenericConnector::new(TokioRuntimeProvider::new())).build();
+            let ips: Vec<Ipv4Addr> = resolver.ipv4_lookup("embedded.dev").await.unwrap().iter().map(|a| a.0).collect();
+            assert_eq!(ips, vec![Ipv4Addr::new(10, 1, 2, 3)]);
+
+            server.shutdown().await;
+        });
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_ephemeral_seed_domains() {
+        let path = std::env::temp_dir().join(format!("felix-seeds-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .sqlite(path.clone())
+            .domain("stored.test", Ipv4Addr::new(10, 0, 0, 1))
+            .ephemeral_domain("Stored.test.", Ipv4Addr::new(10, 0, 0, 2))
+            .ephemeral_domain("intranet.test", Ipv4Addr::new(10, 0, 0, 3))
+            .build()
+            .await
+            .unwrap();
+        let state = server.state();
+        assert_eq!(state.resolve("stored.test").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.resolve("intranet.test").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+        drop(server);
+
+        // only the stored mapping reached the database
+        let store = SqliteDomainStore::new(&path).await.unwrap();
+        assert_eq!(store.list().await.unwrap(), vec![("stored.test".to_string(), Ipv4Addr::new(10, 0, 0, 1))]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_handover_sockets() {
+        use std::os::fd::{AsRawFd, IntoRawFd, OwnedFd};
+        use handover::{Inherited, SocketKind, adopt, parse_entry};
+
+        let addr: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        assert_eq!(parse_entry("udp:127.0.0.1:53=5"), Some((SocketKind::Udp, addr, 5)));
+        assert_eq!(parse_entry("tcp:[::1]:53=7"), Some((SocketKind::Tcp, "[::1]:53".parse().unwrap(), 7)));
+        for bad in ["sctp:127.0.0.1:53=5", "udp:127.0.0.1:53", "udp:localhost:53=5", "udp:127.0.0.1:53=x"] {
+            assert_eq!(parse_entry(bad), None, "{}", bad);
+        }
+
+        // only open sockets bound where the entry says are adopted
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bound = socket.local_addr().unwrap();
+        let fd = socket.into_raw_fd();
+        assert!(adopt(-1, None).is_none());
+        assert!(adopt(fd, Some(addr)).is_none());
+        let owned = adopt(fd, Some(bound)).unwrap();
+        assert_eq!(owned.as_raw_fd(), fd);
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        assert!(adopt(file.as_raw_fd(), None).is_none());
+
+        let fd = || OwnedFd::from(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+        let other: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let mut inherited = Inherited::default();
+        inherited.sockets = vec![
+            (SocketKind::Udp, addr, fd()),
+            (SocketKind::Tcp, addr, fd()),
+            (SocketKind::Udp, addr, fd()),
+            (SocketKind::Udp, other, fd()),
+        ];
+        assert_eq!(inherited.take(SocketKind::Udp, addr).len(), 2);
+        assert!(inherited.take(SocketKind::Udp, addr).is_empty());
+        assert!(inherited.take(SocketKind::Udp, "127.0.0.1:0".parse().unwrap()).is_empty());
+        assert_eq!(inherited.take(SocketKind::Tcp, addr).len(), 1);
+        assert_eq!(inherited.sockets.len(), 1);
+    }
+
+    #[test]
+    fn test_server_handle_wait_and_is_running() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            let mut handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+            assert!(handle.is_running());
+
+            // wait() blocks while the server is up
+            let waited = tokio::time::timeout(std::time::Duration::from_millis(50), handle.wait()).await;
+            assert!(waited.is_err());
+            assert!(handle.is_running());
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight_queries() {
+        use hickory_proto::op::{Message, Query, ResponseCode};
+        use hickory_proto::rr::{Name, RecordType};
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // an upstream that never answers keeps the query in flight until the forward timeout
+            let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let state = ResolverState::new(silent.local_addr().unwrap());
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+            let mut query = Message::new();
+            query.set_id(4242);
+            query.add_query(Query::query(Name::from_ascii("slow.example.").unwrap(), RecordType::A));
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&query.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            handle.shutdown().await;
+
+            let mut buf = [0u8; 512];
+            let n = tokio::time::timeout(std::time::Duration::from_secs(1), client.recv(&mut buf)).await.unwrap().unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.id(), 4242);
+            assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        });
+    }
+
+    #[test]
+    fn test_secondary_follows_primary() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let primary = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            primary.add_domain("before.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+            let mgmt = Management::new(primary.clone());
+            mgmt.add_token("repl", Role::ReadOnly);
+            let api = run_management_server("127.0.0.1:0".parse().unwrap(), mgmt).await.unwrap();
+
+            let secondary = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            secondary.add_domain("stale.dev", Ipv4Addr::new(10, 9, 9, 9)).await.unwrap();
+            let follower = follow_primary(api.local_addr(), "repl", secondary.clone());
+
+            async fn wait_for(state: &ResolverState, expected: Vec<(String, Ipv4Addr)>) {
+                for _ in 0..100 {
+                    let mut domains = state.list_domains().await.unwrap();
+                    domains.sort();
+                    if domains == expected {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                panic!("secondary never converged to {:?}", expected);
+            }
+
+            // snapshot replaces the secondary's mappings
+            wait_for(&secondary, vec![("before.dev".to_string(), Ipv4Addr::new(10, 0, 0, 1))]).await;
+
+            // live changes follow
+            primary.add_domain("after.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+            primary.remove_domain("before.dev").await.unwrap();
+            wait_for(&secondary, vec![("after.dev".to_string(), Ipv4Addr::new(10, 0, 0, 2))]).await;
+
+            follower.shutdown().await;
+            api.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_views_by_listener() {
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let loopback = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let lan = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let mgmt = Management::new(state.clone());
+        mgmt.add_token("admin", Role::Admin);
+        mgmt.add_view_domain("admin", "lan", "app.dev", Ipv4Addr::new(192, 168, 1, 20)).unwrap();
+        mgmt.bind_view("admin", lan.local_addr(), Some("lan")).unwrap();
+        assert!(matches!(mgmt.bind_view("admin", lan.local_addr(), Some("LAN")), Err(Error::InvalidView(_))));
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |server: SocketAddr| {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap().answers()[0].data().as_a().unwrap().0
+        };
+        assert_eq!(ask(loopback.local_addr()).await, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ask(lan.local_addr()).await, Ipv4Addr::new(192, 168, 1, 20));
+
+        let views = mgmt.views("admin").unwrap();
+        assert_eq!(views, vec![ViewSummary { name: "lan".into(), domains: 1, listeners: vec![lan.local_addr()] }]);
+
+        mgmt.bind_view("admin", lan.local_addr(), None).unwrap();
+        assert_eq!(ask(lan.local_addr()).await, Ipv4Addr::new(10, 0, 0, 1));
+
+        // the running listener follows later bindings and removals
+        mgmt.bind_view("admin", lan.local_addr(), Some("lan")).unwrap();
+        assert_eq!(ask(lan.local_addr()).await, Ipv4Addr::new(192, 168, 1, 20));
+        state.remove_view("lan");
+        assert_eq!(ask(lan.local_addr()).await, Ipv4Addr::new(10, 0, 0, 1));
+        assert!(state.views().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_list_per_listener() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(203, 0, 113, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("api.mycompany.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let options = ListenOptions { search: vec!["Corp.example.".into(), "mycompany.dev".into()], ..Default::default() };
+        let searching = run_udp_server_with("127.0.0.1:0".parse().unwrap(), &options, state.clone()).await.unwrap();
+        let plain = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |server: SocketAddr, name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap()
+        };
+
+        // the expansion with a local answer wins, under the name as asked
+        let resp = ask(searching.local_addr(), "api.").await;
+        assert_eq!(resp.answers()[0].name().to_utf8(), "api.");
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(10, 0, 0, 1));
+        // otherwise the first is forwarded
+        let resp = ask(searching.local_addr(), "web.").await;
+        assert_eq!(resp.answers()[0].name().to_utf8(), "web.");
+        assert_eq!(upstream.query_count("web.corp.example."), 1);
+        // names with a dot and listeners without a search list are left alone
+        ask(searching.local_addr(), "web.dev.").await;
+        assert_eq!(upstream.query_count("web.dev.corp.example."), 0);
+        ask(plain.local_addr(), "web.").await;
+        assert_eq!(upstream.query_count("web."), 1);
+        assert_eq!(upstream.query_count("web.corp.example."), 1);
+
+        searching.shutdown().await;
+        plain.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_answers_rejected() {
+        use testing::{PoisonTest, Spoof};
+
+        let harness = PoisonTest::start().await.unwrap();
+        let state = ResolverState::new(harness.addr());
+        state.set_failover(FailoverConfig { tcp: true, ..Default::default() });
+        let server = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let results = harness.run(server.local_addr()).await.unwrap();
+        assert_eq!(results.iter().map(|r| r.spoof).collect::<Vec<_>>(), Spoof::ALL);
+        for result in &results {
+            assert!(result.rejected, "{:?} got through: {:?}", result.spoof, result.answers);
+        }
+        // the genuine answers were cached, the planted record was not
+        let forged = testing::FORGED_ADDR.to_string();
+        let cached = state.cache_entries();
+        assert!(cached.len() >= 5, "{:?}", cached);
+        assert!(cached.iter().all(|e| e.answers.iter().all(|a| !a.contains(&forged))), "{:?}", cached);
+
+        server.shutdown().await;
+        harness.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_single_label_policy() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let upstream = spawn_static_upstream(Ipv4Addr::new(203, 0, 113, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("nas", Ipv4Addr::new(10, 0, 0, 9)).await.unwrap();
+        state.add_domain("api.corp.example", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let options = ListenOptions { search: vec!["corp.example".into()], ..Default::default() };
+        let server = run_udp_server_with("127.0.0.1:0".parse().unwrap(), &options, state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap()
+        };
+        let a = |resp: Message| resp.answers()[0].data().as_a().unwrap().0;
+
+        assert_eq!(state.single_label(), SingleLabel::Expand);
+        assert_eq!(a(ask("api.").await), Ipv4Addr::new(10, 0, 0, 1));
+        // the expanded name follows the forward rule for the search suffix
+        let corp = spawn_static_upstream(Ipv4Addr::new(10, 1, 0, 1), 60).await;
+        state.add_forward_rule(ForwardRule { suffix: "corp.example".into(), upstream: corp.addr() }).await.unwrap();
+        assert_eq!(a(ask("wiki.").await), Ipv4Addr::new(10, 1, 0, 1));
+        assert_eq!(corp.query_count("wiki.corp.example."), 1);
+        assert_eq!(upstream.query_count("wiki.corp.example."), 0);
+
+        state.set_single_label(SingleLabel::Forward);
+        assert_eq!(a(ask("api.").await), Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(upstream.query_count("api."), 1);
+
+        state.set_single_label(SingleLabel::Refuse);
+        assert_eq!(ask("nas.").await.response_code(), ResponseCode::Refused);
+        // questions about a TLD are not address lookups
+        let q = proto::build_query("com.", RecordType::NS).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_ne!(proto::decode(&buf[..n]).unwrap().response_code(), ResponseCode::Refused);
+        assert_eq!(upstream.query_count("com."), 1);
+        // localhost is still answered by the reserved stage
+        assert_eq!(a(ask("localhost.").await), Ipv4Addr::LOCALHOST);
+
+        state.set_single_label(SingleLabel::Mappings);
+        assert_eq!(a(ask("nas.").await), Ipv4Addr::new(10, 0, 0, 9));
+        assert_eq!(ask("printer.").await.response_code(), ResponseCode::NXDomain);
+        assert_eq!(ask("api.").await.response_code(), ResponseCode::NXDomain);
+        // names with a dot are not affected
+        assert_eq!(a(ask("www.example.").await), Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(upstream.query_count("printer."), 0);
+        assert_eq!(upstream.query_count("api."), 1);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_reserved_tlds() {
+        use hickory_proto::op::ResponseCode;
+
+        let upstream = spawn_static_upstream(Ipv4Addr::new(203, 0, 113, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("app.test", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("api.localhost", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        let server = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |name: &str, qtype: RecordType| {
+            let q = proto::build_query(name, qtype).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap()
+        };
+
+        let resp = ask("web.localhost.", RecordType::A).await;
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::LOCALHOST);
+        let resp = ask("localhost.", RecordType::AAAA).await;
+        assert_eq!(resp.answers()[0].data().as_aaaa().unwrap().0, std::net::Ipv6Addr::LOCALHOST);
+        let resp = ask("web.localhost.", RecordType::MX).await;
+        assert!(resp.answers().is_empty() && resp.response_code() == ResponseCode::NoError);
+        // mappings still win
+        let resp = ask("api.localhost.", RecordType::A).await;
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(10, 0, 0, 2));
+
+        let resp = ask("app.test.", RecordType::A).await;
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ask("missing.test.", RecordType::A).await.response_code(), ResponseCode::NXDomain);
+        assert_eq!(upstream.query_count("missing.test."), 0);
+
+        state.set_reserved_tlds(ReservedTlds { localhost: false, test_local_only: false });
+        let resp = ask("missing.test.", RecordType::A).await;
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(203, 0, 113, 1));
+        let resp = ask("web.localhost.", RecordType::A).await;
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(203, 0, 113, 1));
+    }
+
+    #[tokio::test]
+    async fn test_domain_delays() {
+        use std::time::Duration;
+        use testing::SimClock;
+
+        let clock = SimClock::pause();
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("*.slow.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("fast.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.set_domain_delay("*.slow.dev", Duration::from_secs(30)).unwrap();
+        assert_eq!(state.domain_delay("api.slow.dev."), Some(Duration::from_secs(30)));
+        let server = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |name: &str| {
+            let before = clock.elapsed();
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            client.recv(&mut buf).await.unwrap();
+            clock.elapsed() - before
+        };
+        assert!(ask("api.slow.dev.").await >= Duration::from_secs(30));
+        assert!(ask("fast.dev.").await < Duration::from_secs(1));
+
+        state.remove_domain("*.slow.dev").await.unwrap();
+        assert!(state.domain_delays().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_projects() {
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(state.add_project("MyApp", ip).await.unwrap(), vec!["myapp.test", "*.myapp.test"]);
+        assert_eq!(state.resolve("myapp.test").await.unwrap(), Some(ip));
+        assert_eq!(state.resolve("api.myapp.test").await.unwrap(), Some(ip));
+
+        state.set_project_template(ProjectTemplate::new(["{project}.dev", "api.{project}.dev", "shared.dev"]));
+        assert_eq!(state.add_project("shop", ip).await.unwrap(), vec!["shop.dev", "api.shop.dev"]);
+        assert_eq!(state.resolve("shared.dev").await.unwrap(), None);
+
+        // a bad name adds nothing
+        assert!(state.add_project("bad_name!", ip).await.is_err());
+        assert_eq!(state.list_domains().await.unwrap().len(), 4);
+
+        state.remove_project("shop").await.unwrap();
+        assert_eq!(state.resolve("api.shop.dev").await.unwrap(), None);
+        assert_eq!(state.resolve("myapp.test").await.unwrap(), Some(ip));
+    }
+
+    #[tokio::test]
+    async fn test_state_snapshot_restore() {
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let clean = state.snapshot().await.unwrap();
+
+        state.add_domain("case.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 3)).await.unwrap();
+        state.add_record("case.dev", RecordData::TXT(rdata::TXT::new(vec!["x".into()]))).await.unwrap();
+        state.set_upstream("127.0.0.1:10".parse().unwrap());
+        state.set_enabled(false);
+        state.set_domain_delay("app.dev", std::time::Duration::from_secs(1)).unwrap();
+        state.set_answer_order("app.dev", AnswerOrder::Shuffle).unwrap();
+        state.add_view_domain("lan", "app.dev", Ipv4Addr::new(192, 168, 1, 2)).unwrap();
+        state.set_project_template(ProjectTemplate::new(["{project}.dev"]));
+
+        state.restore(&clean).await.unwrap();
+        assert_eq!(state.resolve("app.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(state.resolve("case.dev").await.unwrap(), None);
+        assert!(state.lookup_records("case.dev", RecordType::TXT).is_empty());
+        assert_eq!(state.upstream(), "127.0.0.1:9".parse().unwrap());
+        assert!(state.enabled());
+        assert!(state.domain_delays().is_empty());
+        assert!(state.answer_orders().is_empty());
+        assert!(state.views().is_empty());
+        assert_eq!(*state.project_template(), ProjectTemplate::default());
+
+        // the snapshot stays usable for the next case
+        state.add_domain("other.dev", Ipv4Addr::new(10, 0, 0, 4)).await.unwrap();
+        state.restore(&clean).await.unwrap();
+        assert_eq!(state.resolve("other.dev").await.unwrap(), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_state_snapshot_restore_with_sqlite() {
+        let state = ResolverState::new_with_sqlite("127.0.0.1:9".parse().unwrap(), ":memory:").await.unwrap();
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("gone.dev", Ipv4Addr::new(10, 0, 0, 5)).await.unwrap();
+        state.add_ephemeral_domain("box.dev", Ipv4Addr::new(10, 0, 0, 6)).unwrap();
+        let clean = state.snapshot().await.unwrap();
+
+        state.add_domain("case.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 3)).await.unwrap();
+        state.remove_domain("gone.dev").await.unwrap();
+        state.remove_ephemeral_domain("box.dev");
+
+        state.restore(&clean).await.unwrap();
+        let mut domains = state.list_domains().await.unwrap();
+        domains.sort();
+        assert_eq!(
+            domains,
+            vec![
+                ("app.dev".to_string(), Ipv4Addr::new(10, 0, 0, 1)),
+                ("box.dev".to_string(), Ipv4Addr::new(10, 0, 0, 6)),
+                ("gone.dev".to_string(), Ipv4Addr::new(10, 0, 0, 5)),
+            ]
+        );
+        assert_eq!(state.resolve("case.dev").await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_report() {
+        use std::time::Duration;
+
+        let state = ResolverState::new("127.0.0.1:53".parse().unwrap());
+        let upstream = AnswerSource::Upstream("1.1.1.1:53".parse().unwrap());
+        for (name, qtype, source, n) in [
+            ("api.app.dev.", RecordType::A, AnswerSource::LocalExact, 3),
+            ("Web.app.dev.", RecordType::A, AnswerSource::LocalWildcard, 1),
+            ("example.com.", RecordType::AAAA, upstream, 2),
+        ] {
+            for _ in 0..n {
+                state.record_query_name(name, qtype, source);
+            }
+        }
+        tokio::time::advance(Duration::from_secs(10 * 60)).await;
+        state.record_query_name("web.app.dev.", RecordType::A, AnswerSource::LocalWildcard);
+        state.record_query_name("web.app.dev.", RecordType::A, AnswerSource::Cache);
+
+        let recent = state.query_report(Duration::from_secs(5 * 60), 10);
+        assert_eq!((recent.window, recent.queries), (300, 2));
+        assert_eq!(recent.names.len(), 1);
+        assert_eq!((recent.names[0].name.as_str(), recent.names[0].count), ("web.app.dev", 2));
+
+        let hour = state.query_report(Duration::from_secs(3600), 2);
+        assert_eq!(hour.queries, 8);
+        let names: Vec<_> = hour.names.iter().map(|n| (n.name.as_str(), n.count, n.source.as_str())).collect();
+        assert_eq!(names, [("api.app.dev", 3, "local-exact"), ("web.app.dev", 3, "cache")]);
+        let qtypes: Vec<_> = hour.qtypes.iter().map(|t| (t.qtype.as_str(), t.count)).collect();
+        assert_eq!(qtypes, [("A", 6), ("AAAA", 2)]);
+        let sources: Vec<_> = hour.sources.iter().map(|s| (s.source.as_str(), s.count)).collect();
+        assert_eq!(sources, [("local-exact", 3), ("local-wildcard", 2), ("upstream-1.1.1.1:53", 2), ("cache", 1)]);
+
+        // buckets older than a day are dropped
+        tokio::time::advance(query_stats::MAX_WINDOW).await;
+        state.record_query_name("late.dev.", RecordType::A, AnswerSource::LocalExact);
+        assert_eq!(state.query_report(Duration::from_secs(u64::MAX), 10).queries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_answer_orders() {
+        use hickory_proto::op::Message;
+
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain_address("app.dev", Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        state.add_domain_address("app.dev", Ipv4Addr::new(10, 0, 0, 3)).unwrap();
+        let server = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async || {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let len = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..len]).unwrap();
+            resp.answers().iter().map(|r| r.data().to_string()).collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(ask().await, "10.0.0.1 10.0.0.2 10.0.0.3");
+        assert_eq!(ask().await, "10.0.0.1 10.0.0.2 10.0.0.3");
+
+        state.set_answer_order("app.dev", AnswerOrder::RoundRobin).unwrap();
+        assert_eq!(ask().await, "10.0.0.1 10.0.0.2 10.0.0.3");
+        assert_eq!(ask().await, "10.0.0.2 10.0.0.3 10.0.0.1");
+        assert_eq!(ask().await, "10.0.0.3 10.0.0.1 10.0.0.2");
+
+        state.set_answer_order("app.dev", AnswerOrder::Shuffle).unwrap();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..50 {
+            let answer = ask().await;
+            let mut sorted: Vec<_> = answer.split(' ').collect();
+            sorted.sort();
+            assert_eq!(sorted, ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+            seen.insert(answer);
+        }
+        assert!(seen.len() > 1);
+        assert_eq!(state.answer_orders(), vec![("app.dev".to_string(), AnswerOrder::Shuffle)]);
+
+        state.remove_domain("app.dev").await.unwrap();
+        assert!(state.answer_orders().is_empty());
+        assert!(state.lookup_records("app.dev", RecordType::A).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hosts_sync_follows_file() {
+        let path = std::env::temp_dir().join(format!("felix-hosts-{}", std::process::id()));
+        std::fs::write(&path, "# comment\n127.0.0.1 localhost\n10.0.0.5 db.lan db # primary\n::1 ip6-localhost\n10.0.0.6 db.lan\n").unwrap();
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("kept.lan", Ipv4Addr::new(10, 0, 0, 9)).await.unwrap();
+        state.add_domain("db", Ipv4Addr::new(10, 0, 0, 8)).await.unwrap();
+
+        let config = HostsSyncConfig { path: path.clone(), interval: std::time::Duration::from_millis(20) };
+        let handle = start_hosts_sync(config, state.clone()).await.unwrap();
+        assert_eq!(state.resolve("db.lan").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 5)));
+        // mappings made by others are neither overwritten nor removed
+        assert_eq!(state.resolve("db").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 8)));
+        assert_eq!(state.resolve("ip6-localhost").await.unwrap(), None);
+
+        std::fs::write(&path, "10.0.0.7 db.lan\n").unwrap();
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        while state.resolve("db.lan").await.unwrap() != Some(Ipv4Addr::new(10, 0, 0, 7)) {
+            assert!(tokio::time::Instant::now() < deadline, "hosts file change was not picked up");
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(state.resolve("db").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 8)));
+
+        handle.shutdown().await;
+        assert_eq!(state.resolve("db.lan").await.unwrap(), None);
+        assert_eq!(state.resolve("kept.lan").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 9)));
+        assert_eq!(state.resolve("db").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 8)));
+
+        // nothing to remove, nothing announced
+        state.add_ephemeral_domain("other.lan", Ipv4Addr::new(10, 0, 0, 10)).unwrap();
+        let mut changes = state.subscribe_changes();
+        assert!(!state.remove_ephemeral_domain("gone.lan"));
+        assert!(state.remove_ephemeral_domain("other.lan"));
+        assert!(matches!(changes.try_recv(), Ok(DomainChange::Removed { domain }) if domain == "other.lan"));
+        assert!(changes.try_recv().is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_llmnr_answers_mapped_names() {
+        use hickory_proto::op::{Message, MessageType};
+
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("devbox", Ipv4Addr::new(10, 0, 0, 7)).await.unwrap();
+        let config = LlmnrConfig { bind: "127.0.0.1:0".parse().unwrap(), multicast: false };
+        let handle = start_llmnr(config, state).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        let q = proto::build_query("devbox.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        let resp = Message::from_vec(&buf[..n]).unwrap();
+        assert_eq!(resp.message_type(), MessageType::Response);
+        assert_eq!(resp.id(), q.id());
+        assert_eq!(resp.answers()[0].data().as_a().unwrap().0, Ipv4Addr::new(10, 0, 0, 7));
+
+        // unknown names get no response at all
+        let q = proto::build_query("elsewhere.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+        let silent = tokio::time::timeout(std::time::Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert!(silent.is_err());
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_gossip_peers_converge() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let unicast = |peers: Vec<SocketAddr>| GossipConfig {
+                bind: "127.0.0.1:0".parse().unwrap(),
+                mdns: None,
+                peers,
+                interval: std::time::Duration::from_millis(50),
+                secret: "team-secret".into(),
+            };
+
+            let a = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            let b = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            a.add_domain("shared.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+
+            let ga = start_gossip(unicast(vec![]), a.clone()).await.unwrap();
+            // a learns about b from b's first announcement
+            let gb = start_gossip(unicast(vec![ga.local_addr()]), b.clone()).await.unwrap();
+
+            async fn eventually(state: &ResolverState, name: &str, expected: Option<Ipv4Addr>) {
+                for _ in 0..100 {
+                    if state.resolve(name).await.unwrap() == expected {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                panic!("{} never became {:?}", name, expected);
+            }
+
+            eventually(&b, "shared.dev", Some(Ipv4Addr::new(10, 0, 0, 1))).await;
+
+            // later writes win, removals propagate as tombstones
+            b.add_domain("shared.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+            eventually(&a, "shared.dev", Some(Ipv4Addr::new(10, 0, 0, 2))).await;
+            a.remove_domain("shared.dev").await.unwrap();
+            eventually(&b, "shared.dev", None).await;
+
+            // a peer cannot bypass b's own safe-IP policy
+            b.set_safe_ips(true);
+            a.add_domain("cdn.dev", Ipv4Addr::new(1, 1, 1, 1)).await.unwrap();
+
+            // an instance with another secret is heard by nobody
+            let c = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            c.add_domain("intruder.dev", Ipv4Addr::new(10, 0, 0, 3)).await.unwrap();
+            let gc = start_gossip(
+                GossipConfig { secret: "guess".into(), ..unicast(vec![ga.local_addr(), gb.local_addr()]) },
+                c.clone(),
+            )
+            .await
+            .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            assert_eq!(a.resolve("intruder.dev").await.unwrap(), None);
+            assert_eq!(b.resolve("intruder.dev").await.unwrap(), None);
+            assert_eq!(b.resolve("cdn.dev").await.unwrap(), None);
+            // the rejected entry was not recorded, so it applies once allowed
+            b.set_safe_ips(false);
+            eventually(&b, "cdn.dev", Some(Ipv4Addr::new(1, 1, 1, 1))).await;
+            assert!(matches!(
+                start_gossip(GossipConfig { secret: String::new(), ..unicast(vec![]) }, c).await,
+                Err(Error::Config(BuildError::MissingGossipSecret))
+            ));
+
+            gc.shutdown().await;
+
+            ga.shutdown().await;
+            gb.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_raft_cluster() {
+        use raft::{RaftConfig, RaftNode};
+
+        async fn leader_of(nodes: &[RaftNode]) -> RaftNode {
+            for _ in 0..200 {
+                if let Some(leader) = nodes.iter().find(|n| n.is_leader()) {
+                    return leader.clone();
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            panic!("no leader elected");
+        }
+
+        async fn eventually(state: &ResolverState, name: &str, expected: Option<Ipv4Addr>) {
+            for _ in 0..100 {
+                if state.resolve(name).await.unwrap() == expected {
+                    return;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            panic!("{} never became {:?}", name, expected);
+        }
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let dir = std::env::temp_dir().join(format!("felix-raft-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            // held together, so the three ports differ
+            let listeners: Vec<_> = (0..3).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+            let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+            drop(listeners);
+            let config = |id: u64| RaftConfig {
+                id,
+                bind: addrs[id as usize - 1],
+                peers: (1..=3).filter(|p| *p != id).map(|p| (p, addrs[p as usize - 1])).collect(),
+                secret: "cluster-secret".into(),
+                state_path: Some(dir.join(format!("{}.json", id))),
+                heartbeat: std::time::Duration::from_millis(20),
+                election_timeout: std::time::Duration::from_millis(150),
+            };
+
+            let states: Vec<ResolverState> = (0..3).map(|_| ResolverState::new("8.8.8.8:53".parse().unwrap())).collect();
+            let mut handles = Vec::new();
+            for id in 1..=3 {
+                handles.push(Some(start_raft(config(id), states[id as usize - 1].clone()).await.unwrap()));
+            }
+            let nodes: Vec<RaftNode> = handles.iter().flatten().map(|h| h.node()).collect();
+            let state_of = |node: &RaftNode| &states[node.id() as usize - 1];
+
+            // a follower forwards to the leader and returns once it applied the change
+            let leader = leader_of(&nodes).await;
+            let follower = nodes.iter().find(|n| n.id() != leader.id()).unwrap().clone();
+            follower.add_domain("one.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+            assert_eq!(state_of(&follower).resolve("one.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+            for state in &states {
+                eventually(state, "one.dev", Some(Ipv4Addr::new(10, 0, 0, 1))).await;
+            }
+
+            // the proposing member's policies apply before anything is logged
+            state_of(&follower).set_safe_ips(true);
+            assert!(matches!(follower.add_domain("cdn.dev", Ipv4Addr::new(1, 1, 1, 1)).await, Err(Error::PublicAddress(_))));
+
+            // two of three members keep taking changes
+            handles[leader.id() as usize - 1].take().unwrap().shutdown().await;
+            let survivors: Vec<RaftNode> = nodes.iter().filter(|n| n.id() != leader.id()).cloned().collect();
+            let mgmt = Management::new(state_of(&survivors[0]).clone());
+            mgmt.add_token("root", Role::Admin);
+            mgmt.set_cluster(Some(survivors[0].clone()));
+            mgmt.remove_domain("root", "one.dev").await.unwrap();
+            mgmt.add_domain("root", "two.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+            leader_of(&survivors).await;
+            for node in &survivors {
+                eventually(state_of(node), "one.dev", None).await;
+                eventually(state_of(node), "two.dev", Some(Ipv4Addr::new(10, 0, 0, 2))).await;
+            }
+
+            // the old leader rejoins from its saved log with an empty state
+            let rejoined = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            let handle = start_raft(config(leader.id()), rejoined.clone()).await.unwrap();
+            eventually(&rejoined, "two.dev", Some(Ipv4Addr::new(10, 0, 0, 2))).await;
+            assert_eq!(rejoined.resolve("one.dev").await.unwrap(), None);
+
+            assert!(matches!(
+                start_raft(RaftConfig::default(), rejoined).await,
+                Err(Error::Config(BuildError::MissingRaftSecret))
+            ));
+
+            handle.shutdown().await;
+            for handle in handles.into_iter().flatten() {
+                handle.shutdown().await;
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_gossip_mdns_discovery() {
+        // a group port of our own, so the test does not talk to real responders
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let config = GossipConfig {
+            bind: "0.0.0.0:0".parse().unwrap(),
+            mdns: Some(std::net::SocketAddrV4::new(gossip::MDNS_GROUP, port)),
+            peers: Vec::new(),
+            interval: std::time::Duration::from_millis(50),
+            secret: "team-secret".into(),
+        };
+
+        let a = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        let b = ResolverState::new("8.8.8.8:53".parse().unwrap());
+        a.add_domain("found.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let ga = start_gossip(config.clone(), a.clone()).await.unwrap();
+        let gb = start_gossip(config, b.clone()).await.unwrap();
+
+        for _ in 0..100 {
+            if b.resolve("found.dev").await.unwrap().is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(b.resolve("found.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+        ga.shutdown().await;
+        gb.shutdown().await;
+    }
+
+    #[test]
+    fn test_server_applies_script_hooks() {
+        use hickory_proto::op::{Message, Query, ResponseCode};
+        use hickory_proto::rr::{Name, RData, RecordType};
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            state.add_domain("real.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+            state.set_script_hooks(Some(ScriptHooks::from_source(r#"
+                fn on_query(q) {
+                    if q.name == "alias.dev" { return #{ name: "real.dev" }; }
+                    if q.name == "blocked.dev" { return #{ refuse: true }; }
+                }
+            "#).unwrap()));
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let ask = |name: &str| {
+                let mut q = Message::new();
+                q.set_id(7);
+                q.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+                q.to_vec().unwrap()
+            };
+            let mut buf = [0u8; 512];
+
+            client.send_to(&ask("alias.dev."), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.answers()[0].name().to_utf8(), "alias.dev.");
+            assert_eq!(resp.answers()[0].data(), &RData::A(Ipv4Addr::new(10, 0, 0, 1).into()));
+
+            client.send_to(&ask("blocked.dev."), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), ResponseCode::Refused);
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_custom_pipeline_stage() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use pipeline::{Answer, QueryContext};
+        use hickory_proto::op::{Message, Query, ResponseCode};
+        use hickory_proto::rr::{Name, RecordType};
+
+        struct NxInternal(AtomicUsize);
+
+        #[async_trait::async_trait]
+        impl Stage for NxInternal {
+            fn name(&self) -> &'static str {
+                "nx-internal"
+            }
+
+            async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+                if ctx.name().ends_with(".internal") {
+                    return Ok(Some(Answer::new(ctx.reply_with_code(ResponseCode::NXDomain), QueryOutcome::Local)));
+                }
+                Ok(None)
+            }
+
+            async fn on_response(&self, _ctx: &QueryContext, _answer: &mut Answer) -> Result<()> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        struct Broken;
+
+        #[async_trait::async_trait]
+        impl Stage for Broken {
+            fn name(&self) -> &'static str {
+                "broken"
+            }
+
+            async fn on_query(&self, _ctx: &mut QueryContext) -> Result<Option<Answer>> {
+                Ok(None)
+            }
+
+            async fn on_response(&self, _ctx: &QueryContext, _answer: &mut Answer) -> Result<()> {
+                Err(Error::Script("broken".into()))
+            }
+        }
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            state.add_domain("local.internal", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+
+            let stage = std::sync::Arc::new(NxInternal(AtomicUsize::new(0)));
+            let mut pipeline = Pipeline::standard();
+            pipeline.insert_before("local", stage.clone());
+            assert_eq!(
+                pipeline.stage_names(),
+                vec!["acl", "quota", "acme-update", "chaos", "hooks", "policy", "type-block", "single-label", "nx-internal", "local", "reserved", "rewrite", "cache", "forward"]
+            );
+            // a failing on_response does not cost the client its answer
+            pipeline.insert_before("nx-internal", std::sync::Arc::new(Broken));
+            state.set_pipeline(pipeline);
+
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut q = Message::new();
+            q.set_id(9);
+            q.add_query(Query::query(Name::from_ascii("local.internal.").unwrap(), RecordType::A));
+            client.send_to(&q.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), ResponseCode::NXDomain);
+            assert_eq!(stage.0.load(Ordering::SeqCst), 1);
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_rewrite_rules_on_forwarded_answers() {
+        use hickory_proto::op::{Message, Query};
+        use hickory_proto::rr::{Name, RData, RecordType};
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = spawn_static_upstream(Ipv4Addr::new(93, 184, 216, 34), 86400).await;
+            let state = ResolverState::new(upstream.addr());
+            state.set_rewrite_rules(vec![RewriteRule::ReplaceAddress {
+                from: Ipv4Addr::new(93, 184, 216, 34).into(),
+                to: Ipv4Addr::new(127, 0, 0, 1).into(),
+            }]);
+            state.set_ttl_clamps(vec![TtlClamp { suffix: None, min: None, max: Some(300) }]);
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut q = Message::new();
+            q.set_id(11);
+            q.add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+            client.send_to(&q.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.id(), 11);
+            assert_eq!(resp.answers()[0].data(), &RData::A(Ipv4Addr::new(127, 0, 0, 1).into()));
+            assert_eq!(resp.answers()[0].ttl(), 300);
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_ttl_clamps_before_caching() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(10, 0, 0, 9), 5).await;
+        let state = ResolverState::new(upstream.addr());
+        state.set_ttl_clamps(vec![
+            TtlClamp { suffix: None, min: Some(30), max: Some(3600) },
+            TtlClamp { suffix: Some("flaky.example".into()), min: None, max: Some(2) },
+        ]);
+        assert_eq!(state.ttl_clamp_for("api.flaky.example.").unwrap().max, Some(2));
+        assert_eq!(state.ttl_clamp_for("notflaky.example").unwrap().min, Some(30));
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap().answers()[0].ttl()
+        };
+        assert_eq!(ask("example.com.").await, 30);
+        assert_eq!(ask("api.flaky.example.").await, 2);
+
+        // the cache holds the clamped answers
+        let cached: Vec<(String, u32)> = state.cache_entries().into_iter().map(|e| (e.name, e.ttl)).collect();
+        assert_eq!(cached.len(), 2);
+        assert!(cached.iter().all(|(name, ttl)| if name == "example.com" { *ttl > 5 } else { *ttl <= 2 }), "{:?}", cached);
+    }
+
+    #[test]
+    fn test_bailiwick_filtering() {
+        use hickory_proto::dnssec::rdata::{DNSSECRData, NSEC};
+        use hickory_proto::rr::{Name, RData, Record, rdata::{CNAME, NS, NULL, SOA}};
+
+        let name = |n: &str| Name::from_ascii(n).unwrap();
+        let a = |owner: &str, ip: [u8; 4]| Record::from_rdata(name(owner), 60, RData::A(Ipv4Addr::from(ip).into()));
+        let query = proto::build_query("www.shop.example.", RecordType::A).unwrap();
+        let mut reply = proto::build_reply(&query);
+        reply.add_answers([
+            a("cdn.example.net.", [192, 0, 2, 1]),
+            Record::from_rdata(name("www.shop.example."), 60, RData::CNAME(CNAME(name("cdn.example.net.")))),
+            a("bank.example.", [203, 0, 113, 66]),
+        ]);
+        reply.add_name_servers([
+            Record::from_rdata(name("shop.example."), 60, RData::NS(NS(name("ns1.shop.example.")))),
+            Record::from_rdata(name("bank.example."), 60, RData::NS(NS(name("ns.evil.example.")))),
+            Record::from_rdata(
+                name("example."),
+                60,
+                RData::SOA(SOA::new(name("ns.example."), name("hostmaster.example."), 1, 1, 1, 1, 1)),
+            ),
+        ]);
+        reply.add_additionals([a("ns1.shop.example.", [192, 0, 2, 53]), a("ns.evil.example.", [203, 0, 113, 53])]);
+
+        assert_eq!(bailiwick::strip_out_of_bailiwick(&mut reply), 3);
+        let owners = |records: &[Record]| records.iter().map(|r| r.name().to_ascii()).collect::<Vec<_>>();
+        assert_eq!(owners(reply.answers()), ["cdn.example.net.", "www.shop.example."]);
+        assert_eq!(owners(reply.name_servers()), ["shop.example.", "example."]);
+        assert_eq!(owners(reply.additionals()), ["ns1.shop.example."]);
+        assert_eq!(bailiwick::strip_out_of_bailiwick(&mut reply), 0);
+
+        // DNAMEs above the question and denial records in its zone stay
+        let dname = |owner: &str| {
+            let rdata = RData::Unknown { code: RecordType::Unknown(39), rdata: NULL::with(vec![0]) };
+            Record::from_rdata(name(owner), 60, rdata)
+        };
+        let nsec = |owner: &str| {
+            let rdata = RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(name("zz.new.example."), [RecordType::A])));
+            Record::from_rdata(name(owner), 60, rdata)
+        };
+        let query = proto::build_query("www.old.example.", RecordType::AAAA).unwrap();
+        let mut reply = proto::build_reply(&query);
+        reply.add_answers([
+            dname("old.example."),
+            Record::from_rdata(name("www.old.example."), 60, RData::CNAME(CNAME(name("www.new.example.")))),
+            dname("other.example."),
+        ]);
+        reply.add_name_servers([
+            Record::from_rdata(
+                name("new.example."),
+                60,
+                RData::SOA(SOA::new(name("ns.new.example."), name("hostmaster.new.example."), 1, 1, 1, 1, 1)),
+            ),
+            nsec("a.new.example."),
+            nsec("x.bank.example."),
+        ]);
+        assert_eq!(bailiwick::strip_out_of_bailiwick(&mut reply), 2);
+        assert_eq!(owners(reply.answers()), ["old.example.", "www.old.example."]);
+        assert_eq!(owners(reply.name_servers()), ["new.example.", "a.new.example."]);
+    }
+
+    #[test]
+    fn test_type_block_responses() {
+        use hickory_proto::op::{Message, Query, ResponseCode};
+        use hickory_proto::rr::Name;
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            state.add_domain("app.corp.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+            state.set_type_block_rules(vec![
+                TypeBlockRule { qtype: RecordType::ANY, suffix: None, response: BlockResponse::Refused },
+                TypeBlockRule { qtype: RecordType::AAAA, suffix: Some("corp.dev".into()), response: BlockResponse::NoData },
+                TypeBlockRule { qtype: RecordType::A, suffix: Some("ads.dev".into()), response: BlockResponse::Sinkhole },
+                TypeBlockRule { qtype: RecordType::A, suffix: Some("track.dev".into()), response: BlockResponse::NxDomain },
+                TypeBlockRule {
+                    qtype: RecordType::A,
+                    suffix: Some("social.dev".into()),
+                    response: BlockResponse::Redirect("10.9.9.9".parse().unwrap()),
+                },
+                TypeBlockRule {
+                    qtype: RecordType::AAAA,
+                    suffix: Some("social.dev".into()),
+                    response: BlockResponse::Redirect("10.9.9.9".parse().unwrap()),
+                },
+            ]);
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let mut buf = [0u8; 512];
+            for (name, qtype, code, answer) in [
+                ("app.corp.dev.", RecordType::ANY, ResponseCode::Refused, None),
+                ("app.corp.dev.", RecordType::AAAA, ResponseCode::NoError, None),
+                ("app.corp.dev.", RecordType::A, ResponseCode::NoError, Some("10.0.0.1")),
+                ("x.ads.dev.", RecordType::A, ResponseCode::NoError, Some("0.0.0.0")),
+                ("x.track.dev.", RecordType::A, ResponseCode::NXDomain, None),
+                ("social.dev.", RecordType::A, ResponseCode::NoError, Some("10.9.9.9")),
+                // the redirect is IPv4, so AAAA gets NODATA
+                ("social.dev.", RecordType::AAAA, ResponseCode::NoError, None),
+            ] {
+                let mut q = Message::new();
+                q.add_query(Query::query(Name::from_ascii(name).unwrap(), qtype));
+                client.send_to(&q.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+                let n = client.recv(&mut buf).await.unwrap();
+                let resp = Message::from_vec(&buf[..n]).unwrap();
+                assert_eq!(resp.response_code(), code, "{} {}", name, qtype);
+                let got = resp.answers().first().map(|r| r.data().to_string());
+                assert_eq!(got.as_deref(), answer, "{} {}", name, qtype);
+            }
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_daily_client_quota() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        state.set_quotas(QuotaConfig {
+            daily: Some(100),
+            per_client: [("127.0.0.1".parse().unwrap(), 2)].into_iter().collect(),
+        });
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut buf = [0u8; 512];
+        for code in [ResponseCode::NoError, ResponseCode::NoError, ResponseCode::Refused] {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), code);
+        }
+
+        let stats = state.client_stats();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!(stats.client, "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!((stats.queries_today, stats.refused_today, stats.queries_total), (3, 1, 3));
+        assert_eq!(stats.quota, Some(2));
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_acl() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let net: ClientNet = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains("192.168.1.77".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.1.77".parse().unwrap()));
+        assert!(!net.contains("192.168.2.1".parse().unwrap()));
+        assert!("::1".parse::<ClientNet>().unwrap().contains("::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<ClientNet>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<ClientNet>().is_err());
+        assert!("example.com".parse::<ClientNet>().is_err());
+
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async || {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            Message::from_vec(&buf[..n]).unwrap().response_code()
+        };
+
+        assert_eq!(ask().await, ResponseCode::NoError);
+        state.set_allowed_clients(vec![net]);
+        assert_eq!(ask().await, ResponseCode::Refused);
+        state.set_allowed_clients(vec![net, "127.0.0.0/8".parse().unwrap()]);
+        assert_eq!(ask().await, ResponseCode::NoError);
+
+        handle.shutdown().await;
+    }
+
+    /// Answers every query over TCP on `addr`'s port with `ip`.
+    async fn spawn_tcp_upstream(addr: SocketAddr, ip: Ipv4Addr) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let len = stream.read_u16().await.unwrap();
+                let mut query = vec![0u8; usize::from(len)];
+                stream.read_exact(&mut query).await.unwrap();
+                let reply = proto::encode(&proto::build_a_response(&proto::decode(&query).unwrap(), ip, 60)).unwrap();
+                stream.write_all(&(reply.len() as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_messages() {
+        use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+
+        let state = ResolverState::new("192.0.2.1:53".parse().unwrap());
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exchange = async |packet: &[u8]| {
+            client.send_to(packet, handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = tokio::time::timeout(std::time::Duration::from_millis(300), client.recv(&mut buf)).await.ok()?;
+            Some(proto::decode(&buf[..n.unwrap()]).unwrap())
+        };
+        let query = |op: OpCode, names: &[&str]| {
+            let mut msg = Message::new();
+            msg.set_id(0x4242).set_message_type(MessageType::Query).set_op_code(op);
+            for name in names {
+                msg.add_query(Query::query(name.parse().unwrap(), RecordType::A));
+            }
+            proto::encode(&msg).unwrap()
+        };
+
+        for (packet, code) in [
+            (query(OpCode::Query, &["a.dev.", "b.dev."]), ResponseCode::FormErr),
+            (query(OpCode::Query, &[]), ResponseCode::FormErr),
+            (query(OpCode::Notify, &["a.dev."]), ResponseCode::NotImp),
+            (query(OpCode::Status, &["a.dev."]), ResponseCode::NotImp),
+            // ACME updates are off
+            (query(OpCode::Update, &["a.dev."]), ResponseCode::NotImp),
+        ] {
+            let reply = exchange(&packet).await.unwrap();
+            assert_eq!((reply.id(), reply.response_code()), (0x4242, code));
+            assert_eq!(reply.message_type(), MessageType::Response);
+        }
+        let reply = exchange(&query(OpCode::Notify, &["a.dev."])).await.unwrap();
+        assert_eq!(reply.op_code(), OpCode::Notify);
+
+        // a header promising a question that is not there
+        let mut truncated = query(OpCode::Query, &["a.dev."]);
+        truncated.truncate(14);
+        let reply = exchange(&truncated).await.unwrap();
+        assert_eq!((reply.id(), reply.response_code()), (0x4242, ResponseCode::FormErr));
+
+        // responses and fragments are not answered
+        let mut response = Message::new();
+        response.set_message_type(MessageType::Response).add_query(Query::query("a.dev.".parse().unwrap(), RecordType::A));
+        assert!(exchange(&proto::encode(&response).unwrap()).await.is_none());
+        assert!(exchange(&[0x42, 0x42, 0x01]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_edns_echo() {
+        use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query};
+
+        let state = ResolverState::new("192.0.2.1:53".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 7)).await.unwrap();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |edns: Option<Edns>| {
+            let mut msg = Message::new();
+            msg.set_id(7).set_message_type(MessageType::Query).set_op_code(OpCode::Query);
+            msg.add_query(Query::query("app.dev.".parse().unwrap(), RecordType::A));
+            if let Some(edns) = edns {
+                msg.set_edns(edns);
+            }
+            client.send_to(&proto::encode(&msg).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            proto::decode(&buf[..n]).unwrap()
+        };
+
+        assert!(ask(None).await.extensions().is_none());
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096).set_dnssec_ok(true);
+        let reply = ask(Some(edns.clone())).await;
+        let opt = reply.extensions().as_ref().unwrap();
+        assert_eq!((opt.max_payload(), opt.version(), opt.flags().dnssec_ok), (proto::EDNS_UDP_PAYLOAD, 0, true));
+        assert_eq!(reply.answers().len(), 1);
+
+        // RFC 6891 6.1.3: BADVERS, with the version felix does speak
+        edns.set_version(1);
+        let reply = ask(Some(edns)).await;
+        assert_eq!(u16::from(reply.response_code()), 16);
+        assert_eq!(reply.extensions().as_ref().unwrap().version(), 0);
+        assert!(reply.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelining() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // answers each batch of three queries in reverse order, then hangs up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut queries = Vec::new();
+                while queries.len() < 3 {
+                    let Ok(len) = stream.read_u16().await else { break };
+                    let mut query = vec![0u8; usize::from(len)];
+                    stream.read_exact(&mut query).await.unwrap();
+                    queries.push(query);
+                }
+                for query in queries.iter().rev() {
+                    let query = proto::decode(query).unwrap();
+                    let reply = proto::encode(&proto::build_a_response(&query, Ipv4Addr::LOCALHOST, 60)).unwrap();
+                    stream.write_all(&(reply.len() as u16).to_be_bytes()).await.unwrap();
+                    stream.write_all(&reply).await.unwrap();
+                }
+            }
+        });
+
+        let pipeline = Arc::new(tcp_pipeline::TcpPipeline::default());
+        let ask = |name: &'static str| {
+            let pipeline = pipeline.clone();
+            async move {
+                // every query carries the same client ID
+                let mut query = proto::build_query(name, RecordType::A).unwrap();
+                query.set_id(0x1234);
+                let packet = proto::encode(&query).unwrap();
+                let reply = pipeline.query(&packet, upstream, std::time::Duration::from_secs(2)).await.unwrap();
+                proto::decode(&reply).unwrap()
+            }
+        };
+
+        let names = ["a.dev.", "b.dev.", "c.dev."];
+        let replies = futures_util::future::join_all(names.map(ask)).await;
+        for (name, reply) in names.iter().zip(&replies) {
+            assert_eq!(reply.id(), 0x1234);
+            assert_eq!(reply.queries()[0].name().to_utf8(), *name);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // the upstream closed that connection; the next batch reconnects
+        let replies = futures_util::future::join_all(names.map(ask)).await;
+        assert_eq!(replies[2].queries()[0].name().to_utf8(), "c.dev.");
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelining_timeout_frees_ids() {
+        use tokio::io::AsyncReadExt;
+
+        // reads queries and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move { while stream.read_u8().await.is_ok() {} });
+            }
+        });
+
+        let pipeline = tcp_pipeline::TcpPipeline::default();
+        let packet = proto::encode(&proto::build_query("slow.dev.", RecordType::A).unwrap()).unwrap();
+        for _ in 0..3 {
+            let res = pipeline.query(&packet, upstream, std::time::Duration::from_millis(50)).await;
+            assert!(matches!(res, Err(Error::UpstreamTimeout(_))));
+        }
+        assert_eq!(pipeline.in_flight(upstream), 0);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failover() {
+        use hickory_proto::op::Message;
+
+        let upstream = MockUpstream::start(MockReply::truncated()).await.unwrap();
+        spawn_tcp_upstream(upstream.addr(), Ipv4Addr::new(10, 0, 0, 7)).await;
+        let state = ResolverState::new(upstream.addr());
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut buf = [0u8; 512];
+        let mut ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            Message::from_vec(&buf[..n]).unwrap()
+        };
+
+        // without failover the truncated answer is relayed
+        assert!(ask("big.dev.").await.truncated());
+
+        state.set_failover(FailoverConfig { tcp: true, ..Default::default() });
+        let reply = ask("big.dev.").await;
+        assert!(!reply.truncated());
+        assert_eq!(reply.answers()[0].data(), &RecordData::A(Ipv4Addr::new(10, 0, 0, 7).into()));
+
+        // with TCP off, the next upstream of the set answers instead
+        let next = spawn_static_upstream(Ipv4Addr::new(10, 0, 0, 8), 60).await;
+        state.set_failover(FailoverConfig { upstreams: vec![upstream.addr(), next.addr()], ..Default::default() });
+        let reply = ask("other.dev.").await;
+        assert!(!reply.truncated());
+        assert_eq!(reply.answers()[0].data(), &RecordData::A(Ipv4Addr::new(10, 0, 0, 8).into()));
+    }
+
+    #[cfg(feature = "doh")]
+    #[tokio::test]
+    async fn test_doh_failover() {
+        use axum::{Router, body::Bytes, routing::post};
+        use hickory_proto::op::Message;
+
+        let app = Router::new().route(
+            "/dns-query",
+            post(|body: Bytes| async move {
+                let query = proto::decode(&body).unwrap();
+                proto::encode(&proto::build_a_response(&query, Ipv4Addr::new(10, 0, 0, 8), 60)).unwrap()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let doh = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let upstream = MockUpstream::start(MockReply::silent()).await.unwrap();
+        let state = ResolverState::new(upstream.addr());
+        state.set_failover(FailoverConfig { tcp: true, doh: Some(doh), ..Default::default() });
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let q = proto::build_query("blocked-network.dev.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        let reply = Message::from_vec(&buf[..n]).unwrap();
+        assert_eq!(reply.answers()[0].data(), &RecordData::A(Ipv4Addr::new(10, 0, 0, 8).into()));
+    }
+
+    #[cfg(feature = "doh")]
+    #[tokio::test]
+    async fn test_doh_bootstrap() {
+        use axum::{Router, body::Bytes, routing::post};
+        use hickory_proto::op::Message;
+
+        use crate::failover::DohBootstrap;
+
+        let app = Router::new().route(
+            "/dns-query",
+            post(|body: Bytes| async move {
+                let query = proto::decode(&body).unwrap();
+                proto::encode(&proto::build_a_response(&query, Ipv4Addr::new(10, 0, 0, 8), 60)).unwrap()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // a name only the bootstrap knows
+        let doh = format!("http://doh.bootstrap.test:{}/dns-query", listener.local_addr().unwrap().port());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let bootstrap = spawn_static_upstream(Ipv4Addr::LOCALHOST, 60).await;
+
+        let upstream = MockUpstream::start(MockReply::silent()).await.unwrap();
+        let state = ResolverState::new(upstream.addr());
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for (i, doh_bootstrap) in [DohBootstrap::Resolver(bootstrap.addr()), DohBootstrap::Pinned(vec![Ipv4Addr::LOCALHOST.into()])]
+            .into_iter()
+            .enumerate()
+        {
+            state.set_failover(FailoverConfig { doh: Some(doh.clone()), doh_bootstrap, ..Default::default() });
+            let q = proto::build_query(&format!("blocked-{}.dev.", i), RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let reply = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(reply.answers()[0].data(), &RecordData::A(Ipv4Addr::new(10, 0, 0, 8).into()), "{}", i);
+        }
+
+        // a bootstrap resolver that is felix itself would loop
+        state.set_failover(FailoverConfig { doh: Some(doh), doh_bootstrap: DohBootstrap::Resolver(handle.local_addr()), ..Default::default() });
+        assert_eq!(state.looping_upstreams(), vec![handle.local_addr()]);
+    }
+
+    #[tokio::test]
+    async fn test_answer_sources() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(10, 0, 0, 9), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("*.app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("api.app.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        let mut events = state.subscribe_queries();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut buf = [0u8; 512];
+        for (name, source) in [
+            ("api.app.dev.", AnswerSource::LocalExact),
+            ("web.app.dev.", AnswerSource::LocalWildcard),
+            ("other.dev.", AnswerSource::Upstream(upstream.addr())),
+            ("other.dev.", AnswerSource::Cache),
+        ] {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            client.recv(&mut buf).await.unwrap();
+            assert_eq!(events.recv().await.unwrap().source, source, "{}", name);
+        }
+        assert_eq!(AnswerSource::Upstream("1.1.1.1:53".parse().unwrap()).to_string(), "upstream-1.1.1.1:53");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_trace() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(10, 0, 0, 9), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("**.app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.set_policy_rules(vec![PolicyRule {
+            suffix: "ads.dev".into(),
+            action: PolicyAction::Block(BlockResponse::NxDomain),
+            schedule: None,
+        }]);
+
+        let trace = state.resolve_trace("API.eu.app.dev.").await.unwrap();
+        assert_eq!(trace.name, "api.eu.app.dev");
+        assert_eq!(trace.stage.as_deref(), Some("local"));
+        assert_eq!(trace.decided_by, Some(TraceCheck::Wildcard));
+        assert_eq!(trace.answer, "10.0.0.1");
+        let checks: Vec<(TraceCheck, &str, bool)> =
+            trace.steps.iter().map(|s| (s.check, s.subject.as_str(), s.matched)).collect();
+        let upstream_addr = upstream.addr().to_string();
+        assert_eq!(
+            checks,
+            vec![
+                (TraceCheck::Bypass, "enabled", false),
+                (TraceCheck::UpstreamSelection, upstream_addr.as_str(), true),
+                // the client checks are left out
+                (TraceCheck::Stage, "acme-update", false),
+                (TraceCheck::Stage, "chaos", false),
+                (TraceCheck::Stage, "hooks", false),
+                (TraceCheck::Policy, "ads.dev", false),
+                (TraceCheck::Stage, "type-block", false),
+                (TraceCheck::Stage, "single-label", false),
+                (TraceCheck::Exact, "api.eu.app.dev", false),
+                (TraceCheck::Wildcard, "*.eu.app.dev", false),
+                (TraceCheck::Wildcard, "**.eu.app.dev", false),
+                (TraceCheck::Wildcard, "**.app.dev", true),
+            ]
+        );
+        assert_eq!(trace.steps.last().unwrap().level, Some(2));
+
+        let blocked = state.resolve_trace("www.ads.dev").await.unwrap();
+        assert_eq!(blocked.decided_by, Some(TraceCheck::Policy));
+        assert_eq!(blocked.answer, hickory_proto::op::ResponseCode::NXDomain.to_string());
+
+        // custom stages show up like the standard ones
+        struct Noop;
+
+        #[async_trait::async_trait]
+        impl Stage for Noop {
+            fn name(&self) -> &'static str {
+                "noop"
+            }
+
+            async fn on_query(&self, _ctx: &mut pipeline::QueryContext) -> Result<Option<pipeline::Answer>> {
+                Ok(None)
+            }
+        }
+
+        let mut pipeline = Pipeline::standard();
+        pipeline.insert_before("forward", std::sync::Arc::new(Noop));
+        state.set_pipeline(pipeline);
+
+        let forwarded = state.resolve_trace("other.dev").await.unwrap();
+        assert_eq!(forwarded.stage.as_deref(), Some("forward"));
+        assert_eq!(forwarded.decided_by, Some(TraceCheck::Upstream));
+        assert_eq!(forwarded.answer, "10.0.0.9");
+        let last: Vec<(TraceCheck, &str)> =
+            forwarded.steps.iter().rev().take(3).map(|s| (s.check, s.subject.as_str())).collect();
+        assert_eq!(
+            last,
+            vec![
+                (TraceCheck::Upstream, upstream_addr.as_str()),
+                (TraceCheck::Stage, "noop"),
+                (TraceCheck::Cache, upstream_addr.as_str()),
+            ]
+        );
+        // tracing does not fill the cache
+        assert!(state.cache_entries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_policy_rules() {
+        use hickory_proto::op::{Message, ResponseCode};
+        use policy::Weekday;
+
+        let all_week = Schedule::days(vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ]);
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("work.social.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        state.add_domain("news.dev", Ipv4Addr::new(10, 0, 0, 2)).await.unwrap();
+        state.set_policy_rules(vec![
+            PolicyRule { suffix: "work.social.dev".into(), action: PolicyAction::Allow, schedule: None },
+            PolicyRule {
+                suffix: "social.dev".into(),
+                action: PolicyAction::Block(BlockResponse::NxDomain),
+                schedule: Some(all_week),
+            },
+            // never in schedule
+            PolicyRule {
+                suffix: "news.dev".into(),
+                action: PolicyAction::Block(BlockResponse::Refused),
+                schedule: Some(Schedule::days(Vec::new())),
+            },
+        ]);
+        state.set_policy_time_zone(PolicyTimeZone::Fixed(chrono::FixedOffset::east_opt(3600).unwrap()));
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut buf = [0u8; 512];
+        for (name, code) in [
+            ("www.social.dev.", ResponseCode::NXDomain),
+            ("work.social.dev.", ResponseCode::NoError),
+            ("news.dev.", ResponseCode::NoError),
+        ] {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), code, "{}", name);
+        }
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_chaos_identification_queries() {
+        use hickory_proto::op::{Message, Query, ResponseCode};
+        use hickory_proto::rr::{DNSClass, Name, RData};
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
+            state.set_chaos_config(ChaosConfig {
+                version: ChaosAnswer::Text("hidden".into()),
+                hostname: ChaosAnswer::Refuse,
+            });
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let ask = |name: &str| {
+                let mut query = Query::query(Name::from_ascii(name).unwrap(), RecordType::TXT);
+                query.set_query_class(DNSClass::CH);
+                let mut q = Message::new();
+                q.add_query(query);
+                q.to_vec().unwrap()
+            };
+            let mut buf = [0u8; 512];
+
+            client.send_to(&ask("version.bind."), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            let RData::TXT(txt) = resp.answers()[0].data() else { panic!("expected TXT") };
+            assert_eq!(txt.to_string(), "hidden");
+            assert_eq!(resp.answers()[0].dns_class(), DNSClass::CH);
+
+            client.send_to(&ask("hostname.bind."), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), ResponseCode::Refused);
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn test_typed_records() {
+        use hickory_proto::op::Message;
+        use std::net::Ipv6Addr;
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+            state.add_record("app.dev", RecordData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 1)))).await.unwrap();
+            state.add_record("app.dev", RecordData::AAAA(rdata::AAAA(Ipv6Addr::LOCALHOST))).await.unwrap();
+            state.add_record("app.dev", RecordData::TXT(rdata::TXT::new(vec!["v=1".into()]))).await.unwrap();
+            state.add_record("www.app.dev", RecordData::CNAME(rdata::CNAME("app.dev.".parse().unwrap()))).await.unwrap();
+            // A records land in the domain store
+            assert_eq!(state.resolve("app.dev").await.unwrap(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+            assert_eq!(state.list_records().len(), 3);
+
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buf = [0u8; 512];
+            for (name, qtype, rtype, answers) in [
+                ("app.dev.", RecordType::AAAA, RecordType::AAAA, 1),
+                ("app.dev.", RecordType::TXT, RecordType::TXT, 1),
+                ("app.dev.", RecordType::ANY, RecordType::A, 3),
+                ("www.app.dev.", RecordType::AAAA, RecordType::CNAME, 1),
+            ] {
+                let q = proto::build_query(name, qtype).unwrap();
+                client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+                let n = client.recv(&mut buf).await.unwrap();
+                let resp = Message::from_vec(&buf[..n]).unwrap();
+                assert!(resp.authoritative(), "{} {}", name, qtype);
+                assert_eq!(resp.answers().len(), answers, "{} {}", name, qtype);
+                assert_eq!(resp.answers()[0].record_type(), rtype, "{} {}", name, qtype);
+            }
+
+            state.remove_records("app.dev", RecordType::TXT).await.unwrap();
+            assert!(state.lookup_records("app.dev", RecordType::TXT).is_empty());
+            handle.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_port_mapping_answers() {
+        use hickory_proto::{op::Message, rr::rdata::svcb::SvcParamValue};
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .service("app.dev", "127.0.0.1:3000".parse().unwrap())
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let addr = server.local_addrs()[0];
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        let mut ask = async |name: &str, qtype| {
+            let q = proto::build_query(name, qtype).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), addr).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            Message::from_vec(&buf[..n]).unwrap()
+        };
+
+        assert_eq!(ask("app.dev.", RecordType::A).await.answers().len(), 1);
+        for name in ["app.dev.", "_http._tcp.app.dev."] {
+            let resp = ask(name, RecordType::SRV).await;
+            let srv = resp.answers()[0].data().as_srv().unwrap();
+            assert_eq!((srv.port(), srv.target().to_string().as_str()), (3000, "app.dev."), "{}", name);
+        }
+        let resp = ask("app.dev.", RecordType::HTTPS).await;
+        let https = resp.answers()[0].data().as_https().unwrap();
+        assert!(https.0.svc_params().iter().any(|(_, v)| *v == SvcParamValue::Port(3000)));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_loop_protection() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let own = server.local_addrs()[0];
+        let state = server.state();
+
+        assert!(matches!(state.save_upstream(own).await, Err(Error::UpstreamLoop(a)) if a == own));
+        let rule = ForwardRule { suffix: "corp".into(), upstream: own };
+        assert!(matches!(state.add_forward_rule(rule).await, Err(Error::UpstreamLoop(_))));
+        assert!(matches!(state.add_upstream_mapping("x.dev", own).await, Err(Error::UpstreamLoop(_))));
+
+        // an upstream set without checks is refused at query time
+        state.set_upstream(own);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let q = proto::build_query("example.com.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), own).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(Message::from_vec(&buf[..n]).unwrap().response_code(), ResponseCode::ServFail);
+
+        let cname = |target: &str| RecordData::CNAME(rdata::CNAME(target.parse().unwrap()));
+        state.add_record("a.dev", cname("b.dev.")).await.unwrap();
+        let err = state.add_record("b.dev", cname("a.dev.")).await.unwrap_err();
+        assert_eq!(err.to_string(), "CNAME chain loops: b.dev -> a.dev -> b.dev");
+        assert!(matches!(state.add_record("*.c.dev", cname("x.c.dev.")).await, Err(Error::AliasLoop(_))));
+        // pointing out of the table is fine
+        state.add_record("b.dev", cname("example.com.")).await.unwrap();
+
+        server.shutdown().await;
+
+        // resolved sends queries to felix in every mode, so its stub is no upstream
+        for mode in [ResolvedMode::Upstream, ResolvedMode::Split(vec!["test".into()]), ResolvedMode::TakeOver] {
+            let mut server = FelixServer::builder()
+                .listen("127.0.0.1:0".parse().unwrap())
+                .upstream(systemd_resolved::RESOLVED_STUB)
+                .systemd_resolved(mode.clone())
+                .in_memory()
+                .build()
+                .await
+                .unwrap();
+            assert!(matches!(server.start().await, Err(Error::UpstreamLoop(_))), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_resolved_drop_in() {
+        let felix = "127.0.0.1:5353".parse().unwrap();
+        let split = ResolvedMode::Split(vec!["test".into(), ".dev.".into()]);
+        assert_eq!(
+            systemd_resolved::drop_in(&split, felix),
+            "# Written by felix and removed when it stops\n[Resolve]\nDNS=127.0.0.1:5353\nDomains=~test ~dev\n"
+        );
+        assert!(systemd_resolved::drop_in(&ResolvedMode::Upstream, felix).ends_with("Domains=~.\n"));
+        assert!(systemd_resolved::drop_in(&ResolvedMode::TakeOver, felix).ends_with("[Resolve]\nDNSStubListener=no\n"));
+    }
+
+    #[tokio::test]
+    async fn test_negative_answers_carry_soa() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .local_only(LocalOnly::NxDomain)
+            .type_block_rule(TypeBlockRule { qtype: RecordType::AAAA, suffix: None, response: BlockResponse::NoData })
+            .negative_soa(NegativeSoa { ttl: 30, ..Default::default() })
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let addr = server.local_addrs()[0];
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for (qtype, code) in [(RecordType::A, ResponseCode::NXDomain), (RecordType::AAAA, ResponseCode::NoError)] {
+            let q = proto::build_query("missing.dev.", qtype).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), addr).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.response_code(), code);
+            assert!(resp.answers().is_empty());
+            let soa = &resp.name_servers()[0];
+            assert_eq!(soa.name().to_string(), "missing.dev.");
+            assert_eq!(soa.ttl(), 30);
+            let hickory_proto::rr::RData::SOA(soa) = soa.data() else { panic!("not an SOA: {:?}", soa) };
+            assert_eq!(soa.minimum(), 30);
+        }
+
+        server.state().set_negative_soa(NegativeSoa { ttl: 5, ..Default::default() });
+        let q = proto::build_query("missing.dev.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), addr).await.unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(Message::from_vec(&buf[..n]).unwrap().name_servers()[0].ttl(), 5);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_local_only_mode() {
+        use hickory_proto::op::{Message, ResponseCode};
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain("app.dev", Ipv4Addr::LOCALHOST)
+            .local_only(LocalOnly::NxDomain)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let addr = server.local_addrs()[0];
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        let mut ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), addr).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            Message::from_vec(&buf[..n]).unwrap().response_code()
+        };
+
+        assert_eq!(ask("app.dev.").await, ResponseCode::NoError);
+        assert_eq!(ask("example.com.").await, ResponseCode::NXDomain);
+        server.state().set_local_only(Some(LocalOnly::Refused));
+        assert_eq!(ask("example.com.").await, ResponseCode::Refused);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_disabled_passes_queries_through() {
+        use hickory_proto::op::Message;
+
+        let upstream = spawn_static_upstream(Ipv4Addr::new(192, 0, 2, 9), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("app.dev", Ipv4Addr::new(10, 0, 0, 1)).await.unwrap();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+        let mgmt = Management::new(state.clone());
+        mgmt.add_token("root", Role::Admin);
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        let mut ask = async || {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            Message::from_vec(&buf[..n]).unwrap().answers()[0].data().as_a().unwrap().0
+        };
+
+        assert_eq!(ask().await, Ipv4Addr::new(10, 0, 0, 1));
+        mgmt.set_enabled("root", false).unwrap();
+        assert_eq!(ask().await, Ipv4Addr::new(192, 0, 2, 9));
+        mgmt.set_enabled("root", true).unwrap();
+        assert_eq!(ask().await, Ipv4Addr::new(10, 0, 0, 1));
+
+        handle.shutdown().await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_listen_on_interface() {
+        let mut server = FelixServer::builder()
+            .listen_on_interface("127.0.0.1:0".parse().unwrap(), "lo")
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain("app.dev", Ipv4Addr::LOCALHOST)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), server.local_addrs()[0]).await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(proto::decode(&buf[..n]).unwrap().answers().len(), 1);
+        server.shutdown().await;
+
+        let mut server = FelixServer::builder()
+            .listen_on_interface("127.0.0.1:0".parse().unwrap(), "nosuchif0")
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .build()
+            .await
+            .unwrap();
+        assert!(matches!(server.start().await, Err(Error::Bind(..))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_workers() {
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        state.add_domain("app.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        let options = ListenOptions { workers: 4, ..Default::default() };
+        let handle = run_udp_server_with("127.0.0.1:0".parse().unwrap(), &options, state.clone()).await.unwrap();
+
+        // separate client sockets hash to different workers
+        for _ in 0..16 {
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            assert_eq!(proto::decode(&buf[..n]).unwrap().answers().len(), 1);
+        }
+        assert!(state.listening());
+
+        handle.shutdown().await;
+        assert!(!state.listening());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_udp_batches() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(192, 0, 2, 7), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("app.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        let options = ListenOptions { batch: 8, ..Default::default() };
+        let handle = run_udp_server_with("127.0.0.1:0".parse().unwrap(), &options, state.clone()).await.unwrap();
+
+        // a burst of local and forwarded queries, queued before any is read
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 0..20u16 {
+            let name = if id.is_multiple_of(2) { "app.dev." } else { "example.org." };
+            let mut q = proto::build_query(name, RecordType::A).unwrap();
+            q.set_id(id);
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+        }
+        let mut ids = Vec::new();
+        for _ in 0..20 {
+            let mut buf = [0u8; 512];
+            let n = tokio::time::timeout(std::time::Duration::from_secs(2), client.recv(&mut buf)).await.unwrap().unwrap();
+            let reply = proto::decode(&buf[..n]).unwrap();
+            let expected = if reply.id().is_multiple_of(2) { Ipv4Addr::LOCALHOST } else { Ipv4Addr::new(192, 0, 2, 7) };
+            assert_eq!(reply.answers()[0].data(), &hickory_proto::rr::RData::A(expected.into()));
+            ids.push(reply.id());
+        }
+        ids.sort();
+        assert_eq!(ids, (0..20).collect::<Vec<_>>());
+
+        handle.shutdown().await;
+        assert!(!state.listening());
+    }
+
+    #[tokio::test]
+    async fn test_server_on_dedicated_runtime() {
+        let dedicated = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain("app.dev", Ipv4Addr::LOCALHOST)
+            .runtime(dedicated.handle().clone())
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+
+        // a blocking client stalls this single-threaded test runtime, so
+        // the answer can only come from the dedicated one
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+        client.send_to(&proto::encode(&q).unwrap(), server.local_addrs()[0]).unwrap();
+        let mut buf = [0u8; 512];
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(proto::decode(&buf[..n]).unwrap().answers().len(), 1);
+
+        server.shutdown().await;
+        dedicated.shutdown_background();
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_against_mock_upstream() {
+        use hickory_proto::op::ResponseCode;
+
+        let upstream = MockUpstream::start(MockReply::a(Ipv4Addr::new(192, 0, 2, 1))).await.unwrap();
+        upstream.on("broken.example", MockReply::servfail());
+        upstream.on("big.example", MockReply::truncated());
+        upstream.on("slow.example", MockReply::a(Ipv4Addr::new(192, 0, 2, 2)).delayed(std::time::Duration::from_millis(300)));
+        upstream.on("down.example", MockReply::silent());
+        upstream.once("flaky.example", MockReply::refused());
+        let state = ResolverState::new(upstream.addr());
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = tokio::time::timeout(std::time::Duration::from_secs(4), client.recv(&mut buf)).await.unwrap().unwrap();
+            let reply = proto::decode(&buf[..n]).unwrap();
+            // the upstream saw an id of felix's own, the client gets its own back
+            assert_eq!(reply.id(), q.id());
+            reply
+        };
+
+        assert_eq!(ask("broken.example.").await.response_code(), ResponseCode::ServFail);
+        assert!(ask("big.example.").await.truncated());
+        assert_eq!(ask("slow.example.").await.answers().len(), 1);
+        // the upstream timeout turns silence into SERVFAIL
+        assert_eq!(ask("down.example.").await.response_code(), ResponseCode::ServFail);
+        assert_eq!(ask("flaky.example.").await.response_code(), ResponseCode::Refused);
+        assert_eq!(ask("flaky.example.").await.answers().len(), 1);
+        assert_eq!(upstream.query_count("flaky.example"), 2);
+        let first = upstream.queries()[0].clone();
+        assert_eq!(first, testing::MockQuery { name: "broken.example".into(), qtype: RecordType::A, id: first.id });
+        let ids: std::collections::HashSet<u16> = upstream.queries().iter().map(|q| q.id).collect();
+        assert!(ids.len() > 1, "{:?}", ids);
+
+        handle.shutdown().await;
+        upstream.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_expiry_on_virtual_time() {
+        use hickory_proto::op::ResponseCode;
+        use std::time::{Duration, Instant};
+        use testing::SimClock;
+
+        let real = Instant::now();
+        let clock = SimClock::pause();
+        let upstream = MockUpstream::start(MockReply::a(Ipv4Addr::new(192, 0, 2, 1)).ttl(300)).await.unwrap();
+        upstream.on("down.example", MockReply::silent());
+        let state = ResolverState::new(upstream.addr());
+        state.set_cache_config(CacheConfig { max_entries: 16, prefetch_hits: 0 });
+        let mut events = state.subscribe_queries();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut ask = async |name: &str| {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            (proto::decode(&buf[..n]).unwrap(), events.recv().await.unwrap().outcome)
+        };
+
+        assert_eq!(ask("app.example.").await.1, QueryOutcome::Forwarded);
+        clock.advance(Duration::from_secs(100)).await;
+        let (resp, outcome) = ask("app.example.").await;
+        assert_eq!(outcome, QueryOutcome::Cached);
+        assert_eq!(resp.answers()[0].ttl(), 200);
+        clock.advance(Duration::from_secs(201)).await;
+        assert_eq!(ask("app.example.").await.1, QueryOutcome::Forwarded);
+        assert_eq!(upstream.query_count("app.example"), 2);
+
+        // the upstream timeout passes without waiting for it
+        let before = clock.elapsed();
+        let (resp, _) = ask("down.example.").await;
+        assert_eq!(resp.response_code(), ResponseCode::ServFail);
+        assert!(clock.elapsed() - before >= Duration::from_secs(2));
+        assert!(real.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_upstream_fault_injection() {
+        use hickory_proto::op::ResponseCode;
+        use std::time::{Duration, Instant};
+        use testing::{SimClock, UpstreamFaults};
+
+        let real = Instant::now();
+        let clock = SimClock::pause();
+        let upstream = spawn_static_upstream(Ipv4Addr::new(192, 0, 2, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async || {
+            state.flush_cache(None);
+            let mut q = proto::build_query("app.example.", RecordType::A).unwrap();
+            q.set_id(1);
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            buf[..n].to_vec()
+        };
+        let clean = ask().await;
+
+        state.set_upstream_faults(Some(UpstreamFaults { delay: 1.0, delay_by: Duration::from_secs(30), ..Default::default() }));
+        let before = clock.elapsed();
+        assert_eq!(ask().await, clean);
+        assert!(clock.elapsed() - before >= Duration::from_secs(30));
+
+        state.set_upstream_faults(Some(UpstreamFaults { drop: 1.0, ..Default::default() }));
+        assert_eq!(proto::decode(&ask().await).unwrap().response_code(), ResponseCode::ServFail);
+
+        state.set_upstream_faults(Some(UpstreamFaults { corrupt: 1.0, ..Default::default() }));
+        let corrupted = ask().await;
+        assert_ne!(corrupted, clean);
+        assert_eq!(corrupted[..2], clean[..2]);
+
+        // half the answers dropped, reproducibly for the same seed
+        let faults = UpstreamFaults { drop: 0.5, seed: 7, ..Default::default() };
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            state.set_upstream_faults(Some(faults.clone()));
+            let mut codes = Vec::new();
+            for _ in 0..20 {
+                codes.push(proto::decode(&ask().await).unwrap().response_code());
+            }
+            runs.push(codes);
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].contains(&ResponseCode::ServFail) && runs[0].contains(&ResponseCode::NoError));
+        assert!(real.elapsed() < Duration::from_secs(2));
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_upstream_alerts() {
+        use std::time::Duration;
+        use testing::{SimClock, UpstreamFaults};
+
+        let _clock = SimClock::pause();
+        let upstream = spawn_static_upstream(Ipv4Addr::new(192, 0, 2, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.set_alert_config(AlertConfig { down_after: 2, servfail_rate: 0.5, window: Duration::from_secs(10), min_queries: 2 });
+        let mut alerts = state.subscribe_alerts();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ask = async || {
+            state.flush_cache(None);
+            let q = proto::build_query("app.example.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let mut buf = [0u8; 512];
+            client.recv(&mut buf).await.unwrap();
+        };
+
+        state.set_upstream_faults(Some(UpstreamFaults { drop: 1.0, ..Default::default() }));
+        ask().await;
+        assert!(state.down_upstreams().is_empty());
+        ask().await;
+        assert_eq!(alerts.recv().await.unwrap(), UpstreamEvent::UpstreamDown { upstream: upstream.addr(), failures: 2 });
+        assert_eq!(state.down_upstreams(), vec![upstream.addr()]);
+
+        // the first answer brings it back and closes the all-SERVFAIL window
+        state.set_upstream_faults(None);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        ask().await;
+        assert_eq!(alerts.recv().await.unwrap(), UpstreamEvent::UpstreamUp { upstream: upstream.addr() });
+        assert_eq!(alerts.recv().await.unwrap(), UpstreamEvent::ServfailRateHigh { rate: 1.0, queries: 2 });
+        assert!(state.down_upstreams().is_empty());
+
+        ask().await;
+        ask().await;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        ask().await;
+        // windows are counted from the start, so which of these queries
+        // close one depends on how long each took
+        let event = alerts.recv().await.unwrap();
+        assert!(matches!(event, UpstreamEvent::ServfailRateNormal { rate: 0.0, queries: 2.. }), "{:?}", event);
+
+        let json = serde_json::to_value(UpstreamEvent::UpstreamUp { upstream: upstream.addr() }).unwrap();
+        assert_eq!(json, serde_json::json!({"type": "upstream_up", "upstream": upstream.addr().to_string()}));
+
+        handle.shutdown().await;
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[tokio::test]
+    async fn test_change_webhooks() {
+        use axum::{Json, Router, routing::post};
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                tx.send(body).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+        let webhooks =
+            start_webhooks(WebhookConfig { change_urls: vec![url], ..Default::default() }, state.clone()).await.unwrap();
+
+        state.add_domain("api.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        let body = rx.recv().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "text": "api.dev now points to 127.0.0.1",
+                "event": {"type": "set", "domain": "api.dev", "ip": "127.0.0.1"},
+            })
+        );
+
+        // alerts go to their own URLs, none here
+        state.set_alert_config(AlertConfig { down_after: 1, ..Default::default() });
+        state.record_upstream_health(QueryOutcome::ServFail, AnswerSource::Upstream("127.0.0.1:9".parse().unwrap()), true);
+        state.remove_domain("api.dev").await.unwrap();
+        let body = rx.recv().await.unwrap();
+        assert_eq!(body["event"], serde_json::json!({"type": "removed", "domain": "api.dev"}));
+
+        // one URL gets its payloads in the order of the changes
+        for i in 0..20 {
+            state.add_domain(&format!("n{}.dev", i), Ipv4Addr::LOCALHOST).await.unwrap();
+        }
+        for i in 0..20 {
+            assert_eq!(rx.recv().await.unwrap()["event"]["domain"], format!("n{}.dev", i));
+        }
+
+        webhooks.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_statsd_metrics() {
+        let agent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain("app.dev", Ipv4Addr::LOCALHOST)
+            .statsd(StatsdConfig {
+                addr: agent.local_addr().unwrap(),
+                tags: true,
+                flush_interval: std::time::Duration::from_millis(50),
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 1500];
+        for _ in 0..2 {
+            let q = proto::build_query("app.dev.", RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addrs()[0]).await.unwrap();
+            client.recv(&mut buf).await.unwrap();
+        }
+
+        // the two queries may land in separate flushes
+        let (mut counted, mut timings) = (0, 0);
+        while timings < 2 {
+            let n = tokio::time::timeout(std::time::Duration::from_secs(2), agent.recv(&mut buf)).await.unwrap().unwrap();
+            for line in std::str::from_utf8(&buf[..n]).unwrap().lines() {
+                if let Some(count) = line.strip_prefix("felix.queries:") {
+                    let (n, tags) = count.split_once('|').unwrap();
+                    assert_eq!(tags, "c|#outcome:local,qtype:a,source:local-exact");
+                    counted += n.parse::<u32>().unwrap();
+                } else {
+                    assert!(line.starts_with("felix.query_time:") && line.ends_with("|ms|#outcome:local,source:local-exact"), "{}", line);
+                    timings += 1;
+                }
+            }
+        }
+        assert_eq!(counted, 2);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshot() {
+        let upstream = spawn_static_upstream(Ipv4Addr::new(203, 0, 113, 1), 60).await;
+        let state = ResolverState::new(upstream.addr());
+        state.add_domain("app.dev", Ipv4Addr::LOCALHOST).await.unwrap();
+        let server = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for name in ["app.dev.", "app.dev.", "other.dev."] {
+            let q = proto::build_query(name, RecordType::A).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), server.local_addr()).await.unwrap();
+            client.recv(&mut buf).await.unwrap();
+        }
+
+        // samples are recorded just after the reply is sent
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(2);
+        while state.metrics().total_queries() < 3 {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let snapshot = state.metrics();
+        let series: Vec<_> = snapshot.queries.iter().map(|m| (m.source, m.outcome, m.latency.count)).collect();
+        assert_eq!(
+            series,
+            vec![
+                (AnswerSource::LocalExact, QueryOutcome::Local, 2),
+                (AnswerSource::Upstream(upstream.addr()), QueryOutcome::Forwarded, 1),
+            ]
+        );
+        let local = &snapshot.queries[0].latency;
+        assert_eq!(local.buckets.len(), metrics::LATENCY_BUCKETS_US.len() + 1);
+        assert_eq!(local.buckets.iter().sum::<u64>(), 2);
+        assert!(local.quantile_bound(0.5).is_some_and(|d| d <= std::time::Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_cache_and_prefetch() {
+        use hickory_proto::op::Message;
+        use std::time::Duration;
+        use testing::SimClock;
+
+        let clock = SimClock::pause();
+        let upstream = MockUpstream::start(MockReply::a(Ipv4Addr::new(93, 184, 216, 34)).ttl(100)).await.unwrap();
+        let state = ResolverState::new(upstream.addr());
+        state.set_cache_config(CacheConfig { max_entries: 16, prefetch_hits: 2 });
+        let mut events = state.subscribe_queries();
+        let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state).await.unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        let mut ask = async |id: u16| {
+            let mut q = proto::build_query("popular.example.", RecordType::A).unwrap();
+            q.set_id(id);
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.id(), id);
+            assert_eq!(resp.answers().len(), 1);
+            events.recv().await.unwrap().outcome
+        };
+
+        assert_eq!(ask(1).await, QueryOutcome::Forwarded);
+        assert_eq!(ask(2).await, QueryOutcome::Cached);
+        assert_eq!(upstream.query_count("popular.example"), 1);
+        // second hit in the last tenth of the TTL: refreshed in the background
+        clock.advance(Duration::from_secs(91)).await;
+        assert_eq!(ask(3).await, QueryOutcome::Cached);
+        while upstream.query_count("popular.example") < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(ask(4).await, QueryOutcome::Cached);
+        assert_eq!(upstream.query_count("popular.example"), 2);
+        // the refreshed entry outlives the original one
+        clock.advance(Duration::from_secs(20)).await;
+        assert_eq!(ask(5).await, QueryOutcome::Cached);
+        assert_eq!(upstream.query_count("popular.example"), 2);
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_query_ids_are_random() {
+        let ids: std::collections::HashSet<u16> =
+            (0..16).map(|_| proto::build_query("example.com.", RecordType::A).unwrap().id()).collect();
+        assert!(ids.len() > 1);
+    }
+
+    #[test]
+    fn test_acme_challenges() {
+        use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+        use hickory_proto::rr::{DNSClass, Name, Record};
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = ResolverState::new("127.0.0.1:9".parse().unwrap());
+            state.set_acme_challenge("example.dev", "apex-token").unwrap();
+            state.set_acme_challenge("*.example.dev", "wildcard-token").unwrap();
+            assert_eq!(state.lookup_records("_acme-challenge.example.dev", RecordType::TXT).len(), 2);
+
+            let handle = run_udp_server("127.0.0.1:0".parse().unwrap(), state.clone()).await.unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buf = [0u8; 512];
+            let q = proto::build_query("_acme-challenge.example.dev.", RecordType::TXT).unwrap();
+            client.send_to(&proto::encode(&q).unwrap(), handle.local_addr()).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Message::from_vec(&buf[..n]).unwrap();
+            assert_eq!(resp.answers().len(), 2);
+            assert!(resp.answers().iter().all(|r| r.ttl() == acme::CHALLENGE_TTL));
+
+            state.clear_acme_challenge("_acme-challenge.example.dev.", Some("apex-token")).unwrap();
+            assert_eq!(state.lookup_records("_acme-challenge.example.dev", RecordType::TXT).len(), 1);
+
+            // RFC 2136: only when signed with the configured key, only
+            // challenge TXT records
+            let key = |secret: &[u8]| {
+                hickory_proto::dnssec::tsig::TSigner::new(
+                    secret.to_vec(),
+                    hickory_proto::dnssec::rdata::tsig::TsigAlgorithm::HmacSha256,
+                    Name::from_ascii("certbot.").unwrap(),
+                    300,
+                )
+                .unwrap()
+            };
+            let signed = |mut msg: Message, signer: &hickory_proto::dnssec::tsig::TSigner| {
+                msg.finalize(signer, chrono::Utc::now().timestamp() as u32).unwrap();
+                msg
+            };
+            let update = |name: &str, class: DNSClass| {
+                let mut msg = Message::new();
+                msg.set_id(7).set_message_type(MessageType::Query).set_op_code(OpCode::Update);
+                msg.add_query(Query::query(Name::from_ascii("example.dev.").unwrap(), RecordType::SOA));
+                let mut record = Record::from_rdata(
+                    Name::from_ascii(name).unwrap(),
+                    0,
+                    RecordData::TXT(rdata::TXT::new(vec!["updated".into()])),
+                );
+                record.set_dns_class(class);
+                msg.add_name_server(record);
+                msg
+            };
+            let mut send = async |msg: Message| {
+                client.send_to(&msg.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+                let n = client.recv(&mut buf).await.unwrap();
+                let resp = Message::from_vec(&buf[..n]).unwrap();
+                // accepted or refused on its merits, the reply is signed too
+                if resp.response_code() != ResponseCode::NotAuth && resp.response_code() != ResponseCode::NotImp {
+                    assert_eq!(resp.signature().len(), 1);
+                }
+                resp.response_code()
+            };
+
+            let good = key(b"0123456789abcdef0123456789abcdef");
+            assert_eq!(send(signed(update("_acme-challenge.app.dev.", DNSClass::IN), &good)).await, ResponseCode::NotImp);
+            state.set_acme_update_key(Some(UpdateKey::new("certbot", b"0123456789abcdef0123456789abcdef".to_vec()).unwrap()));
+            assert_eq!(send(update("_acme-challenge.app.dev.", DNSClass::IN)).await, ResponseCode::NotAuth);
+            let wrong = key(b"not the secret");
+            assert_eq!(send(signed(update("_acme-challenge.app.dev.", DNSClass::IN), &wrong)).await, ResponseCode::NotAuth);
+            assert!(state.lookup_records("_acme-challenge.app.dev", RecordType::TXT).is_empty());
+
+            assert_eq!(send(signed(update("_acme-challenge.app.dev.", DNSClass::IN), &good)).await, ResponseCode::NoError);
+            assert_eq!(state.lookup_records("_acme-challenge.app.dev", RecordType::TXT).len(), 1);
+            // the client can check the reply's signature against its request
+            let mut msg = update("_acme-challenge.app.dev.", DNSClass::IN);
+            let mut verify = msg.finalize(&good, chrono::Utc::now().timestamp() as u32).unwrap().unwrap();
+            client.send_to(&msg.to_vec().unwrap(), handle.local_addr()).await.unwrap();
+            let mut reply = [0u8; 512];
+            let n = client.recv(&mut reply).await.unwrap();
+            assert!(verify(&reply[..n]).is_ok());
+            assert_eq!(send(signed(update("app.dev.", DNSClass::IN), &good)).await, ResponseCode::Refused);
+            assert_eq!(send(signed(update("_acme-challenge.app.dev.", DNSClass::NONE), &good)).await, ResponseCode::NoError);
+            assert!(state.lookup_records("_acme-challenge.app.dev", RecordType::TXT).is_empty());
+
+            assert!("certbot:c2VjcmV0".parse::<UpdateKey>().is_ok());
+            assert!("certbot".parse::<UpdateKey>().is_err());
+            assert!("certbot:not base64!".parse::<UpdateKey>().is_err());
+
+            handle.shutdown().await;
+        });
+    }
+
+    #[tokio::test]
+    async fn test_health_and_readiness_endpoints() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn get(addr: SocketAddr, path: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!("GET {} HTTP/1.1\r\nHost: felix\r\nConnection: close\r\n\r\n", path);
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            resp
+        }
+
+        let upstream = spawn_static_upstream(Ipv4Addr::new(10, 0, 0, 1), 60).await;
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .management("127.0.0.1:0".parse().unwrap())
+            .upstream(upstream.addr())
+            .in_memory()
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let api = server.management_addr().unwrap();
+
+        let health = get(api, "/healthz").await;
+        assert!(health.starts_with("HTTP/1.1 200"), "{}", health);
+        assert!(health.contains(r#""listening":true"#), "{}", health);
+
+        let ready = get(api, "/readyz").await;
+        assert!(ready.starts_with("HTTP/1.1 200"), "{}", ready);
+
+        // nothing listens here, so the probe times out
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.state().set_upstream(dead.local_addr().unwrap());
+        let ready = get(api, "/readyz").await;
+        assert!(ready.starts_with("HTTP/1.1 503"), "{}", ready);
+        assert!(ready.contains("timed out"), "{}", ready);
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_otlp_export_on_shutdown() {
+        use std::io::{Read, Write};
+
+        // minimal collector: records request lines, answers 200
+        let collector = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", collector.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in collector.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let head = String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or_default().to_string();
+                let _ = tx.send(head);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            }
+        });
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .in_memory()
+            .domain("otel.dev", Ipv4Addr::new(10, 0, 0, 9))
+            .otlp_endpoint(endpoint)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let addr = server.local_addrs()[0];
+
+        let mut query = hickory_proto::op::Message::new();
+        query.set_id(7).add_query(hickory_proto::op::Query::query(
+            hickory_proto::rr::Name::from_utf8("otel.dev.").unwrap(),
+            RecordType::A,
+        ));
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query.to_vec().unwrap(), addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        client.recv_from(&mut buf).await.unwrap();
+
+        server.shutdown().await;
+
+        let paths: Vec<String> = rx.try_iter().collect();
+        assert!(paths.iter().any(|p| p.starts_with("POST /v1/traces")), "{:?}", paths);
+        assert!(paths.iter().any(|p| p.starts_with("POST /v1/metrics")), "{:?}", paths);
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_routes_by_host() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // backend echoing the request line it got
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = backend.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                let line = String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or_default().to_string();
+                let resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", line.len(), line);
+                stream.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+
+        async fn get(addr: SocketAddr, host: &str) -> String {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req = format!("GET /hello HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host);
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            resp
+        }
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .http_proxy("127.0.0.1:0".parse().unwrap())
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain("*.app.dev", Ipv4Addr::LOCALHOST)
+            .domain_port("*.app.dev", backend_port)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let proxy = server.proxy_addr().unwrap();
+
+        let resp = get(proxy, "API.app.dev:80").await;
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        assert!(resp.ends_with("GET /hello HTTP/1.1"), "{}", resp);
+        let resp = get(proxy, "other.dev").await;
+        assert!(resp.starts_with("HTTP/1.1 404"), "{}", resp);
+
+        // removing the mapping removes its port
+        server.state().remove_domain("*.app.dev").await.unwrap();
+        assert!(server.state().domain_ports().is_empty());
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_https_proxy_with_local_ca() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = backend.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("felix-ca-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ca = LocalCa::load_or_create(&dir).unwrap();
+        let ca_pem = std::fs::read(ca.cert_path()).unwrap();
+        // the CA is reused, not regenerated
+        let reloaded = LocalCa::load_or_create(&dir).unwrap();
+        assert_eq!(std::fs::read(reloaded.cert_path()).unwrap(), ca_pem);
+
+        let mut server = FelixServer::builder()
+            .listen("127.0.0.1:0".parse().unwrap())
+            .https_proxy("127.0.0.1:0".parse().unwrap(), reloaded)
+            .upstream("127.0.0.1:9".parse().unwrap())
+            .in_memory()
+            .domain_port("app.dev", backend_port)
+            .build()
+            .await
+            .unwrap();
+        server.start().await.unwrap();
+        let proxy = server.https_proxy_addr().unwrap();
+
+        use rustls::pki_types::{CertificateDer, ServerName, pem::PemObject};
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(&ca_pem).unwrap()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(std::sync::Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+
+        let connect = |name: &'static str| {
+            let connector = connector.clone();
+            async move {
+                let tcp = tokio::net::TcpStream::connect(proxy).await.unwrap();
+                connector.connect(ServerName::try_from(name).unwrap(), tcp).await
+            }
+        };
+
+        let mut tls = connect("app.dev").await.unwrap();
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: app.dev\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        tls.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+        assert!(resp.ends_with("ok"), "{}", resp);
+
+        // no certificate for names without a port mapping
+        assert!(connect("other.dev").await.is_err());
+
+        server.shutdown().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
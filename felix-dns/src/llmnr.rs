@@ -0,0 +1,156 @@
+//! LLMNR responder (RFC 4795), for Windows machines that fall back to it
+//! when DNS has no answer for a name.
+//!
+//! LLMNR messages are DNS messages with a few header bits renamed. Only
+//! names with a felix mapping are answered; anything else is left to other
+//! responders on the link, as the RFC requires. IPv4 only.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode},
+    rr::{RData, Record, RecordType},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle};
+
+use crate::{Error, ResolverState, Result, name::NormalizedName};
+
+pub const LLMNR_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+/// RFC 4795 section 2.8 recommends 30 seconds.
+const LLMNR_TTL: u32 = 30;
+
+#[derive(Debug, Clone)]
+pub struct LlmnrConfig {
+    pub bind: SocketAddr,
+    /// Join the LLMNR multicast group; off to answer unicast queries only.
+    pub multicast: bool,
+}
+
+impl Default for LlmnrConfig {
+    fn default() -> Self {
+        Self { bind: "0.0.0.0:5355".parse().unwrap(), multicast: true }
+    }
+}
+
+pub struct LlmnrHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl LlmnrHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+pub async fn start_llmnr(config: LlmnrConfig, state: ResolverState) -> Result<LlmnrHandle> {
+    let socket = bind_socket(&config)?;
+    let local_addr = socket.local_addr()?;
+
+    log::info!("LLMNR responder listening on {}", local_addr);
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                recv = socket.recv_from(&mut buf) => {
+                    let (n, peer) = match recv {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!("LLMNR recv error: {:?}", e);
+                            continue;
+                        }
+                    };
+                    let Some(resp) = answer(&state, &buf[..n]).await else {
+                        continue;
+                    };
+                    // responses always go back unicast (section 2.4)
+                    match resp.to_vec() {
+                        Ok(bytes) => {
+                            if let Err(e) = socket.send_to(&bytes, peer).await {
+                                log::debug!("LLMNR send to {} failed: {:?}", peer, e);
+                            }
+                        }
+                        Err(e) => log::warn!("Failed to encode LLMNR response: {}", e),
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(LlmnrHandle {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        task,
+    })
+}
+
+fn bind_socket(config: &LlmnrConfig) -> Result<UdpSocket> {
+    let bind = |e| Error::Bind(config.bind, e);
+    let socket = Socket::new(Domain::for_address(config.bind), Type::DGRAM, Some(Protocol::UDP)).map_err(bind)?;
+    // Windows hosts and other responders may hold the port too
+    socket.set_reuse_address(true).map_err(bind)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).map_err(bind)?;
+    socket.set_nonblocking(true).map_err(bind)?;
+    socket.bind(&config.bind.into()).map_err(bind)?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    if config.multicast {
+        socket.join_multicast_v4(LLMNR_GROUP, Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(false)?;
+    }
+    Ok(socket)
+}
+
+/// The response to one LLMNR query, or `None` to stay silent: for
+/// malformed or non-query messages and for names felix does not map.
+async fn answer(state: &ResolverState, bytes: &[u8]) -> Option<Message> {
+    let request = Message::from_vec(bytes).ok()?;
+    // section 2.1.1: queries carry exactly one question
+    if request.message_type() != MessageType::Query
+        || request.op_code() != OpCode::Query
+        || request.queries().len() != 1
+    {
+        return None;
+    }
+    let query = &request.queries()[0];
+    let name = NormalizedName::from_wire(query.name());
+    let ip = match state.resolve(&name).await {
+        Ok(ip) => ip?,
+        Err(e) => {
+            log::warn!("LLMNR lookup of {} failed: {}", name, e);
+            return None;
+        }
+    };
+
+    // the C, TC and T bits (where DNS has AA, TC and RD) all stay clear
+    let mut resp = Message::new();
+    resp.set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .add_query(query.clone());
+    // a name felix maps but without records of the asked type gets an
+    // empty answer (section 2.1)
+    if matches!(query.query_type(), RecordType::A | RecordType::ANY) {
+        resp.add_answer(Record::from_rdata(query.name().clone(), LLMNR_TTL, RData::A(ip.into())));
+    }
+    for (data, _) in state.record_answers(&name, query.query_type()) {
+        if data.record_type() != RecordType::A {
+            resp.add_answer(Record::from_rdata(query.name().clone(), LLMNR_TTL, data));
+        }
+    }
+    log::debug!("LLMNR answered {} {}", name, query.query_type());
+    Some(resp)
+}
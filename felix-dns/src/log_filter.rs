@@ -0,0 +1,105 @@
+//! Log filters that can be changed while running, e.g. to turn on
+//! `felix_dns::server_handler=debug` for a few minutes through the
+//! management API instead of restarting with `RUST_LOG`.
+//!
+//! The filter wraps whichever backend felix installs (stderr or syslog).
+//! When the host application set its own logger, filters are its business
+//! and `set_log_filter` fails.
+
+use std::sync::RwLock;
+
+use env_filter::Filter;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::{Error, Result};
+
+/// Target of the one-line-per-query logs (queries at debug, local answers
+/// and SERVFAILs at info), so they can be tuned on their own, e.g.
+/// `info,felix_dns::query=warn` keeps everything but them.
+pub const QUERY_TARGET: &str = "felix_dns::query";
+
+/// The filter spec as given, and its parsed form.
+static FILTER: RwLock<Option<(String, Filter)>> = RwLock::new(None);
+
+struct FilteredLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let allowed = FILTER.read().unwrap().as_ref().is_some_and(|(_, f)| f.enabled(metadata));
+        allowed && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if FILTER.read().unwrap().as_ref().is_some_and(|(_, f)| f.matches(record)) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn parse(spec: &str) -> Result<Filter> {
+    env_filter::Builder::new()
+        .try_parse(spec)
+        .map(|b| b.build())
+        .map_err(|e| Error::LogFilter(format!("{:?}: {}", spec, e)))
+}
+
+fn apply(spec: &str, filter: Filter) {
+    log::set_max_level(filter.filter());
+    *FILTER.write().unwrap() = Some((spec.to_string(), filter));
+}
+
+/// Installs `inner` as the global logger behind a filter in `RUST_LOG`
+/// syntax. `inner` should let everything through; the filter decides.
+pub fn install(inner: Box<dyn Log>, spec: &str) -> Result<()> {
+    let filter = parse(spec)?;
+    log::set_boxed_logger(Box::new(FilteredLogger { inner }))
+        .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+    apply(spec, filter);
+    Ok(())
+}
+
+/// Installs an `env_logger` filtered by `RUST_LOG`, or by `default` when
+/// it is unset or does not parse.
+pub fn install_env_logger(default: &str) -> Result<()> {
+    let spec = match std::env::var("RUST_LOG") {
+        Ok(spec) if parse(&spec).is_ok() => spec,
+        Ok(spec) => {
+            // no logger to report this through yet
+            eprintln!("warning: ignoring invalid RUST_LOG {:?}", spec);
+            default.to_string()
+        }
+        Err(_) => default.to_string(),
+    };
+    install(Box::new(stderr_logger()), &spec)
+}
+
+/// An `env_logger` that lets everything through, honouring `RUST_LOG_STYLE`.
+pub fn stderr_logger() -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    builder.filter_level(LevelFilter::Trace).build()
+}
+
+/// Replaces the filter of a running logger, e.g. `info,felix_dns::server_handler=debug`.
+pub fn set_log_filter(spec: &str) -> Result<()> {
+    let filter = parse(spec)?;
+    if FILTER.read().unwrap().is_none() {
+        return Err(Error::LoggerNotInstalled);
+    }
+    apply(spec, filter);
+    log::info!("Log filter set to {:?}", spec);
+    Ok(())
+}
+
+/// The filter in effect, `None` when felix did not install the logger.
+pub fn log_filter() -> Option<String> {
+    FILTER.read().unwrap().as_ref().map(|(spec, _)| spec.clone())
+}
@@ -0,0 +1,47 @@
+//! Periodic SQLite housekeeping for long-running instances: free pages are
+//! returned to the filesystem and planner statistics kept current, see
+//! `SqliteDomainStore::maintain`.
+
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use crate::{Result, SqliteDomainStore};
+
+pub struct Maintenance {
+    store: SqliteDomainStore,
+    /// Keeps a manual run from overlapping a scheduled one.
+    running: tokio::sync::Mutex<()>,
+}
+
+impl Maintenance {
+    /// Starts the maintenance loop, which stops once the returned handle is
+    /// dropped. The first run happens one `interval` after start.
+    pub(crate) fn start(store: SqliteDomainStore, interval: Duration) -> Arc<Self> {
+        let maintenance = Arc::new(Self { store, running: tokio::sync::Mutex::new(()) });
+
+        let weak: Weak<Self> = Arc::downgrade(&maintenance);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(maintenance) = weak.upgrade() else { break };
+                if let Err(e) = maintenance.run().await {
+                    log::warn!("Database maintenance failed, will retry: {}", e);
+                }
+            }
+        });
+
+        maintenance
+    }
+
+    /// Runs maintenance now, returning the pages freed.
+    pub async fn run(&self) -> Result<u64> {
+        let _guard = self.running.lock().await;
+        let freed = self.store.maintain().await?;
+        log::debug!("Database maintenance freed {} pages", freed);
+        Ok(freed)
+    }
+}
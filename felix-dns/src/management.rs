@@ -0,0 +1,367 @@
+use std::{collections::HashMap, fmt, net::{Ipv4Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    AnswerOrder, CacheEntry, ClientStats, DomainPage, ForwardRule, ListQuery, QueryReport, ResolverState, Result,
+    conflicts::{self, MappingConflict},
+    raft::RaftNode,
+    trace::ResolveTrace,
+    views::ViewSummary,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// May list and search domains, and read stats, cache entries and events.
+    ReadOnly,
+    /// May additionally mutate domains, the upstream and the enabled flag.
+    Admin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessError {
+    /// The token is not registered.
+    Unauthenticated,
+    /// The token is valid but its role is too weak for the operation.
+    Forbidden { required: Role, actual: Role },
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::Unauthenticated => write!(f, "unknown management token"),
+            AccessError::Forbidden { required, actual } => {
+                write!(f, "operation requires {:?} role, token has {:?}", required, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManagementStats {
+    pub domains: usize,
+    pub enabled: bool,
+    pub upstream: SocketAddr,
+}
+
+/// Token-checked front door to a `ResolverState`.
+///
+/// Every operation takes the caller's token; reads need `Role::ReadOnly`,
+/// mutations need `Role::Admin`. With a Raft cluster attached, domain and
+/// project adds and removes go through its log.
+#[derive(Clone)]
+pub struct Management {
+    state: ResolverState,
+    tokens: Arc<RwLock<HashMap<String, Role>>>,
+    cluster: Arc<RwLock<Option<RaftNode>>>,
+}
+
+impl Management {
+    pub fn new(state: ResolverState) -> Self {
+        Self {
+            state,
+            tokens: Arc::new(RwLock::new(HashMap::new())),
+            cluster: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Routes domain mutations through `cluster`, or applies them locally
+    /// again with `None`.
+    pub fn set_cluster(&self, cluster: Option<RaftNode>) {
+        *self.cluster.write() = cluster;
+    }
+
+    fn cluster(&self) -> Option<RaftNode> {
+        self.cluster.read().clone()
+    }
+
+    pub fn add_token(&self, token: impl Into<String>, role: Role) {
+        self.tokens.write().insert(token.into(), role);
+    }
+
+    pub fn revoke_token(&self, token: &str) {
+        self.tokens.write().remove(token);
+    }
+
+    pub fn state(&self) -> &ResolverState {
+        &self.state
+    }
+
+    pub fn authorize(&self, token: &str, required: Role) -> std::result::Result<Role, AccessError> {
+        // every registered token is compared in constant time, so response
+        // times do not reveal how much of a guess was right
+        let mut found = None;
+        for (known, role) in self.tokens.read().iter() {
+            if bool::from(known.as_bytes().ct_eq(token.as_bytes())) {
+                found = Some(*role);
+            }
+        }
+        let actual = found.ok_or(AccessError::Unauthenticated)?;
+
+        if actual < required {
+            return Err(AccessError::Forbidden { required, actual });
+        }
+
+        Ok(actual)
+    }
+
+    pub async fn list_domains(&self, token: &str) -> Result<Vec<(String, Ipv4Addr)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        self.state.list_domains().await
+    }
+
+    pub async fn list_domains_paged(&self, token: &str, query: &ListQuery) -> Result<DomainPage> {
+        self.authorize(token, Role::ReadOnly)?;
+        self.state.list_domains_paged(query).await
+    }
+
+    pub async fn search_domains(&self, token: &str, term: &str, limit: usize) -> Result<Vec<(String, Ipv4Addr)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        self.state.search_domains(term, limit).await
+    }
+
+    /// Every overlapping mapping, see `conflicts::find_conflicts`.
+    pub async fn conflicts(&self, token: &str) -> Result<Vec<MappingConflict>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(conflicts::find_conflicts(&self.state.list_domains().await?))
+    }
+
+    /// How `name` would be answered, see `ResolverState::resolve_trace`.
+    pub async fn resolve_trace(&self, token: &str, name: &str) -> Result<ResolveTrace> {
+        self.authorize(token, Role::ReadOnly)?;
+        self.state.resolve_trace(name).await
+    }
+
+    pub async fn stats(&self, token: &str) -> Result<ManagementStats> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(ManagementStats {
+            domains: self.state.list_domains().await?.len(),
+            enabled: self.state.enabled(),
+            upstream: self.state.upstream(),
+        })
+    }
+
+    pub async fn add_domain(&self, token: &str, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        match self.cluster() {
+            Some(cluster) => cluster.add_domain(domain, ip).await,
+            None => self.state.add_domain(domain, ip).await,
+        }
+    }
+
+    /// `add_domain` exempt from the safe-IP policy.
+    pub async fn add_domain_allow_public(&self, token: &str, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        match self.cluster() {
+            Some(cluster) => cluster.add_domain_allow_public(domain, ip).await,
+            None => self.state.add_domain_allow_public(domain, ip).await,
+        }
+    }
+
+    pub async fn remove_domain(&self, token: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        match self.cluster() {
+            Some(cluster) => cluster.remove_domain(domain).await,
+            None => self.state.remove_domain(domain).await,
+        }
+    }
+
+    pub async fn add_project(&self, token: &str, project: &str, ip: Ipv4Addr) -> Result<Vec<String>> {
+        self.authorize(token, Role::Admin)?;
+        let Some(cluster) = self.cluster() else {
+            return self.state.add_project(project, ip).await;
+        };
+        // all checked first, as `ResolverState::add_project` does
+        let domains = self.state.project_template().expand(project);
+        for domain in &domains {
+            crate::resolver_state::checked_key(domain)?;
+            self.state.check_mapping(domain, ip)?;
+        }
+        for domain in &domains {
+            cluster.add_domain_allow_public(domain, ip).await?;
+        }
+        Ok(domains)
+    }
+
+    pub async fn remove_project(&self, token: &str, project: &str) -> Result<Vec<String>> {
+        self.authorize(token, Role::Admin)?;
+        let Some(cluster) = self.cluster() else {
+            return self.state.remove_project(project).await;
+        };
+        let domains = self.state.project_template().expand(project);
+        for domain in &domains {
+            cluster.remove_domain(domain).await?;
+        }
+        Ok(domains)
+    }
+
+    /// Changes the upstream, saving it when the state is backed by SQLite.
+    pub async fn set_upstream(&self, token: &str, addr: SocketAddr) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.save_upstream(addr).await
+    }
+
+    pub fn forward_rules(&self, token: &str) -> Result<Vec<ForwardRule>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.forward_rules().to_vec())
+    }
+
+    pub async fn add_forward_rule(&self, token: &str, rule: ForwardRule) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.add_forward_rule(rule).await
+    }
+
+    pub async fn remove_forward_rule(&self, token: &str, suffix: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_forward_rule(suffix).await
+    }
+
+    pub fn upstream_mappings(&self, token: &str) -> Result<Vec<(String, SocketAddr)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.upstream_mappings())
+    }
+
+    pub async fn add_upstream_mapping(&self, token: &str, domain: &str, upstream: SocketAddr) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.add_upstream_mapping(domain, upstream).await
+    }
+
+    pub async fn remove_upstream_mapping(&self, token: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_upstream_mapping(domain).await
+    }
+
+    pub fn domain_ports(&self, token: &str) -> Result<Vec<(String, u16)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.domain_ports())
+    }
+
+    pub async fn set_domain_port(&self, token: &str, domain: &str, port: u16) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.set_domain_port(domain, port).await
+    }
+
+    pub async fn remove_domain_port(&self, token: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_domain_port(domain).await
+    }
+
+    pub fn domain_delays(&self, token: &str) -> Result<Vec<(String, Duration)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.domain_delays())
+    }
+
+    pub fn set_domain_delay(&self, token: &str, domain: &str, delay: Duration) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.set_domain_delay(domain, delay)
+    }
+
+    pub fn remove_domain_delay(&self, token: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_domain_delay(domain);
+        Ok(())
+    }
+
+    pub fn answer_orders(&self, token: &str) -> Result<Vec<(String, AnswerOrder)>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.answer_orders())
+    }
+
+    pub fn set_answer_order(&self, token: &str, domain: &str, order: AnswerOrder) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.set_answer_order(domain, order)
+    }
+
+    pub fn remove_answer_order(&self, token: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_answer_order(domain);
+        Ok(())
+    }
+
+    pub fn views(&self, token: &str) -> Result<Vec<ViewSummary>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.views())
+    }
+
+    /// `None` for an unknown view.
+    pub fn view_domains(&self, token: &str, view: &str) -> Result<Option<Vec<(String, Ipv4Addr)>>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.view_domains(view))
+    }
+
+    pub fn add_view_domain(&self, token: &str, view: &str, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.add_view_domain(view, domain, ip)
+    }
+
+    pub fn remove_view_domain(&self, token: &str, view: &str, domain: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_view_domain(view, domain);
+        Ok(())
+    }
+
+    pub fn remove_view(&self, token: &str, view: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.remove_view(view);
+        Ok(())
+    }
+
+    /// See `ResolverState::bind_view`.
+    pub fn bind_view(&self, token: &str, listener: SocketAddr, view: Option<&str>) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.bind_view(listener, view)
+    }
+
+    pub fn set_acme_challenge(&self, token: &str, domain: &str, value: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.set_acme_challenge(domain, value)
+    }
+
+    pub fn clear_acme_challenge(&self, token: &str, domain: &str, value: Option<&str>) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.clear_acme_challenge(domain, value)
+    }
+
+    pub fn cache_entries(&self, token: &str) -> Result<Vec<CacheEntry>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.cache_entries())
+    }
+
+    pub fn client_stats(&self, token: &str) -> Result<Vec<ClientStats>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.client_stats())
+    }
+
+    pub fn query_report(&self, token: &str, window: Duration, top: usize) -> Result<QueryReport> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(self.state.query_report(window, top))
+    }
+
+    /// Purges cached answers for `name`, or all of them with `None`.
+    pub fn flush_cache(&self, token: &str, name: Option<&str>) -> Result<usize> {
+        self.authorize(token, Role::Admin)?;
+        Ok(self.state.flush_cache(name))
+    }
+
+    pub fn set_enabled(&self, token: &str, enabled: bool) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        self.state.set_enabled(enabled);
+        Ok(())
+    }
+
+    /// The process-wide log filter, see `log_filter::log_filter`.
+    pub fn log_filter(&self, token: &str) -> Result<Option<String>> {
+        self.authorize(token, Role::ReadOnly)?;
+        Ok(crate::log_filter::log_filter())
+    }
+
+    pub fn set_log_filter(&self, token: &str, spec: &str) -> Result<()> {
+        self.authorize(token, Role::Admin)?;
+        crate::log_filter::set_log_filter(spec)
+    }
+}
@@ -0,0 +1,839 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, oneshot};
+
+use crate::{
+    AnswerOrder, Error, ListQuery, ListSort, Result,
+    handover,
+    management::{AccessError, Management, Role},
+    replication::ReplicationMessage,
+    server_handler::probe_upstream,
+};
+
+const TOTAL_COUNT: &str = "x-total-count";
+
+pub struct ManagementHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    /// Kept to pass on to a successor, see `handover`.
+    listener: std::net::TcpListener,
+}
+
+impl ManagementHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(crate) fn listener(&self) -> &std::net::TcpListener {
+        &self.listener
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+pub async fn run_management_server(listen_addr: SocketAddr, mgmt: Management) -> Result<ManagementHandle> {
+    let (listener, kept) = handover::listen_tcp(listen_addr).await?;
+    let local_addr = listener.local_addr()?;
+
+    log::info!("Management API listening on {}", local_addr);
+
+    let app = Router::new()
+        .route("/events", get(events))
+        .route("/changes", get(changes))
+        .route("/alerts", get(alerts))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/stats", get(stats))
+        .route("/stats/queries", get(query_report))
+        .route("/domains", get(domain_list))
+        .route("/search", get(domain_search))
+        .route("/domains/{domain}", put(domain_put).delete(domain_delete))
+        .route("/projects/{project}", put(project_put).delete(project_delete))
+        .route("/domains/{domain}/delay", put(delay_put).delete(delay_delete))
+        .route("/delays", get(delay_list))
+        .route("/domains/{domain}/order", put(order_put).delete(order_delete))
+        .route("/orders", get(order_list))
+        .route("/conflicts", get(conflict_list))
+        .route("/enabled", put(set_enabled))
+        .route("/log-filter", get(log_filter_get).put(log_filter_put))
+        .route("/cache", get(cache_list).delete(cache_flush))
+        .route("/clients", get(client_list))
+        .route("/resolve", get(resolve_trace))
+        .route("/views", get(view_list))
+        .route("/views/{view}", axum::routing::delete(view_delete))
+        .route("/views/{view}/domains", get(view_domain_list))
+        .route("/views/{view}/domains/{domain}", put(view_domain_put).delete(view_domain_delete))
+        .route("/listeners/{listener}/view", put(listener_view_put))
+        .route("/acme/present", post(acme_present))
+        .route("/acme/cleanup", post(acme_cleanup))
+        .with_state(mgmt);
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+        });
+        if let Err(e) = server.await {
+            log::warn!("Management server error: {:?}", e);
+        }
+    });
+
+    Ok(ManagementHandle {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        listener: kept,
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Token from `Authorization: Bearer ...`, falling back to `?token=` for
+/// browser WebSocket clients that cannot set headers.
+pub(crate) fn request_token(headers: &HeaderMap, query: &TokenQuery) -> String {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string)
+        .or_else(|| query.token.clone())
+        .unwrap_or_default()
+}
+
+pub(crate) fn access_error_response(err: AccessError) -> Response {
+    let status = match err {
+        AccessError::Unauthenticated => StatusCode::UNAUTHORIZED,
+        AccessError::Forbidden { .. } => StatusCode::FORBIDDEN,
+    };
+    (status, err.to_string()).into_response()
+}
+
+fn error_response(err: Error) -> Response {
+    match err {
+        Error::Access(e) => access_error_response(e),
+        e @ (Error::InvalidDomain(..) | Error::PublicAddress(_) | Error::PublicSuffix(..) | Error::LogFilter(_) | Error::InvalidView(_)) => {
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+        e @ Error::LoggerNotInstalled => (StatusCode::CONFLICT, e.to_string()).into_response(),
+        e @ Error::Cluster(_) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response(),
+        e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn from_result(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Check { ok: true, error: None },
+            Err(e) => Check {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+fn health_status(ok: bool) -> StatusCode {
+    if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }
+}
+
+/// Liveness: the process runs and a DNS listener is bound. Unauthenticated
+/// so orchestrators can probe it.
+async fn healthz(State(mgmt): State<Management>) -> Response {
+    let listening = mgmt.state().listening();
+    (health_status(listening), axum::Json(serde_json::json!({ "listening": listening }))).into_response()
+}
+
+/// Readiness: the store answers and the upstream replies to a probe query.
+async fn readyz(State(mgmt): State<Management>) -> Response {
+    let state = mgmt.state();
+    let (store, upstream) = tokio::join!(state.check_storage(), probe_upstream(state.upstream()));
+    let store = Check::from_result(store);
+    let upstream = Check::from_result(upstream);
+    let listening = state.listening();
+
+    let status = health_status(listening && store.ok && upstream.ok);
+    let body = serde_json::json!({ "listening": listening, "store": store, "upstream": upstream });
+    (status, axum::Json(body)).into_response()
+}
+
+async fn stats(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.stats(&request_token(&headers, &query)).await {
+        Ok(stats) => axum::Json(stats).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+fn default_report_window() -> u64 {
+    3600
+}
+
+fn default_report_top() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct ReportParams {
+    /// Seconds.
+    #[serde(default = "default_report_window")]
+    window: u64,
+    #[serde(default = "default_report_top")]
+    top: usize,
+}
+
+/// `GET /stats/queries?window=3600&top=20` counts the queries of the last
+/// hour by type and source, with the 20 most queried names.
+async fn query_report(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Query(params): Query<ReportParams>,
+) -> Response {
+    match mgmt.query_report(&request_token(&headers, &query), Duration::from_secs(params.window), params.top) {
+        Ok(report) => axum::Json(report).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DomainMapping {
+    domain: String,
+    ip: Ipv4Addr,
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    filter: Option<String>,
+    #[serde(default)]
+    sort: ListSort,
+}
+
+/// `GET /domains?offset=0&limit=50&filter=api&sort=-ip` lists one page;
+/// `X-Total-Count` holds how many domains match the filter in total.
+async fn domain_list(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Query(params): Query<ListParams>,
+) -> Response {
+    let list = ListQuery { offset: params.offset, limit: params.limit, filter: params.filter, sort: params.sort };
+    match mgmt.list_domains_paged(&request_token(&headers, &query), &list).await {
+        Ok(page) => {
+            let domains: Vec<DomainMapping> =
+                page.domains.into_iter().map(|(domain, ip)| DomainMapping { domain, ip }).collect();
+            ([(TOTAL_COUNT, page.total.to_string())], axum::Json(domains)).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    #[serde(default = "default_search_limit")]
+    limit: usize,
+}
+
+fn default_search_limit() -> usize {
+    20
+}
+
+/// `GET /search?q=api&limit=20` finds the domains containing `api`, best
+/// match first, for search-as-you-type.
+async fn domain_search(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Query(params): Query<SearchParams>,
+) -> Response {
+    match mgmt.search_domains(&request_token(&headers, &query), &params.q, params.limit).await {
+        Ok(found) => {
+            let domains: Vec<DomainMapping> = found.into_iter().map(|(domain, ip)| DomainMapping { domain, ip }).collect();
+            axum::Json(domains).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct DomainTarget {
+    ip: Ipv4Addr,
+    /// Overrides the safe-IP policy for this mapping.
+    #[serde(default)]
+    allow_public: bool,
+}
+
+/// `PUT /domains/app.dev {"ip": "127.0.0.1"}` adds or replaces a mapping,
+/// answering with `{"warnings": [...]}` for overlaps with wildcards.
+async fn domain_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+    axum::Json(body): axum::Json<DomainTarget>,
+) -> Response {
+    let token = request_token(&headers, &query);
+    let added = if body.allow_public {
+        mgmt.add_domain_allow_public(&token, &domain, body.ip).await
+    } else {
+        mgmt.add_domain(&token, &domain, body.ip).await
+    };
+    if let Err(e) = added {
+        return error_response(e);
+    }
+    let warnings: Vec<String> = match mgmt.state().mapping_conflicts(&domain).await {
+        Ok(conflicts) => conflicts.iter().map(ToString::to_string).collect(),
+        Err(e) => {
+            log::warn!("Checking {} for conflicts failed: {}", domain, e);
+            Vec::new()
+        }
+    };
+    axum::Json(serde_json::json!({ "warnings": warnings })).into_response()
+}
+
+#[derive(Deserialize)]
+struct ProjectTarget {
+    ip: Ipv4Addr,
+}
+
+/// `PUT /projects/myapp {"ip": "127.0.0.1"}` maps every domain of the
+/// project template, answering with `{"domains": [...]}`.
+async fn project_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(project): Path<String>,
+    axum::Json(body): axum::Json<ProjectTarget>,
+) -> Response {
+    match mgmt.add_project(&request_token(&headers, &query), &project, body.ip).await {
+        Ok(domains) => axum::Json(serde_json::json!({ "domains": domains })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn project_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(project): Path<String>,
+) -> Response {
+    match mgmt.remove_project(&request_token(&headers, &query), &project).await {
+        Ok(domains) => axum::Json(serde_json::json!({ "domains": domains })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DomainDelay {
+    domain: String,
+    ms: u64,
+}
+
+async fn delay_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.domain_delays(&request_token(&headers, &query)) {
+        Ok(delays) => {
+            let delays: Vec<DomainDelay> =
+                delays.into_iter().map(|(domain, delay)| DomainDelay { domain, ms: delay.as_millis() as u64 }).collect();
+            axum::Json(delays).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct Delay {
+    ms: u64,
+}
+
+/// `PUT /domains/slow.dev/delay {"ms": 2000}` holds local answers for
+/// `slow.dev` back by two seconds.
+async fn delay_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+    axum::Json(body): axum::Json<Delay>,
+) -> Response {
+    match mgmt.set_domain_delay(&request_token(&headers, &query), &domain, Duration::from_millis(body.ms)) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delay_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+) -> Response {
+    match mgmt.remove_domain_delay(&request_token(&headers, &query), &domain) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DomainOrder {
+    domain: String,
+    order: AnswerOrder,
+}
+
+async fn order_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.answer_orders(&request_token(&headers, &query)) {
+        Ok(orders) => {
+            let orders: Vec<DomainOrder> = orders.into_iter().map(|(domain, order)| DomainOrder { domain, order }).collect();
+            axum::Json(orders).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct Order {
+    order: AnswerOrder,
+}
+
+/// `PUT /domains/app.dev/order {"order": "round-robin"}` rotates the
+/// addresses answered for `app.dev`.
+async fn order_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+    axum::Json(body): axum::Json<Order>,
+) -> Response {
+    match mgmt.set_answer_order(&request_token(&headers, &query), &domain, body.order) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn order_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+) -> Response {
+    match mgmt.remove_answer_order(&request_token(&headers, &query), &domain) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn conflict_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.conflicts(&request_token(&headers, &query)).await {
+        Ok(conflicts) => axum::Json(conflicts).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn domain_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(domain): Path<String>,
+) -> Response {
+    match mgmt.remove_domain(&request_token(&headers, &query), &domain).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct Enabled {
+    enabled: bool,
+}
+
+/// `PUT /enabled {"enabled": false}` switches to bypass mode.
+async fn set_enabled(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    axum::Json(body): axum::Json<Enabled>,
+) -> Response {
+    match mgmt.set_enabled(&request_token(&headers, &query), body.enabled) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogFilter {
+    /// `RUST_LOG` syntax, e.g. `info,felix_dns::server_handler=debug`.
+    filter: Option<String>,
+}
+
+async fn log_filter_get(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.log_filter(&request_token(&headers, &query)) {
+        Ok(filter) => axum::Json(LogFilter { filter }).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn log_filter_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    axum::Json(body): axum::Json<LogFilter>,
+) -> Response {
+    let Some(filter) = body.filter else {
+        return (StatusCode::BAD_REQUEST, "missing filter").into_response();
+    };
+    match mgmt.set_log_filter(&request_token(&headers, &query), &filter) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn cache_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.cache_entries(&request_token(&headers, &query)) {
+        Ok(entries) => axum::Json(entries).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn client_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.client_stats(&request_token(&headers, &query)) {
+        Ok(clients) => axum::Json(clients).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn view_list(State(mgmt): State<Management>, headers: HeaderMap, Query(query): Query<TokenQuery>) -> Response {
+    match mgmt.views(&request_token(&headers, &query)) {
+        Ok(views) => axum::Json(views).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn view_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(view): Path<String>,
+) -> Response {
+    match mgmt.remove_view(&request_token(&headers, &query), &view) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn view_domain_list(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(view): Path<String>,
+) -> Response {
+    match mgmt.view_domains(&request_token(&headers, &query), &view) {
+        Ok(Some(domains)) => {
+            let domains: Vec<DomainMapping> = domains.into_iter().map(|(domain, ip)| DomainMapping { domain, ip }).collect();
+            axum::Json(domains).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, format!("no view {:?}", view)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// `PUT /views/lan/domains/app.dev {"ip": "192.168.1.20"}` maps a name in
+/// the `lan` view, creating the view if needed.
+async fn view_domain_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path((view, domain)): Path<(String, String)>,
+    axum::Json(body): axum::Json<ViewTarget>,
+) -> Response {
+    match mgmt.add_view_domain(&request_token(&headers, &query), &view, &domain, body.ip) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ViewTarget {
+    ip: Ipv4Addr,
+}
+
+async fn view_domain_delete(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path((view, domain)): Path<(String, String)>,
+) -> Response {
+    match mgmt.remove_view_domain(&request_token(&headers, &query), &view, &domain) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListenerView {
+    view: Option<String>,
+}
+
+/// `PUT /listeners/192.168.1.5:53/view {"view": "lan"}` serves the `lan`
+/// view to queries arriving there; `{"view": null}` unbinds it.
+async fn listener_view_put(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Path(listener): Path<SocketAddr>,
+    axum::Json(body): axum::Json<ListenerView>,
+) -> Response {
+    match mgmt.bind_view(&request_token(&headers, &query), listener, body.view.as_deref()) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveQuery {
+    name: String,
+}
+
+/// `GET /resolve?name=api.app.dev` explains how the name is answered.
+async fn resolve_trace(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Query(resolve): Query<ResolveQuery>,
+) -> Response {
+    match mgmt.resolve_trace(&request_token(&headers, &query), &resolve.name).await {
+        Ok(trace) => axum::Json(trace).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+    name: Option<String>,
+}
+
+/// `DELETE /cache` purges everything, `DELETE /cache?name=...` one name.
+async fn cache_flush(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    Query(flush): Query<FlushQuery>,
+) -> Response {
+    match mgmt.flush_cache(&request_token(&headers, &query), flush.name.as_deref()) {
+        Ok(flushed) => axum::Json(serde_json::json!({ "flushed": flushed })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Body of the ACME endpoints, the same as lego's `httpreq` provider sends.
+#[derive(Deserialize)]
+struct AcmeChallenge {
+    /// The certificate name or its `_acme-challenge` name.
+    fqdn: String,
+    value: Option<String>,
+}
+
+async fn acme_present(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    axum::Json(challenge): axum::Json<AcmeChallenge>,
+) -> Response {
+    let Some(value) = challenge.value else {
+        return (StatusCode::BAD_REQUEST, "missing value").into_response();
+    };
+    match mgmt.set_acme_challenge(&request_token(&headers, &query), &challenge.fqdn, &value) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Without a value, clears every value for the name.
+async fn acme_cleanup(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    axum::Json(challenge): axum::Json<AcmeChallenge>,
+) -> Response {
+    let token = request_token(&headers, &query);
+    match mgmt.clear_acme_challenge(&token, &challenge.fqdn, challenge.value.as_deref()) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn events(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if let Err(e) = mgmt.authorize(&request_token(&headers, &query), Role::ReadOnly) {
+        return access_error_response(e);
+    }
+
+    ws.on_upgrade(move |socket| stream_events(socket, mgmt))
+}
+
+async fn stream_events(mut socket: WebSocket, mgmt: Management) {
+    let mut rx = mgmt.state().subscribe_queries();
+
+    loop {
+        tokio::select! {
+            ev = rx.recv() => {
+                let ev = match ev {
+                    Ok(ev) => ev,
+                    Err(RecvError::Lagged(n)) => {
+                        log::debug!("Event subscriber lagged, dropped {} events", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let text = match serde_json::to_string(&ev) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        log::warn!("Failed to encode query event: {:?}", e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Streams `UpstreamEvent`s as JSON text frames, see `alerts`.
+async fn alerts(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if let Err(e) = mgmt.authorize(&request_token(&headers, &query), Role::ReadOnly) {
+        return access_error_response(e);
+    }
+
+    ws.on_upgrade(move |socket| stream_alerts(socket, mgmt))
+}
+
+async fn stream_alerts(mut socket: WebSocket, mgmt: Management) {
+    let mut rx = mgmt.state().subscribe_alerts();
+
+    loop {
+        tokio::select! {
+            alert = rx.recv() => {
+                let alert = match alert {
+                    Ok(a) => a,
+                    Err(RecvError::Lagged(n)) => {
+                        log::debug!("Alert subscriber lagged, dropped {} alerts", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if send_json(&mut socket, &alert).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+async fn changes(
+    State(mgmt): State<Management>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if let Err(e) = mgmt.authorize(&request_token(&headers, &query), Role::ReadOnly) {
+        return access_error_response(e);
+    }
+
+    ws.on_upgrade(move |socket| stream_changes(socket, mgmt))
+}
+
+async fn stream_changes(mut socket: WebSocket, mgmt: Management) {
+    // subscribe before taking the snapshot so no change falls in between
+    let mut rx = mgmt.state().subscribe_changes();
+
+    let domains = match mgmt.state().list_domains().await {
+        Ok(d) => d,
+        Err(e) => {
+            log::warn!("Failed to snapshot domains for replication: {}", e);
+            return;
+        }
+    };
+    if send_json(&mut socket, &ReplicationMessage::Snapshot { domains }).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            change = rx.recv() => {
+                let change = match change {
+                    Ok(c) => c,
+                    Err(RecvError::Lagged(n)) => {
+                        // the secondary resyncs from a fresh snapshot on reconnect
+                        log::warn!("Replica lagged by {} changes, disconnecting", n);
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if send_json(&mut socket, &ReplicationMessage::from(change)).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+async fn send_json<T: serde::Serialize>(socket: &mut WebSocket, value: &T) -> std::result::Result<(), ()> {
+    let text = serde_json::to_string(value).map_err(|e| log::warn!("Failed to encode frame: {:?}", e))?;
+    socket.send(Message::Text(text.into())).await.map_err(|_| ())
+}
@@ -0,0 +1,191 @@
+//! In-process query metrics, for embedders that publish through their own
+//! telemetry stack instead of statsd or OTLP.
+//!
+//! Every handled query adds its round-trip time, from receiving the
+//! request to sending the reply, to a histogram for its answer source and
+//! outcome. `ResolverState::metrics` returns a snapshot of all of them;
+//! counts only grow, so consumers diff snapshots for rates.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use crate::{
+    events::{AnswerSource, QueryOutcome},
+    statsd::QuerySample,
+};
+
+/// Upper bounds of the histogram buckets, in microseconds. A final bucket
+/// counts everything slower.
+pub const LATENCY_BUCKETS_US: &[u64] = &[
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LatencyHistogram {
+    /// Queries per bucket of `LATENCY_BUCKETS_US`, not cumulative, plus
+    /// the overflow bucket last.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self { buckets: vec![0; LATENCY_BUCKETS_US.len() + 1], count: 0, sum_us: 0 }
+    }
+}
+
+impl LatencyHistogram {
+    /// Upper bound of the bucket holding the `q` quantile (0.0 to 1.0), or
+    /// `None` if nothing was recorded or it falls in the overflow bucket.
+    pub fn quantile_bound(&self, q: f64) -> Option<Duration> {
+        let rank = ((self.count as f64) * q.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return LATENCY_BUCKETS_US.get(i).map(|&us| Duration::from_micros(us));
+            }
+        }
+        None
+    }
+}
+
+/// One histogram of the snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueryMetrics {
+    pub source: AnswerSource,
+    pub outcome: QueryOutcome,
+    pub latency: LatencyHistogram,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    /// Sorted by source, then outcome.
+    pub queries: Vec<QueryMetrics>,
+}
+
+impl MetricsSnapshot {
+    pub fn total_queries(&self) -> u64 {
+        self.queries.iter().map(|m| m.latency.count).sum()
+    }
+}
+
+/// Sources without an address, indexing `Metrics::fixed` with the
+/// outcome; upstreams get their histograms on first use.
+const FIXED_SOURCES: [AnswerSource; 7] = [
+    AnswerSource::LocalExact,
+    AnswerSource::LocalWildcard,
+    AnswerSource::LocalRecord,
+    AnswerSource::Script,
+    AnswerSource::Cache,
+    AnswerSource::Blocklist,
+    AnswerSource::Synthesized,
+];
+
+/// Indexed by `QueryOutcome as usize`.
+const OUTCOMES: [QueryOutcome; 7] = [
+    QueryOutcome::Local,
+    QueryOutcome::Forwarded,
+    QueryOutcome::Cached,
+    QueryOutcome::ServFail,
+    QueryOutcome::Refused,
+    QueryOutcome::Blocked,
+    QueryOutcome::NotForwarded,
+];
+
+/// A `LatencyHistogram` that queries add to without taking a lock.
+struct AtomicHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    fn record(&self, elapsed: Duration) {
+        let us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum_us.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| Some(sum.saturating_add(us)));
+    }
+
+    /// `None` while nothing was recorded.
+    fn load(&self) -> Option<LatencyHistogram> {
+        let count = self.count.load(Ordering::Relaxed);
+        (count > 0).then(|| LatencyHistogram {
+            buckets: self.buckets.iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+            count,
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+        })
+    }
+}
+
+pub(crate) struct Metrics {
+    /// `FIXED_SOURCES` × `OUTCOMES`, row by row.
+    fixed: Box<[AtomicHistogram]>,
+    /// Written only the first time an upstream answers with an outcome.
+    upstreams: RwLock<HashMap<(SocketAddr, QueryOutcome), Arc<AtomicHistogram>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            fixed: (0..FIXED_SOURCES.len() * OUTCOMES.len()).map(|_| AtomicHistogram::default()).collect(),
+            upstreams: RwLock::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, sample: &QuerySample) {
+        let AnswerSource::Upstream(addr) = sample.source else {
+            let row = FIXED_SOURCES.iter().position(|&s| s == sample.source).expect("every other source is fixed");
+            self.fixed[row * OUTCOMES.len() + sample.outcome as usize].record(sample.elapsed);
+            return;
+        };
+        let key = (addr, sample.outcome);
+        let existing = self.upstreams.read().get(&key).cloned();
+        let histogram = existing.unwrap_or_else(|| self.upstreams.write().entry(key).or_default().clone());
+        histogram.record(sample.elapsed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let fixed = FIXED_SOURCES
+            .iter()
+            .flat_map(|&source| OUTCOMES.iter().map(move |&outcome| (source, outcome)))
+            .zip(self.fixed.iter())
+            .filter_map(|((source, outcome), h)| h.load().map(|latency| QueryMetrics { source, outcome, latency }));
+        let upstreams: Vec<QueryMetrics> = self
+            .upstreams
+            .read()
+            .iter()
+            .filter_map(|(&(addr, outcome), h)| {
+                h.load().map(|latency| QueryMetrics { source: AnswerSource::Upstream(addr), outcome, latency })
+            })
+            .collect();
+        let mut queries: Vec<QueryMetrics> = fixed.chain(upstreams).collect();
+        queries.sort_by_key(|m| (m.source.to_string(), m.outcome.as_str()));
+        MetricsSnapshot { queries }
+    }
+}
@@ -0,0 +1,94 @@
+//! recvmmsg/sendmmsg wrappers for the UDP listener's batched mode, moving
+//! up to a batch of datagrams per syscall.
+//!
+//! Both are non-blocking and meant to run inside `UdpSocket::async_io`,
+//! which retries them once the socket is ready again after `WouldBlock`.
+
+use std::{io, net::SocketAddr, os::fd::AsRawFd, ptr};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use socket2::{SockAddr, SockAddrStorage};
+use tokio::net::UdpSocket;
+
+/// Receives up to one datagram into the spare capacity of each buffer,
+/// filling them in order. Returns the senders of the datagrams received;
+/// buffers past that count are left untouched.
+pub(crate) fn recv_batch(socket: &UdpSocket, bufs: &mut [BytesMut]) -> io::Result<Vec<SocketAddr>> {
+    let mut names: Vec<SockAddrStorage> = bufs.iter().map(|_| SockAddrStorage::zeroed()).collect();
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| {
+            let spare = buf.chunk_mut();
+            libc::iovec { iov_base: spare.as_mut_ptr().cast(), iov_len: spare.len() }
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .map(|(iov, name)| {
+            let mut hdr = empty_header();
+            hdr.msg_hdr.msg_name = (name as *mut SockAddrStorage).cast();
+            hdr.msg_hdr.msg_namelen = name.size_of();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+
+    // SAFETY: every header points at a live sockaddr_storage and at one
+    // iovec covering its buffer's spare capacity, all outliving the call
+    let n = unsafe {
+        libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as u32, libc::MSG_DONTWAIT, ptr::null_mut())
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut peers = Vec::with_capacity(n as usize);
+    for ((msg, buf), name) in msgs.iter().zip(bufs.iter_mut()).zip(names).take(n as usize) {
+        // SAFETY: the kernel wrote msg_len bytes into the spare capacity
+        // and msg_namelen bytes of address
+        let peer = unsafe {
+            buf.advance_mut(msg.msg_len as usize);
+            SockAddr::new(name, msg.msg_hdr.msg_namelen)
+        };
+        peers.push(peer.as_socket().ok_or_else(|| io::Error::other("datagram from a non-IP address"))?);
+    }
+    Ok(peers)
+}
+
+/// Sends each packet to its address in order. Returns how many went out,
+/// which may be fewer than given; an error means the first one failed.
+pub(crate) fn send_batch(socket: &UdpSocket, packets: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+    let names: Vec<SockAddr> = packets.iter().map(|(_, to)| SockAddr::from(*to)).collect();
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(packet, _)| libc::iovec { iov_base: packet.as_ptr().cast_mut().cast(), iov_len: packet.len() })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(&names)
+        .map(|(iov, name)| {
+            let mut hdr = empty_header();
+            hdr.msg_hdr.msg_name = name.as_ptr().cast_mut().cast();
+            hdr.msg_hdr.msg_namelen = name.len();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+
+    // SAFETY: the headers point at addresses and packets that outlive the
+    // call, and sendmmsg only reads through them
+    let n = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as u32, libc::MSG_DONTWAIT) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn empty_header() -> libc::mmsghdr {
+    // SAFETY: mmsghdr is plain data; null pointers and zero lengths are a
+    // valid empty message
+    unsafe { std::mem::zeroed() }
+}
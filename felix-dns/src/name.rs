@@ -0,0 +1,167 @@
+//! Domain names as felix keys mappings by, and checks on them before they
+//! are stored, so a mapping that could never match a query is rejected up
+//! front.
+
+use std::{borrow::Borrow, fmt, ops::Deref};
+
+use hickory_proto::rr::{Name, domain::Label};
+
+/// Longest name in presentation form, without the trailing dot.
+const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Why a domain name was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    /// Longer than 253 characters.
+    TooLong(usize),
+    /// Two dots in a row, or a leading dot.
+    EmptyLabel,
+    /// A label longer than 63 characters.
+    LabelTooLong(String),
+    /// Only letters, digits, hyphens and underscores (as in `_acme-challenge`)
+    /// are allowed.
+    InvalidCharacter(char),
+    /// A label starting or ending with a hyphen.
+    Hyphen(String),
+    /// `*` or `**` anywhere but as the whole leftmost label.
+    MisplacedWildcard,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "name is empty"),
+            NameError::TooLong(len) => write!(f, "name is {} characters, at most {} allowed", len, MAX_NAME_LEN),
+            NameError::EmptyLabel => write!(f, "name has an empty label"),
+            NameError::LabelTooLong(label) => {
+                write!(f, "label {:?} is {} characters, at most {} allowed", label, label.len(), MAX_LABEL_LEN)
+            }
+            NameError::InvalidCharacter(c) => write!(f, "character {:?} is not allowed", c),
+            NameError::Hyphen(label) => write!(f, "label {:?} starts or ends with a hyphen", label),
+            NameError::MisplacedWildcard => write!(f, "* and ** are only allowed as the whole leftmost label"),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// A domain name as mappings are keyed and looked up: ASCII lowercase,
+/// Unicode labels as punycode, without the trailing dot. `Bücher.dev.`
+/// and `xn--bcher-kva.dev` are the same name, as are a query for either
+/// and a mapping for either.
+///
+/// Normalizing never fails; labels that cannot be encoded are kept, only
+/// lowercased, for `validate_domain` to reject.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NormalizedName(String);
+
+impl NormalizedName {
+    pub fn new(name: &str) -> Self {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_ascii() {
+            return Self(name.to_ascii_lowercase());
+        }
+        let labels: Vec<String> = name
+            .split('.')
+            .map(|label| {
+                if label.is_ascii() {
+                    return label.to_ascii_lowercase();
+                }
+                Label::from_utf8(label).map_or_else(|_| label.to_lowercase(), |puny| puny.to_ascii())
+            })
+            .collect();
+        Self(labels.join("."))
+    }
+
+    /// `new`, rejecting names that cannot be stored; see `validate_domain`.
+    pub fn checked(name: &str) -> Result<Self, NameError> {
+        let normalized = Self::new(name);
+        check(&normalized.0)?;
+        Ok(normalized)
+    }
+
+    /// A name off the wire, already punycode where it has Unicode labels.
+    pub fn from_wire(name: &Name) -> Self {
+        Self::new(&name.to_ascii())
+    }
+
+    /// With the trailing dot, as names in questions are written; the root
+    /// is `.`.
+    pub fn fqdn(&self) -> String {
+        format!("{}.", self.0)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for NormalizedName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for NormalizedName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<NormalizedName> for String {
+    fn from(name: NormalizedName) -> Self {
+        name.0
+    }
+}
+
+impl fmt::Display for NormalizedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks `domain` (`app.dev`, `*.app.dev`, `**.app.dev`, an optional
+/// trailing dot, Unicode labels) can be stored and matched once
+/// normalized.
+pub fn validate_domain(domain: &str) -> Result<(), NameError> {
+    check(&NormalizedName::new(domain).0)
+}
+
+fn check(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(NameError::TooLong(name.len()));
+    }
+
+    for (i, label) in name.split('.').enumerate() {
+        if label.is_empty() {
+            return Err(NameError::EmptyLabel);
+        }
+        if label == "*" || label == "**" {
+            if i == 0 {
+                continue;
+            }
+            return Err(NameError::MisplacedWildcard);
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(NameError::LabelTooLong(label.to_string()));
+        }
+        if let Some(c) = label.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_')) {
+            return Err(if c == '*' { NameError::MisplacedWildcard } else { NameError::InvalidCharacter(c) });
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(NameError::Hyphen(label.to_string()));
+        }
+    }
+    Ok(())
+}
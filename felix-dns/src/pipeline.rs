@@ -0,0 +1,1037 @@
+//! The query pipeline run by the UDP server for every parsed request.
+//!
+//! Stages run in order until one produces an `Answer`; then every stage
+//! that ran gets `on_response` in reverse order, like middleware. The
+//! standard pipeline is `acl` → `quota` → `acme-update` → `chaos` → `hooks` → `policy` → `type-block` → `single-label` →
+//! `local` → `reserved` → `rewrite` → `cache` → `forward`; custom stages are
+//! spliced in by name:
+//!
+//! ```ignore
+//! let mut pipeline = Pipeline::standard();
+//! pipeline.insert_before("forward", Arc::new(MyStage));
+//! state.set_pipeline(pipeline);
+//! ```
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{DNSClass, Name, RData, Record, RecordType, rdata::{SOA, TXT}},
+};
+
+use crate::{
+    ResolverState, Result,
+    acl::AclStage,
+    acme::AcmeUpdateStage,
+    bailiwick,
+    clients::QuotaStage,
+    events::{AnswerSource, QueryOutcome},
+    policy::PolicyStage,
+    proto,
+    failover,
+    log_filter::QUERY_TARGET,
+    server_handler::{rename_question, restore_question},
+    trace::{self, TraceCheck, Tracer},
+};
+
+/// Everything a stage knows about the query being handled.
+pub struct QueryContext {
+    pub state: ResolverState,
+    pub client: SocketAddr,
+    /// The request exactly as received, for verbatim forwarding. Shares
+    /// the listener's receive buffer rather than copying it.
+    pub raw: Bytes,
+    pub request: Message,
+    /// First question name, as asked (fully qualified).
+    pub qname: String,
+    pub qtype: RecordType,
+    /// Name used for local lookup and forwarding; stages may rewrite it.
+    pub lookup_name: String,
+    /// Resolver the `forward` stage will use; stages may override it.
+    pub upstream: SocketAddr,
+    /// View bound to the listener the query arrived on; the `local` stage
+    /// answers A queries from its mappings instead of the default ones.
+    pub view: Option<Arc<str>>,
+    /// Search list of the listener the query arrived on, lowercased and
+    /// without dots at either end; see `SingleLabelStage`.
+    pub search: Arc<[String]>,
+    /// Set for `ResolverState::resolve_trace`: stages record their checks
+    /// in it and leave out side effects such as filling the cache.
+    pub(crate) trace: Option<Tracer>,
+}
+
+impl QueryContext {
+    /// `qname` without the trailing dot.
+    pub fn name(&self) -> &str {
+        self.qname.trim_end_matches('.')
+    }
+
+    pub fn is_rewritten(&self) -> bool {
+        self.lookup_name != self.qname
+    }
+
+    /// An empty response echoing the request's id and first question.
+    pub fn reply(&self) -> Message {
+        proto::build_reply(&self.request)
+    }
+
+    pub fn reply_with_code(&self, code: ResponseCode) -> Message {
+        proto::build_response_code(&self.request, code)
+    }
+
+    /// Adds the state's synthesized SOA to an authoritative NXDOMAIN or
+    /// empty answer, so clients cache the miss for the negative TTL.
+    pub fn add_negative_soa(&self, resp: &mut Message) {
+        match Name::from_utf8(&self.qname) {
+            Ok(zone) => proto::add_negative_soa(resp, zone, &self.state.negative_soa()),
+            Err(e) => log::debug!("No SOA for {}: {}", self.qname, e),
+        }
+    }
+}
+
+pub enum Response {
+    Message(Message),
+    /// Encoded bytes sent unchanged, e.g. by a custom stage.
+    Raw(Vec<u8>),
+}
+
+pub struct Answer {
+    pub response: Response,
+    pub outcome: QueryOutcome,
+    pub source: AnswerSource,
+}
+
+impl Answer {
+    /// The source follows from `outcome` for blocked and cached answers
+    /// and is `Synthesized` otherwise; see `with_source`.
+    pub fn new(response: Message, outcome: QueryOutcome) -> Self {
+        let source = match outcome {
+            QueryOutcome::Blocked => AnswerSource::Blocklist,
+            QueryOutcome::Cached => AnswerSource::Cache,
+            _ => AnswerSource::Synthesized,
+        };
+        Self { response: Response::Message(response), outcome, source }
+    }
+
+    pub fn with_source(mut self, source: AnswerSource) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+#[async_trait]
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns `Some` to answer the query and stop the pipeline.
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>>;
+
+    /// Sees (and may modify) the answer on its way back to the client.
+    async fn on_response(&self, _ctx: &QueryContext, _answer: &mut Answer) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Stage>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl Pipeline {
+    pub fn empty() -> Self {
+        Self { stages: Vec::new() }
+    }
+
+    pub fn standard() -> Self {
+        Self {
+            stages: vec![
+                Arc::new(AclStage),
+                Arc::new(QuotaStage),
+                Arc::new(AcmeUpdateStage),
+                Arc::new(ChaosStage),
+                Arc::new(HookStage),
+                Arc::new(PolicyStage),
+                Arc::new(TypeBlockStage),
+                Arc::new(SingleLabelStage),
+                Arc::new(LocalStage),
+                Arc::new(ReservedStage),
+                Arc::new(RewriteStage),
+                Arc::new(CacheStage),
+                Arc::new(ForwardStage),
+            ],
+        }
+    }
+
+    /// Only client checks and forwarding, for bypass mode (see
+    /// `ResolverState::set_enabled`).
+    pub fn passthrough() -> Self {
+        Self { stages: vec![Arc::new(AclStage), Arc::new(QuotaStage), Arc::new(ForwardStage)] }
+    }
+
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    pub fn push(&mut self, stage: Arc<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    /// Inserts before the stage called `name`, or at the end if there is none.
+    pub fn insert_before(&mut self, name: &str, stage: Arc<dyn Stage>) {
+        let at = self.position(name).unwrap_or(self.stages.len());
+        self.stages.insert(at, stage);
+    }
+
+    /// Inserts after the stage called `name`, or at the end if there is none.
+    pub fn insert_after(&mut self, name: &str, stage: Arc<dyn Stage>) {
+        let at = self.position(name).map_or(self.stages.len(), |i| i + 1);
+        self.stages.insert(at, stage);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.stages.retain(|s| s.name() != name);
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|s| s.name() == name)
+    }
+
+    /// Runs the stages; a query no stage answers gets SERVFAIL. A stage
+    /// failing in `on_response` is logged and skipped, so the client still
+    /// gets the answer as the other stages left it.
+    pub async fn run(&self, ctx: &mut QueryContext) -> Result<Answer> {
+        let mut ran = 0;
+        let mut answer = None;
+        for stage in &self.stages {
+            ran += 1;
+            let recorded = ctx.trace.as_ref().map(Tracer::len);
+            let answered = stage.on_query(ctx).await?;
+            if let (Some(t), Some(recorded)) = (&mut ctx.trace, recorded) {
+                t.stage_done(stage.name(), recorded, answered.is_some());
+            }
+            if let Some(a) = answered {
+                answer = Some(a);
+                break;
+            }
+        }
+
+        let mut answer = match answer {
+            Some(a) => a,
+            None => Answer::new(proto::build_servfail(&ctx.request), QueryOutcome::ServFail),
+        };
+        for stage in self.stages[..ran].iter().rev() {
+            if let Err(e) = stage.on_response(ctx, &mut answer).await {
+                log::warn!("Stage {} failed on the answer for {}: {}", stage.name(), ctx.qname, e);
+            }
+        }
+
+        Ok(answer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChaosAnswer {
+    Text(String),
+    Refuse,
+}
+
+/// Answers for CHAOS-class identification queries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChaosConfig {
+    /// `version.bind`
+    pub version: ChaosAnswer,
+    /// `hostname.bind` and `id.server`
+    pub hostname: ChaosAnswer,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            version: ChaosAnswer::Text(format!("felix {}", env!("CARGO_PKG_VERSION"))),
+            hostname: ChaosAnswer::Refuse,
+        }
+    }
+}
+
+/// Answers CHAOS-class queries itself; they are never forwarded. Anything
+/// other than the identification names is refused.
+pub struct ChaosStage;
+
+#[async_trait]
+impl Stage for ChaosStage {
+    fn name(&self) -> &'static str {
+        "chaos"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        if ctx.request.queries()[0].query_class() != DNSClass::CH {
+            return Ok(None);
+        }
+
+        let config = ctx.state.chaos_config();
+        let name = ctx.name().to_ascii_lowercase();
+        let answer = match name.as_str() {
+            "version.bind" => &config.version,
+            "hostname.bind" | "id.server" => &config.hostname,
+            _ => &ChaosAnswer::Refuse,
+        };
+
+        let text = match answer {
+            ChaosAnswer::Text(text) if ctx.qtype == RecordType::TXT || ctx.qtype == RecordType::ANY => text,
+            _ => return Ok(Some(Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused))),
+        };
+
+        let mut record = Record::from_rdata(Name::from_utf8(&ctx.qname)?, 0, RData::TXT(TXT::new(vec![text.clone()])));
+        record.set_dns_class(DNSClass::CH);
+        let mut resp = ctx.reply();
+        resp.set_authoritative(true);
+        resp.add_answer(record);
+        Ok(Some(Answer::new(resp, QueryOutcome::Local)))
+    }
+}
+
+/// Applies the script and WASM plugin decisions: refuse, answer, rewrite
+/// the lookup name, or override the upstream.
+pub struct HookStage;
+
+#[async_trait]
+impl Stage for HookStage {
+    fn name(&self) -> &'static str {
+        "hooks"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let qtype = ctx.qtype.to_string();
+        let decision = ctx
+            .state
+            .script_hooks()
+            .map(|h| h.on_query(ctx.name(), &qtype, ctx.client))
+            .unwrap_or_default();
+
+        // the first plugin with an opinion decides, unless the script already did
+        #[cfg(feature = "wasm-plugins")]
+        let decision = if decision == Default::default() {
+            ctx.state
+                .wasm_plugins()
+                .iter()
+                .map(|p| p.on_query(ctx.name(), &qtype, ctx.client))
+                .find(|d| *d != Default::default())
+                .unwrap_or(decision)
+        } else {
+            decision
+        };
+
+        if decision.refuse {
+            log::info!("Refused {} from {} by script", ctx.qname, ctx.client);
+            let answer = Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused);
+            return Ok(Some(answer.with_source(AnswerSource::Script)));
+        }
+        if let Some(ip) = decision.answer
+            && (ctx.qtype == RecordType::A || ctx.qtype == RecordType::ANY)
+        {
+            return Ok(Some(a_answer(ctx, ip).with_source(AnswerSource::Script)));
+        }
+        if let Some(name) = decision.name {
+            ctx.lookup_name = name;
+        }
+        if let Some(upstream) = decision.upstream {
+            ctx.upstream = upstream;
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// NOERROR with an empty answer section.
+    NoData,
+    Refused,
+    NxDomain,
+    /// `0.0.0.0` for A and `::` for AAAA, so clients fail fast without
+    /// retrying other resolvers; other types get NODATA.
+    Sinkhole,
+    /// Answers A or AAAA queries, whichever matches the address family,
+    /// with this address, e.g. a local block page; other types get NODATA.
+    Redirect(IpAddr),
+}
+
+/// How names without a local answer are answered when forwarding is
+/// disabled, see `ResolverState::set_local_only`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalOnly {
+    NxDomain,
+    Refused,
+}
+
+/// The SOA added to the authority section of locally generated NXDOMAIN
+/// and empty answers (RFC 2308). Its owner is the queried name; clients
+/// cache the miss for `ttl` seconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegativeSoa {
+    /// Used as both the record TTL and the SOA minimum.
+    pub ttl: u32,
+    pub mname: Name,
+    /// The zone contact, as a name (`hostmaster.localhost.`).
+    pub rname: Name,
+}
+
+impl Default for NegativeSoa {
+    fn default() -> Self {
+        Self {
+            ttl: 60,
+            mname: Name::from_ascii("localhost.").unwrap(),
+            rname: Name::from_ascii("hostmaster.localhost.").unwrap(),
+        }
+    }
+}
+
+/// Blocks one query type, everywhere or for a domain suffix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeBlockRule {
+    pub qtype: RecordType,
+    /// Applies to this domain and its subdomains; `None` applies everywhere.
+    pub suffix: Option<String>,
+    pub response: BlockResponse,
+}
+
+impl TypeBlockRule {
+    pub fn matches(&self, name: &str, qtype: RecordType) -> bool {
+        if qtype != self.qtype {
+            return false;
+        }
+        match &self.suffix {
+            Some(suffix) => in_zone(name, suffix),
+            None => true,
+        }
+    }
+}
+
+/// Bounds for the TTLs of forwarded answers, applied before they are
+/// cached and relayed. Covers every record and the negative TTL of an SOA
+/// in the authority section.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TtlClamp {
+    /// Applies to this domain and its subdomains; `None` applies everywhere.
+    /// The longest matching suffix wins over the global clamp.
+    pub suffix: Option<String>,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+impl TtlClamp {
+    pub fn matches(&self, name: &str) -> bool {
+        match &self.suffix {
+            Some(suffix) => in_zone(name, suffix),
+            None => true,
+        }
+    }
+
+    pub fn clamp(&self, ttl: u32) -> u32 {
+        let ttl = self.min.map_or(ttl, |min| ttl.max(min));
+        self.max.map_or(ttl, |max| ttl.min(max))
+    }
+
+    /// Clamps every TTL in `msg`; returns whether any changed.
+    pub fn apply(&self, msg: &mut Message) -> bool {
+        let mut changed = false;
+        let mut answers = msg.take_answers();
+        let mut name_servers = msg.take_name_servers();
+        let mut additionals = msg.take_additionals();
+        for record in answers.iter_mut().chain(name_servers.iter_mut()).chain(additionals.iter_mut()) {
+            let ttl = self.clamp(record.ttl());
+            changed |= ttl != record.ttl();
+            record.set_ttl(ttl);
+            if let RData::SOA(soa) = record.data() {
+                let minimum = self.clamp(soa.minimum());
+                if minimum != soa.minimum() {
+                    let soa = SOA::new(
+                        soa.mname().clone(),
+                        soa.rname().clone(),
+                        soa.serial(),
+                        soa.refresh(),
+                        soa.retry(),
+                        soa.expire(),
+                        minimum,
+                    );
+                    record.set_data(RData::SOA(soa));
+                    changed = true;
+                }
+            }
+        }
+        msg.insert_answers(answers);
+        msg.insert_name_servers(name_servers);
+        msg.insert_additionals(additionals);
+        changed
+    }
+}
+
+/// Drops out-of-bailiwick records from an upstream answer and applies the
+/// TTL clamp for `qname`, before it is cached or relayed.
+pub(crate) fn sanitize_reply(state: &ResolverState, qname: &str, msg: &mut Message) {
+    let stripped = bailiwick::strip_out_of_bailiwick(msg);
+    if stripped > 0 {
+        log::debug!("Dropped {} out-of-bailiwick records from the upstream answer for {}", stripped, qname);
+    }
+    if let Some(clamp) = state.ttl_clamp_for(qname) {
+        clamp.apply(msg);
+    }
+}
+
+/// Sends names under `suffix` to a different upstream than the default,
+/// e.g. `corp.example` to the VPN resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardRule {
+    pub suffix: String,
+    pub upstream: SocketAddr,
+}
+
+impl ForwardRule {
+    pub fn matches(&self, name: &str) -> bool {
+        in_zone(name, &self.suffix)
+    }
+}
+
+/// What picked a query's upstream, see `ResolverState::upstream_route`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamSource {
+    /// The upstream mapping with this key, e.g. `*.internal`.
+    Mapping(String),
+    /// The forward rule for this suffix.
+    ForwardRule(String),
+    Default,
+}
+
+impl fmt::Display for UpstreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamSource::Mapping(key) => write!(f, "upstream mapping {}", key),
+            UpstreamSource::ForwardRule(suffix) => write!(f, "forward rule {}", suffix),
+            UpstreamSource::Default => write!(f, "default upstream"),
+        }
+    }
+}
+
+/// Whether `name` is `suffix` or one of its subdomains, ignoring case and
+/// trailing dots.
+pub(crate) fn in_zone(name: &str, suffix: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let suffix = suffix.trim_end_matches('.');
+    name.eq_ignore_ascii_case(suffix)
+        || (name.len() > suffix.len()
+            && name.as_bytes()[name.len() - suffix.len() - 1] == b'.'
+            && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix))
+}
+
+/// Applies the state's `TypeBlockRule`s; the first matching rule wins.
+pub struct TypeBlockStage;
+
+#[async_trait]
+impl Stage for TypeBlockStage {
+    fn name(&self) -> &'static str {
+        "type-block"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let rules = ctx.state.type_block_rules();
+        let Some(rule) = rules.iter().find(|r| r.matches(ctx.name(), ctx.qtype)) else {
+            return Ok(None);
+        };
+        if let Some(t) = &mut ctx.trace {
+            t.type_block(rule.suffix.as_deref(), rule.response);
+        }
+
+        log::debug!("Blocked {} {} from {} ({:?})", ctx.qtype, ctx.qname, ctx.client, rule.response);
+        block_answer(ctx, rule.response).map(Some)
+    }
+}
+
+/// The answer to a query blocked with `response`.
+pub(crate) fn block_answer(ctx: &QueryContext, response: BlockResponse) -> Result<Answer> {
+    let address = match (response, ctx.qtype) {
+        (BlockResponse::Refused, _) => {
+            let answer = Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused);
+            return Ok(answer.with_source(AnswerSource::Blocklist));
+        }
+        (BlockResponse::NxDomain, _) => {
+            let mut resp = proto::build_nxdomain(&ctx.request);
+            ctx.add_negative_soa(&mut resp);
+            return Ok(Answer::new(resp, QueryOutcome::Blocked));
+        }
+        (BlockResponse::Sinkhole, RecordType::A) => Some(RData::A(Ipv4Addr::UNSPECIFIED.into())),
+        (BlockResponse::Sinkhole, RecordType::AAAA) => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED.into())),
+        (BlockResponse::Redirect(IpAddr::V4(ip)), RecordType::A) => Some(RData::A(ip.into())),
+        (BlockResponse::Redirect(IpAddr::V6(ip)), RecordType::AAAA) => Some(RData::AAAA(ip.into())),
+        _ => None,
+    };
+
+    let mut resp = ctx.reply();
+    resp.set_authoritative(true);
+    match address {
+        Some(data) => {
+            resp.add_answer(Record::from_rdata(Name::from_utf8(&ctx.qname)?, ctx.state.default_ttl(), data));
+        }
+        None => ctx.add_negative_soa(&mut resp),
+    }
+    Ok(Answer::new(resp, QueryOutcome::Blocked))
+}
+
+/// How queries for single-label names like `api` or `nas` are handled,
+/// see `SingleLabelStage`. Bare names sent upstream reveal internal
+/// hostnames to the public resolver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SingleLabel {
+    /// Expand with the listener's search list, or forward as asked on
+    /// listeners without one.
+    #[default]
+    Expand,
+    /// Forward as asked, ignoring search lists.
+    Forward,
+    /// Answer REFUSED, even for mapped names.
+    Refuse,
+    /// Answer from the mappings and records only, NXDOMAIN otherwise.
+    Mappings,
+}
+
+/// Applies the state's `SingleLabel` policy to names without a dot. On
+/// expansion, the first name of the listener's search list with a local
+/// answer wins, as with a stub resolver's `search` directive (`api` is
+/// looked up as `api.mycompany.dev`); without one, the first is
+/// forwarded. Answers keep the name as asked.
+///
+/// Only address lookups (A, AAAA, ANY) are affected: other types for a
+/// bare label are mostly questions about a TLD, such as the NS and DS
+/// queries of a validating resolver, and go on as asked.
+pub struct SingleLabelStage;
+
+#[async_trait]
+impl Stage for SingleLabelStage {
+    fn name(&self) -> &'static str {
+        "single-label"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let label = ctx.lookup_name.trim_end_matches('.');
+        if ctx.is_rewritten() || label.is_empty() || label.contains('.') {
+            return Ok(None);
+        }
+        if !matches!(ctx.qtype, RecordType::A | RecordType::AAAA | RecordType::ANY) {
+            return Ok(None);
+        }
+        // left to the `reserved` stage
+        if label.eq_ignore_ascii_case("localhost") && ctx.state.reserved_tlds().localhost {
+            return Ok(None);
+        }
+
+        match ctx.state.single_label() {
+            SingleLabel::Forward => Ok(None),
+            SingleLabel::Refuse => {
+                log::debug!("Refused single-label {} from {}", ctx.qname, ctx.client);
+                Ok(Some(Answer::new(proto::build_refused(&ctx.request), QueryOutcome::Refused)))
+            }
+            SingleLabel::Mappings => {
+                if has_local_answer(ctx, &ctx.lookup_name).await {
+                    return Ok(None);
+                }
+                let mut resp = proto::build_nxdomain(&ctx.request);
+                ctx.add_negative_soa(&mut resp);
+                Ok(Some(Answer::new(resp, QueryOutcome::NotForwarded)))
+            }
+            SingleLabel::Expand if ctx.search.is_empty() => Ok(None),
+            SingleLabel::Expand => {
+                let candidates: Vec<String> = ctx.search.iter().map(|suffix| format!("{}.{}.", label, suffix)).collect();
+                let mut expanded = None;
+                for candidate in &candidates {
+                    if has_local_answer(ctx, candidate).await {
+                        expanded = Some(candidate);
+                        break;
+                    }
+                }
+                ctx.lookup_name = expanded.unwrap_or(&candidates[0]).clone();
+                // forward rules apply to the expanded name, unless a hook
+                // already picked an upstream
+                if ctx.upstream == ctx.state.upstream_for(&ctx.qname) {
+                    ctx.upstream = ctx.state.upstream_for(&ctx.lookup_name);
+                }
+                log::debug!("Expanded {} to {} for {}", ctx.qname, ctx.lookup_name, ctx.client);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Whether the `local` stage would answer `name`.
+async fn has_local_answer(ctx: &QueryContext, name: &str) -> bool {
+    if ctx.qtype == RecordType::A || ctx.qtype == RecordType::ANY {
+        let found = match &ctx.view {
+            Some(view) => ctx.state.resolve_view_match(view, name).is_some(),
+            None => matches!(ctx.state.resolve_match(name).await, Ok(Some(_))),
+        };
+        if found {
+            return true;
+        }
+    }
+    !ctx.state.record_answers(name, ctx.qtype).is_empty()
+}
+
+/// Answers from the domain store (A) and the typed record table.
+pub struct LocalStage;
+
+#[async_trait]
+impl Stage for LocalStage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let mut ip = None;
+        let mut source = AnswerSource::LocalRecord;
+        if ctx.qtype == RecordType::A || ctx.qtype == RecordType::ANY {
+            let found = match &ctx.view {
+                Some(view) => Ok(ctx.state.resolve_view_match(view, &ctx.lookup_name)),
+                None => ctx.state.resolve_match(&ctx.lookup_name).await,
+            };
+            match found {
+                Ok(Some((key, found))) => {
+                    if let Some(t) = &mut ctx.trace {
+                        t.mapping(&ctx.lookup_name, ctx.state.wildcards(), Some((&key, found)));
+                    }
+                    ip = Some(match ctx.state.script_hooks() {
+                        Some(h) => h.on_answer(ctx.name(), &ctx.qtype.to_string(), ctx.client, found),
+                        None => found,
+                    });
+                    source = if key.starts_with('*') { AnswerSource::LocalWildcard } else { AnswerSource::LocalExact };
+                }
+                Ok(None) => {
+                    if let Some(t) = &mut ctx.trace {
+                        t.mapping(&ctx.lookup_name, ctx.state.wildcards(), None);
+                    }
+                }
+                Err(e) => log::warn!("Local lookup of {} failed: {}", ctx.lookup_name, e),
+            }
+        }
+
+        let records = ctx.state.record_answers(&ctx.lookup_name, ctx.qtype);
+        if ip.is_none() && records.is_empty() {
+            return Ok(None);
+        }
+        if ip.is_none()
+            && let Some(t) = &mut ctx.trace
+        {
+            t.step(TraceCheck::Exact, ctx.lookup_name.trim_end_matches('.'), true, Some(format!("{} records", records.len())));
+        }
+        if let Some(delay) = ctx.state.domain_delay(&ctx.lookup_name) {
+            log::debug!("Holding the answer for {} back by {:?}", ctx.qname, delay);
+            tokio::time::sleep(delay).await;
+        }
+
+        let ttl = ctx.state.default_ttl();
+        let mut resp = match ip {
+            Some(ip) => proto::build_a_response(&ctx.request, ip, ttl),
+            None => {
+                let mut resp = ctx.reply();
+                resp.set_authoritative(true);
+                resp
+            }
+        };
+        let name = Name::from_utf8(&ctx.qname)?;
+        for (data, ttl) in records {
+            resp.add_answer(Record::from_rdata(name.clone(), ttl, data));
+        }
+        ctx.state.order_answers(&ctx.lookup_name, resp.answers_mut());
+        Ok(Some(Answer::new(resp, QueryOutcome::Local).with_source(source)))
+    }
+}
+
+/// Handling of the special-use TLDs of RFC 6761 that have no mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedTlds {
+    /// Answer `localhost` and `*.localhost` with 127.0.0.1 and ::1.
+    pub localhost: bool,
+    /// Answer unmapped `.test` names with NXDOMAIN instead of forwarding.
+    pub test_local_only: bool,
+}
+
+impl Default for ReservedTlds {
+    fn default() -> Self {
+        Self { localhost: true, test_local_only: true }
+    }
+}
+
+/// Answers `.localhost` and `.test` names the `local` stage did not, per
+/// `ReservedTlds`, so they never leak upstream (RFC 6761 6.2, 6.3).
+pub struct ReservedStage;
+
+#[async_trait]
+impl Stage for ReservedStage {
+    fn name(&self) -> &'static str {
+        "reserved"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let config = ctx.state.reserved_tlds();
+        let name = ctx.lookup_name.trim_end_matches('.').to_ascii_lowercase();
+        let under = |tld: &str| name == tld || name.ends_with(&format!(".{}", tld));
+
+        if config.localhost && under("localhost") {
+            let mut resp = ctx.reply();
+            resp.set_authoritative(true);
+            let owner = Name::from_utf8(&ctx.qname)?;
+            let ttl = ctx.state.default_ttl();
+            if matches!(ctx.qtype, RecordType::A | RecordType::ANY) {
+                resp.add_answer(Record::from_rdata(owner.clone(), ttl, RData::A(Ipv4Addr::LOCALHOST.into())));
+            }
+            if matches!(ctx.qtype, RecordType::AAAA | RecordType::ANY) {
+                resp.add_answer(Record::from_rdata(owner, ttl, RData::AAAA(Ipv6Addr::LOCALHOST.into())));
+            }
+            if resp.answers().is_empty() {
+                ctx.add_negative_soa(&mut resp);
+            }
+            return Ok(Some(Answer::new(resp, QueryOutcome::Local)));
+        }
+
+        if config.test_local_only && under("test") {
+            let mut resp = proto::build_nxdomain(&ctx.request);
+            ctx.add_negative_soa(&mut resp);
+            return Ok(Some(Answer::new(resp, QueryOutcome::NotForwarded)));
+        }
+
+        Ok(None)
+    }
+}
+
+/// A rule applied to forwarded answers before they reach the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteRule {
+    /// Replace an A/AAAA address, e.g. a public IP with a local one.
+    ReplaceAddress { from: IpAddr, to: IpAddr },
+}
+
+/// Applies the state's `RewriteRule`s to forwarded answers.
+pub struct RewriteStage;
+
+#[async_trait]
+impl Stage for RewriteStage {
+    fn name(&self) -> &'static str {
+        "rewrite"
+    }
+
+    async fn on_query(&self, _ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        Ok(None)
+    }
+
+    async fn on_response(&self, ctx: &QueryContext, answer: &mut Answer) -> Result<()> {
+        let rules = ctx.state.rewrite_rules();
+        if rules.is_empty() || !matches!(answer.outcome, QueryOutcome::Forwarded | QueryOutcome::Cached) {
+            return Ok(());
+        }
+        let Response::Message(msg) = &mut answer.response else {
+            return Ok(());
+        };
+
+        let mut answers = msg.take_answers();
+        let mut additionals = msg.take_additionals();
+        for record in answers.iter_mut().chain(additionals.iter_mut()) {
+            for rule in rules.iter() {
+                apply_rewrite(rule, record);
+            }
+        }
+        msg.insert_answers(answers);
+        msg.insert_additionals(additionals);
+        Ok(())
+    }
+}
+
+fn apply_rewrite(rule: &RewriteRule, record: &mut Record) {
+    match *rule {
+        RewriteRule::ReplaceAddress { from, to } => {
+            let replacement = match (record.data(), to) {
+                (RData::A(a), IpAddr::V4(to)) if IpAddr::V4(a.0) == from => Some(RData::A(to.into())),
+                (RData::AAAA(a), IpAddr::V6(to)) if IpAddr::V6(a.0) == from => Some(RData::AAAA(to.into())),
+                _ => None,
+            };
+            if let Some(rdata) = replacement {
+                log::debug!("Rewrote {} {} -> {}", record.name(), from, to);
+                record.set_data(rdata);
+            }
+        }
+    }
+}
+
+/// Answers from the cache of forwarded answers and fills it with what
+/// `forward` brings back. Sits after `rewrite`, so cached answers are
+/// stored as the upstream sent them, bar bailiwick filtering and
+/// `TtlClamp`s, and rewritten on every hit.
+pub struct CacheStage;
+
+#[async_trait]
+impl Stage for CacheStage {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        // answers cached before local-only mode was turned on are upstream ones
+        if ctx.is_rewritten() || ctx.state.local_only().is_some() {
+            return Ok(None);
+        }
+        let hit = ctx.state.cache().get(&ctx.qname, ctx.qtype, ctx.upstream);
+        if let Some(t) = &mut ctx.trace {
+            let summary = hit.as_ref().map(|h| trace::summarize(&h.response));
+            t.step(TraceCheck::Cache, ctx.upstream.to_string(), hit.is_some(), summary);
+        }
+        let Some(hit) = hit else {
+            return Ok(None);
+        };
+        if hit.prefetch && ctx.trace.is_none() {
+            tokio::spawn(prefetch(ctx.state.clone(), ctx.qname.clone(), ctx.qtype, ctx.upstream));
+        }
+
+        let mut resp = hit.response;
+        resp.set_id(ctx.request.id());
+        resp.take_queries();
+        resp.add_queries(ctx.request.queries().to_vec());
+        Ok(Some(Answer {
+            response: Response::Message(resp),
+            outcome: QueryOutcome::Cached,
+            source: AnswerSource::Cache,
+        }))
+    }
+
+    async fn on_response(&self, ctx: &QueryContext, answer: &mut Answer) -> Result<()> {
+        if answer.outcome != QueryOutcome::Forwarded || ctx.is_rewritten() || ctx.trace.is_some() {
+            return Ok(());
+        }
+        if let Response::Message(msg) = &answer.response {
+            ctx.state.cache().insert(&ctx.qname, ctx.qtype, ctx.upstream, msg.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Refreshes a popular cache entry ahead of its expiry.
+async fn prefetch(state: ResolverState, name: String, qtype: RecordType, upstream: SocketAddr) {
+    let refreshed: Result<Message> = async {
+        let query = proto::build_query(&name, qtype)?;
+        let reply = failover::forward(&proto::encode(&query)?, upstream, &state.failover()).await?;
+        let mut msg = proto::decode(&reply)?;
+        sanitize_reply(&state, &name, &mut msg);
+        Ok(msg)
+    }
+    .await;
+    let stored = match refreshed {
+        Ok(msg) => state.cache().insert(&name, qtype, upstream, msg),
+        Err(e) => {
+            log::debug!("Prefetching {} {} from {} failed: {}", name, qtype, upstream, e);
+            false
+        }
+    };
+    if stored {
+        log::debug!("Prefetched {} {} from {}", name, qtype, upstream);
+    } else {
+        state.cache().prefetch_failed(&name, qtype);
+    }
+}
+
+/// Relays the query to `ctx.upstream` and parses the answer for the
+/// stages before it, answering SERVFAIL when either fails.
+pub struct ForwardStage;
+
+#[async_trait]
+impl Stage for ForwardStage {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        if let Some(mode) = ctx.state.local_only() {
+            let resp = match mode {
+                LocalOnly::NxDomain => {
+                    let mut resp = proto::build_nxdomain(&ctx.request);
+                    ctx.add_negative_soa(&mut resp);
+                    resp
+                }
+                LocalOnly::Refused => proto::build_refused(&ctx.request),
+            };
+            return Ok(Some(Answer::new(resp, QueryOutcome::NotForwarded)));
+        }
+
+        // set_upstream and plugins can still point here after the checks
+        // made when upstreams are configured
+        if ctx.state.is_own_listener(ctx.upstream) {
+            log::warn!("Not forwarding {}: upstream {} is this instance", ctx.qname, ctx.upstream);
+            let mut resp = proto::build_servfail(&ctx.request);
+            resp.set_authoritative(true);
+            return Ok(Some(Answer::new(resp, QueryOutcome::ServFail)));
+        }
+
+        let forwarded: Result<Message> = async {
+            let failover = ctx.state.failover();
+            let renamed = if ctx.is_rewritten() { Some(Name::from_utf8(&ctx.lookup_name)?) } else { None };
+            let mut packet = match &renamed {
+                Some(target) => rename_question(&ctx.request, target)?,
+                None => ctx.raw.to_vec(),
+            };
+            // a fresh id per upstream query: a forged answer has to guess
+            // it, where the client's may be predictable
+            packet[..2].copy_from_slice(&rand::random::<u16>().to_be_bytes());
+            let reply = failover::forward(&packet, ctx.upstream, &failover).await;
+            #[cfg(any(test, feature = "testing"))]
+            let reply = match ctx.state.upstream_faults() {
+                Some(faults) => faults.apply(ctx.upstream, reply).await,
+                None => reply,
+            };
+            let mut reply = proto::decode(&reply?)?;
+            reply.set_id(ctx.request.id());
+            if let Some(target) = &renamed {
+                restore_question(&mut reply, ctx.request.queries()[0].name(), target);
+            }
+            Ok(reply)
+        }
+        .await;
+        if let Some(t) = &mut ctx.trace {
+            t.answer(TraceCheck::Upstream, ctx.upstream.to_string(), forwarded.as_ref());
+        }
+
+        match forwarded {
+            Ok(mut reply) => {
+                sanitize_reply(&ctx.state, &ctx.qname, &mut reply);
+                if log::log_enabled!(target: QUERY_TARGET, log::Level::Trace) {
+                    let records: Vec<String> = reply.answers().iter().map(Record::to_string).collect();
+                    log::trace!(
+                        target: QUERY_TARGET,
+                        "Forwarded {} for {} to {}: {} [{}]",
+                        ctx.qname, ctx.client, ctx.upstream, reply.response_code(), records.join(", ")
+                    );
+                }
+                Ok(Some(Answer {
+                    response: Response::Message(reply),
+                    outcome: QueryOutcome::Forwarded,
+                    source: AnswerSource::Upstream(ctx.upstream),
+                }))
+            }
+            Err(e) => {
+                log::warn!("Forwarding {} to {} failed: {}", ctx.qname, ctx.upstream, e);
+                let mut resp = proto::build_servfail(&ctx.request);
+                resp.set_authoritative(true);
+                Ok(Some(Answer::new(resp, QueryOutcome::ServFail).with_source(AnswerSource::Upstream(ctx.upstream))))
+            }
+        }
+    }
+}
+
+fn a_answer(ctx: &QueryContext, ip: std::net::Ipv4Addr) -> Answer {
+    Answer::new(proto::build_a_response(&ctx.request, ip, ctx.state.default_ttl()), QueryOutcome::Local)
+}
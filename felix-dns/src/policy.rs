@@ -0,0 +1,118 @@
+//! Domain block and allow rules, optionally limited to a weekly schedule,
+//! e.g. blocking social media on weekdays from 9 to 5.
+//!
+//! Rules are checked in order on every query and the first one that
+//! matches the name and is active at that moment decides: a block rule
+//! answers with its `BlockResponse`, an allow rule lets the query through
+//! to the rest of the pipeline. Schedules are read in the state's
+//! `PolicyTimeZone`.
+
+use async_trait::async_trait;
+use chrono::{Datelike, Local, NaiveDateTime, Utc};
+
+pub use chrono::{FixedOffset, NaiveTime, Weekday};
+
+use crate::{
+    Result,
+    pipeline::{Answer, BlockResponse, QueryContext, Stage, block_answer, in_zone},
+};
+
+/// Days of the week and a daily window, `start` inclusive and `end`
+/// exclusive. A window with `end` before `start` runs past midnight and
+/// belongs to the day it starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Schedule {
+    /// All day on `days`.
+    pub fn days(days: Vec<Weekday>) -> Self {
+        Self { days, start: NaiveTime::MIN, end: NaiveTime::MIN }
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let (day, time) = (at.weekday(), at.time());
+        if self.start < self.end {
+            self.days.contains(&day) && time >= self.start && time < self.end
+        } else {
+            // equal bounds cover the whole day
+            (self.days.contains(&day) && time >= self.start) || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    Block(BlockResponse),
+    /// Exempts the name from later policy rules, e.g. one work site under
+    /// a blocked domain.
+    Allow,
+}
+
+/// Blocks or allows a domain and its subdomains, always or on a schedule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub suffix: String,
+    pub action: PolicyAction,
+    /// `None` applies at all times.
+    pub schedule: Option<Schedule>,
+}
+
+impl PolicyRule {
+    pub fn applies(&self, name: &str, at: NaiveDateTime) -> bool {
+        in_zone(name, &self.suffix) && self.schedule.as_ref().is_none_or(|s| s.contains(at))
+    }
+}
+
+/// Time zone schedules are evaluated in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolicyTimeZone {
+    /// The system's zone, including daylight saving time (`TZ` or
+    /// `/etc/localtime`).
+    #[default]
+    Local,
+    /// A fixed offset from UTC.
+    Fixed(FixedOffset),
+}
+
+impl PolicyTimeZone {
+    pub fn now(&self) -> NaiveDateTime {
+        match self {
+            PolicyTimeZone::Local => Local::now().naive_local(),
+            PolicyTimeZone::Fixed(offset) => Utc::now().with_timezone(offset).naive_local(),
+        }
+    }
+}
+
+/// Applies the state's `PolicyRule`s.
+pub struct PolicyStage;
+
+#[async_trait]
+impl Stage for PolicyStage {
+    fn name(&self) -> &'static str {
+        "policy"
+    }
+
+    async fn on_query(&self, ctx: &mut QueryContext) -> Result<Option<Answer>> {
+        let rules = ctx.state.policy_rules();
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        let now = ctx.state.policy_time_zone().now();
+        let found = rules.iter().position(|r| r.applies(ctx.name(), now));
+        if let Some(t) = &mut ctx.trace {
+            t.policy(&rules, found);
+        }
+        match found.map(|i| &rules[i]) {
+            Some(PolicyRule { action: PolicyAction::Block(response), suffix, .. }) => {
+                log::debug!("Blocked {} from {} by policy for {}", ctx.qname, ctx.client, suffix);
+                block_answer(ctx, *response).map(Some)
+            }
+            Some(PolicyRule { action: PolicyAction::Allow, .. }) | None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Project templates: the set of mappings a project usually needs, created
+//! from its name in one go, e.g. `myapp.test` and `*.myapp.test` for
+//! `ResolverState::add_project("myapp", ip)`.
+
+/// Placeholder replaced with the project name.
+pub const PROJECT_PLACEHOLDER: &str = "{project}";
+
+/// Domain patterns, each containing `{project}`, e.g. `api.{project}.dev`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectTemplate {
+    pub patterns: Vec<String>,
+}
+
+impl Default for ProjectTemplate {
+    fn default() -> Self {
+        Self { patterns: vec!["{project}.test".into(), "*.{project}.test".into()] }
+    }
+}
+
+impl ProjectTemplate {
+    pub fn new(patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self { patterns: patterns.into_iter().map(Into::into).collect() }
+    }
+
+    /// The domains of `project`, in pattern order. Patterns without the
+    /// placeholder are skipped, as they would be shared by every project.
+    pub fn expand(&self, project: &str) -> Vec<String> {
+        let project = project.trim_matches('.').to_ascii_lowercase();
+        self.patterns
+            .iter()
+            .filter(|p| p.contains(PROJECT_PLACEHOLDER))
+            .map(|p| p.replace(PROJECT_PLACEHOLDER, &project))
+            .collect()
+    }
+}
@@ -0,0 +1,105 @@
+//! Typed helpers for building and encoding DNS messages, shared by the
+//! listeners, pipeline stages and tests.
+
+use std::net::Ipv4Addr;
+
+use hickory_proto::{
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, Record, RecordType, rdata::SOA},
+    serialize::binary::{BinEncodable, BinEncoder},
+};
+
+use crate::{Result, pipeline::NegativeSoa};
+
+/// UDP payload size advertised in felix's OPT records, the DNS Flag Day
+/// 2020 value that keeps answers clear of IP fragmentation.
+pub const EDNS_UDP_PAYLOAD: u16 = 1232;
+
+/// A recursive query for `name` with a random id, so off-path forgers
+/// have to guess it.
+pub fn build_query(name: &str, qtype: RecordType) -> Result<Message> {
+    let mut msg = Message::new();
+    msg.set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_utf8(name)?, qtype));
+    Ok(msg)
+}
+
+/// An empty NOERROR response echoing the request's id, RD flag and first
+/// question.
+pub fn build_reply(request: &Message) -> Message {
+    let mut resp = Message::new();
+    resp.set_id(request.id());
+    resp.set_message_type(MessageType::Response);
+    resp.set_op_code(OpCode::Query);
+    resp.set_recursion_desired(request.recursion_desired());
+    if let Some(query) = request.queries().first() {
+        resp.add_query(query.clone());
+    }
+    resp
+}
+
+pub fn build_response_code(request: &Message, code: ResponseCode) -> Message {
+    let mut resp = build_reply(request);
+    resp.set_response_code(code);
+    resp
+}
+
+/// An authoritative answer with one A record for the first question's name.
+pub fn build_a_response(request: &Message, ip: Ipv4Addr, ttl: u32) -> Message {
+    let mut resp = build_reply(request);
+    resp.set_authoritative(true);
+    if let Some(query) = request.queries().first() {
+        resp.add_answer(Record::from_rdata(query.name().clone(), ttl, RData::A(ip.into())));
+    }
+    resp
+}
+
+pub fn build_nxdomain(request: &Message) -> Message {
+    let mut resp = build_response_code(request, ResponseCode::NXDomain);
+    resp.set_authoritative(true);
+    resp
+}
+
+/// Appends `soa` to the authority section with `zone` as its owner.
+pub fn add_negative_soa(resp: &mut Message, zone: Name, soa: &NegativeSoa) {
+    let rdata = SOA::new(soa.mname.clone(), soa.rname.clone(), 1, 3600, 600, 86400, soa.ttl);
+    resp.add_name_server(Record::from_rdata(zone, soa.ttl, RData::SOA(rdata)));
+}
+
+pub fn build_servfail(request: &Message) -> Message {
+    build_response_code(request, ResponseCode::ServFail)
+}
+
+pub fn build_refused(request: &Message) -> Message {
+    build_response_code(request, ResponseCode::Refused)
+}
+
+/// Gives `resp` an OPT record when `request` carried one and `resp` has
+/// none yet (RFC 6891 7), copying the DO bit (RFC 3225 3). Extended
+/// response codes set on `resp` are carried in it when encoded.
+pub fn echo_edns(request: &Message, resp: &mut Message) {
+    let Some(asked) = request.extensions() else { return };
+    if resp.extensions().is_some() {
+        return;
+    }
+    let mut edns = Edns::new();
+    edns.set_max_payload(EDNS_UDP_PAYLOAD).set_dnssec_ok(asked.flags().dnssec_ok);
+    resp.set_edns(edns);
+}
+
+pub fn encode(msg: &Message) -> Result<Vec<u8>> {
+    Ok(msg.to_vec()?)
+}
+
+/// Appends the wire form of `msg` to `buf`, reusing its allocation.
+pub fn encode_into(msg: &Message, buf: &mut Vec<u8>) -> Result<()> {
+    msg.emit(&mut BinEncoder::new(buf))?;
+    Ok(())
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Message> {
+    Ok(Message::from_vec(bytes)?)
+}
@@ -0,0 +1,215 @@
+//! HTTP reverse proxy for dev domains, puma-dev style: each connection is
+//! routed by its `Host` header to the port mapped for that name (see
+//! `ResolverState::set_domain_port`), at the address the name resolves to,
+//! or 127.0.0.1 when it has no A mapping.
+//!
+//! Routing happens on the first request of a connection; the rest of it,
+//! WebSocket upgrades included, is relayed as-is.
+//!
+//! With the `tls` feature the same routing is offered over HTTPS, with
+//! certificates minted by the local CA (see `tls::LocalCa`).
+
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+use crate::{ResolverState, Result, handover};
+
+/// Longest request head read before giving up on finding `Host`.
+const MAX_HEAD: usize = 16 * 1024;
+
+pub struct ProxyHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+    /// Kept to pass on to a successor, see `handover`.
+    listener: std::net::TcpListener,
+}
+
+impl ProxyHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(crate) fn listener(&self) -> &std::net::TcpListener {
+        &self.listener
+    }
+
+    /// Stops accepting connections; open ones run to completion.
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+pub async fn run_http_proxy(listen_addr: SocketAddr, state: ResolverState) -> Result<ProxyHandle> {
+    accept_loop(listen_addr, "HTTP", move |stream| {
+        let state = state.clone();
+        async move { proxy_connection(stream, &state).await }
+    })
+    .await
+}
+
+/// Like `run_http_proxy`, terminating TLS with a certificate from `ca` for
+/// each mapped name clients connect to.
+#[cfg(feature = "tls")]
+pub async fn run_https_proxy(
+    listen_addr: SocketAddr,
+    state: ResolverState,
+    ca: Arc<crate::tls::LocalCa>,
+) -> Result<ProxyHandle> {
+    let acceptor = tokio_rustls::TlsAcceptor::from(ca.server_config(state.clone())?);
+    accept_loop(listen_addr, "HTTPS", move |stream| {
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        async move {
+            let stream = acceptor.accept(stream).await?;
+            proxy_connection(stream, &state).await
+        }
+    })
+    .await
+}
+
+async fn accept_loop<F, Fut>(listen_addr: SocketAddr, scheme: &str, handle: F) -> Result<ProxyHandle>
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (listener, kept) = handover::listen_tcp(listen_addr).await?;
+    let local_addr = listener.local_addr()?;
+
+    log::info!("{} proxy listening on {}", scheme, local_addr);
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        let conn = handle(stream);
+                        tokio::spawn(async move {
+                            if let Err(e) = conn.await {
+                                log::debug!("Proxying connection from {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("Proxy accept error: {:?}", e),
+                },
+            }
+        }
+    });
+
+    Ok(ProxyHandle {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        task: Some(task),
+        listener: kept,
+    })
+}
+
+/// Routes one client connection, plain or already TLS-terminated.
+pub(crate) async fn proxy_connection<S>(mut client: S, state: &ResolverState) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = match read_head(&mut client).await? {
+        Head::Complete(head) => head,
+        Head::Closed => return Ok(()),
+        Head::TooLarge => return respond(&mut client, "431 Request Header Fields Too Large", "request head too large").await,
+    };
+    let Some(host) = host_header(&head) else {
+        return respond(&mut client, "400 Bad Request", "missing Host header").await;
+    };
+    let Some(target) = target_for(state, &host).await else {
+        return respond(&mut client, "404 Not Found", &format!("felix has no port mapped for {}", host)).await;
+    };
+
+    let mut upstream = match TcpStream::connect(target).await {
+        Ok(s) => s,
+        Err(e) => {
+            log::debug!("Proxy target {} for {} unreachable: {}", target, host, e);
+            return respond(&mut client, "502 Bad Gateway", &format!("{} ({}) is not accepting connections", host, target)).await;
+        }
+    };
+    log::debug!("Proxying {} to {}", host, target);
+    upstream.write_all(&head).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Where requests for `host` go, if it has a port mapped.
+pub(crate) async fn target_for(state: &ResolverState, host: &str) -> Option<SocketAddr> {
+    let port = state.domain_port(host)?;
+    let ip = state.resolve(host).await.ok().flatten().unwrap_or(Ipv4Addr::LOCALHOST);
+    Some(SocketAddr::from((ip, port)))
+}
+
+enum Head {
+    /// Everything read so far, up to and possibly past the blank line.
+    Complete(Vec<u8>),
+    Closed,
+    TooLarge,
+}
+
+async fn read_head<S: AsyncRead + Unpin>(client: &mut S) -> Result<Head> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(Head::Closed);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if head_end(&buf).is_some() {
+            return Ok(Head::Complete(buf));
+        }
+        if buf.len() > MAX_HEAD {
+            return Ok(Head::TooLarge);
+        }
+    }
+}
+
+fn head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// The `Host` header without its port, lowercased.
+fn host_header(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(&head[..head_end(head)?]).ok()?;
+    let value = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("host").then(|| value.trim())
+    })?;
+    let host = match value.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => value.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+async fn respond<S: AsyncWrite + Unpin>(client: &mut S, status: &str, body: &str) -> Result<()> {
+    let resp = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        body.len() + 1,
+        body
+    );
+    client.write_all(resp.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Guardrail against mapping real domains by accident.
+//!
+//! A mapping for `github.com` silently hijacks it for everyone using the
+//! resolver. With a `PublicSuffixGuard` set, mappings whose name falls
+//! under a suffix from the embedded public suffix list (ICANN section) are
+//! logged or refused. Names under the reserved dev TLDs (`.test`,
+//! `.localhost`, `.internal`, ...) and single-label names are never
+//! affected.
+
+use std::{collections::HashSet, sync::LazyLock};
+
+/// What to do with a mapping under a public suffix, see
+/// `ResolverState::set_public_suffix_guard`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublicSuffixGuard {
+    /// Keep the mapping but log a warning.
+    Warn,
+    /// Reject it with `Error::PublicSuffix`.
+    Refuse,
+}
+
+/// Suffixes reserved for private use (RFC 2606, RFC 6761, RFC 6762,
+/// RFC 8375 and ICANN's `.internal`), which never reach the public DNS.
+pub const DEV_SUFFIXES: &[&str] = &["test", "localhost", "internal", "example", "invalid", "local", "home.arpa"];
+
+struct SuffixList {
+    rules: HashSet<&'static str>,
+    /// Parents of `*.` rules.
+    wildcards: HashSet<&'static str>,
+    /// `!` rules, names that are registrable despite a wildcard.
+    exceptions: HashSet<&'static str>,
+}
+
+static LIST: LazyLock<SuffixList> = LazyLock::new(|| {
+    let mut list = SuffixList { rules: HashSet::new(), wildcards: HashSet::new(), exceptions: HashSet::new() };
+    for line in include_str!("public_suffix_list.dat").lines() {
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if let Some(parent) = line.strip_prefix("*.") {
+            list.wildcards.insert(parent);
+        } else if let Some(name) = line.strip_prefix('!') {
+            list.exceptions.insert(name);
+        } else {
+            list.rules.insert(line);
+        }
+    }
+    list
+});
+
+/// The longest public suffix `name` falls under, or `None` when it is
+/// under a dev TLD, has a single label or matches no rule. Wildcard
+/// prefixes of mapping keys are ignored.
+pub fn public_suffix(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_start_matches("**.").trim_start_matches("*.");
+    if !name.contains('.') || DEV_SUFFIXES.iter().any(|dev| name == *dev || name.ends_with(&format!(".{}", dev))) {
+        return None;
+    }
+
+    let list = &*LIST;
+    let mut suffix = name;
+    loop {
+        let parent = suffix.split_once('.').map(|(_, parent)| parent);
+        if list.exceptions.contains(suffix) {
+            return parent.map(str::to_string);
+        }
+        if list.rules.contains(suffix) || parent.is_some_and(|p| list.wildcards.contains(p)) {
+            return Some(suffix.to_string());
+        }
+        suffix = parent?;
+    }
+}
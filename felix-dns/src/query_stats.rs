@@ -0,0 +1,172 @@
+//! Rolling query counts by type, answer source and name, to see which
+//! names a machine resolves most and whether its wildcard mappings are
+//! hit.
+//!
+//! Queries are counted in one-minute buckets kept for `MAX_WINDOW`;
+//! `ResolverState::query_report` sums the buckets of the window asked for,
+//! so windows are rounded up to whole minutes. Each bucket tracks at most
+//! `MAX_NAMES_PER_BUCKET` names, so a flood of random names cannot grow
+//! it; queries for names past that are only counted as `untracked`.
+//!
+//! Buckets are shared with reports under `Arc`, so a report only holds the
+//! lock to copy pointers and sums outside it; the current bucket is copied
+//! on its next write if a report still reads it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+use hickory_proto::rr::RecordType;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{events::AnswerSource, name::NormalizedName};
+
+/// Longest window a report can cover.
+pub const MAX_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_NAMES_PER_BUCKET: usize = 1024;
+const BUCKET_SECS: u64 = 60;
+
+/// Queries handled within a window, busiest first in every list.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryReport {
+    /// Seconds covered, the window asked for rounded up to whole minutes
+    /// and capped at `MAX_WINDOW`.
+    pub window: u64,
+    pub queries: u64,
+    pub qtypes: Vec<TypeCount>,
+    /// By answer source, e.g. `local-wildcard` or `upstream-1.1.1.1:53`.
+    pub sources: Vec<SourceCount>,
+    /// The most queried names, as many as asked for.
+    pub names: Vec<NameCount>,
+    /// Queries for names dropped from their bucket's name list.
+    pub untracked: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeCount {
+    pub qtype: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameCount {
+    pub name: String,
+    pub count: u64,
+    /// How the last of these queries was answered.
+    pub source: String,
+}
+
+#[derive(Clone)]
+struct Bucket {
+    /// Minutes since `QueryStats::started`.
+    minute: u64,
+    qtypes: HashMap<RecordType, u64>,
+    sources: HashMap<AnswerSource, u64>,
+    names: HashMap<String, (u64, AnswerSource)>,
+    untracked: u64,
+}
+
+impl Bucket {
+    fn new(minute: u64) -> Self {
+        Self { minute, qtypes: HashMap::new(), sources: HashMap::new(), names: HashMap::new(), untracked: 0 }
+    }
+}
+
+pub(crate) struct QueryStats {
+    started: Instant,
+    /// Oldest first.
+    buckets: Mutex<VecDeque<Arc<Bucket>>>,
+}
+
+impl Default for QueryStats {
+    fn default() -> Self {
+        Self { started: Instant::now(), buckets: Mutex::default() }
+    }
+}
+
+impl QueryStats {
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / BUCKET_SECS
+    }
+
+    pub fn record(&self, name: &str, qtype: RecordType, source: AnswerSource) {
+        let minute = self.minute();
+        let mut buckets = self.buckets.lock();
+        if buckets.back().is_none_or(|b| b.minute != minute) {
+            let kept = MAX_WINDOW.as_secs() / BUCKET_SECS;
+            while buckets.front().is_some_and(|b| minute - b.minute >= kept) {
+                buckets.pop_front();
+            }
+            buckets.push_back(Arc::new(Bucket::new(minute)));
+        }
+        let bucket = Arc::make_mut(buckets.back_mut().expect("pushed above"));
+        *bucket.qtypes.entry(qtype).or_default() += 1;
+        *bucket.sources.entry(source).or_default() += 1;
+        let name = NormalizedName::new(name).into_string();
+        let tracked = bucket.names.len();
+        match bucket.names.get_mut(&name) {
+            Some((count, last)) => {
+                *count += 1;
+                *last = source;
+            }
+            None if tracked < MAX_NAMES_PER_BUCKET => {
+                bucket.names.insert(name, (1, source));
+            }
+            None => bucket.untracked += 1,
+        }
+    }
+
+    /// Sums the buckets of the last `window` and keeps the `top` names.
+    pub fn report(&self, window: Duration, top: usize) -> QueryReport {
+        let minutes = window.min(MAX_WINDOW).as_secs().div_ceil(BUCKET_SECS).max(1);
+        let now = self.minute();
+        let mut qtypes: HashMap<RecordType, u64> = HashMap::new();
+        let mut sources: HashMap<AnswerSource, u64> = HashMap::new();
+        let mut names: HashMap<String, (u64, AnswerSource)> = HashMap::new();
+        let mut untracked = 0;
+        let window: Vec<Arc<Bucket>> = self.buckets.lock().iter().filter(|b| now - b.minute < minutes).cloned().collect();
+        // oldest first, so the last source seen wins
+        for bucket in &window {
+            for (qtype, n) in &bucket.qtypes {
+                *qtypes.entry(*qtype).or_default() += n;
+            }
+            for (source, n) in &bucket.sources {
+                *sources.entry(*source).or_default() += n;
+            }
+            for (name, (n, source)) in &bucket.names {
+                let entry = names.entry(name.clone()).or_default();
+                entry.0 += n;
+                entry.1 = *source;
+            }
+            untracked += bucket.untracked;
+        }
+
+        let mut report = QueryReport {
+            window: minutes * BUCKET_SECS,
+            queries: qtypes.values().sum(),
+            qtypes: qtypes.into_iter().map(|(qtype, count)| TypeCount { qtype: qtype.to_string(), count }).collect(),
+            sources: sources.into_iter().map(|(source, count)| SourceCount { source: source.to_string(), count }).collect(),
+            names: names
+                .into_iter()
+                .map(|(name, (count, source))| NameCount { name, count, source: source.to_string() })
+                .collect(),
+            untracked,
+        };
+        // ties in a stable order
+        report.qtypes.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.qtype.cmp(&b.qtype)));
+        report.sources.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.source.cmp(&b.source)));
+        report.names.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        report.names.truncate(top);
+        report
+    }
+}
@@ -0,0 +1,771 @@
+//! Clustered mode for small server deployments: domain adds and removes
+//! go through a Raft log replicated across the members (usually three),
+//! so any member keeps answering, and accepting changes, while one of
+//! them is down.
+//!
+//! The leader appends each mutation to its log and replicates it; once a
+//! majority stored it, every member applies it to its own `ResolverState`
+//! in log order. A member that is not the leader forwards mutations to it
+//! and returns once it applied the result locally too. Queries are always
+//! answered from the local state.
+//!
+//! Members talk over a TCP port of their own; every frame is signed with
+//! the shared secret, as gossip packets are. Term, vote and log are kept
+//! in a JSON file when `RaftConfig::state_path` is set, which a member
+//! needs to rejoin safely after a restart. The log is not compacted, and
+//! only mutations made through the cluster are replicated: mappings seeded
+//! on one member stay local to it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Notify, oneshot, watch},
+    task::{JoinHandle, JoinSet},
+    time::{Instant, interval, timeout},
+};
+
+use crate::{
+    BuildError, Error, ResolverState, Result,
+    gossip::{sign, verify},
+};
+
+/// How long one vote or append round trip may take.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a mutation may take to commit and apply.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most entries sent in one append.
+const ENTRIES_PER_APPEND: usize = 64;
+const MAX_FRAME: usize = 16 << 20;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// This member's id, unique in the cluster.
+    pub id: u64,
+    pub bind: SocketAddr,
+    /// The other members' ids and addresses.
+    pub peers: Vec<(u64, SocketAddr)>,
+    /// Shared by every member; frames signed with any other are dropped.
+    pub secret: String,
+    /// Where term, vote and log are kept across restarts; `None` keeps
+    /// them in memory, for tests.
+    pub state_path: Option<PathBuf>,
+    /// How often the leader sends appends, empty ones as heartbeats.
+    pub heartbeat: Duration,
+    /// A follower that hears nothing from a leader for this long, plus a
+    /// random share of it again, starts an election.
+    pub election_timeout: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            id: 1,
+            bind: "0.0.0.0:5389".parse().unwrap(),
+            peers: Vec::new(),
+            secret: String::new(),
+            state_path: None,
+            heartbeat: Duration::from_millis(100),
+            election_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One replicated change; domains are normalized and were checked against
+/// the proposing member's policies before they entered the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Mutation {
+    /// Appended by every new leader, so entries of earlier terms commit.
+    Noop,
+    Set { domain: String, ip: Ipv4Addr },
+    Remove { domain: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LogEntry {
+    term: u64,
+    mutation: Mutation,
+}
+
+/// What has to survive a restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    term: u64,
+    voted_for: Option<u64>,
+    log: Vec<LogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Vote { term: u64, candidate: u64, last_log_index: u64, last_log_term: u64 },
+    Append { term: u64, leader: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64 },
+    /// A mutation forwarded to the leader.
+    Propose { mutation: Mutation },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Response {
+    Vote { term: u64, granted: bool },
+    /// `last_index` is how far the follower's log matches on success, and
+    /// where the leader should look next on failure.
+    Append { term: u64, success: bool, last_index: u64 },
+    /// The log index the mutation was applied at, or why it was not.
+    Proposed { index: Option<u64>, error: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Core {
+    stored: Stored,
+    role: Role,
+    leader: Option<u64>,
+    /// Log indexes are 1-based; 0 means none.
+    commit: u64,
+    applied: u64,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    /// Peers with an append in flight.
+    sending: HashSet<u64>,
+    election_deadline: Instant,
+    /// Local proposals waiting for their index, with the term they were
+    /// appended in.
+    waiters: HashMap<u64, (u64, oneshot::Sender<Result<()>>)>,
+}
+
+impl Core {
+    fn last_index(&self) -> u64 {
+        self.stored.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.stored.log.get(i as usize - 1).map_or(0, |e| e.term),
+        }
+    }
+}
+
+struct Shared {
+    id: u64,
+    peers: Vec<(u64, SocketAddr)>,
+    secret: Vec<u8>,
+    config: RaftConfig,
+    core: Mutex<Core>,
+    apply: Notify,
+    applied: watch::Sender<u64>,
+    state: ResolverState,
+}
+
+/// A running cluster member; clones share it. Mutations made through it
+/// are replicated, see the module docs.
+#[derive(Clone)]
+pub struct RaftNode {
+    shared: Arc<Shared>,
+}
+
+pub struct RaftHandle {
+    local_addr: SocketAddr,
+    node: RaftNode,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl RaftHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn node(&self) -> RaftNode {
+        self.node.clone()
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// Joins `state` to the cluster described by `config`.
+pub async fn start_raft(config: RaftConfig, state: ResolverState) -> Result<RaftHandle> {
+    if config.secret.is_empty() {
+        return Err(Error::Config(BuildError::MissingRaftSecret));
+    }
+    let stored = match &config.state_path {
+        Some(path) => load(path)?,
+        None => Stored::default(),
+    };
+    let listener = TcpListener::bind(config.bind).await.map_err(|e| Error::Bind(config.bind, e))?;
+    let local_addr = listener.local_addr()?;
+
+    let shared = Arc::new(Shared {
+        id: config.id,
+        peers: config.peers.clone(),
+        secret: config.secret.as_bytes().to_vec(),
+        core: Mutex::new(Core {
+            stored,
+            role: Role::Follower,
+            leader: None,
+            commit: 0,
+            applied: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            sending: HashSet::new(),
+            election_deadline: Instant::now() + election_delay(&config),
+            waiters: HashMap::new(),
+        }),
+        config,
+        apply: Notify::new(),
+        applied: watch::Sender::new(0),
+        state,
+    });
+    log::info!("Raft member {} listening on {}", shared.id, local_addr);
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let node = RaftNode { shared: shared.clone() };
+    let task = tokio::spawn(async move {
+        let mut tasks = JoinSet::new();
+        tasks.spawn(run_apply(shared.clone()));
+        let mut tick = interval(shared.config.heartbeat);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                _ = tick.tick() => shared.tick(),
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        tasks.spawn(serve(shared.clone(), stream));
+                    }
+                    Err(e) => log::warn!("Raft accept error: {:?}", e),
+                },
+            }
+        }
+        // dropping the set aborts the apply loop and open connections
+    });
+
+    Ok(RaftHandle { local_addr, node, shutdown_tx: Some(shutdown_tx), task })
+}
+
+impl RaftNode {
+    pub fn id(&self) -> u64 {
+        self.shared.id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.shared.core.lock().role == Role::Leader
+    }
+
+    /// The member this one follows, if it knows of one.
+    pub fn leader(&self) -> Option<u64> {
+        self.shared.core.lock().leader
+    }
+
+    /// `ResolverState::add_domain` through the cluster.
+    pub async fn add_domain(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.shared.state.check_mapping(domain, ip)?;
+        self.add_domain_allow_public(domain, ip).await
+    }
+
+    /// `ResolverState::add_domain_allow_public` through the cluster.
+    pub async fn add_domain_allow_public(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        let domain = crate::resolver_state::checked_key(domain)?;
+        self.shared.propose(Mutation::Set { domain, ip }, true).await.map(drop)
+    }
+
+    /// `ResolverState::remove_domain` through the cluster.
+    pub async fn remove_domain(&self, domain: &str) -> Result<()> {
+        let domain = crate::resolver_state::change_key(domain);
+        self.shared.propose(Mutation::Remove { domain }, true).await.map(drop)
+    }
+}
+
+impl Shared {
+    fn has_quorum(&self, members: usize) -> bool {
+        members * 2 > self.peers.len() + 1
+    }
+
+    fn address_of(&self, id: u64) -> Option<SocketAddr> {
+        self.peers.iter().find(|(p, _)| *p == id).map(|(_, addr)| *addr)
+    }
+
+    fn persist(&self, core: &Core) -> io::Result<()> {
+        let Some(path) = &self.config.state_path else { return Ok(()) };
+        // written aside and renamed, so a crash leaves the old or the new file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&core.stored)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Follows whoever has `term`, forgetting an older vote.
+    fn step_down(&self, core: &mut Core, term: u64) {
+        if term > core.stored.term {
+            core.stored.term = term;
+            core.stored.voted_for = None;
+            core.leader = None;
+            if let Err(e) = self.persist(core) {
+                log::error!("Failed to save Raft state: {}", e);
+            }
+        }
+        core.role = Role::Follower;
+        core.votes.clear();
+    }
+
+    fn tick(self: &Arc<Self>) {
+        let role = {
+            let core = self.core.lock();
+            match core.role {
+                Role::Leader => Role::Leader,
+                _ if Instant::now() >= core.election_deadline => Role::Candidate,
+                _ => Role::Follower,
+            }
+        };
+        match role {
+            Role::Leader => self.replicate_all(),
+            Role::Candidate => self.start_election(),
+            Role::Follower => {}
+        }
+    }
+
+    fn start_election(self: &Arc<Self>) {
+        let (request, term) = {
+            let mut core = self.core.lock();
+            core.stored.term += 1;
+            core.stored.voted_for = Some(self.id);
+            core.role = Role::Candidate;
+            core.leader = None;
+            core.votes = HashSet::from([self.id]);
+            core.election_deadline = Instant::now() + election_delay(&self.config);
+            if let Err(e) = self.persist(&core) {
+                log::error!("Failed to save Raft state, not standing for election: {}", e);
+                core.role = Role::Follower;
+                return;
+            }
+            log::info!("Raft member {} stands for election in term {}", self.id, core.stored.term);
+            if self.has_quorum(core.votes.len()) {
+                self.become_leader(&mut core);
+                drop(core);
+                self.replicate_all();
+                return;
+            }
+            let request = Request::Vote {
+                term: core.stored.term,
+                candidate: self.id,
+                last_log_index: core.last_index(),
+                last_log_term: core.term_at(core.last_index()),
+            };
+            (Arc::new(request), core.stored.term)
+        };
+
+        for &(peer, addr) in &self.peers {
+            let (shared, request) = (self.clone(), request.clone());
+            tokio::spawn(async move {
+                let Ok(Response::Vote { term: theirs, granted }) = call(addr, &shared.secret, &request, RPC_TIMEOUT).await
+                else {
+                    return;
+                };
+                let mut core = shared.core.lock();
+                if theirs > core.stored.term {
+                    shared.step_down(&mut core, theirs);
+                    return;
+                }
+                if granted && core.role == Role::Candidate && core.stored.term == term {
+                    core.votes.insert(peer);
+                    if shared.has_quorum(core.votes.len()) {
+                        shared.become_leader(&mut core);
+                        drop(core);
+                        shared.replicate_all();
+                    }
+                }
+            });
+        }
+    }
+
+    fn become_leader(&self, core: &mut Core) {
+        core.role = Role::Leader;
+        core.leader = Some(self.id);
+        let next = core.last_index() + 1;
+        for &(peer, _) in &self.peers {
+            core.next_index.insert(peer, next);
+            core.match_index.insert(peer, 0);
+        }
+        let term = core.stored.term;
+        core.stored.log.push(LogEntry { term, mutation: Mutation::Noop });
+        if let Err(e) = self.persist(core) {
+            log::error!("Failed to save Raft state: {}", e);
+        }
+        log::info!("Raft member {} leads term {}", self.id, term);
+        self.advance_commit(core);
+    }
+
+    /// Commits the newest entry of this term that a majority stored.
+    fn advance_commit(&self, core: &mut Core) {
+        let mut index = core.last_index();
+        while index > core.commit && core.term_at(index) == core.stored.term {
+            let stored_by = 1 + self.peers.iter().filter(|(p, _)| core.match_index.get(p).is_some_and(|m| *m >= index)).count();
+            if self.has_quorum(stored_by) {
+                core.commit = index;
+                self.apply.notify_one();
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    fn replicate_all(self: &Arc<Self>) {
+        for &(peer, addr) in &self.peers {
+            let shared = self.clone();
+            tokio::spawn(async move { shared.replicate(peer, addr).await });
+        }
+    }
+
+    /// Sends `peer` the entries it is missing, or a heartbeat.
+    async fn replicate(self: Arc<Self>, peer: u64, addr: SocketAddr) {
+        let (request, term) = {
+            let mut core = self.core.lock();
+            if core.role != Role::Leader || !core.sending.insert(peer) {
+                return;
+            }
+            let next = core.next_index.get(&peer).copied().unwrap_or(1).max(1);
+            let prev = next - 1;
+            let entries: Vec<LogEntry> =
+                core.stored.log.iter().skip(prev as usize).take(ENTRIES_PER_APPEND).cloned().collect();
+            let request = Request::Append {
+                term: core.stored.term,
+                leader: self.id,
+                prev_log_index: prev,
+                prev_log_term: core.term_at(prev),
+                entries,
+                leader_commit: core.commit,
+            };
+            (request, core.stored.term)
+        };
+
+        let response = call(addr, &self.secret, &request, RPC_TIMEOUT).await;
+        let more = {
+            let mut core = self.core.lock();
+            core.sending.remove(&peer);
+            let Ok(Response::Append { term: theirs, success, last_index }) = response else {
+                return;
+            };
+            if theirs > core.stored.term {
+                self.step_down(&mut core, theirs);
+                return;
+            }
+            if core.role != Role::Leader || core.stored.term != term {
+                return;
+            }
+            if success {
+                let matched = core.match_index.entry(peer).or_default();
+                *matched = (*matched).max(last_index);
+                let matched = *matched;
+                core.next_index.insert(peer, matched + 1);
+                self.advance_commit(&mut core);
+                matched < core.last_index()
+            } else {
+                let next = core.next_index.entry(peer).or_insert(1);
+                *next = (*next - 1).min(last_index + 1).max(1);
+                true
+            }
+        };
+        if more {
+            Box::pin(self.replicate(peer, addr)).await;
+        }
+    }
+
+    fn on_vote(&self, term: u64, candidate: u64, last_log_index: u64, last_log_term: u64) -> Response {
+        let mut core = self.core.lock();
+        if term > core.stored.term {
+            self.step_down(&mut core, term);
+        }
+        let ours = (core.term_at(core.last_index()), core.last_index());
+        let granted = term == core.stored.term
+            && core.stored.voted_for.is_none_or(|v| v == candidate)
+            && (last_log_term, last_log_index) >= ours;
+        if granted && core.stored.voted_for != Some(candidate) {
+            core.stored.voted_for = Some(candidate);
+            if let Err(e) = self.persist(&core) {
+                log::error!("Failed to save Raft state, withholding vote: {}", e);
+                core.stored.voted_for = None;
+                return Response::Vote { term: core.stored.term, granted: false };
+            }
+        }
+        if granted {
+            core.election_deadline = Instant::now() + election_delay(&self.config);
+        }
+        Response::Vote { term: core.stored.term, granted }
+    }
+
+    fn on_append(
+        &self,
+        term: u64,
+        leader: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> Response {
+        let mut core = self.core.lock();
+        if term < core.stored.term {
+            return Response::Append { term: core.stored.term, success: false, last_index: core.last_index() };
+        }
+        self.step_down(&mut core, term);
+        core.leader = Some(leader);
+        core.election_deadline = Instant::now() + election_delay(&self.config);
+
+        if prev_log_index > core.last_index() {
+            return Response::Append { term, success: false, last_index: core.last_index() };
+        }
+        if core.term_at(prev_log_index) != prev_log_term {
+            return Response::Append { term, success: false, last_index: prev_log_index - 1 };
+        }
+
+        let mut changed = false;
+        for (offset, entry) in entries.iter().enumerate() {
+            let index = prev_log_index + 1 + offset as u64;
+            if index <= core.last_index() {
+                if core.term_at(index) == entry.term {
+                    continue;
+                }
+                // a conflicting suffix was never committed
+                core.stored.log.truncate(index as usize - 1);
+                let lost: Vec<u64> = core.waiters.keys().copied().filter(|i| *i >= index).collect();
+                for i in lost {
+                    if let Some((_, tx)) = core.waiters.remove(&i) {
+                        let _ = tx.send(Err(Error::Cluster("leadership changed before the mutation committed".into())));
+                    }
+                }
+            }
+            core.stored.log.push(entry.clone());
+            changed = true;
+        }
+        if changed && let Err(e) = self.persist(&core) {
+            log::error!("Failed to save Raft log: {}", e);
+            return Response::Append { term, success: false, last_index: prev_log_index };
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        if leader_commit > core.commit {
+            core.commit = leader_commit.min(last_new);
+            self.apply.notify_one();
+        }
+        Response::Append { term, success: true, last_index: last_new }
+    }
+
+    /// Commits `mutation` through the leader and waits until this member
+    /// applied it, returning its log index.
+    async fn propose(self: &Arc<Self>, mutation: Mutation, forward: bool) -> Result<u64> {
+        enum Route {
+            Local(u64, oneshot::Receiver<Result<()>>),
+            Forward(SocketAddr),
+            Wait,
+        }
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            let route = {
+                let mut core = self.core.lock();
+                match core.role {
+                    Role::Leader => {
+                        let term = core.stored.term;
+                        core.stored.log.push(LogEntry { term, mutation: mutation.clone() });
+                        if let Err(e) = self.persist(&core) {
+                            core.stored.log.pop();
+                            return Err(e.into());
+                        }
+                        let index = core.last_index();
+                        let (tx, rx) = oneshot::channel();
+                        core.waiters.insert(index, (term, tx));
+                        self.advance_commit(&mut core);
+                        Route::Local(index, rx)
+                    }
+                    _ => match core.leader.and_then(|l| self.address_of(l)) {
+                        Some(addr) if forward => Route::Forward(addr),
+                        // an election is under way
+                        None if forward && Instant::now() < deadline => Route::Wait,
+                        _ => return Err(Error::Cluster("no leader to take the mutation".into())),
+                    },
+                }
+            };
+
+            match route {
+                Route::Local(index, rx) => {
+                    self.replicate_all();
+                    return match timeout(PROPOSE_TIMEOUT, rx).await {
+                        Ok(Ok(result)) => result.map(|()| index),
+                        Ok(Err(_)) => Err(Error::Cluster("stopped before the mutation committed".into())),
+                        Err(_) => {
+                            self.core.lock().waiters.remove(&index);
+                            Err(Error::Cluster("mutation did not commit in time".into()))
+                        }
+                    };
+                }
+                Route::Wait => tokio::time::sleep(self.config.heartbeat).await,
+                Route::Forward(addr) => {
+                    let request = Request::Propose { mutation: mutation.clone() };
+                    let (index, error) = match call(addr, &self.secret, &request, PROPOSE_TIMEOUT).await {
+                        Ok(Response::Proposed { index, error }) => (index, error),
+                        Ok(other) => {
+                            return Err(Error::Cluster(format!("unexpected answer from the leader: {:?}", other)));
+                        }
+                        // The leader went away; ask whoever follows it. Sets and
+                        // removes are idempotent, so sending one twice is harmless.
+                        Err(Error::Io(_)) if Instant::now() < deadline => {
+                            tokio::time::sleep(self.config.heartbeat).await;
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if let Some(error) = error {
+                        return Err(Error::Cluster(error));
+                    }
+                    let index = index.ok_or_else(|| Error::Cluster("leader did not say where it applied".into()))?;
+                    // read-your-writes on this member too
+                    let mut applied = self.applied.subscribe();
+                    return match timeout(PROPOSE_TIMEOUT, applied.wait_for(|a| *a >= index)).await {
+                        Ok(Ok(_)) => Ok(index),
+                        _ => Err(Error::Cluster("mutation committed but was not applied here in time".into())),
+                    };
+                }
+            }
+        }
+    }
+
+    async fn apply_entry(&self, mutation: &Mutation) -> Result<()> {
+        match mutation {
+            Mutation::Noop => Ok(()),
+            Mutation::Set { domain, ip } => self.state.add_domain_allow_public(domain, *ip).await,
+            Mutation::Remove { domain } => self.state.remove_domain(domain).await,
+        }
+    }
+}
+
+/// Applies committed entries in order, answering local proposals.
+async fn run_apply(shared: Arc<Shared>) {
+    loop {
+        shared.apply.notified().await;
+        loop {
+            let (index, entry) = {
+                let core = shared.core.lock();
+                if core.applied >= core.commit {
+                    break;
+                }
+                let index = core.applied + 1;
+                (index, core.stored.log[index as usize - 1].clone())
+            };
+            let result = shared.apply_entry(&entry.mutation).await;
+            let waiter = {
+                let mut core = shared.core.lock();
+                core.applied = index;
+                core.waiters.remove(&index)
+            };
+            shared.applied.send_replace(index);
+            match waiter {
+                Some((term, tx)) if term == entry.term => {
+                    let _ = tx.send(result);
+                }
+                Some((_, tx)) => {
+                    let _ = tx.send(Err(Error::Cluster("leadership changed before the mutation committed".into())));
+                }
+                None => {
+                    if let Err(e) = result {
+                        log::warn!("Failed to apply Raft entry {}: {}", index, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers one member's requests until it hangs up.
+async fn serve(shared: Arc<Shared>, mut stream: TcpStream) {
+    loop {
+        let request: Request = match read_frame(&mut stream, &shared.secret).await {
+            Ok(request) => request,
+            Err(e) => {
+                if !matches!(&e, Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof) {
+                    log::debug!("Dropping Raft connection: {}", e);
+                }
+                return;
+            }
+        };
+        let response = match request {
+            Request::Vote { term, candidate, last_log_index, last_log_term } => {
+                shared.on_vote(term, candidate, last_log_index, last_log_term)
+            }
+            Request::Append { term, leader, prev_log_index, prev_log_term, entries, leader_commit } => {
+                shared.on_append(term, leader, prev_log_index, prev_log_term, entries, leader_commit)
+            }
+            // proposals are forwarded once at most, so they cannot bounce
+            Request::Propose { mutation } => match shared.propose(mutation, false).await {
+                Ok(index) => Response::Proposed { index: Some(index), error: None },
+                Err(e) => Response::Proposed { index: None, error: Some(e.to_string()) },
+            },
+        };
+        if write_frame(&mut stream, &shared.secret, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn call(addr: SocketAddr, secret: &[u8], request: &Request, limit: Duration) -> Result<Response> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        write_frame(&mut stream, secret, request).await?;
+        read_frame(&mut stream, secret).await
+    };
+    timeout(limit, exchange).await.map_err(|_| Error::Cluster(format!("member {} did not answer in time", addr)))?
+}
+
+/// A length-prefixed, signed JSON frame.
+async fn write_frame(stream: &mut TcpStream, secret: &[u8], message: &impl Serialize) -> Result<()> {
+    let frame = sign(secret, serde_json::to_vec(message).map_err(io::Error::from)?);
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_frame<T: DeserializeOwned>(stream: &mut TcpStream, secret: &[u8]) -> Result<T> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Raft frame too large").into());
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    let body =
+        verify(secret, &frame).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unauthenticated Raft frame"))?;
+    Ok(serde_json::from_slice(body).map_err(io::Error::from)?)
+}
+
+fn load(path: &PathBuf) -> Result<Stored> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes).map_err(io::Error::from)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Stored::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The election timeout plus a random share of it, so members rarely
+/// stand at the same time.
+fn election_delay(config: &RaftConfig) -> Duration {
+    let base = config.election_timeout;
+    base + base.mul_f64(rand::random::<f64>())
+}
@@ -0,0 +1,103 @@
+//! Typed records other than the A mappings held by the domain stores, e.g.
+//! AAAA, TXT, MX or CNAME. Kept in memory only for now.
+
+use std::collections::HashMap;
+
+use hickory_proto::rr::{RData, RecordType};
+
+use crate::{
+    Error, Result,
+    name::NormalizedName,
+    store::{Wildcards, candidate_keys},
+};
+
+/// Longest CNAME chain accepted, as in common recursive resolvers.
+pub(crate) const MAX_ALIAS_CHAIN: usize = 16;
+
+#[derive(Clone, Default)]
+pub(crate) struct RecordTable {
+    /// Normalized name (or `*.suffix`) to its records, each with a TTL
+    /// overriding the default.
+    map: HashMap<String, Vec<(RData, Option<u32>)>>,
+}
+
+impl RecordTable {
+    /// Adds `data`, or updates its TTL if the name already has it.
+    pub(crate) fn add(&mut self, name: String, data: RData, ttl: Option<u32>) {
+        let records = self.map.entry(name).or_default();
+        match records.iter_mut().find(|(r, _)| *r == data) {
+            Some(existing) => existing.1 = ttl,
+            None => records.push((data, ttl)),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, name: &str, rtype: RecordType) {
+        self.retain(name, |r| r.record_type() != rtype);
+    }
+
+    /// Removes one record, leaving others of the same type.
+    pub(crate) fn remove_data(&mut self, name: &str, data: &RData) {
+        self.retain(name, |r| r != data);
+    }
+
+    fn retain(&mut self, name: &str, keep: impl Fn(&RData) -> bool) {
+        if let Some(records) = self.map.get_mut(name) {
+            records.retain(|(r, _)| keep(r));
+            if records.is_empty() {
+                self.map.remove(name);
+            }
+        }
+    }
+
+    /// Records of `qtype` for the closest owner of `name` (exact name, then
+    /// wildcards). A CNAME answers any other type, as in a real zone.
+    pub(crate) fn lookup(&self, name: &str, qtype: RecordType) -> Vec<(RData, Option<u32>)> {
+        let Some(records) = candidate_keys(name, Wildcards::default()).iter().find_map(|k| self.map.get(k)) else {
+            return Vec::new();
+        };
+        let of_type = |t: RecordType| records.iter().filter(move |(r, _)| r.record_type() == t).cloned();
+        if qtype == RecordType::ANY {
+            return records.clone();
+        }
+        let matching: Vec<_> = of_type(qtype).collect();
+        if matching.is_empty() {
+            return of_type(RecordType::CNAME).collect();
+        }
+        matching
+    }
+
+    /// Fails if adding `owner CNAME target` would make a chain of local
+    /// aliases loop or run past `MAX_ALIAS_CHAIN` links. Chains leaving the
+    /// table end there; upstream loops are the resolver's concern.
+    pub(crate) fn check_alias(&self, owner: &str, target: &str) -> Result<()> {
+        let mut chain = vec![owner.to_string()];
+        let mut next = Some(target.to_string());
+        while let Some(name) = next {
+            let looped = chain.contains(&name);
+            chain.push(name);
+            if looped || chain.len() - 1 > MAX_ALIAS_CHAIN {
+                return Err(Error::AliasLoop(chain));
+            }
+            let name = chain.last().unwrap();
+            next = candidate_keys(name, Wildcards::default()).iter().find_map(|k| {
+                if k == owner {
+                    return Some(Some(target.to_string()));
+                }
+                let records = self.map.get(k)?;
+                Some(records.iter().find_map(|(r, _)| match r {
+                    RData::CNAME(cname) => Some(NormalizedName::from_wire(&cname.0).into_string()),
+                    _ => None,
+                }))
+            })
+            .flatten();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Vec<(String, RData)> {
+        self.map
+            .iter()
+            .flat_map(|(name, records)| records.iter().map(move |(r, _)| (name.clone(), r.clone())))
+            .collect()
+    }
+}
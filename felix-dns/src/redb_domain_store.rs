@@ -0,0 +1,333 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use redb::{Database, ReadableTable, TableDefinition, backends::InMemoryBackend};
+
+use crate::{
+    Error, ForwardRule, Result,
+    name::NormalizedName,
+    store::{DomainPage, ListQuery, candidate_keys, search_list, Wildcards},
+};
+
+const MAPPINGS: TableDefinition<&str, u32> = TableDefinition::new("domain_mappings");
+const SETTINGS: TableDefinition<&str, &str> = TableDefinition::new("settings");
+const FORWARD_RULES: TableDefinition<&str, &str> = TableDefinition::new("forward_rules");
+const UPSTREAM_MAPPINGS: TableDefinition<&str, &str> = TableDefinition::new("upstream_mappings");
+const DOMAIN_PORTS: TableDefinition<&str, u16> = TableDefinition::new("domain_ports");
+
+const UPSTREAM_KEY: &str = "upstream";
+
+/// Mappings in a redb file: pure Rust, no SQLite needed. Same semantics as
+/// `SqliteDomainStore`.
+#[derive(Clone)]
+pub struct RedbDomainStore {
+    db: Arc<Database>,
+    wildcards: Wildcards,
+}
+
+impl RedbDomainStore {
+    /// Opens or creates the database at `path`; `:memory:` keeps it in RAM.
+    pub async fn new(path: &str) -> Result<Self> {
+        let path = path.to_string();
+        let db = tokio::task::spawn_blocking(move || {
+            if path == ":memory:" {
+                Database::builder().create_with_backend(InMemoryBackend::new())
+            } else {
+                Database::create(&path)
+            }
+        })
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))??;
+
+        let store = Self {
+            db: Arc::new(db),
+            wildcards: Wildcards::default(),
+        };
+        // reads fail on tables that were never created
+        store
+            .write(|txn| {
+                txn.open_table(MAPPINGS)?;
+                txn.open_table(SETTINGS)?;
+                txn.open_table(FORWARD_RULES)?;
+                txn.open_table(UPSTREAM_MAPPINGS)?;
+                txn.open_table(DOMAIN_PORTS)?;
+                Ok(())
+            })
+            .await?;
+        Ok(store)
+    }
+
+    /// See `Wildcards::apex`.
+    pub fn with_wildcard_apex(mut self, enabled: bool) -> Self {
+        self.wildcards.apex = enabled;
+        self
+    }
+
+    pub fn with_wildcards(mut self, wildcards: Wildcards) -> Self {
+        self.wildcards = wildcards;
+        self
+    }
+
+    /// Runs `f` in a read transaction on the blocking pool; redb reads can
+    /// hit the disk.
+    async fn read<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&redb::ReadTransaction) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(&db.begin_read()?))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))?
+    }
+
+    /// Runs `f` in a write transaction on the blocking pool and commits it.
+    async fn write<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&redb::WriteTransaction) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let txn = db.begin_write()?;
+            let out = f(&txn)?;
+            txn.commit()?;
+            Ok(out)
+        })
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?
+    }
+
+    pub async fn set(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        let domain = NormalizedName::checked(domain).map_err(|e| Error::InvalidDomain(domain.to_string(), e))?;
+        self.write(move |txn| {
+            txn.open_table(MAPPINGS)?.insert(domain.as_str(), u32::from(ip))?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove(&self, domain: &str) -> Result<()> {
+        let domain = NormalizedName::new(domain);
+        self.write(move |txn| {
+            txn.open_table(MAPPINGS)?.remove(domain.as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Applies sets (`Some`) and removals (`None`) of already-normalized
+    /// domains in one transaction.
+    pub async fn apply_batch(&self, ops: &[(String, Option<Ipv4Addr>)]) -> Result<()> {
+        let ops = ops.to_vec();
+        self.write(move |txn| {
+            let mut table = txn.open_table(MAPPINGS)?;
+            for (domain, ip) in &ops {
+                match ip {
+                    Some(ip) => table.insert(domain.as_str(), u32::from(*ip))?,
+                    None => table.remove(domain.as_str())?,
+                };
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn resolve(&self, qname: &str) -> Result<Option<Ipv4Addr>> {
+        Ok(self.resolve_with(qname, |_| None).await?.map(|(_, ip)| ip))
+    }
+
+    /// See `SqliteDomainStore::resolve_with`.
+    pub(crate) async fn resolve_with(
+        &self,
+        qname: &str,
+        staged: impl Fn(&str) -> Option<Option<Ipv4Addr>>,
+    ) -> Result<Option<(String, Ipv4Addr)>> {
+        let keys = candidate_keys(&NormalizedName::new(qname), self.wildcards);
+        let stored = {
+            let keys = keys.clone();
+            self.read(move |txn| {
+                let table = txn.open_table(MAPPINGS)?;
+                let mut stored = Vec::with_capacity(keys.len());
+                for key in &keys {
+                    stored.push(table.get(key.as_str())?.map(|v| Ipv4Addr::from(v.value())));
+                }
+                Ok(stored)
+            })
+            .await?
+        };
+        for (key, stored) in keys.into_iter().zip(stored) {
+            if let Some(ip) = staged(&key).unwrap_or(stored) {
+                return Ok(Some((key, ip)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, Ipv4Addr)>> {
+        self.read(|txn| {
+            let table = txn.open_table(MAPPINGS)?;
+            let mut result = Vec::new();
+            for entry in table.iter()? {
+                let (domain, ip) = entry?;
+                result.push((domain.value().to_string(), Ipv4Addr::from(ip.value())));
+            }
+            Ok(result)
+        })
+        .await
+    }
+
+    /// redb keeps keys in byte order only, so this filters and sorts a
+    /// full listing.
+    pub async fn list_paged(&self, query: &ListQuery) -> Result<DomainPage> {
+        Ok(query.apply(self.list().await?))
+    }
+
+    /// Up to `limit` domains containing `term`, best match first; a scan,
+    /// like `list_paged`.
+    pub async fn search(&self, term: &str, limit: usize) -> Result<Vec<(String, Ipv4Addr)>> {
+        Ok(search_list(self.list().await?, term, limit))
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.read(|txn| {
+            txn.open_table(MAPPINGS)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn count(&self) -> Result<u64> {
+        use redb::ReadableTableMetadata;
+        self.read(|txn| Ok(txn.open_table(MAPPINGS)?.len()?)).await
+    }
+
+    pub async fn upstream(&self) -> Result<Option<SocketAddr>> {
+        let value = self
+            .read(|txn| Ok(txn.open_table(SETTINGS)?.get(UPSTREAM_KEY)?.map(|v| v.value().to_string())))
+            .await?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        match value.parse() {
+            Ok(addr) => Ok(Some(addr)),
+            Err(_) => {
+                log::warn!("Ignoring invalid stored upstream {:?}", value);
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn set_upstream(&self, addr: SocketAddr) -> Result<()> {
+        self.write(move |txn| {
+            txn.open_table(SETTINGS)?.insert(UPSTREAM_KEY, addr.to_string().as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn forward_rules(&self) -> Result<Vec<ForwardRule>> {
+        let mut rules = Vec::new();
+        for (suffix, upstream) in self.read_pairs(FORWARD_RULES).await? {
+            match upstream.parse() {
+                Ok(upstream) => rules.push(ForwardRule { suffix, upstream }),
+                Err(_) => log::warn!("Ignoring forward rule for {} with invalid upstream {:?}", suffix, upstream),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub async fn set_forward_rule(&self, rule: &ForwardRule) -> Result<()> {
+        let rule = rule.clone();
+        self.write(move |txn| {
+            txn.open_table(FORWARD_RULES)?
+                .insert(rule.suffix.as_str(), rule.upstream.to_string().as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_forward_rule(&self, suffix: &str) -> Result<()> {
+        let suffix = suffix.to_string();
+        self.write(move |txn| {
+            txn.open_table(FORWARD_RULES)?.remove(suffix.as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn upstream_mappings(&self) -> Result<Vec<(String, SocketAddr)>> {
+        let mut mappings = Vec::new();
+        for (domain, upstream) in self.read_pairs(UPSTREAM_MAPPINGS).await? {
+            match upstream.parse() {
+                Ok(upstream) => mappings.push((domain, upstream)),
+                Err(_) => log::warn!("Ignoring upstream mapping for {} with invalid upstream {:?}", domain, upstream),
+            }
+        }
+        Ok(mappings)
+    }
+
+    pub async fn set_upstream_mapping(&self, domain: &str, upstream: SocketAddr) -> Result<()> {
+        let domain = domain.to_string();
+        self.write(move |txn| {
+            txn.open_table(UPSTREAM_MAPPINGS)?
+                .insert(domain.as_str(), upstream.to_string().as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_upstream_mapping(&self, domain: &str) -> Result<()> {
+        let domain = domain.to_string();
+        self.write(move |txn| {
+            txn.open_table(UPSTREAM_MAPPINGS)?.remove(domain.as_str())?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn domain_ports(&self) -> Result<Vec<(String, u16)>> {
+        self.read(|txn| {
+            let table = txn.open_table(DOMAIN_PORTS)?;
+            let mut ports = Vec::new();
+            for entry in table.iter()? {
+                let (domain, port) = entry?;
+                ports.push((domain.value().to_string(), port.value()));
+            }
+            Ok(ports)
+        })
+        .await
+    }
+
+    /// Every row of a string-to-string table.
+    async fn read_pairs(&self, definition: TableDefinition<'static, &'static str, &'static str>) -> Result<Vec<(String, String)>> {
+        self.read(move |txn| {
+            let table = txn.open_table(definition)?;
+            let mut pairs = Vec::new();
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                pairs.push((key.value().to_string(), value.value().to_string()));
+            }
+            Ok(pairs)
+        })
+        .await
+    }
+
+    pub async fn set_domain_port(&self, domain: &str, port: u16) -> Result<()> {
+        let domain = domain.to_string();
+        self.write(move |txn| {
+            txn.open_table(DOMAIN_PORTS)?.insert(domain.as_str(), port)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_domain_port(&self, domain: &str) -> Result<()> {
+        let domain = domain.to_string();
+        self.write(move |txn| {
+            txn.open_table(DOMAIN_PORTS)?.remove(domain.as_str())?;
+            Ok(())
+        })
+        .await
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{Error, ResolverState, Result, events::DomainChange};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Frames sent on the primary's `/changes` WebSocket: one snapshot on
+/// connect, then every change as it happens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationMessage {
+    Snapshot { domains: Vec<(String, Ipv4Addr)> },
+    Set { domain: String, ip: Ipv4Addr },
+    Removed { domain: String },
+}
+
+impl From<DomainChange> for ReplicationMessage {
+    fn from(change: DomainChange) -> Self {
+        match change {
+            DomainChange::Set { domain, ip } => ReplicationMessage::Set { domain, ip },
+            DomainChange::Removed { domain } => ReplicationMessage::Removed { domain },
+        }
+    }
+}
+
+pub struct SecondaryHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl SecondaryHandle {
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// Keeps `state` in sync with the primary whose management API listens on
+/// `primary`. Each (re)connect replaces the local mapping set with the
+/// primary's snapshot; local writes on a secondary are overwritten.
+pub fn follow_primary(primary: SocketAddr, token: impl Into<String>, state: ResolverState) -> SecondaryHandle {
+    let url = format!("ws://{}/changes?token={}", primary, token.into());
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+
+    let task = tokio::spawn(async move {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                res = replicate_once(&url, &state) => {
+                    match res {
+                        Ok(()) => {
+                            log::info!("Primary {} closed the change stream", primary);
+                            backoff = Duration::from_secs(1);
+                        }
+                        Err(e) => log::warn!("Replication from {} failed: {}", primary, e),
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+
+    SecondaryHandle {
+        shutdown_tx: Some(shutdown_tx),
+        task,
+    }
+}
+
+async fn replicate_once(url: &str, state: &ResolverState) -> Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    while let Some(msg) = ws.next().await {
+        let text = match msg.map_err(|e| std::io::Error::other(e.to_string()))? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let msg: ReplicationMessage = match serde_json::from_str(&text) {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!("Ignoring malformed replication frame: {}", e);
+                continue;
+            }
+        };
+        apply(state, msg).await?;
+    }
+
+    Ok(())
+}
+
+async fn apply(state: &ResolverState, msg: ReplicationMessage) -> Result<()> {
+    match msg {
+        ReplicationMessage::Snapshot { domains } => {
+            let keep: HashSet<&str> = domains.iter().map(|(d, _)| d.as_str()).collect();
+            for (domain, _) in state.list_domains().await? {
+                if !keep.contains(domain.as_str()) {
+                    state.remove_domain(&domain).await?;
+                }
+            }
+            // checked against this replica's own policy, not the primary's
+            for (domain, ip) in &domains {
+                set_checked(state, domain, *ip).await?;
+            }
+            log::info!("Applied primary snapshot with {} domains", domains.len());
+        }
+        ReplicationMessage::Set { domain, ip } => set_checked(state, &domain, ip).await?,
+        ReplicationMessage::Removed { domain } => state.remove_domain(&domain).await?,
+    }
+    Ok(())
+}
+
+/// Skips a mapping this replica's safe-IP or public suffix policy rejects
+/// rather than dropping the whole stream over it.
+async fn set_checked(state: &ResolverState, domain: &str, ip: Ipv4Addr) -> Result<()> {
+    match state.add_domain(domain, ip).await {
+        Err(e @ (Error::PublicAddress(_) | Error::PublicSuffix(..))) => {
+            log::warn!("Ignoring replicated mapping {} -> {}: {}", domain, ip, e);
+            Ok(())
+        }
+        res => res,
+    }
+}
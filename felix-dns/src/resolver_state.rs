@@ -1,41 +1,239 @@
-use std::{net::{Ipv4Addr, SocketAddr}, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+    sync::Arc,
+};
 
+use hickory_proto::rr::Record;
 use parking_lot::RwLock;
-use anyhow::Result;
+use tokio::sync::{broadcast, mpsc};
 
-use crate::{domain_map::DomainMap, sqlite_domain_store::SqliteDomainStore};
+use crate::{
+    Error, Result,
+    acl::ClientNet,
+    acme::{self, UpdateKey},
+    answer_order::{AnswerOrder, AnswerOrders},
+    alerts::{AlertConfig, UpstreamEvent, UpstreamMonitor},
+    cache::{CacheConfig, CacheEntry, ResponseCache},
+    failover::FailoverConfig,
+    metrics::{Metrics, MetricsSnapshot},
+    query_stats::{QueryReport, QueryStats},
+    clients::{ClientStats, ClientTracker, QuotaConfig},
+    conflicts::{self, MappingConflict},
+    cow_map::CowMap,
+    domain_map::DomainMap,
+    name::{NormalizedName, validate_domain},
+    events::{AnswerSource, DomainChange, QueryEvent, QueryOutcome},
+    RecordData, RecordType,
+    rdata::{HTTPS, SRV, TXT, svcb::{SVCB, SvcParamKey, SvcParamValue}},
+    policy::{PolicyRule, PolicyTimeZone},
+    projects::ProjectTemplate,
+    public_suffix::{PublicSuffixGuard, public_suffix},
+    pipeline::{ChaosConfig, ForwardRule, LocalOnly, UpstreamSource, NegativeSoa, Pipeline, ReservedTlds, RewriteRule, SingleLabel, TtlClamp, TypeBlockRule},
+    records::RecordTable,
+    scripting::ScriptHooks,
+    statsd::QuerySample,
+    store::{DomainPage, ListQuery, PersistentStore, Wildcards, candidate_keys, search_list},
+    trace::{self, ResolveTrace},
+    views::{self, ViewSlot, ViewSummary, Views},
+    write_behind::WriteBehind,
+};
 
+const QUERY_EVENT_CAPACITY: usize = 1024;
+const DOMAIN_CHANGE_CAPACITY: usize = 256;
+const DEFAULT_TTL: u32 = 60;
+/// RFC 2181 section 8: TTLs are 31-bit values.
+const MAX_TTL: u32 = i32::MAX as u32;
+
+/// Where mappings live. More backends may be added, so matches outside
+/// this crate need a wildcard arm.
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum DomainStorage {
     InMemory(Arc<RwLock<DomainMap>>),
-    Sqlite(SqliteDomainStore),
+    /// Ephemeral mappings in memory, checked before the persistent layer
+    /// (SQLite or redb) underneath.
+    Layered {
+        overlay: Arc<RwLock<DomainMap>>,
+        base: PersistentStore,
+        /// Stages writes to `base` when write-behind is enabled.
+        write_behind: Option<Arc<WriteBehind>>,
+    },
 }
 
+type UpstreamRoute = (SocketAddr, UpstreamSource);
+
 #[derive(Clone)]
 pub struct ResolverState {
     enabled: Arc<RwLock<bool>>,
+    acme_update_key: Arc<RwLock<Option<UpdateKey>>>,
+    local_only: Arc<RwLock<Option<LocalOnly>>>,
+    safe_ips: Arc<RwLock<bool>>,
+    public_suffix_guard: Arc<RwLock<Option<PublicSuffixGuard>>>,
+    /// Addresses of the running DNS listeners.
+    listeners: Arc<RwLock<Vec<SocketAddr>>>,
     storage: DomainStorage,
+    records: Arc<RwLock<RecordTable>>,
+    cache: ResponseCache,
+    clients: ClientTracker,
     upstream: Arc<RwLock<SocketAddr>>,
+    forward_rules: Arc<RwLock<Arc<Vec<ForwardRule>>>>,
+    upstream_mappings: CowMap<String, SocketAddr>,
+    /// Forward rules and upstream mappings merged into one table keyed
+    /// like A mappings, rebuilt whenever either changes.
+    upstream_routes: CowMap<String, UpstreamRoute>,
+    domain_ports: CowMap<String, u16>,
+    domain_delays: CowMap<String, Duration>,
+    answer_orders: AnswerOrders,
+    project_template: Arc<RwLock<Arc<ProjectTemplate>>>,
+    views: Arc<RwLock<Views>>,
+    wildcards: Wildcards,
+    query_events: broadcast::Sender<QueryEvent>,
+    domain_changes: broadcast::Sender<DomainChange>,
+    default_ttl: u32,
+    script: Arc<RwLock<Option<Arc<ScriptHooks>>>>,
+    pipeline: Arc<RwLock<Arc<Pipeline>>>,
+    rewrite_rules: Arc<RwLock<Arc<Vec<RewriteRule>>>>,
+    type_block_rules: Arc<RwLock<Arc<Vec<TypeBlockRule>>>>,
+    ttl_clamps: Arc<RwLock<Arc<Vec<TtlClamp>>>>,
+    policy_rules: Arc<RwLock<Arc<Vec<PolicyRule>>>>,
+    policy_time_zone: Arc<RwLock<PolicyTimeZone>>,
+    chaos: Arc<RwLock<Arc<ChaosConfig>>>,
+    reserved_tlds: Arc<RwLock<ReservedTlds>>,
+    single_label: Arc<RwLock<SingleLabel>>,
+    negative_soa: Arc<RwLock<Arc<NegativeSoa>>>,
+    failover: Arc<RwLock<Arc<FailoverConfig>>>,
+    allowed_clients: Arc<RwLock<Arc<Vec<ClientNet>>>>,
+    metrics: Arc<Metrics>,
+    query_stats: Arc<QueryStats>,
+    upstream_monitor: Arc<UpstreamMonitor>,
+    /// Queue of the running statsd exporter, if any.
+    query_samples: Arc<RwLock<Option<mpsc::Sender<QuerySample>>>>,
+    #[cfg(feature = "wasm-plugins")]
+    plugins: Arc<RwLock<Vec<Arc<crate::wasm_plugin::WasmPlugin>>>>,
+    /// Scheduled SQLite housekeeping; stops when the last clone is dropped.
+    #[cfg(feature = "sqlite")]
+    maintenance: Option<Arc<crate::maintenance::Maintenance>>,
+    #[cfg(any(test, feature = "testing"))]
+    upstream_faults: Arc<RwLock<Option<Arc<crate::testing::FaultInjector>>>>,
+}
+
+/// The in-memory state of a `ResolverState`, see `ResolverState::snapshot`.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone)]
+pub struct StateSnapshot {
+    enabled: bool,
+    acme_update_key: Option<UpdateKey>,
+    local_only: Option<LocalOnly>,
+    safe_ips: bool,
+    public_suffix_guard: Option<PublicSuffixGuard>,
+    domains: DomainMap,
+    /// The database's mappings, with persistent storage.
+    stored_domains: Option<Arc<HashMap<String, Ipv4Addr>>>,
+    records: RecordTable,
+    upstream: SocketAddr,
+    forward_rules: Arc<Vec<ForwardRule>>,
+    upstream_mappings: Arc<HashMap<String, SocketAddr>>,
+    domain_ports: Arc<HashMap<String, u16>>,
+    domain_delays: Arc<HashMap<String, Duration>>,
+    answer_orders: Arc<HashMap<String, AnswerOrder>>,
+    project_template: Arc<ProjectTemplate>,
+    views: Views,
+    script: Option<Arc<ScriptHooks>>,
+    pipeline: Arc<Pipeline>,
+    rewrite_rules: Arc<Vec<RewriteRule>>,
+    type_block_rules: Arc<Vec<TypeBlockRule>>,
+    ttl_clamps: Arc<Vec<TtlClamp>>,
+    policy_rules: Arc<Vec<PolicyRule>>,
+    policy_time_zone: PolicyTimeZone,
+    cache_config: Arc<CacheConfig>,
+    quotas: Arc<QuotaConfig>,
+    chaos: Arc<ChaosConfig>,
+    reserved_tlds: ReservedTlds,
+    single_label: SingleLabel,
+    negative_soa: Arc<NegativeSoa>,
+    failover: Arc<FailoverConfig>,
+    allowed_clients: Arc<Vec<ClientNet>>,
+    #[cfg(feature = "wasm-plugins")]
+    plugins: Vec<Arc<crate::wasm_plugin::WasmPlugin>>,
+    upstream_faults: Option<Arc<crate::testing::FaultInjector>>,
 }
 
 impl ResolverState {
+    /// In-memory state forwarding to `upstream`. Use `ResolverState::builder()`
+    /// for anything more elaborate.
     pub fn new(upstream: SocketAddr) -> Self {
-        Self {
-            enabled: Arc::new(RwLock::new(true)),
-            storage: DomainStorage::InMemory(Arc::new(RwLock::new(DomainMap::new()))),
-            upstream: Arc::new(RwLock::new(upstream)),
-        }
+        Self::from_parts(
+            DomainStorage::InMemory(Arc::new(RwLock::new(DomainMap::new()))),
+            upstream,
+            DEFAULT_TTL,
+        )
     }
-    
+
+    #[cfg(feature = "sqlite")]
     pub async fn new_with_sqlite(upstream: SocketAddr, database_path: &str) -> Result<Self> {
-        let sqlite_store = SqliteDomainStore::new(database_path).await?;
-        Ok(Self {
+        Self::builder().upstream(upstream).sqlite(database_path).build().await
+    }
+
+    pub fn builder() -> ResolverStateBuilder {
+        ResolverStateBuilder::default()
+    }
+
+    fn from_parts(storage: DomainStorage, upstream: SocketAddr, default_ttl: u32) -> Self {
+        Self {
             enabled: Arc::new(RwLock::new(true)),
-            storage: DomainStorage::Sqlite(sqlite_store),
+            acme_update_key: Arc::new(RwLock::new(None)),
+            local_only: Arc::new(RwLock::new(None)),
+            safe_ips: Arc::new(RwLock::new(false)),
+            public_suffix_guard: Arc::new(RwLock::new(None)),
+            listeners: Arc::new(RwLock::new(Vec::new())),
+            storage,
+            records: Arc::new(RwLock::new(RecordTable::default())),
+            cache: ResponseCache::default(),
+            clients: ClientTracker::default(),
             upstream: Arc::new(RwLock::new(upstream)),
-        })
+            forward_rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            upstream_mappings: CowMap::default(),
+            upstream_routes: CowMap::default(),
+            domain_ports: CowMap::default(),
+            domain_delays: CowMap::default(),
+            answer_orders: AnswerOrders::default(),
+            project_template: Arc::new(RwLock::new(Arc::new(ProjectTemplate::default()))),
+            views: Arc::new(RwLock::new(Views::default())),
+            wildcards: Wildcards::default(),
+            query_events: broadcast::channel(QUERY_EVENT_CAPACITY).0,
+            domain_changes: broadcast::channel(DOMAIN_CHANGE_CAPACITY).0,
+            default_ttl,
+            script: Arc::new(RwLock::new(None)),
+            pipeline: Arc::new(RwLock::new(Arc::new(Pipeline::standard()))),
+            rewrite_rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            type_block_rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            ttl_clamps: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            policy_rules: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            policy_time_zone: Arc::new(RwLock::new(PolicyTimeZone::default())),
+            chaos: Arc::new(RwLock::new(Arc::new(ChaosConfig::default()))),
+            reserved_tlds: Arc::new(RwLock::new(ReservedTlds::default())),
+            single_label: Arc::new(RwLock::new(SingleLabel::default())),
+            negative_soa: Arc::new(RwLock::new(Arc::new(NegativeSoa::default()))),
+            failover: Arc::new(RwLock::new(Arc::new(FailoverConfig::default()))),
+            allowed_clients: Arc::new(RwLock::new(Arc::new(Vec::new()))),
+            metrics: Arc::new(Metrics::default()),
+            query_stats: Arc::new(QueryStats::default()),
+            upstream_monitor: Arc::new(UpstreamMonitor::default()),
+            query_samples: Arc::new(RwLock::new(None)),
+            #[cfg(feature = "wasm-plugins")]
+            plugins: Arc::new(RwLock::new(Vec::new())),
+            #[cfg(feature = "sqlite")]
+            maintenance: None,
+            #[cfg(any(test, feature = "testing"))]
+            upstream_faults: Arc::new(RwLock::new(None)),
+        }
     }
 
+    /// Disabled, every query is passed straight through to its upstream
+    /// without local resolution.
     pub fn set_enabled(&self, v: bool) {
         *self.enabled.write() = v;
     }
@@ -44,6 +242,120 @@ impl ResolverState {
         *self.enabled.read()
     }
 
+    /// Restricts new mappings to loopback, RFC 1918 and `0.0.0.0`
+    /// addresses, so a typo cannot hijack a real domain for everyone using
+    /// a shared resolver. Existing mappings are kept; use
+    /// `add_domain_allow_public` for intended exceptions.
+    pub fn set_safe_ips(&self, v: bool) {
+        *self.safe_ips.write() = v;
+    }
+
+    pub fn safe_ips(&self) -> bool {
+        *self.safe_ips.read()
+    }
+
+    fn check_safe_ip(&self, ip: Ipv4Addr) -> Result<()> {
+        if self.safe_ips() && !(ip.is_loopback() || ip.is_private() || ip.is_unspecified()) {
+            return Err(Error::PublicAddress(ip));
+        }
+        Ok(())
+    }
+
+    /// Warns about or refuses new mappings under a real public suffix
+    /// such as `.com`, see `public_suffix`. `None` (the default) allows
+    /// them silently; `add_domain_allow_public` is always exempt.
+    pub fn set_public_suffix_guard(&self, guard: Option<PublicSuffixGuard>) {
+        *self.public_suffix_guard.write() = guard;
+    }
+
+    pub fn public_suffix_guard(&self) -> Option<PublicSuffixGuard> {
+        *self.public_suffix_guard.read()
+    }
+
+    /// The safe-IP and public suffix checks new mappings go through.
+    pub(crate) fn check_mapping(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.check_safe_ip(ip)?;
+        let Some(guard) = self.public_suffix_guard() else { return Ok(()) };
+        let Some(suffix) = public_suffix(domain) else { return Ok(()) };
+        match guard {
+            PublicSuffixGuard::Warn => {
+                log::warn!("Mapping {} shadows a real domain under .{} for every client of this resolver", domain, suffix);
+                Ok(())
+            }
+            PublicSuffixGuard::Refuse => Err(Error::PublicSuffix(domain.to_string(), suffix)),
+        }
+    }
+
+    /// Disables forwarding: names without a local answer get `mode`'s
+    /// response instead of reaching upstream, and the cache is bypassed.
+    /// `None` forwards again.
+    pub fn set_local_only(&self, mode: Option<LocalOnly>) {
+        *self.local_only.write() = mode;
+    }
+
+    pub fn local_only(&self) -> Option<LocalOnly> {
+        *self.local_only.read()
+    }
+
+    /// Whether at least one DNS listener is bound and serving.
+    pub fn listening(&self) -> bool {
+        !self.listeners.read().is_empty()
+    }
+
+    pub(crate) fn listener_started(&self, addr: SocketAddr) {
+        self.listeners.write().push(addr);
+    }
+
+    pub(crate) fn listener_stopped(&self, addr: SocketAddr) {
+        let mut listeners = self.listeners.write();
+        if let Some(i) = listeners.iter().position(|a| *a == addr) {
+            listeners.remove(i);
+        }
+    }
+
+    /// Whether `upstream` is one of this instance's own listeners, so
+    /// forwarding to it would loop. A listener on an unspecified address
+    /// also matches loopback upstreams on its port.
+    pub fn is_own_listener(&self, upstream: SocketAddr) -> bool {
+        self.listeners.read().iter().any(|l| {
+            *l == upstream
+                || (l.port() == upstream.port()
+                    && l.ip().is_unspecified()
+                    && (upstream.ip().is_loopback() || upstream.ip().is_unspecified()))
+        })
+    }
+
+    /// Configured upstreams (default, forward rules, upstream mappings, the
+    /// DoH bootstrap resolver) that point back at this instance.
+    pub fn looping_upstreams(&self) -> Vec<SocketAddr> {
+        let mut upstreams = vec![self.upstream()];
+        upstreams.extend(self.forward_rules().iter().map(|r| r.upstream));
+        upstreams.extend(self.upstream_mappings.snapshot().values().copied());
+        let failover = self.failover();
+        upstreams.extend(failover.upstreams.iter().copied());
+        if let crate::failover::DohBootstrap::Resolver(resolver) = failover.doh_bootstrap {
+            upstreams.push(resolver);
+        }
+        upstreams.retain(|u| self.is_own_listener(*u));
+        upstreams.dedup();
+        upstreams
+    }
+
+    fn check_not_self(&self, upstream: SocketAddr) -> Result<()> {
+        if self.is_own_listener(upstream) {
+            return Err(Error::UpstreamLoop(upstream));
+        }
+        Ok(())
+    }
+
+    /// Checks the domain store can serve reads.
+    pub async fn check_storage(&self) -> Result<()> {
+        match &self.storage {
+            DomainStorage::InMemory(_) => Ok(()),
+            DomainStorage::Layered { base, .. } => base.ping().await,
+        }
+    }
+
     pub fn set_upstream(&self, addr: SocketAddr) {
         *self.upstream.write() = addr;
     }
@@ -52,39 +364,1000 @@ impl ResolverState {
         *self.upstream.read()
     }
 
+    /// Sets the upstream and, with persistent storage, saves it so it survives
+    /// restarts.
+    pub async fn save_upstream(&self, addr: SocketAddr) -> Result<()> {
+        check_upstream(addr)?;
+        self.check_not_self(addr)?;
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.set_upstream(addr).await?;
+        }
+        self.set_upstream(addr);
+        Ok(())
+    }
+
+    pub(crate) fn wildcards(&self) -> Wildcards {
+        self.wildcards
+    }
+
+    pub fn forward_rules(&self) -> Arc<Vec<ForwardRule>> {
+        self.forward_rules.read().clone()
+    }
+
+    /// Upstream for `qname`, see `upstream_route`.
+    pub fn upstream_for(&self, qname: &str) -> SocketAddr {
+        self.upstream_route(qname).0
+    }
+
+    /// Upstream for `qname` and the configuration that chose it. Forward
+    /// rules and upstream mappings are one lookup, in the order A mappings
+    /// are matched: the most specific entry wins, and a forward rule for
+    /// `corp.example` counts as both `corp.example` and `**.corp.example`.
+    /// An upstream mapping beats a forward rule with the same key. Names
+    /// neither covers go to the default upstream.
+    pub fn upstream_route(&self, qname: &str) -> UpstreamRoute {
+        let routes = self.upstream_routes.snapshot();
+        if !routes.is_empty()
+            && let Some(route) = candidate_keys(&change_key(qname), self.wildcards)
+                .iter()
+                .find_map(|k| routes.get(k))
+        {
+            return route.clone();
+        }
+        (self.upstream(), UpstreamSource::Default)
+    }
+
+    fn rebuild_upstream_routes(&self) {
+        let mut routes = HashMap::new();
+        for (key, upstream) in self.upstream_mappings.snapshot().iter() {
+            routes.insert(key.clone(), (*upstream, UpstreamSource::Mapping(key.clone())));
+        }
+        for rule in self.forward_rules.read().iter() {
+            let route = (rule.upstream, UpstreamSource::ForwardRule(rule.suffix.clone()));
+            routes.entry(format!("**.{}", rule.suffix)).or_insert_with(|| route.clone());
+            routes.entry(rule.suffix.clone()).or_insert(route);
+        }
+        self.upstream_routes.replace(routes);
+    }
+
+    /// Adds or replaces the rule for `rule.suffix`, saving it with persistent
+    /// storage.
+    pub async fn add_forward_rule(&self, rule: ForwardRule) -> Result<()> {
+        check_upstream(rule.upstream)?;
+        self.check_not_self(rule.upstream)?;
+        let suffix = checked_key(&rule.suffix)?;
+        let rule = ForwardRule { suffix, ..rule };
+
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.set_forward_rule(&rule).await?;
+        }
+        {
+            let mut rules = self.forward_rules.write();
+            let mut updated: Vec<ForwardRule> = rules.iter().filter(|r| r.suffix != rule.suffix).cloned().collect();
+            updated.push(rule);
+            *rules = Arc::new(updated);
+        }
+        self.rebuild_upstream_routes();
+        Ok(())
+    }
+
+    pub async fn remove_forward_rule(&self, suffix: &str) -> Result<()> {
+        let suffix = change_key(suffix);
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.remove_forward_rule(&suffix).await?;
+        }
+        {
+            let mut rules = self.forward_rules.write();
+            *rules = Arc::new(rules.iter().filter(|r| r.suffix != suffix).cloned().collect());
+        }
+        self.rebuild_upstream_routes();
+        Ok(())
+    }
+
+    /// Mappings whose value is an upstream resolver: names matching them
+    /// (exactly or through a `*.` or `**.` wildcard, like A mappings) are
+    /// forwarded there. Sorted by domain.
+    pub fn upstream_mappings(&self) -> Vec<(String, SocketAddr)> {
+        self.upstream_mappings.sorted()
+    }
+
+    /// Forwards `domain` (e.g. `*.internal`) to `upstream`, saving the
+    /// mapping with persistent storage.
+    pub async fn add_upstream_mapping(&self, domain: &str, upstream: SocketAddr) -> Result<()> {
+        check_upstream(upstream)?;
+        self.check_not_self(upstream)?;
+        let key = checked_key(domain)?;
+
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.set_upstream_mapping(&key, upstream).await?;
+        }
+        self.upstream_mappings.set(key, upstream);
+        self.rebuild_upstream_routes();
+        Ok(())
+    }
+
+    pub async fn remove_upstream_mapping(&self, domain: &str) -> Result<()> {
+        let key = change_key(domain);
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.remove_upstream_mapping(&key).await?;
+        }
+        self.upstream_mappings.remove(&key);
+        self.rebuild_upstream_routes();
+        Ok(())
+    }
+
+    /// Maps `domain` to `ip` in `view`, creating the view if needed. See
+    /// `views` for how views are served.
+    pub fn add_view_domain(&self, view: &str, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        views::check_view_name(view)?;
+        let key = checked_key(domain)?;
+        self.check_mapping(&key, ip)?;
+        self.views.write().set(view, &key, ip, self.wildcards);
+        Ok(())
+    }
+
+    pub fn remove_view_domain(&self, view: &str, domain: &str) {
+        self.views.write().remove(view, &change_key(domain));
+    }
+
+    /// The view's mappings sorted by domain, `None` for an unknown view.
+    pub fn view_domains(&self, view: &str) -> Option<Vec<(String, Ipv4Addr)>> {
+        self.views.read().domains(view)
+    }
+
+    /// Drops `view` and returns its listeners to the default mappings.
+    pub fn remove_view(&self, view: &str) {
+        self.views.write().remove_view(view);
+    }
+
+    /// Answers queries arriving on `listener` from `view`, or from the
+    /// default mappings again with `None`. `listener` is the address the
+    /// DNS server is bound to, as in `ServerHandle::local_addr`.
+    pub fn bind_view(&self, listener: SocketAddr, view: Option<&str>) -> Result<()> {
+        if let Some(view) = view {
+            views::check_view_name(view)?;
+        }
+        self.views.write().bind(listener, view, self.wildcards);
+        Ok(())
+    }
+
+    pub fn views(&self) -> Vec<ViewSummary> {
+        self.views.read().summaries()
+    }
+
+    /// Where `listener` finds the view bound to it, now or later.
+    pub(crate) fn view_slot(&self, listener: SocketAddr) -> ViewSlot {
+        self.views.write().slot(listener)
+    }
+
+    pub(crate) fn resolve_view_match(&self, view: &str, qname: &str) -> Option<(String, Ipv4Addr)> {
+        self.views.read().resolve_match(view, qname)
+    }
+
+    /// TTL used for locally answered records.
+    pub fn default_ttl(&self) -> u32 {
+        self.default_ttl
+    }
+
+    /// Replaces the stages every query runs through.
+    pub fn set_pipeline(&self, pipeline: Pipeline) {
+        *self.pipeline.write() = Arc::new(pipeline);
+    }
+
+    pub fn pipeline(&self) -> Arc<Pipeline> {
+        self.pipeline.read().clone()
+    }
+
+    /// Rules applied, in order, to every forwarded answer.
+    pub fn set_rewrite_rules(&self, rules: Vec<RewriteRule>) {
+        *self.rewrite_rules.write() = Arc::new(rules);
+    }
+
+    pub fn rewrite_rules(&self) -> Arc<Vec<RewriteRule>> {
+        self.rewrite_rules.read().clone()
+    }
+
+    /// Query types to block, checked in order before local resolution.
+    pub fn set_type_block_rules(&self, rules: Vec<TypeBlockRule>) {
+        *self.type_block_rules.write() = Arc::new(rules);
+    }
+
+    pub fn type_block_rules(&self) -> Arc<Vec<TypeBlockRule>> {
+        self.type_block_rules.read().clone()
+    }
+
+    /// TTL bounds for forwarded answers: at most one global clamp
+    /// (`suffix: None`) and any number per domain suffix.
+    pub fn set_ttl_clamps(&self, clamps: Vec<TtlClamp>) {
+        *self.ttl_clamps.write() = Arc::new(clamps);
+    }
+
+    pub fn ttl_clamps(&self) -> Arc<Vec<TtlClamp>> {
+        self.ttl_clamps.read().clone()
+    }
+
+    /// The clamp for `qname`: the longest matching suffix, else the global
+    /// one.
+    pub fn ttl_clamp_for(&self, qname: &str) -> Option<TtlClamp> {
+        let clamps = self.ttl_clamps.read().clone();
+        clamps
+            .iter()
+            .filter(|c| c.matches(qname))
+            .max_by_key(|c| c.suffix.as_ref().map_or(0, |s| s.trim_end_matches('.').len() + 1))
+            .cloned()
+    }
+
+    /// Domain block and allow rules; the first one that matches and is
+    /// in schedule wins.
+    pub fn set_policy_rules(&self, rules: Vec<PolicyRule>) {
+        *self.policy_rules.write() = Arc::new(rules);
+    }
+
+    pub fn policy_rules(&self) -> Arc<Vec<PolicyRule>> {
+        self.policy_rules.read().clone()
+    }
+
+    /// Zone the policy rules' schedules are read in.
+    pub fn set_policy_time_zone(&self, zone: PolicyTimeZone) {
+        *self.policy_time_zone.write() = zone;
+    }
+
+    pub fn policy_time_zone(&self) -> PolicyTimeZone {
+        *self.policy_time_zone.read()
+    }
+
+    /// Size of the cache of forwarded answers and when to prefetch entries.
+    pub fn set_cache_config(&self, config: CacheConfig) {
+        self.cache.set_config(config);
+    }
+
+    pub fn cache_config(&self) -> Arc<CacheConfig> {
+        self.cache.config()
+    }
+
+    /// Networks clients must query from; anyone else is refused. Empty
+    /// allows every client.
+    pub fn set_allowed_clients(&self, nets: Vec<ClientNet>) {
+        *self.allowed_clients.write() = Arc::new(nets);
+    }
+
+    pub fn allowed_clients(&self) -> Arc<Vec<ClientNet>> {
+        self.allowed_clients.read().clone()
+    }
+
+    /// Daily query quotas; clients over theirs are refused.
+    pub fn set_quotas(&self, quotas: QuotaConfig) {
+        self.clients.set_quotas(quotas);
+    }
+
+    pub fn quotas(&self) -> Arc<QuotaConfig> {
+        self.clients.quotas()
+    }
+
+    /// Counts a query from `client`; false once it is over its quota.
+    pub fn admit_client(&self, client: IpAddr) -> bool {
+        self.clients.admit(client, self.policy_time_zone().now().date())
+    }
+
+    /// Query counts by client, busiest today first.
+    pub fn client_stats(&self) -> Vec<ClientStats> {
+        self.clients.stats(self.policy_time_zone().now().date())
+    }
+
+    /// Cached forwarded answers with their remaining TTLs.
+    pub fn cache_entries(&self) -> Vec<CacheEntry> {
+        self.cache.entries()
+    }
+
+    /// Purges cached answers for `name`, or all of them with `None`.
+    /// Returns how many were purged.
+    pub fn flush_cache(&self, name: Option<&str>) -> usize {
+        self.cache.flush(name)
+    }
+
+    pub(crate) fn cache(&self) -> &ResponseCache {
+        &self.cache
+    }
+
+    /// Answers for `version.bind` / `hostname.bind` CHAOS queries.
+    pub fn set_chaos_config(&self, config: ChaosConfig) {
+        *self.chaos.write() = Arc::new(config);
+    }
+
+    pub fn chaos_config(&self) -> Arc<ChaosConfig> {
+        self.chaos.read().clone()
+    }
+
+    /// Injects the given faults into every forwarded answer, or stops with
+    /// `None`. For testing applications against an unreliable upstream;
+    /// only built with the `testing` feature.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_upstream_faults(&self, faults: Option<crate::testing::UpstreamFaults>) {
+        *self.upstream_faults.write() = faults.map(|f| Arc::new(crate::testing::FaultInjector::new(f)));
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn upstream_faults(&self) -> Option<Arc<crate::testing::FaultInjector>> {
+        self.upstream_faults.read().clone()
+    }
+
+    /// Captures the state: mappings (with persistent storage, both the
+    /// ephemeral layer and the database), typed records, views, upstreams,
+    /// rules, per-domain settings and flags. Cached answers, client
+    /// counters, metrics and listeners are not part of it. Upstream
+    /// settings the database also keeps are captured as the running state
+    /// sees them.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn snapshot(&self) -> Result<StateSnapshot> {
+        // every field is named so a new one cannot be forgotten here
+        let Self {
+            enabled,
+            acme_update_key,
+            local_only,
+            safe_ips,
+            public_suffix_guard,
+            listeners: _,
+            storage,
+            records,
+            cache,
+            clients,
+            upstream,
+            forward_rules,
+            upstream_mappings,
+            upstream_routes: _,
+            domain_ports,
+            domain_delays,
+            answer_orders,
+            project_template,
+            views,
+            wildcards: _,
+            query_events: _,
+            domain_changes: _,
+            default_ttl: _,
+            script,
+            pipeline,
+            rewrite_rules,
+            type_block_rules,
+            ttl_clamps,
+            policy_rules,
+            policy_time_zone,
+            chaos,
+            reserved_tlds,
+            single_label,
+            negative_soa,
+            failover,
+            allowed_clients,
+            metrics: _,
+            query_stats: _,
+            upstream_monitor: _,
+            query_samples: _,
+            #[cfg(feature = "wasm-plugins")]
+            plugins,
+            #[cfg(feature = "sqlite")]
+            maintenance: _,
+            upstream_faults,
+        } = self;
+        let (domains, stored_domains) = match storage {
+            DomainStorage::InMemory(map) => (map.read().clone(), None),
+            DomainStorage::Layered { overlay, base, write_behind } => {
+                if let Some(queue) = write_behind {
+                    queue.flush().await?;
+                }
+                let stored = base.list().await?.into_iter().collect();
+                (overlay.read().clone(), Some(Arc::new(stored)))
+            }
+        };
+        Ok(StateSnapshot {
+            enabled: *enabled.read(),
+            acme_update_key: acme_update_key.read().clone(),
+            local_only: *local_only.read(),
+            safe_ips: *safe_ips.read(),
+            public_suffix_guard: *public_suffix_guard.read(),
+            domains,
+            stored_domains,
+            records: records.read().clone(),
+            upstream: *upstream.read(),
+            forward_rules: forward_rules.read().clone(),
+            upstream_mappings: upstream_mappings.snapshot(),
+            domain_ports: domain_ports.snapshot(),
+            domain_delays: domain_delays.snapshot(),
+            answer_orders: answer_orders.all(),
+            project_template: project_template.read().clone(),
+            views: views.read().clone(),
+            script: script.read().clone(),
+            pipeline: pipeline.read().clone(),
+            rewrite_rules: rewrite_rules.read().clone(),
+            type_block_rules: type_block_rules.read().clone(),
+            ttl_clamps: ttl_clamps.read().clone(),
+            policy_rules: policy_rules.read().clone(),
+            policy_time_zone: *policy_time_zone.read(),
+            cache_config: cache.config(),
+            quotas: clients.quotas(),
+            chaos: chaos.read().clone(),
+            reserved_tlds: *reserved_tlds.read(),
+            single_label: *single_label.read(),
+            negative_soa: negative_soa.read().clone(),
+            failover: failover.read().clone(),
+            allowed_clients: allowed_clients.read().clone(),
+            #[cfg(feature = "wasm-plugins")]
+            plugins: plugins.read().clone(),
+            upstream_faults: upstream_faults.read().clone(),
+        })
+    }
+
+    /// Puts back the state captured by `snapshot` and flushes the cache, so
+    /// tests can reset between cases without recreating servers. Mappings
+    /// are swapped without publishing domain changes; in the database only
+    /// the ones that differ are written.
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn restore(&self, snapshot: &StateSnapshot) -> Result<()> {
+        match &self.storage {
+            DomainStorage::InMemory(map) => *map.write() = snapshot.domains.clone(),
+            DomainStorage::Layered { overlay, base, write_behind } => {
+                *overlay.write() = snapshot.domains.clone();
+                if let Some(queue) = write_behind {
+                    queue.flush().await?;
+                }
+                let stored = snapshot.stored_domains.clone().unwrap_or_default();
+                let current: HashMap<String, Ipv4Addr> = base.list().await?.into_iter().collect();
+                let mut ops: Vec<(String, Option<Ipv4Addr>)> =
+                    current.keys().filter(|d| !stored.contains_key(*d)).map(|d| (d.clone(), None)).collect();
+                ops.extend(
+                    stored
+                        .iter()
+                        .filter(|(d, ip)| current.get(*d) != Some(ip))
+                        .map(|(d, ip)| (d.clone(), Some(*ip))),
+                );
+                if !ops.is_empty() {
+                    base.apply_batch(&ops).await?;
+                }
+            }
+        }
+        *self.enabled.write() = snapshot.enabled;
+        *self.acme_update_key.write() = snapshot.acme_update_key.clone();
+        *self.local_only.write() = snapshot.local_only;
+        *self.safe_ips.write() = snapshot.safe_ips;
+        *self.public_suffix_guard.write() = snapshot.public_suffix_guard;
+        *self.records.write() = snapshot.records.clone();
+        *self.upstream.write() = snapshot.upstream;
+        *self.forward_rules.write() = snapshot.forward_rules.clone();
+        self.upstream_mappings.restore(snapshot.upstream_mappings.clone());
+        self.rebuild_upstream_routes();
+        self.domain_ports.restore(snapshot.domain_ports.clone());
+        self.domain_delays.restore(snapshot.domain_delays.clone());
+        self.answer_orders.set_all(snapshot.answer_orders.clone());
+        *self.project_template.write() = snapshot.project_template.clone();
+        self.views.write().restore(&snapshot.views);
+        *self.script.write() = snapshot.script.clone();
+        *self.pipeline.write() = snapshot.pipeline.clone();
+        *self.rewrite_rules.write() = snapshot.rewrite_rules.clone();
+        *self.type_block_rules.write() = snapshot.type_block_rules.clone();
+        *self.ttl_clamps.write() = snapshot.ttl_clamps.clone();
+        *self.policy_rules.write() = snapshot.policy_rules.clone();
+        *self.policy_time_zone.write() = snapshot.policy_time_zone;
+        self.cache.set_config(CacheConfig::clone(&snapshot.cache_config));
+        self.clients.set_quotas(QuotaConfig::clone(&snapshot.quotas));
+        *self.chaos.write() = snapshot.chaos.clone();
+        *self.reserved_tlds.write() = snapshot.reserved_tlds;
+        *self.single_label.write() = snapshot.single_label;
+        *self.negative_soa.write() = snapshot.negative_soa.clone();
+        *self.failover.write() = snapshot.failover.clone();
+        *self.allowed_clients.write() = snapshot.allowed_clients.clone();
+        #[cfg(feature = "wasm-plugins")]
+        {
+            *self.plugins.write() = snapshot.plugins.clone();
+        }
+        *self.upstream_faults.write() = snapshot.upstream_faults.clone();
+        self.cache.flush(None);
+        Ok(())
+    }
+
+    /// How unmapped `.localhost` and `.test` names are answered.
+    pub fn set_reserved_tlds(&self, config: ReservedTlds) {
+        *self.reserved_tlds.write() = config;
+    }
+
+    pub fn reserved_tlds(&self) -> ReservedTlds {
+        *self.reserved_tlds.read()
+    }
+
+    /// How queries for names without a dot are handled.
+    pub fn set_single_label(&self, policy: SingleLabel) {
+        *self.single_label.write() = policy;
+    }
+
+    pub fn single_label(&self) -> SingleLabel {
+        *self.single_label.read()
+    }
+
+    /// SOA added to locally generated NXDOMAIN and empty answers.
+    /// How forwarding escalates when UDP to the upstream fails, see
+    /// `failover`.
+    pub fn set_failover(&self, config: FailoverConfig) {
+        *self.failover.write() = Arc::new(config);
+    }
+
+    pub fn failover(&self) -> Arc<FailoverConfig> {
+        self.failover.read().clone()
+    }
+
+    pub fn set_negative_soa(&self, soa: NegativeSoa) {
+        *self.negative_soa.write() = Arc::new(soa);
+    }
+
+    pub fn negative_soa(&self) -> Arc<NegativeSoa> {
+        self.negative_soa.read().clone()
+    }
+
+    /// Installs (or with `None`, removes) the query hook script.
+    pub fn set_script_hooks(&self, hooks: Option<ScriptHooks>) {
+        *self.script.write() = hooks.map(Arc::new);
+    }
+
+    pub fn script_hooks(&self) -> Option<Arc<ScriptHooks>> {
+        self.script.read().clone()
+    }
+
+    /// Appends a plugin; plugins are consulted in order after the script.
+    #[cfg(feature = "wasm-plugins")]
+    pub fn add_wasm_plugin(&self, plugin: crate::wasm_plugin::WasmPlugin) {
+        self.plugins.write().push(Arc::new(plugin));
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    pub fn wasm_plugins(&self) -> Vec<Arc<crate::wasm_plugin::WasmPlugin>> {
+        self.plugins.read().clone()
+    }
+
+    pub fn subscribe_queries(&self) -> broadcast::Receiver<QueryEvent> {
+        self.query_events.subscribe()
+    }
+
+    pub(crate) fn publish_query(&self, event: QueryEvent) {
+        // no subscribers is the common case
+        let _ = self.query_events.send(event);
+    }
+
+    pub(crate) fn set_query_samples(&self, tx: Option<mpsc::Sender<QuerySample>>) {
+        *self.query_samples.write() = tx;
+    }
+
+    /// Adds the sample to `metrics` and queues it for the statsd exporter,
+    /// dropping it there if the queue is full.
+    pub(crate) fn record_query_sample(&self, sample: QuerySample) {
+        self.metrics.record(&sample);
+        if let Some(tx) = &*self.query_samples.read() {
+            let _ = tx.try_send(sample);
+        }
+    }
+
+    /// Latency histograms of the queries handled so far, by answer source
+    /// and outcome, see `metrics`.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub(crate) fn record_query_name(&self, name: &str, qtype: RecordType, source: AnswerSource) {
+        self.query_stats.record(name, qtype, source);
+    }
+
+    /// Queries of the last `window` by type, answer source and name, with
+    /// the `top` most queried names, see `query_stats`.
+    pub fn query_report(&self, window: Duration, top: usize) -> QueryReport {
+        self.query_stats.report(window, top)
+    }
+
+    pub(crate) fn record_upstream_health(&self, outcome: QueryOutcome, source: AnswerSource, servfail: bool) {
+        self.upstream_monitor.record(outcome, source, servfail);
+    }
+
+    /// When upstreams are reported down and the SERVFAIL rate high, see
+    /// `alerts`.
+    pub fn set_alert_config(&self, config: AlertConfig) {
+        self.upstream_monitor.set_config(config);
+    }
+
+    pub fn alert_config(&self) -> AlertConfig {
+        self.upstream_monitor.config()
+    }
+
+    /// Upstreams going down or up and SERVFAIL rate changes.
+    pub fn subscribe_alerts(&self) -> broadcast::Receiver<UpstreamEvent> {
+        self.upstream_monitor.subscribe()
+    }
+
+    /// Upstreams whose last `AlertConfig::down_after` forwards failed.
+    pub fn down_upstreams(&self) -> Vec<SocketAddr> {
+        self.upstream_monitor.down()
+    }
+
+    /// Mutations made through this state, for replication and webhooks.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<DomainChange> {
+        self.domain_changes.subscribe()
+    }
+
+    fn publish_change(&self, change: DomainChange) {
+        let _ = self.domain_changes.send(change);
+    }
+
+    /// With `set_safe_ips` on, `ip` must be loopback or private; see also
+    /// `set_public_suffix_guard`.
     pub async fn add_domain(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        self.check_mapping(domain, ip)?;
+        self.add_domain_allow_public(domain, ip).await
+    }
+
+    /// `add_domain` that skips the safe-IP and public suffix checks, for
+    /// the occasional mapping that deliberately points at a public address
+    /// or overrides a real domain.
+    pub async fn add_domain_allow_public(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        checked_key(domain)?;
+
         match &self.storage {
             DomainStorage::InMemory(domain_map) => {
                 domain_map.write().set(domain.to_string(), ip);
-                Ok(())
             }
-            DomainStorage::Sqlite(store) => {
-                store.set(domain, ip).await
+            DomainStorage::Layered { base, write_behind, .. } => match write_behind {
+                Some(queue) => queue.set(change_key(domain), ip),
+                None => base.set(domain, ip).await?,
+            },
+        }
+
+        self.publish_change(DomainChange::Set { domain: change_key(domain), ip });
+        Ok(())
+    }
+
+    /// Adds a mapping that is never persisted, e.g. for a running container.
+    /// With persistent storage it lives in the memory layer and shadows any
+    /// persistent mapping for the same name until removed or restarted.
+    pub fn add_ephemeral_domain(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        checked_key(domain)?;
+        self.check_mapping(domain, ip)?;
+        self.memory_layer().write().set(domain.to_string(), ip);
+        self.publish_change(DomainChange::Set { domain: change_key(domain), ip });
+        Ok(())
+    }
+
+    /// Maps every domain of `project`'s template to `ip`, returning them.
+    /// All are checked before any is added, so a bad name adds none.
+    pub async fn add_project(&self, project: &str, ip: Ipv4Addr) -> Result<Vec<String>> {
+        let domains = self.project_template().expand(project);
+        for domain in &domains {
+            checked_key(domain)?;
+            self.check_mapping(domain, ip)?;
+        }
+        for domain in &domains {
+            self.add_domain_allow_public(domain, ip).await?;
+        }
+        Ok(domains)
+    }
+
+    /// Removes the mappings `add_project` made with the current template.
+    pub async fn remove_project(&self, project: &str) -> Result<Vec<String>> {
+        let domains = self.project_template().expand(project);
+        for domain in &domains {
+            self.remove_domain(domain).await?;
+        }
+        Ok(domains)
+    }
+
+    /// Domains `add_project` creates for each project. Kept in memory only.
+    pub fn set_project_template(&self, template: ProjectTemplate) {
+        *self.project_template.write() = Arc::new(template);
+    }
+
+    pub fn project_template(&self) -> Arc<ProjectTemplate> {
+        self.project_template.read().clone()
+    }
+
+    /// Drops a mapping added with `add_ephemeral_domain`, leaving any
+    /// persistent mapping for the name in place. Returns whether there was
+    /// one.
+    pub fn remove_ephemeral_domain(&self, domain: &str) -> bool {
+        let removed = self.memory_layer().write().remove(domain).is_some();
+        if removed {
+            self.publish_change(DomainChange::Removed { domain: change_key(domain) });
+        }
+        removed
+    }
+
+    /// `add_ephemeral_domain` for mirrors of another source: only applies
+    /// while the memory layer maps `domain` to `current` (`None`: not at
+    /// all), so a mapping made by someone else is never overwritten.
+    /// Returns whether `ip` was set.
+    pub(crate) fn replace_ephemeral_domain(&self, domain: &str, current: Option<Ipv4Addr>, ip: Ipv4Addr) -> Result<bool> {
+        checked_key(domain)?;
+        self.check_mapping(domain, ip)?;
+        {
+            let mut layer = self.memory_layer().write();
+            if layer.get(domain) != current {
+                return Ok(false);
+            }
+            layer.set(domain.to_string(), ip);
+        }
+        self.publish_change(DomainChange::Set { domain: change_key(domain), ip });
+        Ok(true)
+    }
+
+    /// `remove_ephemeral_domain` for mirrors: only removes `domain` while
+    /// it still maps to `ip`.
+    pub(crate) fn remove_ephemeral_domain_if(&self, domain: &str, ip: Ipv4Addr) -> bool {
+        {
+            let mut layer = self.memory_layer().write();
+            if layer.get(domain) != Some(ip) {
+                return false;
             }
+            layer.remove(domain);
         }
+        self.publish_change(DomainChange::Removed { domain: change_key(domain) });
+        true
     }
-    
+
+    /// Like `add_ephemeral_domain`, kept for existing callers.
     pub fn add_domain_sync(&self, domain: &str, ip: Ipv4Addr) {
+        if let Err(e) = self.add_ephemeral_domain(domain, ip) {
+            log::warn!("add_domain_sync: {}", e);
+        }
+    }
+
+    /// Writes staged write-behind changes to the database now. A no-op otherwise.
+    pub async fn flush(&self) -> Result<()> {
         match &self.storage {
-            DomainStorage::InMemory(domain_map) => {
-                domain_map.write().set(domain.to_string(), ip);
-            }
-            DomainStorage::Sqlite(_) => {
-                log::warn!("add_domain_sync called with SQLite storage - use add_domain instead");
-            }
+            DomainStorage::Layered { write_behind: Some(queue), .. } => queue.flush().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the scheduled SQLite maintenance now, returning the pages
+    /// freed. `None` when no maintenance interval is configured.
+    #[cfg(feature = "sqlite")]
+    pub async fn run_maintenance(&self) -> Result<Option<u64>> {
+        match &self.maintenance {
+            Some(maintenance) => maintenance.run().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn memory_layer(&self) -> &Arc<RwLock<DomainMap>> {
+        match &self.storage {
+            DomainStorage::InMemory(domain_map) => domain_map,
+            DomainStorage::Layered { overlay, .. } => overlay,
         }
     }
 
+    /// Removes the mapping from every layer.
     pub async fn remove_domain(&self, domain: &str) -> Result<()> {
         match &self.storage {
             DomainStorage::InMemory(domain_map) => {
                 domain_map.write().remove(domain);
-                Ok(())
             }
-            DomainStorage::Sqlite(store) => {
-                store.remove(domain).await
+            DomainStorage::Layered { overlay, base, write_behind } => {
+                match write_behind {
+                    Some(queue) => queue.remove(change_key(domain)),
+                    None => base.remove(domain).await?,
+                }
+                overlay.write().remove(domain);
             }
         }
+        if self.domain_ports.contains_key(&change_key(domain)) {
+            self.remove_domain_port(domain).await?;
+        }
+        self.remove_domain_delay(domain);
+        self.answer_orders.remove(&change_key(domain));
+        self.records.write().remove(&change_key(domain), RecordType::A);
+
+        self.publish_change(DomainChange::Removed { domain: change_key(domain) });
+        Ok(())
+    }
+
+    /// Port the HTTP proxy routes `domain` (exact or `*.suffix`, like A
+    /// mappings) to, saved with persistent storage.
+    pub async fn set_domain_port(&self, domain: &str, port: u16) -> Result<()> {
+        let key = checked_key(domain)?;
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.set_domain_port(&key, port).await?;
+        }
+        self.domain_ports.set(key, port);
+        Ok(())
+    }
+
+    pub async fn remove_domain_port(&self, domain: &str) -> Result<()> {
+        let key = change_key(domain);
+        if let DomainStorage::Layered { base: store, .. } = &self.storage {
+            store.remove_domain_port(&key).await?;
+        }
+        self.domain_ports.remove(&key);
+        Ok(())
+    }
+
+    /// Target port for `qname`, matched like A mappings.
+    pub fn domain_port(&self, qname: &str) -> Option<u16> {
+        let ports = self.domain_ports.snapshot();
+        candidate_keys(&change_key(qname), self.wildcards)
+            .iter()
+            .find_map(|k| ports.get(k).copied())
+    }
+
+    /// Sorted by domain.
+    pub fn domain_ports(&self) -> Vec<(String, u16)> {
+        self.domain_ports.sorted()
+    }
+
+    /// Holds local answers for `domain` (exact or `*.suffix`, like A
+    /// mappings) back by `delay`, so clients can be tested against a slow
+    /// resolver. Kept in memory only.
+    pub fn set_domain_delay(&self, domain: &str, delay: Duration) -> Result<()> {
+        self.domain_delays.set(checked_key(domain)?, delay);
+        Ok(())
+    }
+
+    pub fn remove_domain_delay(&self, domain: &str) {
+        self.domain_delays.remove(&change_key(domain));
+    }
+
+    /// Delay for local answers to `qname`, matched like A mappings.
+    pub fn domain_delay(&self, qname: &str) -> Option<Duration> {
+        let delays = self.domain_delays.snapshot();
+        if delays.is_empty() {
+            return None;
+        }
+        candidate_keys(&change_key(qname), self.wildcards)
+            .iter()
+            .find_map(|k| delays.get(k).copied())
+    }
+
+    /// Sorted by domain.
+    pub fn domain_delays(&self) -> Vec<(String, Duration)> {
+        self.domain_delays.sorted()
+    }
+
+    /// Adds `ip` as a further address of `domain`, answered after its
+    /// mapping. Held in memory like typed records; removing the mapping
+    /// drops them too. See `set_answer_order` for their order.
+    pub fn add_domain_address(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        let key = checked_key(domain)?;
+        self.check_mapping(domain, ip)?;
+        self.records.write().add(key, RecordData::A(ip.into()), None);
+        Ok(())
+    }
+
+    /// Order of the addresses in local answers for `domain` (exact or
+    /// `*.suffix`, like A mappings) when it has several. Kept in memory
+    /// only.
+    pub fn set_answer_order(&self, domain: &str, order: AnswerOrder) -> Result<()> {
+        let key = checked_key(domain)?;
+        self.answer_orders.set(key, order);
+        Ok(())
+    }
+
+    pub fn remove_answer_order(&self, domain: &str) {
+        self.answer_orders.remove(&change_key(domain));
+    }
+
+    /// Sorted by domain.
+    pub fn answer_orders(&self) -> Vec<(String, AnswerOrder)> {
+        self.answer_orders.list()
+    }
+
+    /// Reorders the addresses among the local `answers` for `qname`.
+    pub(crate) fn order_answers(&self, qname: &str, answers: &mut [Record]) {
+        let keys = candidate_keys(&change_key(qname), self.wildcards);
+        if let Some((key, order)) = self.answer_orders.find(&keys) {
+            self.answer_orders.apply(&key, order, answers);
+        }
+    }
+
+    /// Adds a typed record. A records become regular (persisted) mappings;
+    /// other types are held in memory and not replicated.
+    pub async fn add_record(&self, name: &str, data: RecordData) -> Result<()> {
+        if let RecordData::A(a) = data {
+            return self.add_domain(name, a.0).await;
+        }
+        let key = checked_key(name)?;
+        let mut records = self.records.write();
+        if let RecordData::CNAME(cname) = &data {
+            records.check_alias(&key, &change_key(&cname.0.to_utf8()))?;
+        }
+        records.add(key, data, None);
+        Ok(())
+    }
+
+    /// Removes every record of `rtype` owned by `name`.
+    pub async fn remove_records(&self, name: &str, rtype: RecordType) -> Result<()> {
+        if rtype == RecordType::A {
+            return self.remove_domain(name).await;
+        }
+        self.records.write().remove(&change_key(name), rtype);
+        Ok(())
+    }
+
+    /// Typed records answering `qtype` for `qname`; A mappings are looked up
+    /// with `resolve`.
+    pub fn lookup_records(&self, qname: &str, qtype: RecordType) -> Vec<RecordData> {
+        self.record_answers(qname, qtype).into_iter().map(|(data, _)| data).collect()
+    }
+
+    /// Like `lookup_records`, with each record's TTL.
+    pub(crate) fn record_answers(&self, qname: &str, qtype: RecordType) -> Vec<(RecordData, u32)> {
+        let answers: Vec<_> = self
+            .records
+            .read()
+            .lookup(&change_key(qname), qtype)
+            .into_iter()
+            .map(|(data, ttl)| (data, ttl.unwrap_or(self.default_ttl)))
+            .collect();
+        if !answers.is_empty() {
+            return answers;
+        }
+        self.port_answer(qname, qtype).map(|data| vec![(data, self.default_ttl)]).unwrap_or_default()
+    }
+
+    /// SRV and HTTPS records carrying the proxy port of a name, for clients
+    /// that can connect to it directly. SRV is answered for the name itself
+    /// and for `_service._proto.name`; both point at the name, so its A
+    /// mapping (or the upstream) supplies the address.
+    fn port_answer(&self, qname: &str, qtype: RecordType) -> Option<RecordData> {
+        let target = match qtype {
+            RecordType::SRV => {
+                let labels: Vec<&str> = qname.splitn(3, '.').collect();
+                match labels.as_slice() {
+                    [service, proto, host] if service.starts_with('_') && proto.starts_with('_') => *host,
+                    _ => qname,
+                }
+            }
+            RecordType::HTTPS => qname,
+            _ => return None,
+        };
+        let port = self.domain_port(target)?;
+        let name = hickory_proto::rr::Name::from_utf8(target).ok()?;
+        Some(match qtype {
+            RecordType::SRV => RecordData::SRV(SRV::new(0, 0, port, name)),
+            // ServiceMode with target "." (the owner name itself)
+            _ => RecordData::HTTPS(HTTPS(SVCB::new(
+                1,
+                hickory_proto::rr::Name::root(),
+                vec![(SvcParamKey::Port, SvcParamValue::Port(port))],
+            ))),
+        })
+    }
+
+    /// Publishes `value` as a TXT record at the ACME challenge name for
+    /// `domain`, with a short TTL. Values for the same name accumulate, as
+    /// when `example.dev` and `*.example.dev` are validated together.
+    pub fn set_acme_challenge(&self, domain: &str, value: &str) -> Result<()> {
+        let name = acme::challenge_name(domain);
+        validate_domain(&name).map_err(|e| Error::InvalidDomain(domain.to_string(), e))?;
+        let txt = RecordData::TXT(TXT::new(vec![value.to_string()]));
+        self.records.write().add(name, txt, Some(acme::CHALLENGE_TTL));
+        Ok(())
+    }
+
+    /// Removes `value` from the challenge name for `domain`, or every value
+    /// with `None`.
+    pub fn clear_acme_challenge(&self, domain: &str, value: Option<&str>) -> Result<()> {
+        let name = acme::challenge_name(domain);
+        let mut records = self.records.write();
+        match value {
+            Some(value) => records.remove_data(&name, &RecordData::TXT(TXT::new(vec![value.to_string()]))),
+            None => records.remove(&name, RecordType::TXT),
+        }
+        Ok(())
+    }
+
+    /// Accepts RFC 2136 updates of ACME challenge records signed with
+    /// `key`, see `acme::AcmeUpdateStage`. `None`, the default, rejects all
+    /// updates.
+    pub fn set_acme_update_key(&self, key: Option<UpdateKey>) {
+        *self.acme_update_key.write() = key;
+    }
+
+    pub fn acme_update_key(&self) -> Option<UpdateKey> {
+        self.acme_update_key.read().clone()
+    }
+
+    /// All typed records added with `add_record`, except A.
+    pub fn list_records(&self) -> Vec<(String, RecordData)> {
+        self.records.read().list()
     }
 
     pub async fn list_domains(&self) -> Result<Vec<(String, Ipv4Addr)>> {
@@ -92,34 +1365,385 @@ impl ResolverState {
             DomainStorage::InMemory(domain_map) => {
                 Ok(domain_map.read().list())
             }
-            DomainStorage::Sqlite(store) => {
-                store.list().await
+            DomainStorage::Layered { overlay, base, write_behind } => {
+                let ephemeral = overlay.read().list();
+                let mut domains = base.list().await?;
+                if let Some(queue) = write_behind {
+                    let staged = queue.snapshot();
+                    domains.retain(|(d, _)| !staged.contains_key(d));
+                    domains.extend(staged.into_iter().filter_map(|(d, ip)| Some((d, ip?))));
+                }
+                domains.retain(|(d, _)| !ephemeral.iter().any(|(e, _)| e == d));
+                domains.extend(ephemeral);
+                Ok(domains)
             }
         }
     }
 
+    /// Overlaps between `domain` and wildcards around or under it, see
+    /// `conflicts::conflicts_with`.
+    pub async fn mapping_conflicts(&self, domain: &str) -> Result<Vec<MappingConflict>> {
+        Ok(conflicts::conflicts_with(&change_key(domain), &self.list_domains().await?))
+    }
+
+    /// A page of `list_domains`. Persistent stores filter and sort in the
+    /// database while no unflushed or ephemeral mappings need merging.
+    pub async fn list_domains_paged(&self, query: &ListQuery) -> Result<DomainPage> {
+        if let DomainStorage::Layered { overlay, base, write_behind } = &self.storage
+            && overlay.read().is_empty()
+            && write_behind.as_ref().is_none_or(|queue| queue.pending() == 0)
+        {
+            return base.list_paged(query).await;
+        }
+        Ok(query.apply(self.list_domains().await?))
+    }
+
+    /// Up to `limit` domains containing `term`, ignoring case, best match
+    /// first; see `store::search_rank`. Persistent stores search their
+    /// index, and unflushed and ephemeral mappings are merged on top.
+    pub async fn search_domains(&self, term: &str, limit: usize) -> Result<Vec<(String, Ipv4Addr)>> {
+        let DomainStorage::Layered { overlay, base, write_behind } = &self.storage else {
+            return Ok(search_list(self.list_domains().await?, term, limit));
+        };
+        let mut overrides: HashMap<String, Option<Ipv4Addr>> =
+            write_behind.as_ref().map(|queue| queue.snapshot()).unwrap_or_default();
+        overrides.extend(overlay.read().list().into_iter().map(|(d, ip)| (d, Some(ip))));
+        if overrides.is_empty() {
+            return base.search(term, limit).await;
+        }
+
+        // each override hides at most one stored match
+        let mut found = base.search(term, limit.saturating_add(overrides.len())).await?;
+        found.retain(|(d, _)| !overrides.contains_key(d));
+        found.extend(overrides.into_iter().filter_map(|(d, ip)| Some((d, ip?))));
+        Ok(search_list(found, term, limit))
+    }
+
+    /// How an A query for `qname` would be answered, check by check. See
+    /// `trace`.
+    pub async fn resolve_trace(&self, qname: &str) -> Result<ResolveTrace> {
+        trace::trace(self, qname).await
+    }
+
     pub async fn resolve(&self, qname: &str) -> Result<Option<Ipv4Addr>> {
-        println!("Resolving {} in domain map", qname);
+        Ok(self.resolve_match(qname).await?.map(|(_, ip)| ip))
+    }
+
+    /// Like `resolve`, also returning the mapping key that matched, e.g.
+    /// `*.app.dev` for a wildcard.
+    pub async fn resolve_match(&self, qname: &str) -> Result<Option<(String, Ipv4Addr)>> {
+        log::trace!("Resolving {} in domain map", qname);
         match &self.storage {
             DomainStorage::InMemory(domain_map) => {
-                Ok(domain_map.read().resolve(qname))
+                Ok(domain_map.read().resolve_match(qname))
             }
-            DomainStorage::Sqlite(store) => {
-                store.resolve(qname).await
+            DomainStorage::Layered { overlay, base, write_behind } => {
+                if let Some(found) = overlay.read().resolve_match(qname) {
+                    return Ok(Some(found));
+                }
+                match write_behind {
+                    Some(queue) => base.resolve_with(qname, |key| queue.staged(key)).await,
+                    None => base.resolve_with(qname, |_| None).await,
+                }
             }
         }
     }
     
+    /// Only sees the memory layer; use `resolve` with persistent storage.
     pub fn resolve_sync(&self, qname: &str) -> Option<Ipv4Addr> {
-        println!("Resolving {} in domain map", qname);
+        log::trace!("Resolving {} in domain map", qname);
+        self.memory_layer().read().resolve(qname)
+    }
+}
+
+pub(crate) fn change_key(domain: &str) -> String {
+    NormalizedName::new(domain).into_string()
+}
+
+/// `change_key` for a name about to be stored, rejecting invalid ones.
+pub(crate) fn checked_key(domain: &str) -> Result<String> {
+    let key = NormalizedName::checked(domain).map_err(|e| Error::InvalidDomain(domain.to_string(), e))?;
+    Ok(key.into_string())
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    MissingUpstream,
+    /// Upstream has port 0 or an unspecified address.
+    InvalidUpstream(SocketAddr),
+    /// TTL is zero or exceeds 2^31 - 1.
+    InvalidTtl(u32),
+    EmptyDatabasePath,
+    NoListeners,
+    ZeroFlushInterval,
+    ZeroMaintenanceInterval,
+    /// Gossip was enabled without a shared secret.
+    MissingGossipSecret,
+    /// A Raft member was started without a shared secret.
+    MissingRaftSecret,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingUpstream => write!(f, "no upstream resolver configured"),
+            BuildError::InvalidUpstream(addr) => write!(f, "invalid upstream resolver address {}", addr),
+            BuildError::InvalidTtl(ttl) => write!(f, "default TTL {} out of range 1..={}", ttl, MAX_TTL),
+            BuildError::EmptyDatabasePath => write!(f, "database path is empty"),
+            BuildError::NoListeners => write!(f, "at least one listen address is required"),
+            BuildError::ZeroFlushInterval => write!(f, "write-behind flush interval must be non-zero"),
+            BuildError::ZeroMaintenanceInterval => write!(f, "database maintenance interval must be non-zero"),
+            BuildError::MissingGossipSecret => write!(f, "gossip requires a shared secret"),
+            BuildError::MissingRaftSecret => write!(f, "Raft clustering requires a shared secret"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Debug, Clone, Default)]
+enum StorageKind {
+    #[default]
+    InMemory,
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+    #[cfg(feature = "redb")]
+    Redb(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResolverStateBuilder {
+    upstream: Option<SocketAddr>,
+    fallback_upstream: Option<SocketAddr>,
+    forward_rules: Vec<ForwardRule>,
+    upstream_mappings: Vec<(String, SocketAddr)>,
+    storage: StorageKind,
+    default_ttl: Option<u32>,
+    wildcards: Wildcards,
+    write_behind: Option<Duration>,
+    #[cfg(feature = "sqlite")]
+    sqlite_connections: Option<u32>,
+    #[cfg(feature = "sqlite")]
+    sqlite_maintenance: Option<Duration>,
+    #[cfg(feature = "sqlcipher")]
+    sqlite_key: Option<crate::DatabaseKey>,
+}
+
+impl ResolverStateBuilder {
+    /// With persistent storage this replaces the saved upstream.
+    pub fn upstream(mut self, addr: SocketAddr) -> Self {
+        self.upstream = Some(addr);
+        self
+    }
+
+    /// Used when neither `upstream` nor the database provides one.
+    pub(crate) fn fallback_upstream(mut self, addr: SocketAddr) -> Self {
+        self.fallback_upstream = Some(addr);
+        self
+    }
+
+    /// With persistent storage the rule is saved alongside the ones already
+    /// stored.
+    pub fn forward_rule(mut self, rule: ForwardRule) -> Self {
+        self.forward_rules.push(rule);
+        self
+    }
+
+    /// Forwards `domain` (exact or `*.suffix`) to `upstream`; saved like
+    /// `forward_rule`.
+    pub fn upstream_mapping(mut self, domain: impl Into<String>, upstream: SocketAddr) -> Self {
+        self.upstream_mappings.push((domain.into(), upstream));
+        self
+    }
+
+    pub fn in_memory(mut self) -> Self {
+        self.storage = StorageKind::InMemory;
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(mut self, database_path: impl Into<String>) -> Self {
+        self.storage = StorageKind::Sqlite(database_path.into());
+        self
+    }
+
+    /// Most SQLite connections, each served by its own thread, see
+    /// `SqliteDomainStore::with_connections`.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_connections(mut self, connections: u32) -> Self {
+        self.sqlite_connections = Some(connections);
+        self
+    }
+
+    /// Runs `SqliteDomainStore::maintain` every `interval` in the
+    /// background. Ignored for other storage.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_maintenance(mut self, interval: Duration) -> Self {
+        self.sqlite_maintenance = Some(interval);
+        self
+    }
+
+    /// Opens the SQLite database with SQLCipher, see
+    /// `SqliteDomainStore::encrypted`.
+    #[cfg(feature = "sqlcipher")]
+    pub fn sqlite_key(mut self, key: crate::DatabaseKey) -> Self {
+        self.sqlite_key = Some(key);
+        self
+    }
+
+    /// Stores mappings in a redb file instead of SQLite; `:memory:` keeps
+    /// the database in RAM.
+    #[cfg(feature = "redb")]
+    pub fn redb(mut self, database_path: impl Into<String>) -> Self {
+        self.storage = StorageKind::Redb(database_path.into());
+        self
+    }
+
+    pub fn default_ttl(mut self, ttl: u32) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Lets `*.example.dev` answer `example.dev` as well.
+    pub fn wildcard_apex(mut self, enabled: bool) -> Self {
+        self.wildcards.apex = enabled;
+        self
+    }
+
+    /// Stages persistent-store writes in memory and commits them in one transaction
+    /// every `interval`. Reads see staged writes immediately; anything not
+    /// yet flushed is lost on a crash, so call `ResolverState::flush` before
+    /// exiting.
+    pub fn write_behind(mut self, interval: Duration) -> Self {
+        self.write_behind = Some(interval);
+        self
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), BuildError> {
+        // a database may supply the upstream, checked in `build`
+        match self.upstream.or(self.fallback_upstream) {
+            Some(upstream) => check_upstream(upstream)?,
+            None if matches!(self.storage, StorageKind::InMemory) => return Err(BuildError::MissingUpstream),
+            None => {}
+        }
+        for rule in &self.forward_rules {
+            check_upstream(rule.upstream)?;
+        }
+        for (_, upstream) in &self.upstream_mappings {
+            check_upstream(*upstream)?;
+        }
+
+        if let Some(ttl) = self.default_ttl
+            && (ttl == 0 || ttl > MAX_TTL)
+        {
+            return Err(BuildError::InvalidTtl(ttl));
+        }
+
+        if self.write_behind == Some(Duration::ZERO) {
+            return Err(BuildError::ZeroFlushInterval);
+        }
+        #[cfg(feature = "sqlite")]
+        if self.sqlite_maintenance == Some(Duration::ZERO) {
+            return Err(BuildError::ZeroMaintenanceInterval);
+        }
+
         match &self.storage {
-            DomainStorage::InMemory(domain_map) => {
-                domain_map.read().resolve(qname)
+            StorageKind::InMemory => {}
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite(path) if path.is_empty() => return Err(BuildError::EmptyDatabasePath),
+            #[cfg(feature = "redb")]
+            StorageKind::Redb(path) if path.is_empty() => return Err(BuildError::EmptyDatabasePath),
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(not(any(feature = "sqlite", feature = "redb")), allow(unused_variables))]
+    pub async fn build(self) -> Result<ResolverState> {
+        self.validate()?;
+
+        #[cfg(feature = "sqlite")]
+        let mut maintenance = None;
+        let base: Option<PersistentStore> = match self.storage {
+            StorageKind::InMemory => None,
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite(path) => {
+                let connections =
+                    self.sqlite_connections.unwrap_or(crate::sqlite_domain_store::DEFAULT_CONNECTIONS);
+                #[cfg(feature = "sqlcipher")]
+                let store = match &self.sqlite_key {
+                    Some(key) => crate::SqliteDomainStore::encrypted(&path, connections, key).await?,
+                    None => crate::SqliteDomainStore::with_connections(&path, connections).await?,
+                };
+                #[cfg(not(feature = "sqlcipher"))]
+                let store = crate::SqliteDomainStore::with_connections(&path, connections).await?;
+                maintenance = self
+                    .sqlite_maintenance
+                    .map(|interval| crate::maintenance::Maintenance::start(store.clone(), interval));
+                Some(PersistentStore::Sqlite(store.with_wildcards(self.wildcards)))
+            }
+            #[cfg(feature = "redb")]
+            StorageKind::Redb(path) => Some(PersistentStore::Redb(
+                crate::RedbDomainStore::new(&path).await?.with_wildcards(self.wildcards),
+            )),
+        };
+
+        let (storage, stored_upstream, stored_rules, stored_mappings, stored_ports) = match base {
+            None => {
+                let mut map = DomainMap::new();
+                map.set_wildcards(self.wildcards);
+                (DomainStorage::InMemory(Arc::new(RwLock::new(map))), None, Vec::new(), Vec::new(), Vec::new())
             }
-            DomainStorage::Sqlite(_) => {
-                log::warn!("resolve_sync called with SQLite storage - use resolve instead");
-                None
+            Some(store) => {
+                let mut overlay = DomainMap::new();
+                overlay.set_wildcards(self.wildcards);
+                if let Some(addr) = self.upstream {
+                    store.set_upstream(addr).await?;
+                }
+                let upstream = store.upstream().await?;
+                let rules = store.forward_rules().await?;
+                let mappings = store.upstream_mappings().await?;
+                let ports = store.domain_ports().await?;
+                let write_behind = self.write_behind.map(|interval| WriteBehind::start(store.clone(), interval));
+                let storage = DomainStorage::Layered {
+                    overlay: Arc::new(RwLock::new(overlay)),
+                    base: store,
+                    write_behind,
+                };
+                (storage, upstream, rules, mappings, ports)
             }
+        };
+
+        let upstream = self
+            .upstream
+            .or(stored_upstream)
+            .or(self.fallback_upstream)
+            .ok_or(BuildError::MissingUpstream)?;
+        let mut state = ResolverState::from_parts(storage, upstream, self.default_ttl.unwrap_or(DEFAULT_TTL));
+        state.wildcards = self.wildcards;
+        #[cfg(feature = "sqlite")]
+        {
+            state.maintenance = maintenance;
+        }
+        *state.forward_rules.write() = Arc::new(stored_rules);
+        state.upstream_mappings.replace(stored_mappings.into_iter().collect());
+        state.rebuild_upstream_routes();
+        state.domain_ports.replace(stored_ports.into_iter().collect());
+        for rule in self.forward_rules {
+            state.add_forward_rule(rule).await?;
+        }
+        for (domain, upstream) in self.upstream_mappings {
+            state.add_upstream_mapping(&domain, upstream).await?;
         }
+        Ok(state)
+    }
+}
+
+fn check_upstream(addr: SocketAddr) -> std::result::Result<(), BuildError> {
+    if addr.port() == 0 || addr.ip().is_unspecified() {
+        return Err(BuildError::InvalidUpstream(addr));
     }
+    Ok(())
 }
@@ -0,0 +1,31 @@
+//! A small seedable PRNG for shuffling answers and rolling fault-injection
+//! dice, where reproducibility matters more than quality.
+
+use std::hash::{BuildHasher, RandomState};
+
+use parking_lot::Mutex;
+
+/// xorshift64*, shared between threads.
+pub(crate) struct XorShift {
+    state: Mutex<u64>,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        Self { state: Mutex::new(seed.max(1)) }
+    }
+
+    /// Seeded differently per process.
+    pub fn random() -> Self {
+        Self::new(RandomState::new().hash_one(0u8))
+    }
+
+    pub fn next(&self) -> u64 {
+        let mut x = self.state.lock();
+        *x ^= *x >> 12;
+        *x ^= *x << 25;
+        *x ^= *x >> 27;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use rhai::{AST, Dynamic, Engine, Map, Scope};
+
+use crate::{Error, Result};
+
+/// Upper bound on operations per hook call, so a runaway script cannot stall the resolver.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What `on_query` asked the handler to do. All fields default to "no change".
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryDecision {
+    /// Resolve (or forward) this name instead of the one asked.
+    pub name: Option<String>,
+    /// Forward to this resolver instead of the configured upstream.
+    pub upstream: Option<SocketAddr>,
+    /// Answer immediately with this address.
+    pub answer: Option<Ipv4Addr>,
+    /// Answer REFUSED.
+    pub refuse: bool,
+}
+
+/// User-supplied Rhai script with optional hook functions:
+///
+/// ```rhai
+/// // q = #{ name, qtype, client }; return () or a map with any of
+/// // name, upstream ("ip:port"), answer ("ip") and refuse (bool)
+/// fn on_query(q) { if q.name.ends_with(".corp") { #{ upstream: "10.0.0.2:53" } } }
+///
+/// // a = #{ name, qtype, client, ip }; return () or a replacement "ip"
+/// fn on_answer(a) { () }
+/// ```
+///
+/// `on_answer` sees locally answered queries; forwarded answers are relayed as received.
+pub struct ScriptHooks {
+    engine: Engine,
+    ast: AST,
+    has_on_query: bool,
+    has_on_answer: bool,
+}
+
+impl ScriptHooks {
+    pub fn from_source(source: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let ast = engine
+            .compile(source)
+            .map_err(|e| Error::Script(e.to_string()))?;
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name && f.params.len() == 1);
+
+        Ok(Self {
+            has_on_query: defines("on_query"),
+            has_on_answer: defines("on_answer"),
+            engine,
+            ast,
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_source(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn on_query(&self, name: &str, qtype: &str, client: SocketAddr) -> QueryDecision {
+        if !self.has_on_query {
+            return QueryDecision::default();
+        }
+
+        let mut arg = Map::new();
+        arg.insert("name".into(), name.into());
+        arg.insert("qtype".into(), qtype.into());
+        arg.insert("client".into(), client.to_string().into());
+
+        let Some(ret) = self.call("on_query", arg) else {
+            return QueryDecision::default();
+        };
+        let Some(map) = ret.try_cast::<Map>() else {
+            return QueryDecision::default();
+        };
+
+        QueryDecision {
+            name: map_str(&map, "name"),
+            upstream: map_str(&map, "upstream").and_then(|s| parse_logged(&s, "upstream")),
+            answer: map_str(&map, "answer").and_then(|s| parse_logged(&s, "answer")),
+            refuse: map.get("refuse").and_then(|v| v.as_bool().ok()).unwrap_or(false),
+        }
+    }
+
+    /// Returns the address to answer with, possibly replaced by the script.
+    pub fn on_answer(&self, name: &str, qtype: &str, client: SocketAddr, ip: Ipv4Addr) -> Ipv4Addr {
+        if !self.has_on_answer {
+            return ip;
+        }
+
+        let mut arg = Map::new();
+        arg.insert("name".into(), name.into());
+        arg.insert("qtype".into(), qtype.into());
+        arg.insert("client".into(), client.to_string().into());
+        arg.insert("ip".into(), ip.to_string().into());
+
+        self.call("on_answer", arg)
+            .and_then(|ret| ret.into_string().ok())
+            .and_then(|s| parse_logged(&s, "on_answer result"))
+            .unwrap_or(ip)
+    }
+
+    fn call(&self, hook: &str, arg: Map) -> Option<Dynamic> {
+        match self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, hook, (arg,)) {
+            Ok(ret) if ret.is_unit() => None,
+            Ok(ret) => Some(ret),
+            Err(e) => {
+                log::warn!("Script hook {} failed: {}", hook, e);
+                None
+            }
+        }
+    }
+}
+
+fn map_str(map: &Map, key: &str) -> Option<String> {
+    map.get(key).and_then(|v| v.clone().into_string().ok())
+}
+
+fn parse_logged<T: std::str::FromStr>(s: &str, what: &str) -> Option<T> {
+    let parsed = s.parse().ok();
+    if parsed.is_none() {
+        log::warn!("Script returned invalid {} {:?}", what, s);
+    }
+    parsed
+}
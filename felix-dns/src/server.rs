@@ -0,0 +1,1033 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use log::LevelFilter;
+use tokio::runtime::Handle;
+
+use crate::{
+    AlertConfig, AnswerOrder, CacheConfig, ClientNet, Error, UpdateKey, FailoverConfig, QuotaConfig, ForwardRule, Management, Result, Role, RewriteRule, TtlClamp, TypeBlockRule,
+    pipeline::{ChaosConfig, LocalOnly, NegativeSoa, ReservedTlds, SingleLabel},
+    policy::{PolicyRule, PolicyTimeZone},
+    public_suffix::PublicSuffixGuard,
+    projects::ProjectTemplate,
+    gossip::{GossipConfig, GossipHandle, start_gossip},
+    raft::{RaftConfig, RaftHandle, start_raft},
+    handover,
+    hosts_sync::{HostsSyncConfig, HostsSyncHandle, start_hosts_sync},
+    llmnr::{LlmnrConfig, LlmnrHandle, start_llmnr},
+    log_filter,
+    management_server::{ManagementHandle, run_management_server},
+    name::NormalizedName,
+    proxy::{ProxyHandle, run_http_proxy},
+    replication::{SecondaryHandle, follow_primary},
+    resolver_state::{BuildError, ResolverState, ResolverStateBuilder},
+    scripting::ScriptHooks,
+    statsd::{StatsdConfig, StatsdHandle, start_statsd},
+    syslog::{SyslogLogger, SyslogTarget},
+    server_handler::{ListenOptions, ServerHandle, run_udp_server_with},
+    system_dns::system_upstreams,
+    systemd_resolved::{RESOLVED_STUB, ResolvedHandle, ResolvedMode, configure_resolved},
+};
+
+/// Configuration for an embedded felix instance. Obtain one with
+/// `FelixServer::builder()`.
+pub struct FelixServerBuilder {
+    listeners: Vec<(SocketAddr, ListenOptions)>,
+    management: Option<SocketAddr>,
+    proxy: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    https_proxy: Option<(SocketAddr, std::sync::Arc<crate::tls::LocalCa>)>,
+    tokens: Vec<(String, Role)>,
+    primary: Option<(SocketAddr, String)>,
+    gossip: Option<GossipConfig>,
+    raft: Option<RaftConfig>,
+    llmnr: Option<LlmnrConfig>,
+    statsd: Option<StatsdConfig>,
+    #[cfg(feature = "webhooks")]
+    webhooks: Option<crate::webhooks::WebhookConfig>,
+    hosts_sync: Option<HostsSyncConfig>,
+    systemd_resolved: Option<ResolvedMode>,
+    script: Option<ScriptHooks>,
+    #[cfg(feature = "wasm-plugins")]
+    plugins: Vec<crate::wasm_plugin::WasmPlugin>,
+    rewrite_rules: Vec<RewriteRule>,
+    type_block_rules: Vec<TypeBlockRule>,
+    ttl_clamps: Vec<TtlClamp>,
+    policy_rules: Vec<PolicyRule>,
+    policy_time_zone: PolicyTimeZone,
+    chaos: ChaosConfig,
+    alerts: AlertConfig,
+    reserved_tlds: ReservedTlds,
+    single_label: SingleLabel,
+    negative_soa: NegativeSoa,
+    cache: CacheConfig,
+    failover: FailoverConfig,
+    quotas: QuotaConfig,
+    allowed_clients: Vec<ClientNet>,
+    acme_update_key: Option<UpdateKey>,
+    local_only: Option<LocalOnly>,
+    safe_ips: bool,
+    public_suffix_guard: Option<PublicSuffixGuard>,
+    system_upstream: bool,
+    runtime: Option<Handle>,
+    state: ResolverStateBuilder,
+    domains: Vec<(String, Ipv4Addr)>,
+    ephemeral_domains: Vec<(String, Ipv4Addr)>,
+    domain_ports: Vec<(String, u16)>,
+    domain_delays: Vec<(String, Duration)>,
+    domain_addresses: Vec<(String, Ipv4Addr)>,
+    projects: Vec<(String, Ipv4Addr)>,
+    project_template: Option<ProjectTemplate>,
+    answer_orders: Vec<(String, AnswerOrder)>,
+    log_level: Option<LevelFilter>,
+    syslog: Option<SyslogTarget>,
+    #[cfg(feature = "otel")]
+    otlp_endpoint: Option<String>,
+}
+
+impl FelixServerBuilder {
+    /// Adds a UDP DNS listener. Port 0 picks a free port, see `FelixServer::local_addrs`.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listeners.push((addr, ListenOptions::default()));
+        self
+    }
+
+    /// Adds a UDP DNS listener serving only packets that arrive on
+    /// `interface`, see `ListenOptions::interface`. Use an unspecified
+    /// address (0.0.0.0:53) to take whatever address the interface has.
+    pub fn listen_on_interface(self, addr: SocketAddr, interface: impl Into<String>) -> Self {
+        self.listen_with(addr, ListenOptions { interface: Some(interface.into()), ..Default::default() })
+    }
+
+    /// Adds a UDP DNS listener with socket options, e.g. SO_REUSEPORT workers.
+    pub fn listen_with(mut self, addr: SocketAddr, options: ListenOptions) -> Self {
+        self.listeners.push((addr, options));
+        self
+    }
+
+    /// Serves the management API on `addr`.
+    pub fn management(mut self, addr: SocketAddr) -> Self {
+        self.management = Some(addr);
+        self
+    }
+
+    /// Runs the HTTP reverse proxy for mappings with a port on `addr`,
+    /// usually port 80.
+    pub fn http_proxy(mut self, addr: SocketAddr) -> Self {
+        self.proxy = Some(addr);
+        self
+    }
+
+    /// Runs the proxy over HTTPS on `addr`, usually port 443, with
+    /// certificates for mapped names issued by `ca`.
+    #[cfg(feature = "tls")]
+    pub fn https_proxy(mut self, addr: SocketAddr, ca: crate::tls::LocalCa) -> Self {
+        self.https_proxy = Some((addr, std::sync::Arc::new(ca)));
+        self
+    }
+
+    pub fn token(mut self, token: impl Into<String>, role: Role) -> Self {
+        self.tokens.push((token.into(), role));
+        self
+    }
+
+    /// Runs as a secondary, mirroring the mappings of the primary whose
+    /// management API listens on `addr`.
+    pub fn replicate_from(mut self, addr: SocketAddr, token: impl Into<String>) -> Self {
+        self.primary = Some((addr, token.into()));
+        self
+    }
+
+    /// Shares mappings with other instances on the LAN.
+    pub fn gossip(mut self, config: GossipConfig) -> Self {
+        self.gossip = Some(config);
+        self
+    }
+
+    /// Joins a Raft cluster that management API domain changes go through.
+    pub fn raft(mut self, config: RaftConfig) -> Self {
+        self.raft = Some(config);
+        self
+    }
+
+    /// Answers LLMNR queries for mapped names.
+    pub fn llmnr(mut self, config: LlmnrConfig) -> Self {
+        self.llmnr = Some(config);
+        self
+    }
+
+    /// Sends query counts and latencies to a statsd agent.
+    pub fn statsd(mut self, config: StatsdConfig) -> Self {
+        self.statsd = Some(config);
+        self
+    }
+
+    /// POSTs upstream alerts and mapping changes to webhook URLs.
+    #[cfg(feature = "webhooks")]
+    pub fn webhooks(mut self, config: crate::webhooks::WebhookConfig) -> Self {
+        self.webhooks = Some(config);
+        self
+    }
+
+    /// Mirrors a hosts file into the resolver while it changes.
+    pub fn hosts_sync(mut self, config: HostsSyncConfig) -> Self {
+        self.hosts_sync = Some(config);
+        self
+    }
+
+    /// Has systemd-resolved send the system's queries to felix while it
+    /// runs, see `systemd_resolved`. `TakeOver` adds a listener on
+    /// 127.0.0.53:53.
+    pub fn systemd_resolved(mut self, mode: ResolvedMode) -> Self {
+        if mode == ResolvedMode::TakeOver {
+            self.listeners.push((RESOLVED_STUB, ListenOptions::default()));
+        }
+        self.systemd_resolved = Some(mode);
+        self
+    }
+
+    /// Adds a rule applied to forwarded answers.
+    pub fn rewrite_rule(mut self, rule: RewriteRule) -> Self {
+        self.rewrite_rules.push(rule);
+        self
+    }
+
+    /// Blocks a query type globally or for a suffix.
+    pub fn type_block_rule(mut self, rule: TypeBlockRule) -> Self {
+        self.type_block_rules.push(rule);
+        self
+    }
+
+    /// Bounds TTLs of forwarded answers globally or for a suffix.
+    pub fn ttl_clamp(mut self, clamp: TtlClamp) -> Self {
+        self.ttl_clamps.push(clamp);
+        self
+    }
+
+    /// Blocks or allows a domain, optionally on a schedule; rules are
+    /// checked in the order added.
+    pub fn policy_rule(mut self, rule: PolicyRule) -> Self {
+        self.policy_rules.push(rule);
+        self
+    }
+
+    /// Zone policy schedules are read in, the system's by default.
+    pub fn policy_time_zone(mut self, zone: PolicyTimeZone) -> Self {
+        self.policy_time_zone = zone;
+        self
+    }
+
+    /// Answers for `version.bind` / `hostname.bind`.
+    pub fn chaos(mut self, config: ChaosConfig) -> Self {
+        self.chaos = config;
+        self
+    }
+
+    /// When an upstream counts as down and the SERVFAIL rate as high.
+    pub fn alerts(mut self, config: AlertConfig) -> Self {
+        self.alerts = config;
+        self
+    }
+
+    /// How unmapped `.localhost` and `.test` names are answered; by
+    /// default neither is forwarded.
+    pub fn reserved_tlds(mut self, config: ReservedTlds) -> Self {
+        self.reserved_tlds = config;
+        self
+    }
+
+    /// How queries for names without a dot are handled; by default they
+    /// are expanded with the listener's search list, or forwarded.
+    pub fn single_label(mut self, policy: SingleLabel) -> Self {
+        self.single_label = policy;
+        self
+    }
+
+    /// SOA, and with it the negative TTL, of locally generated NXDOMAIN
+    /// and empty answers.
+    pub fn negative_soa(mut self, soa: NegativeSoa) -> Self {
+        self.negative_soa = soa;
+        self
+    }
+
+    /// Daily per-client query quotas.
+    pub fn quotas(mut self, quotas: QuotaConfig) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    /// Networks allowed to query; everyone else is refused. Empty, the
+    /// default, allows all clients.
+    pub fn allowed_clients(mut self, nets: Vec<ClientNet>) -> Self {
+        self.allowed_clients = nets;
+        self
+    }
+
+    /// Size of the cache of forwarded answers and when to prefetch entries.
+    pub fn cache(mut self, config: CacheConfig) -> Self {
+        self.cache = config;
+        self
+    }
+
+    /// Retries over TCP and DoH when the upstream's UDP answer is truncated
+    /// or does not arrive.
+    pub fn failover(mut self, config: FailoverConfig) -> Self {
+        self.failover = config;
+        self
+    }
+
+    /// Accepts RFC 2136 updates of `_acme-challenge` TXT records signed
+    /// with `key`.
+    pub fn acme_update_key(mut self, key: UpdateKey) -> Self {
+        self.acme_update_key = Some(key);
+        self
+    }
+
+    /// Never forwards; names without a local answer get `mode`'s response.
+    pub fn local_only(mut self, mode: LocalOnly) -> Self {
+        self.local_only = Some(mode);
+        self
+    }
+
+    /// Only accepts mappings to loopback and private addresses, see
+    /// `ResolverState::set_safe_ips`.
+    pub fn safe_ips(mut self, enabled: bool) -> Self {
+        self.safe_ips = enabled;
+        self
+    }
+
+    /// Warns about or refuses mappings under real public suffixes, see
+    /// `ResolverState::set_public_suffix_guard`.
+    pub fn public_suffix_guard(mut self, guard: PublicSuffixGuard) -> Self {
+        self.public_suffix_guard = Some(guard);
+        self
+    }
+
+    pub fn script_hooks(mut self, hooks: ScriptHooks) -> Self {
+        self.script = Some(hooks);
+        self
+    }
+
+    #[cfg(feature = "wasm-plugins")]
+    pub fn wasm_plugin(mut self, plugin: crate::wasm_plugin::WasmPlugin) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    pub fn in_memory(mut self) -> Self {
+        self.state = self.state.in_memory();
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(mut self, database_path: impl Into<String>) -> Self {
+        self.state = self.state.sqlite(database_path);
+        self
+    }
+
+    #[cfg(feature = "redb")]
+    pub fn redb(mut self, database_path: impl Into<String>) -> Self {
+        self.state = self.state.redb(database_path);
+        self
+    }
+
+    pub fn upstream(mut self, addr: SocketAddr) -> Self {
+        self.state = self.state.upstream(addr);
+        self
+    }
+
+    /// Runs felix's tasks (listeners, APIs, storage) on `runtime` instead of
+    /// the runtime `build` and `start` are called from, e.g. to keep DNS
+    /// off an application runtime with its own tuning.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Most SQLite connections, each served by its own thread.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_connections(mut self, connections: u32) -> Self {
+        self.state = self.state.sqlite_connections(connections);
+        self
+    }
+
+    /// Encrypts the SQLite database with SQLCipher.
+    #[cfg(feature = "sqlcipher")]
+    pub fn sqlite_key(mut self, key: crate::DatabaseKey) -> Self {
+        self.state = self.state.sqlite_key(key);
+        self
+    }
+
+    /// Vacuums and analyzes the SQLite database every `interval`, see
+    /// `SqliteDomainStore::maintain`.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_maintenance(mut self, interval: Duration) -> Self {
+        self.state = self.state.sqlite_maintenance(interval);
+        self
+    }
+
+    /// Forwards to the first resolver the operating system is configured
+    /// with when neither `upstream` nor the database provides one, instead
+    /// of 8.8.8.8. Resolvers that are this instance's own listeners are
+    /// skipped.
+    pub fn system_upstream(mut self) -> Self {
+        self.system_upstream = true;
+        self
+    }
+
+    /// Forwards names under `rule.suffix` to `rule.upstream`.
+    pub fn forward_rule(mut self, rule: ForwardRule) -> Self {
+        self.state = self.state.forward_rule(rule);
+        self
+    }
+
+    /// Forwards `domain` (exact or `*.suffix`) to `upstream`.
+    pub fn upstream_mapping(mut self, domain: impl Into<String>, upstream: SocketAddr) -> Self {
+        self.state = self.state.upstream_mapping(domain, upstream);
+        self
+    }
+
+    /// Batches SQLite writes, see `ResolverStateBuilder::write_behind`.
+    /// Staged writes are flushed on `FelixServer::shutdown`.
+    pub fn write_behind(mut self, interval: Duration) -> Self {
+        self.state = self.state.write_behind(interval);
+        self
+    }
+
+    pub fn default_ttl(mut self, ttl: u32) -> Self {
+        self.state = self.state.default_ttl(ttl);
+        self
+    }
+
+    pub fn wildcard_apex(mut self, enabled: bool) -> Self {
+        self.state = self.state.wildcard_apex(enabled);
+        self
+    }
+
+    /// Seeds a mapping when the server is built.
+    pub fn domain(mut self, domain: impl Into<String>, ip: Ipv4Addr) -> Self {
+        self.domains.push((domain.into(), ip));
+        self
+    }
+
+    /// Seeds a default mapping that is never persisted, e.g. one built into
+    /// the binary. Skipped when the store already maps `domain`, so a
+    /// stored mapping is not shadowed.
+    pub fn ephemeral_domain(mut self, domain: impl Into<String>, ip: Ipv4Addr) -> Self {
+        self.ephemeral_domains.push((domain.into(), ip));
+        self
+    }
+
+    /// Seeds the port the HTTP proxy routes `domain` to.
+    pub fn domain_port(mut self, domain: impl Into<String>, port: u16) -> Self {
+        self.domain_ports.push((domain.into(), port));
+        self
+    }
+
+    /// Holds local answers for `domain` back by `delay`, see
+    /// `ResolverState::set_domain_delay`.
+    pub fn domain_delay(mut self, domain: impl Into<String>, delay: Duration) -> Self {
+        self.domain_delays.push((domain.into(), delay));
+        self
+    }
+
+    /// Adds a further address to `domain`, see
+    /// `ResolverState::add_domain_address`.
+    pub fn domain_address(mut self, domain: impl Into<String>, ip: Ipv4Addr) -> Self {
+        self.domain_addresses.push((domain.into(), ip));
+        self
+    }
+
+    /// Maps the domains of `project`, see `ResolverState::add_project`.
+    pub fn project(mut self, project: impl Into<String>, ip: Ipv4Addr) -> Self {
+        self.projects.push((project.into(), ip));
+        self
+    }
+
+    /// Domains created for each project, `{project}.test` and
+    /// `*.{project}.test` by default.
+    pub fn project_template(mut self, template: ProjectTemplate) -> Self {
+        self.project_template = Some(template);
+        self
+    }
+
+    /// Orders the addresses answered for `domain`, see
+    /// `ResolverState::set_answer_order`.
+    pub fn answer_order(mut self, domain: impl Into<String>, order: AnswerOrder) -> Self {
+        self.answer_orders.push((domain.into(), order));
+        self
+    }
+
+    /// Seeds a mapping with a port, e.g. `app.dev` to 127.0.0.1:3000. The
+    /// port is served through the proxy and in SRV/HTTPS answers.
+    pub fn service(self, domain: impl Into<String>, addr: SocketAddrV4) -> Self {
+        let domain = domain.into();
+        self.domain(domain.clone(), *addr.ip()).domain_port(domain, addr.port())
+    }
+
+    /// Installs an `env_logger` at this level, unless the host app already set a logger.
+    pub fn log_level(mut self, level: LevelFilter) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Sends logs to syslog instead of stderr, at `log_level` (default info).
+    pub fn syslog(mut self, target: SyslogTarget) -> Self {
+        self.syslog = Some(target);
+        self
+    }
+
+    /// Exports query spans and metrics to an OTLP/HTTP collector.
+    #[cfg(feature = "otel")]
+    pub fn otlp_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.otlp_endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Whether `addr` is one of the configured listeners, e.g. felix itself
+    /// installed as the system resolver.
+    fn is_listener(&self, addr: SocketAddr) -> bool {
+        self.listeners.iter().map(|(l, _)| l).any(|l| {
+            *l == addr || (l.port() == addr.port() && l.ip().is_unspecified() && addr.ip().is_loopback())
+        })
+    }
+
+    pub async fn build(mut self) -> Result<FelixServer> {
+        if self.listeners.is_empty() {
+            return Err(BuildError::NoListeners.into());
+        }
+
+        // behind a filter, so the management API can change it later
+        if let Some(target) = &self.syslog {
+            let logger = SyslogLogger::new(target, LevelFilter::Trace)?;
+            let level = self.log_level.unwrap_or(LevelFilter::Info);
+            if log_filter::install(Box::new(logger), &level.to_string().to_lowercase()).is_err() {
+                log::warn!("A logger is already installed, not switching to syslog");
+            }
+        } else if let Some(level) = self.log_level {
+            let _ = log_filter::install(Box::new(log_filter::stderr_logger()), &level.to_string().to_lowercase());
+        }
+
+        #[cfg(feature = "otel")]
+        let telemetry = {
+            let _runtime = self.runtime.as_ref().map(Handle::enter);
+            self.otlp_endpoint
+                .as_deref()
+                .map(crate::telemetry::Telemetry::init)
+                .transpose()?
+        };
+
+        if self.system_upstream {
+            match system_upstreams() {
+                Ok(found) => match found.into_iter().find(|u| !self.is_listener(*u)) {
+                    Some(upstream) => {
+                        log::info!("Using system resolver {}", upstream);
+                        self.state = self.state.fallback_upstream(upstream);
+                    }
+                    None => log::warn!("No usable system resolver found"),
+                },
+                Err(e) => log::warn!("Reading the system resolvers failed: {}", e),
+            }
+        }
+
+        let state = on_runtime(self.runtime.as_ref(), self.state.build()).await?;
+        state.set_script_hooks(self.script);
+        state.set_rewrite_rules(self.rewrite_rules);
+        state.set_type_block_rules(self.type_block_rules);
+        state.set_ttl_clamps(self.ttl_clamps);
+        state.set_policy_rules(self.policy_rules);
+        state.set_policy_time_zone(self.policy_time_zone);
+        state.set_chaos_config(self.chaos);
+        state.set_alert_config(self.alerts);
+        state.set_reserved_tlds(self.reserved_tlds);
+        state.set_single_label(self.single_label);
+        state.set_negative_soa(self.negative_soa);
+        state.set_cache_config(self.cache);
+        state.set_failover(self.failover);
+        state.set_quotas(self.quotas);
+        state.set_allowed_clients(self.allowed_clients);
+        state.set_acme_update_key(self.acme_update_key);
+        state.set_local_only(self.local_only);
+        state.set_safe_ips(self.safe_ips);
+        state.set_public_suffix_guard(self.public_suffix_guard);
+        #[cfg(feature = "wasm-plugins")]
+        for plugin in self.plugins {
+            state.add_wasm_plugin(plugin);
+        }
+        for (domain, ip) in &self.domains {
+            state.add_domain(domain, *ip).await?;
+        }
+        if !self.ephemeral_domains.is_empty() {
+            let stored: HashSet<String> = state.list_domains().await?.into_iter().map(|(d, _)| d).collect();
+            for (domain, ip) in &self.ephemeral_domains {
+                if !stored.contains(NormalizedName::new(domain).as_str()) {
+                    state.add_ephemeral_domain(domain, *ip)?;
+                }
+            }
+        }
+        for (domain, port) in &self.domain_ports {
+            state.set_domain_port(domain, *port).await?;
+        }
+        for (domain, delay) in &self.domain_delays {
+            state.set_domain_delay(domain, *delay)?;
+        }
+        if let Some(template) = self.project_template {
+            state.set_project_template(template);
+        }
+        for (project, ip) in &self.projects {
+            state.add_project(project, *ip).await?;
+        }
+        for (domain, ip) in &self.domain_addresses {
+            state.add_domain_address(domain, *ip)?;
+        }
+        for (domain, order) in &self.answer_orders {
+            state.set_answer_order(domain, *order)?;
+        }
+
+        let mgmt = Management::new(state.clone());
+        for (token, role) in self.tokens {
+            mgmt.add_token(token, role);
+        }
+
+        Ok(FelixServer {
+            listeners: self.listeners,
+            management_addr: self.management,
+            proxy_addr: self.proxy,
+            #[cfg(feature = "tls")]
+            https_proxy_config: self.https_proxy,
+            primary: self.primary,
+            gossip_config: self.gossip,
+            raft_config: self.raft,
+            llmnr_config: self.llmnr,
+            statsd_config: self.statsd,
+            #[cfg(feature = "webhooks")]
+            webhook_config: self.webhooks,
+            hosts_sync_config: self.hosts_sync,
+            resolved_mode: self.systemd_resolved,
+            runtime: self.runtime,
+            state,
+            mgmt,
+            dns: Vec::new(),
+            api: None,
+            proxy: None,
+            #[cfg(feature = "tls")]
+            https_proxy: None,
+            secondary: None,
+            gossip: None,
+            raft: None,
+            llmnr: None,
+            statsd: None,
+            #[cfg(feature = "webhooks")]
+            webhooks: None,
+            hosts_sync: None,
+            resolved: None,
+            #[cfg(feature = "otel")]
+            telemetry,
+        })
+    }
+}
+
+/// An embeddable felix instance: DNS listeners plus optional management API
+/// sharing one `ResolverState`.
+pub struct FelixServer {
+    listeners: Vec<(SocketAddr, ListenOptions)>,
+    management_addr: Option<SocketAddr>,
+    proxy_addr: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    https_proxy_config: Option<(SocketAddr, std::sync::Arc<crate::tls::LocalCa>)>,
+    primary: Option<(SocketAddr, String)>,
+    gossip_config: Option<GossipConfig>,
+    raft_config: Option<RaftConfig>,
+    llmnr_config: Option<LlmnrConfig>,
+    statsd_config: Option<StatsdConfig>,
+    #[cfg(feature = "webhooks")]
+    webhook_config: Option<crate::webhooks::WebhookConfig>,
+    hosts_sync_config: Option<HostsSyncConfig>,
+    resolved_mode: Option<ResolvedMode>,
+    runtime: Option<Handle>,
+    state: ResolverState,
+    mgmt: Management,
+    dns: Vec<ServerHandle>,
+    api: Option<ManagementHandle>,
+    proxy: Option<ProxyHandle>,
+    #[cfg(feature = "tls")]
+    https_proxy: Option<ProxyHandle>,
+    secondary: Option<SecondaryHandle>,
+    gossip: Option<GossipHandle>,
+    raft: Option<RaftHandle>,
+    llmnr: Option<LlmnrHandle>,
+    statsd: Option<StatsdHandle>,
+    #[cfg(feature = "webhooks")]
+    webhooks: Option<crate::webhooks::WebhookHandle>,
+    hosts_sync: Option<HostsSyncHandle>,
+    resolved: Option<ResolvedHandle>,
+    #[cfg(feature = "otel")]
+    telemetry: Option<crate::telemetry::Telemetry>,
+}
+
+impl FelixServer {
+    pub fn builder() -> FelixServerBuilder {
+        FelixServerBuilder {
+            listeners: Vec::new(),
+            management: None,
+            proxy: None,
+            #[cfg(feature = "tls")]
+            https_proxy: None,
+            tokens: Vec::new(),
+            primary: None,
+            gossip: None,
+            raft: None,
+            llmnr: None,
+            statsd: None,
+            #[cfg(feature = "webhooks")]
+            webhooks: None,
+            hosts_sync: None,
+            systemd_resolved: None,
+            script: None,
+            #[cfg(feature = "wasm-plugins")]
+            plugins: Vec::new(),
+            rewrite_rules: Vec::new(),
+            type_block_rules: Vec::new(),
+            ttl_clamps: Vec::new(),
+            policy_rules: Vec::new(),
+            policy_time_zone: PolicyTimeZone::default(),
+            chaos: ChaosConfig::default(),
+            alerts: AlertConfig::default(),
+            reserved_tlds: ReservedTlds::default(),
+            single_label: SingleLabel::default(),
+            negative_soa: NegativeSoa::default(),
+            cache: CacheConfig::default(),
+            failover: FailoverConfig::default(),
+            quotas: QuotaConfig::default(),
+            allowed_clients: Vec::new(),
+            acme_update_key: None,
+            local_only: None,
+            safe_ips: false,
+            public_suffix_guard: None,
+            system_upstream: false,
+            runtime: None,
+            state: ResolverState::builder().fallback_upstream("8.8.8.8:53".parse().unwrap()),
+            domains: Vec::new(),
+            ephemeral_domains: Vec::new(),
+            domain_ports: Vec::new(),
+            domain_delays: Vec::new(),
+            domain_addresses: Vec::new(),
+            projects: Vec::new(),
+            project_template: None,
+            answer_orders: Vec::new(),
+            log_level: None,
+            syslog: None,
+            #[cfg(feature = "otel")]
+            otlp_endpoint: None,
+        }
+    }
+
+    pub fn state(&self) -> &ResolverState {
+        &self.state
+    }
+
+    pub fn management(&self) -> &Management {
+        &self.mgmt
+    }
+
+    /// Binds all listeners. Calling `start` on a running server is a no-op.
+    pub async fn start(&mut self) -> Result<()> {
+        if !self.dns.is_empty() {
+            return Ok(());
+        }
+
+        let runtime = self.runtime.clone();
+        let runtime = runtime.as_ref();
+
+        // in every mode resolved sends queries here, which must not go back
+        if self.resolved_mode.is_some() && self.state.upstream() == RESOLVED_STUB {
+            return Err(Error::UpstreamLoop(RESOLVED_STUB));
+        }
+
+        // the stub has to let go of 127.0.0.53:53 before felix can bind it
+        if let Some(mode @ ResolvedMode::TakeOver) = &self.resolved_mode {
+            self.resolved = Some(configure_resolved(mode, RESOLVED_STUB).await?);
+        }
+
+        for (addr, options) in &self.listeners {
+            let (addr, options, state) = (*addr, options.clone(), self.state.clone());
+            match on_runtime(runtime, async move { run_udp_server_with(addr, &options, state).await }).await {
+                Ok(handle) => self.dns.push(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(upstream) = self.state.looping_upstreams().first() {
+            self.stop_all().await;
+            return Err(Error::UpstreamLoop(*upstream));
+        }
+
+        #[cfg(feature = "doh")]
+        if let Some(host) = crate::failover::system_bootstrapped_host(&self.state.failover())
+            && system_upstreams().unwrap_or_default().iter().any(|u| self.state.is_own_listener(*u))
+        {
+            log::warn!(
+                "The system resolves through felix, so the DoH fallback cannot look up {} when the upstream is down; \
+                 set a bootstrap resolver or pinned addresses for it",
+                host
+            );
+        }
+
+        // before the management API, so no change bypasses the cluster
+        if let Some(config) = &self.raft_config {
+            match on_runtime(runtime, start_raft(config.clone(), self.state.clone())).await {
+                Ok(handle) => {
+                    self.mgmt.set_cluster(Some(handle.node()));
+                    self.raft = Some(handle);
+                }
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(addr) = self.management_addr {
+            match on_runtime(runtime, run_management_server(addr, self.mgmt.clone())).await {
+                Ok(handle) => self.api = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(addr) = self.proxy_addr {
+            match on_runtime(runtime, run_http_proxy(addr, self.state.clone())).await {
+                Ok(handle) => self.proxy = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some((addr, ca)) = &self.https_proxy_config {
+            match on_runtime(runtime, crate::proxy::run_https_proxy(*addr, self.state.clone(), ca.clone())).await {
+                Ok(handle) => self.https_proxy = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some((addr, token)) = &self.primary {
+            let _runtime = runtime.map(Handle::enter);
+            self.secondary = Some(follow_primary(*addr, token.clone(), self.state.clone()));
+        }
+
+        if let Some(config) = &self.gossip_config {
+            match on_runtime(runtime, start_gossip(config.clone(), self.state.clone())).await {
+                Ok(handle) => self.gossip = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(config) = &self.llmnr_config {
+            match on_runtime(runtime, start_llmnr(config.clone(), self.state.clone())).await {
+                Ok(handle) => self.llmnr = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(config) = &self.statsd_config {
+            match on_runtime(runtime, start_statsd(config.clone(), self.state.clone())).await {
+                Ok(handle) => self.statsd = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        #[cfg(feature = "webhooks")]
+        if let Some(config) = &self.webhook_config {
+            match on_runtime(runtime, crate::webhooks::start_webhooks(config.clone(), self.state.clone())).await {
+                Ok(handle) => self.webhooks = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(config) = &self.hosts_sync_config {
+            match on_runtime(runtime, start_hosts_sync(config.clone(), self.state.clone())).await {
+                Ok(handle) => self.hosts_sync = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        // last, so resolved only sends queries once felix answers them
+        if let Some(mode) = self.resolved_mode.clone().filter(|m| *m != ResolvedMode::TakeOver) {
+            let mut felix = self.dns[0].local_addr();
+            if felix.ip().is_unspecified() {
+                felix.set_ip(Ipv4Addr::LOCALHOST.into());
+            }
+            match configure_resolved(&mode, felix).await {
+                Ok(handle) => self.resolved = Some(handle),
+                Err(e) => {
+                    self.stop_all().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        handover::notify_ready();
+        Ok(())
+    }
+
+    /// Starts a new felix, the binary now at this one's path with the same
+    /// arguments, and passes it every DNS, management and proxy socket, see
+    /// `handover`. Returns its pid once it serves; `shutdown` then answers
+    /// the queries already received here and leaves the system resolver
+    /// settings to the new process. On error this one keeps serving.
+    ///
+    /// Staged mappings are flushed first; changes made here after that
+    /// are not seen by the new process. The redb store cannot be opened
+    /// by both at once, so the new process fails to start with it. Unix
+    /// only.
+    #[cfg(unix)]
+    pub async fn hand_over(&mut self) -> Result<u32> {
+        use std::os::fd::AsFd;
+
+        use crate::handover::SocketKind;
+
+        self.state.flush().await?;
+        let mut sockets = Vec::new();
+        for handle in &self.dns {
+            sockets.extend(handle.sockets().iter().map(|s| (SocketKind::Udp, s.as_fd())));
+        }
+        let listeners = [self.api.as_ref().map(ManagementHandle::listener), self.proxy.as_ref().map(ProxyHandle::listener)];
+        #[cfg(feature = "tls")]
+        let listeners = listeners.into_iter().chain([self.https_proxy.as_ref().map(ProxyHandle::listener)]);
+        sockets.extend(listeners.into_iter().flatten().map(|l| (SocketKind::Tcp, l.as_fd())));
+
+        let pid = handover::spawn_successor(&sockets).await?;
+        // the new process configured systemd-resolved again, keep it that way
+        self.resolved = None;
+        Ok(pid)
+    }
+
+    /// Actual bound DNS addresses; empty until `start` succeeds.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.dns.iter().map(ServerHandle::local_addr).collect()
+    }
+
+    /// Actual bound management API address, if one is configured and started.
+    pub fn management_addr(&self) -> Option<SocketAddr> {
+        self.api.as_ref().map(ManagementHandle::local_addr)
+    }
+
+    /// Actual bound HTTP proxy address, if one is configured and started.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.as_ref().map(ProxyHandle::local_addr)
+    }
+
+    /// Actual bound HTTPS proxy address, if one is configured and started.
+    #[cfg(feature = "tls")]
+    pub fn https_proxy_addr(&self) -> Option<SocketAddr> {
+        self.https_proxy.as_ref().map(ProxyHandle::local_addr)
+    }
+
+    /// Stops every listener and service but keeps the state, so `start`
+    /// can bind them again.
+    pub async fn stop(&mut self) {
+        self.stop_all().await;
+    }
+
+    pub async fn shutdown(mut self) {
+        self.stop_all().await;
+        if let Err(e) = self.state.flush().await {
+            log::warn!("Failed to flush staged mappings: {}", e);
+        }
+        #[cfg(feature = "otel")]
+        if let Some(telemetry) = self.telemetry.take() {
+            // the exporters block on HTTP while flushing
+            let _ = tokio::task::spawn_blocking(move || telemetry.shutdown()).await;
+        }
+    }
+
+    async fn stop_all(&mut self) {
+        // first, so resolved stops sending queries before the listeners go
+        if self.resolved_mode != Some(ResolvedMode::TakeOver)
+            && let Some(resolved) = self.resolved.take()
+        {
+            resolved.shutdown().await;
+        }
+        if let Some(hosts_sync) = self.hosts_sync.take() {
+            hosts_sync.shutdown().await;
+        }
+        if let Some(gossip) = self.gossip.take() {
+            gossip.shutdown().await;
+        }
+        if let Some(llmnr) = self.llmnr.take() {
+            llmnr.shutdown().await;
+        }
+        if let Some(secondary) = self.secondary.take() {
+            secondary.shutdown().await;
+        }
+        for handle in self.dns.drain(..) {
+            handle.shutdown().await;
+        }
+        // a taken-over stub once 127.0.0.53:53 is free again
+        if let Some(resolved) = self.resolved.take() {
+            resolved.shutdown().await;
+        }
+        // after the listeners, so their last queries are counted
+        if let Some(statsd) = self.statsd.take() {
+            statsd.shutdown().await;
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(webhooks) = self.webhooks.take() {
+            webhooks.shutdown().await;
+        }
+        if let Some(api) = self.api.take() {
+            api.shutdown().await;
+        }
+        // after the management API, so no change bypasses the cluster
+        if let Some(raft) = self.raft.take() {
+            self.mgmt.set_cluster(None);
+            raft.shutdown().await;
+        }
+        if let Some(proxy) = self.proxy.take() {
+            proxy.shutdown().await;
+        }
+        #[cfg(feature = "tls")]
+        if let Some(proxy) = self.https_proxy.take() {
+            proxy.shutdown().await;
+        }
+    }
+}
+
+/// Awaits `fut` on `runtime` when one is configured, so the tasks it spawns
+/// belong to that runtime.
+async fn on_runtime<T: Send + 'static>(runtime: Option<&Handle>, fut: impl Future<Output = T> + Send + 'static) -> T {
+    match runtime {
+        Some(runtime) => runtime.spawn(fut).await.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())),
+        None => fut.await,
+    }
+}
@@ -1,173 +1,613 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-
-use anyhow::{Context, Result};
-use tokio::{net::UdpSocket, sync::oneshot, time::timeout};
-use trust_dns_proto::{
-    op::{Message, MessageType, OpCode},
-    rr::{Name, RData, Record, RecordType},
-    serialize::binary::{BinEncodable, BinEncoder},
+use std::{net::SocketAddr, sync::{Arc, LazyLock}, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, watch},
+    task::{JoinHandle, JoinSet},
+    time::{Instant, timeout, timeout_at},
+};
+#[cfg(target_os = "linux")]
+use tokio::{io::Interest, sync::mpsc};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{Name, RecordType},
 };
 
-use crate::ResolverState;
+use crate::{
+    Error, ResolverState, Result,
+    events::{AnswerSource, QueryEvent, QueryOutcome},
+    dispatch::{self, Dispatch},
+    handover,
+    log_filter::QUERY_TARGET,
+    name::NormalizedName,
+    pipeline::{Pipeline, QueryContext, Response},
+    proto,
+    statsd::QuerySample,
+    views::ViewSlot,
+};
+
+/// How long `shutdown` waits for in-flight queries. Longer than the upstream
+/// timeout so forwarded queries can still be answered or SERVFAILed.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long `forward_udp` waits for the upstream's answer.
+pub(crate) const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Receive buffer size; larger datagrams are truncated and fail to parse.
+const RECV_BUFFER: usize = 2048;
+/// Idle buffers kept per listener socket for reuse.
+const MAX_POOLED_BUFFERS: usize = 256;
 
 pub struct ServerHandle {
-    shutdown_tx: Option<oneshot::Sender<()>>,
+    local_addr: SocketAddr,
+    shutdown_tx: Option<oneshot::Sender<Duration>>,
+    task: Option<JoinHandle<()>>,
+    /// Kept to pass on to a successor, see `handover`.
+    sockets: Vec<Arc<UdpSocket>>,
 }
 
 impl ServerHandle {
-    pub async fn shutdown(mut self) {
+    /// The address the socket is actually bound to, useful after binding port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.task.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    pub(crate) fn sockets(&self) -> &[Arc<UdpSocket>] {
+        &self.sockets
+    }
+
+    /// Resolves once the receive loop has exited. Cancel-safe.
+    pub async fn wait(&mut self) {
+        if let Some(task) = self.task.as_mut() {
+            let _ = task.await;
+            self.task = None;
+        }
+    }
+
+    /// Stops accepting packets and waits up to `DEFAULT_DRAIN_TIMEOUT` for
+    /// in-flight queries to be answered.
+    pub async fn shutdown(self) {
+        self.shutdown_with_timeout(DEFAULT_DRAIN_TIMEOUT).await;
+    }
+
+    /// Like `shutdown`, with an explicit drain timeout. Handlers still running
+    /// when it expires are aborted.
+    pub async fn shutdown_with_timeout(mut self, drain: Duration) {
         if let Some(tx) = self.shutdown_tx.take() {
-            let _ = tx.send(());
+            let _ = tx.send(drain);
         }
+        self.wait().await;
     }
 }
 
+/// Socket options for a DNS listener beyond its address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListenOptions {
+    /// Only serve packets arriving on this interface, e.g. `docker0` or a
+    /// VPN's `tun0`, whatever address they were sent to. Uses
+    /// SO_BINDTODEVICE, so Linux only; kernels before 5.7 also require
+    /// CAP_NET_RAW.
+    pub interface: Option<String>,
+    /// Sockets bound to the address with SO_REUSEPORT, each with its own
+    /// receive loop, so the kernel spreads queries across cores. 0 or 1
+    /// binds a single socket. Unix only.
+    pub workers: usize,
+    /// Datagrams moved per syscall with recvmmsg/sendmmsg; replies queue
+    /// for a per-socket sender that flushes whatever is waiting. 0 or 1
+    /// receives and sends one at a time. Linux only, ignored elsewhere.
+    pub batch: usize,
+    /// Search list for single-label queries, like resolv.conf's `search`:
+    /// with `mycompany.dev`, `api` is looked up as `api.mycompany.dev`. See
+    /// `pipeline::SingleLabelStage` and `system_dns::system_search_domains`.
+    pub search: Vec<String>,
+}
+
 pub async fn run_udp_server(listen_addr: SocketAddr, state: ResolverState) -> Result<ServerHandle> {
-    let socket = UdpSocket::bind(listen_addr)
-        .await
-        .with_context(|| format!("binding udp socket to {}", listen_addr))?;
+    run_udp_server_with(listen_addr, &ListenOptions::default(), state).await
+}
+
+pub async fn run_udp_server_with(
+    listen_addr: SocketAddr,
+    options: &ListenOptions,
+    state: ResolverState,
+) -> Result<ServerHandle> {
+    // sockets a predecessor passed on, see `handover`, or a new one
+    let mut sockets = Vec::new();
+    for socket in handover::take_udp(listen_addr) {
+        socket.set_nonblocking(true)?;
+        sockets.push(UdpSocket::from_std(socket)?);
+    }
+    if sockets.is_empty() {
+        sockets.push(bind_udp(listen_addr, options).map_err(|e| Error::Bind(listen_addr, e))?);
+    }
+    let local_addr = sockets[0].local_addr()?;
+    // the others join the port the first one got, in case it was 0
+    for _ in sockets.len()..options.workers.max(1) {
+        sockets.push(bind_udp(local_addr, options).map_err(|e| Error::Bind(local_addr, e))?);
+    }
+
+    match &options.interface {
+        Some(interface) => log::info!("Local DNS UDP listening on {} ({})", local_addr, interface),
+        None => log::info!("Local DNS UDP listening on {}", local_addr),
+    }
+    if sockets.len() > 1 {
+        log::info!("Sharding {} across {} sockets", local_addr, sockets.len());
+    }
 
-    log::info!("Local DNS UDP listening on {}", listen_addr);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (drain_tx, drain_rx) = watch::channel(None);
+    state.listener_started(local_addr);
 
-    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let search: Arc<[String]> =
+        options.search.iter().map(|d| d.trim_matches('.').to_ascii_lowercase()).filter(|d| !d.is_empty()).collect();
+    let view = state.view_slot(local_addr);
+    let sockets: Vec<Arc<UdpSocket>> = sockets.into_iter().map(Arc::new).collect();
+    let mut workers = JoinSet::new();
+    for socket in &sockets {
+        let listener = Listener { view: view.clone(), search: search.clone() };
+        workers.spawn(recv_loop(socket.clone(), listener, state.clone(), drain_rx.clone(), options.batch));
+    }
 
-    let socket = Arc::new(socket);
-    let state_clone = state.clone();
+    let task = tokio::spawn(async move {
+        // a dropped handle also stops the server
+        let drain = shutdown_rx.await.unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+        log::info!("Shutting down DNS server");
+        let _ = drain_tx.send(Some(drain));
+        while workers.join_next().await.is_some() {}
+        state.listener_stopped(local_addr);
+    });
 
-    let s = socket.clone();
+    Ok(ServerHandle {
+        local_addr,
+        shutdown_tx: Some(shutdown_tx),
+        task: Some(task),
+        sockets,
+    })
+}
+
+/// What a query needs to know about the listener it arrived on.
+#[derive(Clone)]
+struct Listener {
+    view: ViewSlot,
+    search: Arc<[String]>,
+}
 
-    tokio::spawn(async move {
-        let mut buf = vec![0u8; 2048];
-        loop {
-            tokio::select! {
-                biased;
-                _ = &mut shutdown_rx => {
-                    log::info!("Shutting down DNS server");
-                    break;
+/// Serves one socket until a drain timeout is sent on `drain_rx`, then
+/// waits up to that long for its in-flight queries.
+async fn recv_loop(
+    socket: Arc<UdpSocket>,
+    listener: Listener,
+    state: ResolverState,
+    mut drain_rx: watch::Receiver<Option<Duration>>,
+    batch: usize,
+) {
+    let pool = BufferPool::default();
+    let mut receiver = Receiver::new(socket.clone(), pool.clone(), batch);
+    let (replies, sender) = Replies::start(socket, &pool, batch);
+    let mut received = Vec::new();
+    let mut in_flight = JoinSet::new();
+    let drain = loop {
+        tokio::select! {
+            biased;
+            changed = drain_rx.changed() => {
+                break changed.ok().and_then(|_| *drain_rx.borrow()).unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+            }
+            Some(_) = in_flight.join_next(), if !in_flight.is_empty() => {}
+            recv = receiver.recv(&mut received) => {
+                if let Err(e) = recv {
+                    log::warn!("recv_from error: {:?}", e);
                 }
-                recv = s.recv_from(&mut buf) => {
-                    match recv {
-                        Ok((n, peer)) => {
-                            let packet = buf[..n].to_vec();
-                            let st = state_clone.clone();
-                            let s2 = s.clone();
-                            // spawn to handle concurrently
-                            tokio::spawn(async move {
-                                if let Err(e) = handle_packet(packet, peer, s2, st).await {
-                                    log::warn!("Error handling DNS packet from {}: {:?}", peer, e);
-                                }
-                            });
+                for (packet, peer) in received.drain(..) {
+                    let st = state.clone();
+                    let replies = replies.clone();
+                    let pool = pool.clone();
+                    let listener = listener.clone();
+                    // spawn to handle concurrently
+                    in_flight.spawn(async move {
+                        if let Err(e) = handle_packet(packet, peer, listener, replies, st, pool).await {
+                            log::warn!("Error handling DNS packet from {}: {:?}", peer, e);
                         }
-                        Err(e) => {
-                            log::warn!("recv_from error: {:?}", e);
-                        }
-                    }
+                    });
                 }
             }
         }
-    });
+    };
 
-    Ok(ServerHandle {
-        shutdown_tx: Some(shutdown_tx),
-    })
+    if !in_flight.is_empty() {
+        log::info!("Draining {} in-flight queries", in_flight.len());
+        let drained = timeout(drain, async { while in_flight.join_next().await.is_some() {} }).await;
+        if drained.is_err() {
+            log::warn!("Aborting {} queries still in flight after {:?}", in_flight.len(), drain);
+        }
+    }
+    // the sender stops once it has flushed the replies of every handler
+    in_flight.shutdown().await;
+    drop(replies);
+    if let Some(sender) = sender {
+        let _ = sender.await;
+    }
+}
+
+/// A socket's receive side: one buffer per datagram it can take at once.
+struct Receiver {
+    socket: Arc<UdpSocket>,
+    pool: BufferPool,
+    bufs: Vec<BytesMut>,
+}
+
+impl Receiver {
+    fn new(socket: Arc<UdpSocket>, pool: BufferPool, batch: usize) -> Self {
+        let batch = if cfg!(target_os = "linux") { batch.max(1) } else { 1 };
+        let bufs = (0..batch).map(|_| pool.take()).collect();
+        Self { socket, pool, bufs }
+    }
+
+    /// Waits for at least one datagram and appends all that arrived, with
+    /// their senders, to `out`. Cancel-safe.
+    async fn recv(&mut self, out: &mut Vec<(Bytes, SocketAddr)>) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.bufs.len() > 1 {
+            let peers = self
+                .socket
+                .async_io(Interest::READABLE, || crate::mmsg::recv_batch(&self.socket, &mut self.bufs))
+                .await;
+            let peers = match peers {
+                Ok(peers) => peers,
+                Err(e) => {
+                    self.bufs.iter_mut().for_each(BytesMut::clear);
+                    return Err(e);
+                }
+            };
+            // each handler owns its buffer until it has replied
+            for (buf, peer) in self.bufs.iter_mut().zip(peers) {
+                out.push((std::mem::replace(buf, self.pool.take()).freeze(), peer));
+            }
+            return Ok(());
+        }
+        let (_, peer) = self.socket.recv_buf_from(&mut self.bufs[0]).await?;
+        out.push((std::mem::replace(&mut self.bufs[0], self.pool.take()).freeze(), peer));
+        Ok(())
+    }
+}
+
+/// Where a socket's replies go: straight out, or to a task sending them
+/// in batches.
+#[derive(Clone)]
+enum Replies {
+    Direct(Arc<UdpSocket>),
+    #[cfg(target_os = "linux")]
+    Batched(mpsc::Sender<(Bytes, SocketAddr)>),
+}
+
+impl Replies {
+    /// Also returns the batch sender's task, if one was started.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn start(socket: Arc<UdpSocket>, pool: &BufferPool, batch: usize) -> (Self, Option<JoinHandle<()>>) {
+        #[cfg(target_os = "linux")]
+        if batch > 1 {
+            let (tx, rx) = mpsc::channel(batch * 4);
+            let task = tokio::spawn(send_loop(socket, rx, pool.clone(), batch));
+            return (Replies::Batched(tx), Some(task));
+        }
+        (Replies::Direct(socket), None)
+    }
+
+    async fn send(&self, packet: Bytes, to: SocketAddr, pool: &BufferPool) -> Result<()> {
+        match self {
+            Replies::Direct(socket) => {
+                socket.send_to(&packet, to).await?;
+                pool.give(packet);
+            }
+            #[cfg(target_os = "linux")]
+            Replies::Batched(tx) => {
+                let _ = tx.send((packet, to)).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends queued replies with sendmmsg, as many per call as are waiting,
+/// until every `Replies` handle is dropped.
+#[cfg(target_os = "linux")]
+async fn send_loop(socket: Arc<UdpSocket>, mut rx: mpsc::Receiver<(Bytes, SocketAddr)>, pool: BufferPool, batch: usize) {
+    let mut queue = Vec::with_capacity(batch);
+    while rx.recv_many(&mut queue, batch).await > 0 {
+        let mut sent = 0;
+        while sent < queue.len() {
+            match socket.async_io(Interest::WRITABLE, || crate::mmsg::send_batch(&socket, &queue[sent..])).await {
+                Ok(n) => sent += n.max(1),
+                Err(e) => {
+                    // skip the reply that failed, e.g. to an unreachable client
+                    log::debug!("Reply to {} failed: {:?}", queue[sent].1, e);
+                    sent += 1;
+                }
+            }
+        }
+        for (packet, _) in queue.drain(..) {
+            pool.give(packet);
+        }
+    }
+}
+
+/// Receive buffers passed from a socket's receive loop to its query
+/// handlers and back, so steady traffic does not allocate per packet.
+#[derive(Clone, Default)]
+struct BufferPool(Arc<Mutex<Vec<BytesMut>>>);
+
+impl BufferPool {
+    fn take(&self) -> BytesMut {
+        self.0.lock().pop().unwrap_or_else(|| BytesMut::with_capacity(RECV_BUFFER))
+    }
+
+    /// Returns `packet`'s buffer to the pool if nothing else references it.
+    fn give(&self, packet: Bytes) {
+        if let Ok(buf) = packet.try_into_mut() {
+            self.give_mut(buf);
+        }
+    }
+
+    fn give_mut(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut pool = self.0.lock();
+        if buf.capacity() >= RECV_BUFFER && pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(buf);
+        }
+    }
+}
+
+fn bind_udp(addr: SocketAddr, options: &ListenOptions) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(interface) = &options.interface {
+        bind_device(&socket, interface)?;
+    }
+    if options.workers > 1 {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "SO_REUSEPORT workers need a unix system"))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("binding to interface {} is only supported on Linux", interface),
+    ))
 }
 
 async fn handle_packet(
-    packet: Vec<u8>,
+    packet: Bytes,
     src: SocketAddr,
-    socket: Arc<UdpSocket>,
+    listener: Listener,
+    replies: Replies,
     state: ResolverState,
-) -> anyhow::Result<()> {
-    // parse message
+    pool: BufferPool,
+) -> Result<()> {
+    let received = std::time::Instant::now();
+    #[cfg(feature = "otel")]
+    let started = std::time::SystemTime::now();
+
     let msg = match Message::from_vec(&packet) {
         Ok(m) => m,
         Err(e) => {
-            log::warn!("Failed to parse DNS message from {}: {:?}", src, e);
+            log::debug!("Failed to parse DNS message from {}: {:?}", src, e);
+            let reply = dispatch::format_error(&packet);
+            pool.give(packet);
+            if let Some(reply) = reply {
+                replies.send(reply.into(), src, &pool).await?;
+            }
             return Ok(());
         }
     };
 
-    // we handle only first query
-    if msg.queries().is_empty() {
-        // ignore
-        return Ok(());
-    }
-    let query = &msg.queries()[0];
-    let qname = query.name().to_utf8();
-    let qtype = query.query_type();
-
-    log::debug!("Query from {}: {} {:?}", src, qname, qtype);
-
-    // try local resolve if enabled and mapping exists (only A)
-    if let Ok(Some(ip)) = state.resolve(&qname).await {
-        // Only answer A queries or ANY
-        if qtype == RecordType::A || qtype == RecordType::ANY {
-            let mut resp = Message::new();
-            resp.set_id(msg.id());
-            resp.set_message_type(MessageType::Response);
-            resp.set_op_code(OpCode::Query);
-            resp.set_authoritative(true);
-            resp.add_query(query.clone());
-
-            let name = Name::from_utf8(&qname)?;
-            let record = Record::from_rdata(name, 60, RData::A(ip.into()));
-            resp.add_answer(record);
-
-            let mut out: Vec<u8> = Vec::with_capacity(512);
-            {
-                let mut encoder = BinEncoder::new(&mut out);
-                resp.emit(&mut encoder)?;
-            }
-            socket.send_to(&out, src).await?;
-            log::info!("Answered {} -> {} to {}", qname, ip, src);
+    match dispatch::dispatch(&msg, &state) {
+        Dispatch::Pipeline => {}
+        Dispatch::Ignore => {
+            pool.give(packet);
             return Ok(());
         }
+        Dispatch::Reject(code) => {
+            let questions = msg.queries().len();
+            log::debug!(target: QUERY_TARGET, "{} from {} with {} question(s) -> {}", msg.op_code(), src, questions, code);
+            pool.give(packet);
+            let reply = proto::encode(&dispatch::reject(&msg, code))?;
+            return replies.send(reply.into(), src, &pool).await;
+        }
     }
+    // normalized once, so stages, the cache and stats all key by the same
+    // name; replies still echo the question as asked
+    let qname = NormalizedName::from_wire(msg.queries()[0].name()).fqdn();
+    let qtype = msg.queries()[0].query_type();
 
-    let upstream = state.upstream();
-    match forward_udp_and_relay(&packet, upstream, &socket, src).await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            log::warn!("Forwarding failed: {:?}", e);
-
-            // Create response with SERVFAIL
-            let mut resp = Message::new();
-            resp.set_id(msg.id());
-            resp.set_message_type(MessageType::Response);
-            resp.set_op_code(OpCode::Query);
-            resp.set_authoritative(true);
-            resp.set_response_code(trust_dns_proto::op::ResponseCode::ServFail);
-            resp.add_query(query.clone());
-
-            let mut out: Vec<u8> = Vec::with_capacity(512);
-            {
-                let mut encoder = BinEncoder::new(&mut out);
-                resp.emit(&mut encoder)?;
-            }
-            socket.send_to(&out, src).await?;
+    log::debug!(target: QUERY_TARGET, "Query from {}: {} {:?}", src, qname, qtype);
 
-            log::info!("Answered {} -> SERVFAIL to {}", qname, src);
+    let mut ctx = QueryContext {
+        upstream: state.upstream_for(&qname),
+        view: listener.view.read().clone(),
+        search: listener.search,
+        state: state.clone(),
+        client: src,
+        raw: packet,
+        request: msg,
+        lookup_name: qname.clone(),
+        qname,
+        qtype,
+        trace: None,
+    };
+    // disabled, felix steps aside: no local answers, hooks or cache
+    static PASSTHROUGH: LazyLock<Arc<Pipeline>> = LazyLock::new(|| Arc::new(Pipeline::passthrough()));
+    let pipeline = if state.enabled() { state.pipeline() } else { PASSTHROUGH.clone() };
+    let answer = pipeline.run(&mut ctx).await?;
+
+    let servfail = matches!(&answer.response, Response::Message(m) if m.response_code() == ResponseCode::ServFail);
+    // the request is no longer needed, so its buffer can carry the reply
+    let request = std::mem::take(&mut ctx.raw);
+    let out = match answer.response {
+        Response::Message(mut m) => {
+            proto::echo_edns(&ctx.request, &mut m);
+            let mut out = Vec::from(request.try_into_mut().unwrap_or_else(|_| pool.take()));
+            out.clear();
+            proto::encode_into(&m, &mut out)?;
+            out
+        }
+        Response::Raw(bytes) => {
+            pool.give(request);
+            bytes
+        }
+    };
+    replies.send(out.into(), src, &pool).await?;
 
-            Err(e)
+    let source = answer.source;
+    match answer.outcome {
+        QueryOutcome::Local => log::info!(target: QUERY_TARGET, "Answered {} locally ({}) to {}", ctx.qname, source, src),
+        QueryOutcome::ServFail => {
+            log::info!(target: QUERY_TARGET, "Answered {} -> SERVFAIL ({}) to {}", ctx.qname, source, src)
         }
+        _ => log::debug!(target: QUERY_TARGET, "Answered {} from {} to {}", ctx.qname, source, src),
     }
+    publish(&state, &ctx.qname, qtype, src, answer.outcome, source);
+    state.record_query_sample(QuerySample { outcome: answer.outcome, source, qtype, elapsed: received.elapsed() });
+    state.record_query_name(&ctx.qname, qtype, source);
+    state.record_upstream_health(answer.outcome, source, servfail);
+    #[cfg(feature = "otel")]
+    crate::telemetry::record_query(&ctx.qname, qtype, src, answer.outcome, source, started);
+
+    Ok(())
 }
 
-async fn forward_udp_and_relay(
-    packet: &[u8],
-    upstream: SocketAddr,
-    socket: &UdpSocket,
+fn publish(
+    state: &ResolverState,
+    qname: &str,
+    qtype: RecordType,
     client: SocketAddr,
-) -> anyhow::Result<()> {
+    outcome: QueryOutcome,
+    source: AnswerSource,
+) {
+    state.publish_query(QueryEvent {
+        name: qname.trim_end_matches('.').to_string(),
+        qtype: qtype.to_string(),
+        client,
+        outcome,
+        source,
+    });
+}
+
+/// `msg` with its first question renamed to `rewritten`, ready to forward.
+pub(crate) fn rename_question(msg: &Message, rewritten: &Name) -> Result<Vec<u8>> {
+    let mut query = msg.clone();
+    let mut questions = query.take_queries();
+    questions[0].set_name(rewritten.clone());
+    query.add_queries(questions);
+    Ok(query.to_vec()?)
+}
+
+/// Renames the reply to a `rename_question` query back, so the client
+/// sees the name it asked for.
+pub(crate) fn restore_question(reply: &mut Message, original: &Name, rewritten: &Name) {
+    let mut questions = reply.take_queries();
+    if let Some(q) = questions.first_mut() {
+        q.set_name(original.clone());
+    }
+    reply.add_queries(questions);
+
+    let mut answers = reply.take_answers();
+    for record in &mut answers {
+        if record.name() == rewritten {
+            record.set_name(original.clone());
+        }
+    }
+    reply.insert_answers(answers);
+}
+
+/// Asks `upstream` for the root NS set; any well-formed reply counts as alive.
+pub(crate) async fn probe_upstream(upstream: SocketAddr) -> Result<()> {
+    let query = proto::build_query(".", RecordType::NS)?;
+    let reply = forward_udp(&proto::encode(&query)?, upstream).await?;
+    proto::decode(&reply)?;
+    Ok(())
+}
+
+pub(crate) async fn forward_udp(packet: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
     // talk to upstream using ephemeral socket
     let upstream_socket = UdpSocket::bind("0.0.0.0:0").await?;
     upstream_socket.send_to(packet, upstream).await?;
 
-    // wait for response with timeout
+    // wait for response with timeout, skipping datagrams that cannot be
+    // it: forged answers from other addresses or for another query
     let mut buf = vec![0u8; 4096];
-    let n = timeout(Duration::from_secs(2), upstream_socket.recv_from(&mut buf)).await??;
-    let (size, _peer) = n;
-    socket.send_to(&buf[..size], client).await?;
-    println!("Forwarding to {} from {}", client, upstream);
-    Ok(())
+    let deadline = Instant::now() + UPSTREAM_TIMEOUT;
+    loop {
+        let (size, peer) = timeout_at(deadline, upstream_socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| Error::UpstreamTimeout(upstream))??;
+        if peer != upstream {
+            log::debug!("Ignored a datagram from {} while waiting for {}", peer, upstream);
+            continue;
+        }
+        if !answers_query(packet, &buf[..size]) {
+            log::debug!("Ignored an answer from {} with another id or question", upstream);
+            continue;
+        }
+        buf.truncate(size);
+        return Ok(buf);
+    }
+}
+
+/// Whether `reply` has the id and question of `query`, in the raw wire
+/// format. Errors without a question, e.g. FORMERR, only need the id.
+pub(crate) fn answers_query(query: &[u8], reply: &[u8]) -> bool {
+    if query.get(..2) != reply.get(..2) {
+        return false;
+    }
+    let questions = |msg: &[u8]| msg.get(4..6).map(|n| u16::from_be_bytes([n[0], n[1]]));
+    let rcode = reply.get(3).map_or(0, |flags| flags & 0x0f);
+    if questions(reply) == Some(0) && rcode != 0 {
+        return true;
+    }
+    match (question_end(query), question_end(reply)) {
+        (Some(end), Some(reply_end)) => end == reply_end && query[12..end].eq_ignore_ascii_case(&reply[12..end]),
+        _ => false,
+    }
+}
+
+/// Offset just past the first question: its name, type and class. Names
+/// in questions are never compressed.
+fn question_end(msg: &[u8]) -> Option<usize> {
+    let mut at = 12;
+    loop {
+        let len = usize::from(*msg.get(at)?);
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        at += 1 + len;
+    }
+    let end = at + 1 + 4;
+    (end <= msg.len()).then_some(end)
 }
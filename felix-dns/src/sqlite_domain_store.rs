@@ -1,37 +1,117 @@
-use anyhow::Result;
-use sqlx::{Pool, Sqlite, SqlitePool};
-use std::net::Ipv4Addr;
+use crate::{
+    Error, ForwardRule, Result,
+    name::NormalizedName,
+    store::{DomainPage, ListQuery, ListSort, candidate_keys, search_term, Wildcards},
+};
+use sqlx::{
+    Pool, Row, Sqlite,
+    query::Query,
+    sqlite::{SqliteArguments, SqliteConnectOptions, SqlitePoolOptions},
+};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
+
+const UPSTREAM_KEY: &str = "upstream";
+/// `record_type` of address mappings; `rdata` holds the four octets.
+const TYPE_A: &str = "A";
+/// Shortest search term the trigram index can look up; shorter ones scan
+/// with LIKE.
+const MIN_INDEXED_TERM: usize = 3;
+/// sqlx's default pool size.
+pub(crate) const DEFAULT_CONNECTIONS: u32 = 10;
+
+/// Passphrase of a SQLCipher database. Kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DatabaseKey(String);
+
+impl DatabaseKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+}
+
+impl std::fmt::Debug for DatabaseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DatabaseKey(..)")
+    }
+}
 
 #[derive(Clone)]
 pub struct SqliteDomainStore {
     pool: Pool<Sqlite>,
+    wildcards: Wildcards,
 }
 
 impl SqliteDomainStore {
     pub async fn new(database_path: &str) -> Result<Self> {
+        Self::with_connections(database_path, DEFAULT_CONNECTIONS).await
+    }
+
+    /// Opens the store with at most `connections` connections. SQLite runs
+    /// each connection's queries on a dedicated thread, so this also bounds
+    /// the threads the store uses.
+    pub async fn with_connections(database_path: &str, connections: u32) -> Result<Self> {
+        Self::open(database_path, connections, None).await
+    }
+
+    /// Opens a SQLCipher-encrypted store, creating it encrypted with `key`
+    /// if it does not exist. A wrong key fails here, on the first read.
+    #[cfg(feature = "sqlcipher")]
+    pub async fn encrypted(database_path: &str, connections: u32, key: &DatabaseKey) -> Result<Self> {
+        Self::open(database_path, connections, Some(key)).await
+    }
+
+    #[cfg_attr(not(feature = "sqlcipher"), allow(unused_variables))]
+    async fn open(database_path: &str, connections: u32, key: Option<&DatabaseKey>) -> Result<Self> {
         let connection_string = if database_path == ":memory:" {
             "sqlite::memory:".to_string()
         } else {
             format!("sqlite:{}?mode=rwc", database_path)
         };
-        let pool = SqlitePool::connect(&connection_string).await?;
+        #[allow(unused_mut)]
+        let mut options = SqliteConnectOptions::from_str(&connection_string)?;
+        #[cfg(feature = "sqlcipher")]
+        if let Some(key) = key {
+            // sqlx sends `key` before any other statement on every connection
+            options = options.pragma("key", format!("'{}'", key.0.replace('\'', "''")));
+        }
+        let pool = SqlitePoolOptions::new()
+            .max_connections(connections.max(1))
+            .connect_with(options)
+            .await?;
 
-        let store = Self { pool };
+        let store = Self { pool, wildcards: Wildcards::default() };
         store.initialize_schema().await?;
 
         Ok(store)
     }
 
+    /// See `Wildcards::apex`.
+    pub fn with_wildcard_apex(mut self, enabled: bool) -> Self {
+        self.wildcards.apex = enabled;
+        self
+    }
+
+    pub fn with_wildcards(mut self, wildcards: Wildcards) -> Self {
+        self.wildcards = wildcards;
+        self
+    }
+
     async fn initialize_schema(&self) -> Result<()> {
+        // only takes effect on a new database; `maintain` converts old ones
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL").execute(&self.pool).await?;
+        self.migrate_ip_columns().await?;
+
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS domain_mappings (
-                domain TEXT PRIMARY KEY,
-                ip_a INTEGER NOT NULL,
-                ip_b INTEGER NOT NULL, 
-                ip_c INTEGER NOT NULL,
-                ip_d INTEGER NOT NULL,
+                domain TEXT NOT NULL,
+                record_type TEXT NOT NULL,
+                rdata BLOB NOT NULL,
                 created_at INTEGER DEFAULT (strftime('%s', 'now')),
-                updated_at INTEGER DEFAULT (strftime('%s', 'now'))
+                updated_at INTEGER DEFAULT (strftime('%s', 'now')),
+                PRIMARY KEY (domain, record_type)
             )",
         )
         .execute(&self.pool)
@@ -40,66 +120,283 @@ impl SqliteDomainStore {
         let query = r"CREATE TRIGGER IF NOT EXISTS update_domain_mappings_timestamp
                 AFTER UPDATE ON domain_mappings
                 BEGIN
-                    UPDATE domain_mappings SET updated_at = strftime('%s', 'now') WHERE domain = NEW.domain;
+                    UPDATE domain_mappings SET updated_at = strftime('%s', 'now')
+                    WHERE domain = NEW.domain AND record_type = NEW.record_type;
                 END";
         sqlx::query(query).execute(&self.pool).await?;
+        self.initialize_search_index().await?;
+
+        sqlx::query("CREATE TABLE IF NOT EXISTS settings (key TEXT PRIMARY KEY, value TEXT NOT NULL)")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS forward_rules (suffix TEXT PRIMARY KEY, upstream TEXT NOT NULL)")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS upstream_mappings (domain TEXT PRIMARY KEY, upstream TEXT NOT NULL)")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("CREATE TABLE IF NOT EXISTS domain_ports (domain TEXT PRIMARY KEY, port INTEGER NOT NULL)")
+            .execute(&self.pool)
+            .await?;
 
         Ok(())
     }
 
-    pub async fn set(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
-        let mut normalized_domain = domain.to_ascii_lowercase();
-        if normalized_domain.ends_with('.') {
-            normalized_domain.pop();
+    /// A trigram full-text index over `domain_mappings.domain` for
+    /// `search`, kept in step by triggers. `upsert` updates rows in place,
+    /// since a REPLACE would skip the delete trigger. Filled on first use
+    /// for databases from before the index.
+    async fn initialize_search_index(&self) -> Result<()> {
+        let exists: Option<String> =
+            sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'domain_search'")
+                .fetch_optional(&self.pool)
+                .await?;
+        sqlx::query(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS domain_search USING fts5(
+                domain, content = 'domain_mappings', content_rowid = 'rowid', tokenize = 'trigram'
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        let query = r"CREATE TRIGGER IF NOT EXISTS domain_search_insert
+                AFTER INSERT ON domain_mappings
+                BEGIN
+                    INSERT INTO domain_search (rowid, domain) VALUES (NEW.rowid, NEW.domain);
+                END";
+        sqlx::query(query).execute(&self.pool).await?;
+        let query = r"CREATE TRIGGER IF NOT EXISTS domain_search_delete
+                AFTER DELETE ON domain_mappings
+                BEGIN
+                    INSERT INTO domain_search (domain_search, rowid, domain) VALUES ('delete', OLD.rowid, OLD.domain);
+                END";
+        sqlx::query(query).execute(&self.pool).await?;
+        if exists.is_none() {
+            sqlx::query("INSERT INTO domain_search (domain_search) VALUES ('rebuild')").execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites a `domain_mappings` table from before record types, with
+    /// one integer column per octet, into `record_type` + `rdata` rows.
+    /// Runs in one transaction, so an interrupted migration is redone on
+    /// the next open.
+    async fn migrate_ip_columns(&self) -> Result<()> {
+        let (legacy,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM pragma_table_info('domain_mappings') WHERE name = 'ip_a'")
+                .fetch_one(&self.pool)
+                .await?;
+        if legacy == 0 {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DROP TRIGGER IF EXISTS update_domain_mappings_timestamp").execute(&mut *tx).await?;
+        sqlx::query("ALTER TABLE domain_mappings RENAME TO domain_mappings_legacy").execute(&mut *tx).await?;
+        sqlx::query(
+            "CREATE TABLE domain_mappings (
+                domain TEXT NOT NULL,
+                record_type TEXT NOT NULL,
+                rdata BLOB NOT NULL,
+                created_at INTEGER DEFAULT (strftime('%s', 'now')),
+                updated_at INTEGER DEFAULT (strftime('%s', 'now')),
+                PRIMARY KEY (domain, record_type)
+            )",
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let rows = sqlx::query_as::<_, (String, i32, i32, i32, i32, Option<i64>, Option<i64>)>(
+            "SELECT domain, ip_a, ip_b, ip_c, ip_d, created_at, updated_at FROM domain_mappings_legacy",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let migrated = rows.len();
+        for (domain, a, b, c, d, created_at, updated_at) in rows {
+            sqlx::query(
+                "INSERT INTO domain_mappings (domain, record_type, rdata, created_at, updated_at) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(domain)
+            .bind(TYPE_A)
+            .bind(vec![a as u8, b as u8, c as u8, d as u8])
+            .bind(created_at)
+            .bind(updated_at)
+            .execute(&mut *tx)
+            .await?;
         }
+        sqlx::query("DROP TABLE domain_mappings_legacy").execute(&mut *tx).await?;
+        tx.commit().await?;
 
-        let octets = ip.octets();
+        log::info!("Migrated {} domain mapping(s) to the record type schema", migrated);
+        Ok(())
+    }
+
+    /// The upstream saved with `set_upstream`, if any.
+    pub async fn upstream(&self) -> Result<Option<SocketAddr>> {
+        let value: Option<String> = sqlx::query_scalar("SELECT value FROM settings WHERE key = ?")
+            .bind(UPSTREAM_KEY)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(value.and_then(|v| match v.parse() {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                log::warn!("Ignoring invalid stored upstream {:?}", v);
+                None
+            }
+        }))
+    }
 
+    pub async fn set_upstream(&self, addr: SocketAddr) -> Result<()> {
+        sqlx::query("INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+            .bind(UPSTREAM_KEY)
+            .bind(addr.to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn forward_rules(&self) -> Result<Vec<ForwardRule>> {
+        let rows = sqlx::query("SELECT suffix, upstream FROM forward_rules ORDER BY suffix")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut rules = Vec::with_capacity(rows.len());
+        for row in rows {
+            let suffix: String = row.get("suffix");
+            let upstream: String = row.get("upstream");
+            match upstream.parse() {
+                Ok(upstream) => rules.push(ForwardRule { suffix, upstream }),
+                Err(_) => log::warn!("Ignoring forward rule for {} with invalid upstream {:?}", suffix, upstream),
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Saves `rule`, replacing any rule for the same suffix.
+    pub async fn set_forward_rule(&self, rule: &ForwardRule) -> Result<()> {
         sqlx::query(
-            "INSERT OR REPLACE INTO domain_mappings (domain, ip_a, ip_b, ip_c, ip_d) VALUES (?, ?, ?, ?, ?)",
+            "INSERT INTO forward_rules (suffix, upstream) VALUES (?, ?) ON CONFLICT(suffix) DO UPDATE SET upstream = excluded.upstream",
         )
-        .bind(&normalized_domain)
-        .bind(octets[0] as i32)
-        .bind(octets[1] as i32)
-        .bind(octets[2] as i32)
-        .bind(octets[3] as i32)
+        .bind(&rule.suffix)
+        .bind(rule.upstream.to_string())
         .execute(&self.pool)
         .await?;
+        Ok(())
+    }
 
+    pub async fn remove_forward_rule(&self, suffix: &str) -> Result<()> {
+        sqlx::query("DELETE FROM forward_rules WHERE suffix = ?")
+            .bind(suffix)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
-    pub async fn remove(&self, domain: &str) -> Result<()> {
-        let mut normalized_domain = domain.to_ascii_lowercase();
-        if normalized_domain.ends_with('.') {
-            normalized_domain.pop();
+    /// Mappings whose value is an upstream resolver rather than an address.
+    pub async fn upstream_mappings(&self) -> Result<Vec<(String, SocketAddr)>> {
+        let rows = sqlx::query("SELECT domain, upstream FROM upstream_mappings ORDER BY domain")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut mappings = Vec::with_capacity(rows.len());
+        for row in rows {
+            let domain: String = row.get("domain");
+            let upstream: String = row.get("upstream");
+            match upstream.parse() {
+                Ok(upstream) => mappings.push((domain, upstream)),
+                Err(_) => log::warn!("Ignoring upstream mapping for {} with invalid upstream {:?}", domain, upstream),
+            }
         }
+        Ok(mappings)
+    }
+
+    pub async fn set_upstream_mapping(&self, domain: &str, upstream: SocketAddr) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO upstream_mappings (domain, upstream) VALUES (?, ?) ON CONFLICT(domain) DO UPDATE SET upstream = excluded.upstream",
+        )
+        .bind(domain)
+        .bind(upstream.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_upstream_mapping(&self, domain: &str) -> Result<()> {
+        sqlx::query("DELETE FROM upstream_mappings WHERE domain = ?")
+            .bind(domain)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Target ports of mappings, used by the HTTP proxy.
+    pub async fn domain_ports(&self) -> Result<Vec<(String, u16)>> {
+        let rows = sqlx::query("SELECT domain, port FROM domain_ports ORDER BY domain")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.iter().map(|row| (row.get("domain"), row.get::<u16, _>("port"))).collect())
+    }
 
-        sqlx::query("DELETE FROM domain_mappings WHERE domain = ?")
-            .bind(&normalized_domain)
+    pub async fn set_domain_port(&self, domain: &str, port: u16) -> Result<()> {
+        sqlx::query("INSERT INTO domain_ports (domain, port) VALUES (?, ?) ON CONFLICT(domain) DO UPDATE SET port = excluded.port")
+            .bind(domain)
+            .bind(port)
             .execute(&self.pool)
             .await?;
+        Ok(())
+    }
 
+    pub async fn remove_domain_port(&self, domain: &str) -> Result<()> {
+        sqlx::query("DELETE FROM domain_ports WHERE domain = ?")
+            .bind(domain)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
-    pub async fn resolve(&self, qname: &str) -> Result<Option<Ipv4Addr>> {
-        let mut normalized_qname = qname.to_ascii_lowercase();
-        if normalized_qname.ends_with('.') {
-            normalized_qname.pop();
-        }
+    pub async fn set(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        let domain = NormalizedName::checked(domain).map_err(|e| Error::InvalidDomain(domain.to_string(), e))?;
+        upsert(domain.into_string(), ip).execute(&self.pool).await?;
 
-        if let Some(ip) = self.get_exact_match(&normalized_qname).await? {
-            return Ok(Some(ip));
+        Ok(())
+    }
+
+    pub async fn remove(&self, domain: &str) -> Result<()> {
+        delete(NormalizedName::new(domain).into_string()).execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    /// Applies sets (`Some`) and removals (`None`) of already-normalized
+    /// domains in one transaction.
+    pub async fn apply_batch(&self, ops: &[(String, Option<Ipv4Addr>)]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for (domain, ip) in ops {
+            match ip {
+                Some(ip) => upsert(domain.clone(), *ip).execute(&mut *tx).await?,
+                None => delete(domain.clone()).execute(&mut *tx).await?,
+            };
         }
+        tx.commit().await?;
+        Ok(())
+    }
 
-        let labels: Vec<&str> = normalized_qname.split('.').collect();
-        for i in 0..labels.len().saturating_sub(1) {
-            let suffix = labels[i + 1..].join(".");
-            let wildcard = format!("*.{}", suffix);
+    pub async fn resolve(&self, qname: &str) -> Result<Option<Ipv4Addr>> {
+        Ok(self.resolve_with(qname, |_| None).await?.map(|(_, ip)| ip))
+    }
 
-            if let Some(ip) = self.get_exact_match(&wildcard).await? {
-                return Ok(Some(ip));
+    /// Resolves with `staged` consulted before the table for every candidate
+    /// key: `Some(Some(ip))` answers, `Some(None)` hides the stored row.
+    /// Returns the key that matched along with the address.
+    pub(crate) async fn resolve_with(
+        &self,
+        qname: &str,
+        staged: impl Fn(&str) -> Option<Option<Ipv4Addr>>,
+    ) -> Result<Option<(String, Ipv4Addr)>> {
+        for key in candidate_keys(&NormalizedName::new(qname), self.wildcards) {
+            let found = match staged(&key) {
+                Some(staged) => staged,
+                None => self.get_exact_match(&key).await?,
+            };
+            if let Some(ip) = found {
+                return Ok(Some((key, ip)));
             }
         }
 
@@ -107,39 +404,142 @@ impl SqliteDomainStore {
     }
 
     async fn get_exact_match(&self, domain: &str) -> Result<Option<Ipv4Addr>> {
-        let row = sqlx::query_as::<_, (i32, i32, i32, i32)>(
-            "SELECT ip_a, ip_b, ip_c, ip_d FROM domain_mappings WHERE domain = ?",
-        )
-        .bind(domain)
-        .fetch_optional(&self.pool)
-        .await?;
+        let rdata: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT rdata FROM domain_mappings WHERE domain = ? AND record_type = ?")
+                .bind(domain)
+                .bind(TYPE_A)
+                .fetch_optional(&self.pool)
+                .await?;
 
-        if let Some((ip_a, ip_b, ip_c, ip_d)) = row {
-            let ip = Ipv4Addr::new(ip_a as u8, ip_b as u8, ip_c as u8, ip_d as u8);
-            Ok(Some(ip))
-        } else {
-            Ok(None)
-        }
+        Ok(rdata.and_then(|rdata| decode_a(domain, &rdata)))
     }
 
     pub async fn list(&self) -> Result<Vec<(String, Ipv4Addr)>> {
-        let rows = sqlx::query_as::<_, (String, i32, i32, i32, i32)>(
-            "SELECT domain, ip_a, ip_b, ip_c, ip_d FROM domain_mappings ORDER BY domain",
+        let rows = sqlx::query_as::<_, (String, Vec<u8>)>(
+            "SELECT domain, rdata FROM domain_mappings WHERE record_type = ? ORDER BY domain",
         )
+        .bind(TYPE_A)
         .fetch_all(&self.pool)
         .await?;
 
-        let mut result = Vec::new();
-        for (domain, ip_a, ip_b, ip_c, ip_d) in rows {
-            let ip = Ipv4Addr::new(ip_a as u8, ip_b as u8, ip_c as u8, ip_d as u8);
-            result.push((domain, ip));
+        Ok(rows
+            .into_iter()
+            .filter_map(|(domain, rdata)| decode_a(&domain, &rdata).map(|ip| (domain, ip)))
+            .collect())
+    }
+
+    /// One page of `list`, filtered and sorted by SQLite.
+    pub async fn list_paged(&self, query: &ListQuery) -> Result<DomainPage> {
+        // LIKE ignores ASCII case, matching `ListQuery::matches`
+        let pattern = query.filter.as_deref().map(like_pattern);
+        let filter = if pattern.is_some() { "AND domain LIKE ? ESCAPE '\\'" } else { "" };
+        // rdata compares bytewise, which for A records is address order
+        let order = match query.sort {
+            ListSort::DomainAsc => "domain",
+            ListSort::DomainDesc => "domain DESC",
+            ListSort::IpAsc => "rdata, domain",
+            ListSort::IpDesc => "rdata DESC, domain",
+        };
+
+        let sql = format!(
+            "SELECT domain, rdata FROM domain_mappings WHERE record_type = ? {} ORDER BY {} LIMIT ? OFFSET ?",
+            filter, order
+        );
+        let mut rows = sqlx::query_as::<_, (String, Vec<u8>)>(&sql).bind(TYPE_A);
+        let count_sql = format!("SELECT COUNT(*) FROM domain_mappings WHERE record_type = ? {}", filter);
+        let mut count = sqlx::query_as::<_, (i64,)>(&count_sql).bind(TYPE_A);
+        if let Some(pattern) = &pattern {
+            rows = rows.bind(pattern);
+            count = count.bind(pattern);
         }
+        // a negative LIMIT means none
+        let limit = query.limit.map_or(-1, |l| l.min(i64::MAX as usize) as i64);
+        let rows = rows
+            .bind(limit)
+            .bind(query.offset.min(i64::MAX as usize) as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let (total,) = count.fetch_one(&self.pool).await?;
+
+        let domains = rows
+            .into_iter()
+            .filter_map(|(domain, rdata)| decode_a(&domain, &rdata).map(|ip| (domain, ip)))
+            .collect();
+        Ok(DomainPage { domains, total: total as usize })
+    }
+
+    /// Up to `limit` domains containing `term`, ignoring case, best match
+    /// first as `store::search_rank` orders them. Terms of three or more
+    /// characters are looked up in the trigram index, shorter ones with
+    /// a LIKE scan.
+    pub async fn search(&self, term: &str, limit: usize) -> Result<Vec<(String, Ipv4Addr)>> {
+        let term = search_term(term);
+        if term.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (filter, pattern) = if term.chars().count() >= MIN_INDEXED_TERM {
+            // a quoted phrase, so the term's punctuation is not FTS syntax
+            let phrase = format!("\"{}\"", term.replace('"', "\"\""));
+            ("rowid IN (SELECT rowid FROM domain_search WHERE domain_search MATCH ?3)", phrase)
+        } else {
+            ("domain LIKE ?3 ESCAPE '\\'", like_pattern(&term))
+        };
+        let sql = format!(
+            "SELECT domain, rdata FROM domain_mappings WHERE record_type = ?1 AND {}
+             ORDER BY CASE
+                 WHEN domain = ?2 THEN 0
+                 WHEN instr(domain, ?2) = 1 THEN 1
+                 WHEN instr(domain, '.' || ?2) > 0 THEN 2
+                 ELSE 3
+             END, length(domain), domain
+             LIMIT ?4",
+            filter
+        );
+        let rows = sqlx::query_as::<_, (String, Vec<u8>)>(&sql)
+            .bind(TYPE_A)
+            .bind(&term)
+            .bind(pattern)
+            .bind(limit.min(i64::MAX as usize) as i64)
+            .fetch_all(&self.pool)
+            .await?;
 
-        Ok(result)
+        Ok(rows
+            .into_iter()
+            .filter_map(|(domain, rdata)| decode_a(&domain, &rdata).map(|ip| (domain, ip)))
+            .collect())
+    }
+
+    /// Returns free pages to the filesystem and refreshes the query
+    /// planner's statistics. A database created without incremental
+    /// auto-vacuum is converted with one full VACUUM first. Returns the
+    /// number of pages freed.
+    pub async fn maintain(&self) -> Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        let (auto_vacuum,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum").fetch_one(&mut *conn).await?;
+        let (before,): (i64,) = sqlx::query_as("PRAGMA freelist_count").fetch_one(&mut *conn).await?;
+        if auto_vacuum != 2 {
+            log::info!("Converting the database to incremental auto-vacuum");
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL").execute(&mut *conn).await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+            // VACUUM may renumber the rowids the search index refers to
+            sqlx::query("INSERT INTO domain_search (domain_search) VALUES ('rebuild')").execute(&mut *conn).await?;
+        } else {
+            sqlx::query("PRAGMA incremental_vacuum").execute(&mut *conn).await?;
+        }
+        let (after,): (i64,) = sqlx::query_as("PRAGMA freelist_count").fetch_one(&mut *conn).await?;
+        sqlx::query("ANALYZE").execute(&mut *conn).await?;
+        Ok(before.saturating_sub(after).max(0) as u64)
+    }
+
+    /// Round-trips a trivial query to check the database is usable.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
     }
 
     pub async fn count(&self) -> Result<i64> {
-        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM domain_mappings")
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM domain_mappings WHERE record_type = ?")
+            .bind(TYPE_A)
             .fetch_one(&self.pool)
             .await?;
 
@@ -154,3 +554,33 @@ impl SqliteDomainStore {
         Ok(())
     }
 }
+
+fn upsert<'q>(domain: String, ip: Ipv4Addr) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    sqlx::query(
+        "INSERT INTO domain_mappings (domain, record_type, rdata) VALUES (?, ?, ?)
+         ON CONFLICT (domain, record_type) DO UPDATE SET rdata = excluded.rdata",
+    )
+        .bind(domain)
+        .bind(TYPE_A)
+        .bind(ip.octets().to_vec())
+}
+
+/// The address in an A row, skipping rows whose rdata is not four bytes.
+fn decode_a(domain: &str, rdata: &[u8]) -> Option<Ipv4Addr> {
+    match <[u8; 4]>::try_from(rdata) {
+        Ok(octets) => Some(Ipv4Addr::from(octets)),
+        Err(_) => {
+            log::warn!("Ignoring A mapping for {} with {}-byte rdata", domain, rdata.len());
+            None
+        }
+    }
+}
+
+/// `%filter%`, with LIKE's wildcards in `filter` matched literally.
+fn like_pattern(filter: &str) -> String {
+    format!("%{}%", filter.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+fn delete<'q>(domain: String) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+    sqlx::query("DELETE FROM domain_mappings WHERE domain = ?").bind(domain)
+}
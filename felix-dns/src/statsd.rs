@@ -0,0 +1,162 @@
+//! statsd exporter for query counts and latencies, for setups built around
+//! Datadog or another statsd agent rather than OTLP.
+//!
+//! The UDP handler hands each query to a bounded queue; a background task
+//! sums the counts and sends them with the latency samples once per flush
+//! interval, packed into as few datagrams as fit. Samples are dropped when
+//! the queue is full rather than slowing queries down.
+
+use std::{collections::HashMap, fmt::Write as _, net::SocketAddr, time::Duration};
+
+use hickory_proto::rr::RecordType;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    ResolverState, Result,
+    events::{AnswerSource, QueryOutcome},
+};
+
+/// Keeps datagrams under a typical MTU after IP and UDP headers.
+const MAX_DATAGRAM: usize = 1432;
+/// Samples queued between flushes before new ones are dropped.
+const QUEUE_CAPACITY: usize = 65_536;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdConfig {
+    /// The statsd agent, usually `127.0.0.1:8125`.
+    pub addr: SocketAddr,
+    /// Prepended to every metric name, e.g. `felix.queries`.
+    pub prefix: String,
+    /// Put outcome and query type in DogStatsD tags (`|#outcome:local`)
+    /// instead of the metric name (`felix.queries.local.a`), for agents
+    /// that understand them. Only tags carry the answer source
+    /// (`source:upstream-1.1.1.1:53`), which would make too many names.
+    pub tags: bool,
+    pub flush_interval: Duration,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8125".parse().unwrap(),
+            prefix: "felix".to_string(),
+            tags: false,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One handled query.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QuerySample {
+    pub outcome: QueryOutcome,
+    pub source: AnswerSource,
+    pub qtype: RecordType,
+    pub elapsed: Duration,
+}
+
+pub struct StatsdHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl StatsdHandle {
+    /// Sends what is left since the last flush and stops.
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+pub async fn start_statsd(config: StatsdConfig, state: ResolverState) -> Result<StatsdHandle> {
+    let bind: SocketAddr = if config.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(config.addr).await?;
+
+    log::info!("Sending statsd metrics to {}", config.addr);
+
+    let (tx, mut rx) = mpsc::channel(QUEUE_CAPACITY);
+    state.set_query_samples(Some(tx));
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let mut tick = tokio::time::interval(config.flush_interval);
+        let mut samples = Vec::new();
+        loop {
+            let stopping = tokio::select! {
+                _ = &mut shutdown_rx => true,
+                _ = tick.tick() => false,
+            };
+            while let Ok(sample) = rx.try_recv() {
+                samples.push(sample);
+            }
+            for datagram in encode(&config, &samples) {
+                if let Err(e) = socket.send(datagram.as_bytes()).await {
+                    log::debug!("statsd send to {} failed: {:?}", config.addr, e);
+                }
+            }
+            samples.clear();
+            if stopping {
+                break;
+            }
+        }
+        state.set_query_samples(None);
+    });
+
+    Ok(StatsdHandle {
+        shutdown_tx: Some(shutdown_tx),
+        task,
+    })
+}
+
+/// statsd lines for one flush, packed into datagrams: a counter per
+/// outcome and query type (and answer source, with tags), and every
+/// latency sample as a timer.
+fn encode(config: &StatsdConfig, samples: &[QuerySample]) -> Vec<String> {
+    let mut counts: HashMap<(QueryOutcome, RecordType, Option<AnswerSource>), u64> = HashMap::new();
+    for s in samples {
+        let source = config.tags.then_some(s.source);
+        *counts.entry((s.outcome, s.qtype, source)).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|((outcome, qtype, source), _)| {
+        (outcome.as_str(), qtype.to_string(), source.map(|s| s.to_string()))
+    });
+
+    let prefix = &config.prefix;
+    let mut lines = Vec::with_capacity(counts.len() + samples.len());
+    for ((outcome, qtype, source), n) in counts {
+        let outcome = outcome.as_str();
+        let qtype = qtype.to_string().to_ascii_lowercase();
+        lines.push(match source {
+            Some(source) => format!("{prefix}.queries:{n}|c|#outcome:{outcome},qtype:{qtype},source:{source}"),
+            None => format!("{prefix}.queries.{outcome}.{qtype}:{n}|c"),
+        });
+    }
+    for s in samples {
+        let outcome = s.outcome.as_str();
+        let ms = s.elapsed.as_secs_f64() * 1000.0;
+        lines.push(if config.tags {
+            format!("{prefix}.query_time:{ms:.3}|ms|#outcome:{outcome},source:{}", s.source)
+        } else {
+            format!("{prefix}.query_time.{outcome}:{ms:.3}|ms")
+        });
+    }
+
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(d) if d.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                let _ = write!(d, "\n{}", line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
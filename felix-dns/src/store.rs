@@ -0,0 +1,261 @@
+//! The persistent layer under the in-memory overlay, one variant per
+//! enabled backend feature.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ForwardRule, Result, name::NormalizedName};
+#[cfg(feature = "redb")]
+use crate::redb_domain_store::RedbDomainStore;
+#[cfg(feature = "sqlite")]
+use crate::sqlite_domain_store::SqliteDomainStore;
+
+/// Order of a listing; ties are broken by domain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListSort {
+    #[default]
+    #[serde(rename = "domain")]
+    DomainAsc,
+    #[serde(rename = "-domain")]
+    DomainDesc,
+    #[serde(rename = "ip")]
+    IpAsc,
+    #[serde(rename = "-ip")]
+    IpDesc,
+}
+
+/// One page of a domain listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListQuery {
+    pub offset: usize,
+    /// `None` lists everything after `offset`.
+    pub limit: Option<usize>,
+    /// Only domains containing this text, ignoring case.
+    pub filter: Option<String>,
+    pub sort: ListSort,
+}
+
+impl ListQuery {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|f| domain.to_ascii_lowercase().contains(&f.to_ascii_lowercase()))
+    }
+
+    /// Filters, sorts and slices a full listing, for stores that cannot do
+    /// it themselves.
+    pub fn apply(&self, mut domains: Vec<(String, Ipv4Addr)>) -> DomainPage {
+        domains.retain(|(d, _)| self.matches(d));
+        match self.sort {
+            ListSort::DomainAsc => domains.sort(),
+            ListSort::DomainDesc => domains.sort_by(|a, b| b.0.cmp(&a.0)),
+            ListSort::IpAsc => domains.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))),
+            ListSort::IpDesc => domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        }
+        let total = domains.len();
+        let domains = domains
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect();
+        DomainPage { domains, total }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainPage {
+    pub domains: Vec<(String, Ipv4Addr)>,
+    /// Domains matching the filter across all pages.
+    pub total: usize,
+}
+
+/// How well `domain` matches a search for `term`, best first, or `None`
+/// when it does not contain `term` at all: the whole name, then names
+/// starting with it, then names with a label starting with it, then the
+/// rest; shorter names first within each. Both must be lowercase.
+/// `SqliteDomainStore::search` orders the same way in SQL.
+pub(crate) fn search_rank(domain: &str, term: &str) -> Option<(u8, usize)> {
+    let at = domain.find(term)?;
+    let kind = if domain == term {
+        0
+    } else if at == 0 {
+        1
+    } else if domain.contains(&format!(".{}", term)) {
+        2
+    } else {
+        3
+    };
+    Some((kind, domain.len()))
+}
+
+/// The search term as domains are stored, see `NormalizedName`: `bücher`
+/// finds `xn--bcher-kva.dev`.
+pub(crate) fn search_term(term: &str) -> String {
+    NormalizedName::new(term.trim()).into_string()
+}
+
+/// Up to `limit` of `domains` matching `term`, best first, for stores
+/// without an index.
+pub(crate) fn search_list(domains: Vec<(String, Ipv4Addr)>, term: &str, limit: usize) -> Vec<(String, Ipv4Addr)> {
+    let term = search_term(term);
+    if term.is_empty() {
+        return Vec::new();
+    }
+    let mut found: Vec<_> = domains
+        .into_iter()
+        .filter_map(|(domain, ip)| Some((search_rank(&domain, &term)?, domain, ip)))
+        .collect();
+    found.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    found.into_iter().take(limit).map(|(_, domain, ip)| (domain, ip)).collect()
+}
+
+#[derive(Clone)]
+pub enum PersistentStore {
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteDomainStore),
+    #[cfg(feature = "redb")]
+    Redb(RedbDomainStore),
+}
+
+/// Dispatches `$call` to whichever backend `$store` holds.
+macro_rules! dispatch {
+    ($store:expr, $s:ident => $call:expr) => {
+        match *$store {
+            #[cfg(feature = "sqlite")]
+            PersistentStore::Sqlite(ref $s) => $call,
+            #[cfg(feature = "redb")]
+            PersistentStore::Redb(ref $s) => $call,
+        }
+    };
+}
+
+// with no backend compiled in the enum is empty and the arguments unused
+#[cfg_attr(not(any(feature = "sqlite", feature = "redb")), allow(unused_variables))]
+impl PersistentStore {
+    pub async fn set(&self, domain: &str, ip: Ipv4Addr) -> Result<()> {
+        dispatch!(self, s => s.set(domain, ip).await)
+    }
+
+    pub async fn remove(&self, domain: &str) -> Result<()> {
+        dispatch!(self, s => s.remove(domain).await)
+    }
+
+    pub async fn apply_batch(&self, ops: &[(String, Option<Ipv4Addr>)]) -> Result<()> {
+        dispatch!(self, s => s.apply_batch(ops).await)
+    }
+
+    pub async fn resolve(&self, qname: &str) -> Result<Option<Ipv4Addr>> {
+        Ok(self.resolve_with(qname, |_| None).await?.map(|(_, ip)| ip))
+    }
+
+    pub(crate) async fn resolve_with(
+        &self,
+        qname: &str,
+        staged: impl Fn(&str) -> Option<Option<Ipv4Addr>>,
+    ) -> Result<Option<(String, Ipv4Addr)>> {
+        dispatch!(self, s => s.resolve_with(qname, staged).await)
+    }
+
+    pub async fn list(&self) -> Result<Vec<(String, Ipv4Addr)>> {
+        dispatch!(self, s => s.list().await)
+    }
+
+    pub async fn list_paged(&self, query: &ListQuery) -> Result<DomainPage> {
+        dispatch!(self, s => s.list_paged(query).await)
+    }
+
+    pub async fn search(&self, term: &str, limit: usize) -> Result<Vec<(String, Ipv4Addr)>> {
+        dispatch!(self, s => s.search(term, limit).await)
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        dispatch!(self, s => s.ping().await)
+    }
+
+    pub async fn upstream(&self) -> Result<Option<SocketAddr>> {
+        dispatch!(self, s => s.upstream().await)
+    }
+
+    pub async fn set_upstream(&self, addr: SocketAddr) -> Result<()> {
+        dispatch!(self, s => s.set_upstream(addr).await)
+    }
+
+    pub async fn forward_rules(&self) -> Result<Vec<ForwardRule>> {
+        dispatch!(self, s => s.forward_rules().await)
+    }
+
+    pub async fn set_forward_rule(&self, rule: &ForwardRule) -> Result<()> {
+        dispatch!(self, s => s.set_forward_rule(rule).await)
+    }
+
+    pub async fn remove_forward_rule(&self, suffix: &str) -> Result<()> {
+        dispatch!(self, s => s.remove_forward_rule(suffix).await)
+    }
+
+    pub async fn upstream_mappings(&self) -> Result<Vec<(String, SocketAddr)>> {
+        dispatch!(self, s => s.upstream_mappings().await)
+    }
+
+    pub async fn set_upstream_mapping(&self, domain: &str, upstream: SocketAddr) -> Result<()> {
+        dispatch!(self, s => s.set_upstream_mapping(domain, upstream).await)
+    }
+
+    pub async fn remove_upstream_mapping(&self, domain: &str) -> Result<()> {
+        dispatch!(self, s => s.remove_upstream_mapping(domain).await)
+    }
+
+    pub async fn domain_ports(&self) -> Result<Vec<(String, u16)>> {
+        dispatch!(self, s => s.domain_ports().await)
+    }
+
+    pub async fn set_domain_port(&self, domain: &str, port: u16) -> Result<()> {
+        dispatch!(self, s => s.set_domain_port(domain, port).await)
+    }
+
+    pub async fn remove_domain_port(&self, domain: &str) -> Result<()> {
+        dispatch!(self, s => s.remove_domain_port(domain).await)
+    }
+}
+
+/// How wildcard keys match names, see `candidate_keys`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Wildcards {
+    /// `*.example.dev` and `**.example.dev` also answer `example.dev`
+    /// itself (an exact `example.dev` entry still wins).
+    pub apex: bool,
+}
+
+/// Keys to look up for a normalized name, in precedence order:
+///
+/// 1. the name itself;
+/// 2. `*.name` then `**.name` when wildcards cover their apex;
+/// 3. `*.parent` then `**.parent`: `*.` matches exactly one label, as
+///    in DNS;
+/// 4. `**.ancestor` for every further ancestor, closest first: `**.`
+///    matches any depth.
+///
+/// So `a.b.app.dev` is answered by `*.b.app.dev`, then `**.b.app.dev`,
+/// then `**.app.dev`; `*.app.dev` does not match it.
+pub(crate) fn candidate_keys(name: &str, wildcards: Wildcards) -> Vec<String> {
+    let mut keys = vec![name.to_string()];
+    if wildcards.apex {
+        keys.push(format!("*.{}", name));
+        keys.push(format!("**.{}", name));
+    }
+    let labels: Vec<&str> = name.split('.').collect();
+    for i in 0..labels.len().saturating_sub(1) {
+        let ancestor = labels[i + 1..].join(".");
+        if i == 0 {
+            keys.push(format!("*.{}", ancestor));
+        }
+        keys.push(format!("**.{}", ancestor));
+    }
+    keys
+}
+
+/// The name a wildcard key is anchored at, e.g. `app.dev` for both
+/// `*.app.dev` and `**.app.dev`; `None` for an exact key.
+pub(crate) fn wildcard_base(key: &str) -> Option<&str> {
+    key.strip_prefix("**.").or_else(|| key.strip_prefix("*."))
+}
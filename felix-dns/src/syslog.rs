@@ -0,0 +1,154 @@
+//! RFC 5424 syslog backend for the `log` facade.
+
+use std::{
+    fmt::Write as _,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{Error, Result};
+
+/// `daemon` facility.
+const FACILITY: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTarget {
+    /// Local syslog daemon socket, usually `/dev/log`.
+    Unix(PathBuf),
+    /// Remote collector.
+    Udp(SocketAddr),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = Error;
+
+    /// Parses `unix:///dev/log` or `udp://host:port`.
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(SyslogTarget::Unix(path.into()));
+        }
+        if let Some(addr) = s.strip_prefix("udp://") {
+            return addr
+                .parse()
+                .map(SyslogTarget::Udp)
+                .map_err(|_| Error::Io(std::io::Error::other(format!("invalid syslog address {:?}", addr))));
+        }
+        Err(Error::Io(std::io::Error::other(format!(
+            "unsupported syslog target {:?} (expected unix://PATH or udp://HOST:PORT)",
+            s
+        ))))
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket, SocketAddr),
+}
+
+pub struct SyslogLogger {
+    transport: Transport,
+    level: LevelFilter,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl SyslogLogger {
+    pub fn new(target: &SyslogTarget, level: LevelFilter) -> Result<Self> {
+        let transport = match target {
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Transport::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Unix(_) => {
+                return Err(Error::Io(std::io::Error::other("unix syslog sockets are not supported here")));
+            }
+            SyslogTarget::Udp(addr) => {
+                let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+                Transport::Udp(UdpSocket::bind(bind)?, *addr)
+            }
+        };
+
+        Ok(Self {
+            transport,
+            level,
+            hostname: hostname(),
+            app_name: "felix".to_string(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Installs this logger as the global `log` backend.
+    pub fn init(self) -> Result<()> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))
+            .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID - MSG`, with the
+    /// log target as MSGID.
+    pub fn format(&self, record: &Record) -> String {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let mut line = format!(
+            "<{}>1 {} {} {} {} {} - ",
+            FACILITY * 8 + severity,
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            msg_id(record.target()),
+        );
+        let _ = write!(line, "{}", record.args());
+        line
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        // logging must never take the server down; drop on failure
+        let _ = match &self.transport {
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(line.as_bytes()),
+            Transport::Udp(socket, addr) => socket.send_to(line.as_bytes(), addr),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// MSGID is limited to 32 printable ASCII characters.
+fn msg_id(target: &str) -> String {
+    let id: String = target.chars().filter(|c| c.is_ascii_graphic()).take(32).collect();
+    if id.is_empty() { "-".to_string() } else { id }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,124 @@
+//! Detection of the resolvers the operating system is configured with, so
+//! felix can forward to them instead of a hard-coded public resolver, and
+//! of its search list for single-label names.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::Result;
+
+/// systemd-resolved's stub; the real upstreams are in `RESOLVED_CONF`.
+#[cfg(all(unix, not(target_os = "macos")))]
+const RESOLVED_STUB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 53));
+#[cfg(all(unix, not(target_os = "macos")))]
+const RESOLVED_CONF: &str = "/run/systemd/resolve/resolv.conf";
+
+/// The system's resolvers in order of preference, port 53. Empty when none
+/// are configured.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn system_upstreams() -> Result<Vec<SocketAddr>> {
+    let upstreams = parse_resolv_conf(&std::fs::read_to_string("/etc/resolv.conf")?);
+    if upstreams.iter().all(|u| u.ip() == RESOLVED_STUB)
+        && let Ok(conf) = std::fs::read_to_string(RESOLVED_CONF)
+    {
+        return Ok(parse_resolv_conf(&conf));
+    }
+    Ok(upstreams)
+}
+
+/// The system's resolvers in order of preference, port 53. Empty when none
+/// are configured.
+#[cfg(target_os = "macos")]
+pub fn system_upstreams() -> Result<Vec<SocketAddr>> {
+    // resolv.conf is only a compatibility copy; scutil lists the resolvers
+    // actually in use, the default one first
+    let output = std::process::Command::new("scutil").arg("--dns").output()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let default = text.split("resolver #2").next().unwrap_or_default();
+    let upstreams = dedup(default.lines().filter_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().starts_with("nameserver[").then(|| value.trim().parse().ok())?
+    }));
+    if upstreams.is_empty() {
+        return Ok(parse_resolv_conf(&std::fs::read_to_string("/etc/resolv.conf")?));
+    }
+    Ok(upstreams)
+}
+
+/// The system's resolvers in order of preference, port 53. Empty when none
+/// are configured.
+#[cfg(windows)]
+pub fn system_upstreams() -> Result<Vec<SocketAddr>> {
+    let output = std::process::Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            "(Get-DnsClientServerAddress | Where-Object ServerAddresses).ServerAddresses",
+        ])
+        .output()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    Ok(dedup(text.lines().filter_map(|line| line.trim().parse().ok())))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn system_upstreams() -> Result<Vec<SocketAddr>> {
+    Ok(Vec::new())
+}
+
+/// The search list of /etc/resolv.conf, see `parse_resolv_conf_search`.
+/// Empty when there is none or on systems without one.
+pub fn system_search_domains() -> Result<Vec<String>> {
+    if !cfg!(unix) {
+        return Ok(Vec::new());
+    }
+    match std::fs::read_to_string("/etc/resolv.conf") {
+        Ok(conf) => Ok(parse_resolv_conf_search(&conf)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The search list of a resolv.conf, lowercased and without trailing
+/// dots. As in glibc, the last `search` or `domain` line wins; `domain`
+/// gives a single-entry list.
+pub fn parse_resolv_conf_search(conf: &str) -> Vec<String> {
+    let mut search = Vec::new();
+    for line in conf.lines() {
+        let line = line.split(['#', ';']).next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let limit = match words.next() {
+            Some("search") => usize::MAX,
+            Some("domain") => 1,
+            _ => continue,
+        };
+        search = words
+            .take(limit)
+            .map(|d| d.trim_end_matches('.').to_ascii_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+    }
+    search
+}
+
+/// `nameserver` entries of a resolv.conf, in order.
+pub fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    dedup(conf.lines().filter_map(|line| {
+        let line = line.split(['#', ';']).next()?;
+        let mut words = line.split_whitespace();
+        if words.next()? != "nameserver" {
+            return None;
+        }
+        // link-local IPv6 entries may carry a zone, e.g. fe80::1%eth0
+        words.next()?.split('%').next()?.parse().ok()
+    }))
+}
+
+fn dedup(ips: impl Iterator<Item = IpAddr>) -> Vec<SocketAddr> {
+    let mut upstreams: Vec<SocketAddr> = Vec::new();
+    for ip in ips {
+        let addr = SocketAddr::new(ip, 53);
+        if !ip.is_unspecified() && !upstreams.contains(&addr) {
+            upstreams.push(addr);
+        }
+    }
+    upstreams
+}
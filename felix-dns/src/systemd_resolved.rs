@@ -0,0 +1,116 @@
+//! Coexistence with systemd-resolved, whose stub listener on
+//! 127.0.0.53:53 is what /etc/resolv.conf points at on most Linux
+//! desktops, and which holds port 53 so felix cannot simply take it.
+//!
+//! `ResolvedMode` picks how the system's queries reach felix:
+//!
+//! - `Upstream`: resolved sends every query to felix, which forwards what
+//!   it does not map. felix's own upstream must then not be the stub.
+//! - `Split`: resolved sends only the given domains to felix, on whatever
+//!   port it listens on, and everything else where it did before.
+//! - `TakeOver`: resolved's stub listener is switched off and felix
+//!   answers on 127.0.0.53:53 in its place, so resolv.conf keeps working.
+//!
+//! Every mode writes a resolved.conf drop-in under /run, so it is gone
+//! after a reboot, and restarts resolved; shutdown removes it and restarts
+//! resolved again. All of them need root. Upstream and split make felix a
+//! global DNS server rather than one of a link: resolved refuses per-link
+//! servers on the loopback link. Global servers also serve as a default
+//! route, so in split mode felix still sees other queries now and then and
+//! forwards them. Giving a port in `DNS=` needs systemd 246 or later.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use tokio::process::Command;
+
+use crate::{Error, Result, system_dns::parse_resolv_conf};
+
+/// systemd-resolved's stub listener.
+pub const RESOLVED_STUB: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53)), 53);
+
+const DROP_IN_DIR: &str = "/run/systemd/resolved.conf.d";
+const DROP_IN: &str = "/run/systemd/resolved.conf.d/felix.conf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedMode {
+    /// Make felix resolved's only upstream.
+    Upstream,
+    /// Route these domains, e.g. `test` and `dev`, to felix.
+    Split(Vec<String>),
+    /// Replace resolved's stub listener with felix.
+    TakeOver,
+}
+
+/// Whether /etc/resolv.conf sends queries to resolved's stub.
+pub fn stub_in_use() -> bool {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .map(|conf| parse_resolv_conf(&conf).contains(&RESOLVED_STUB))
+        .unwrap_or(false)
+}
+
+/// Undoes `configure_resolved` on shutdown.
+pub struct ResolvedHandle {
+    drop_in: &'static Path,
+}
+
+impl ResolvedHandle {
+    pub async fn shutdown(self) {
+        let reverted = match tokio::fs::remove_file(self.drop_in).await {
+            Ok(()) => restart_resolved().await,
+            Err(e) => Err(Error::Io(e)),
+        };
+        if let Err(e) = reverted {
+            log::warn!("Failed to revert the systemd-resolved settings: {}", e);
+        }
+    }
+}
+
+/// Routes the system's queries to felix as `mode` says. `felix` is the
+/// address it answers on; with `TakeOver` that must be `RESOLVED_STUB`,
+/// bound after this returns.
+pub async fn configure_resolved(mode: &ResolvedMode, felix: SocketAddr) -> Result<ResolvedHandle> {
+    tokio::fs::create_dir_all(DROP_IN_DIR).await?;
+    tokio::fs::write(DROP_IN, drop_in(mode, felix)).await?;
+    if let Err(e) = restart_resolved().await {
+        let _ = tokio::fs::remove_file(DROP_IN).await;
+        return Err(e);
+    }
+    log::info!("systemd-resolved now sends queries to felix ({:?})", mode);
+    Ok(ResolvedHandle { drop_in: Path::new(DROP_IN) })
+}
+
+/// The resolved.conf drop-in for `mode`.
+pub(crate) fn drop_in(mode: &ResolvedMode, felix: SocketAddr) -> String {
+    let settings = match mode {
+        ResolvedMode::Upstream => format!("DNS={}\nDomains=~.\n", felix),
+        ResolvedMode::Split(domains) => {
+            let domains: Vec<String> = domains.iter().map(|d| format!("~{}", d.trim_matches('.'))).collect();
+            format!("DNS={}\nDomains={}\n", felix, domains.join(" "))
+        }
+        ResolvedMode::TakeOver => "DNSStubListener=no\n".to_string(),
+    };
+    format!("# Written by felix and removed when it stops\n[Resolve]\n{}", settings)
+}
+
+async fn restart_resolved() -> Result<()> {
+    if !Path::new("/run/systemd/system").exists() {
+        return Err(Error::Io(std::io::Error::other("systemd is not running")));
+    }
+    run("systemctl", &["restart", "systemd-resolved"]).await
+}
+
+async fn run(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(format!("running {}: {}", program, e))))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Io(std::io::Error::other(format!("{} {}: {}", program, args.join(" "), stderr.trim()))));
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Shared TCP connections to upstreams. Concurrent queries to the same
+//! upstream are pipelined over one connection and replies are matched by
+//! message ID in whatever order they arrive (RFC 7766 6.2.1), instead of
+//! paying a handshake per query.
+//!
+//! Callers choose their own IDs, so two in-flight queries may carry the
+//! same one; each query gets an ID unique on its connection, counting up
+//! from a random start, and the caller's is restored in the reply.
+//!
+//! Frames are written by one task per connection, so a query cancelled
+//! by its timeout never leaves half a frame on the stream.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{Error, Result};
+
+/// Frames queued for a connection's writer before queries wait.
+const QUEUED_FRAMES: usize = 64;
+
+/// The connection to one upstream, locked while connecting so concurrent
+/// first queries share it.
+type Slot = Arc<tokio::sync::Mutex<Option<Arc<Connection>>>>;
+
+/// Connections by upstream.
+#[derive(Default)]
+pub(crate) struct TcpPipeline {
+    connections: Mutex<HashMap<SocketAddr, Slot>>,
+}
+
+struct Connection {
+    frames: mpsc::Sender<Vec<u8>>,
+    /// Queries waiting for a reply, by the ID sent upstream.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    next_id: AtomicU16,
+    closed: AtomicBool,
+}
+
+impl TcpPipeline {
+    /// Sends `packet` to `upstream` and waits up to `limit` for the reply.
+    /// A reused connection the upstream has since closed is replaced once.
+    pub(crate) async fn query(&self, packet: &[u8], upstream: SocketAddr, limit: Duration) -> Result<Vec<u8>> {
+        timeout(limit, async {
+            let (conn, fresh) = self.connection(upstream).await?;
+            match conn.exchange(packet).await {
+                Ok(reply) => Ok(reply),
+                Err(e) if fresh => Err(e),
+                Err(e) => {
+                    log::debug!("Pipelined connection to {} failed ({}); reconnecting", upstream, e);
+                    let (conn, _) = self.connection(upstream).await?;
+                    conn.exchange(packet).await
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::UpstreamTimeout(upstream))?
+    }
+
+    /// Queries waiting for a reply on the connection to `upstream`.
+    #[cfg(test)]
+    pub(crate) fn in_flight(&self, upstream: SocketAddr) -> usize {
+        let slot = self.connections.lock().get(&upstream).cloned();
+        slot.and_then(|s| s.try_lock().ok()?.as_ref().map(|c| c.pending.lock().len())).unwrap_or(0)
+    }
+
+    /// The open connection to `upstream`, or a new one; `true` when new.
+    async fn connection(&self, upstream: SocketAddr) -> Result<(Arc<Connection>, bool)> {
+        let slot = self.connections.lock().entry(upstream).or_default().clone();
+        let mut slot = slot.lock().await;
+        if let Some(conn) = slot.as_ref()
+            && !conn.closed.load(Ordering::Acquire)
+        {
+            return Ok((conn.clone(), false));
+        }
+
+        let stream = TcpStream::connect(upstream).await?;
+        let (reader, writer) = stream.into_split();
+        let (frames, queued) = mpsc::channel(QUEUED_FRAMES);
+        let conn = Arc::new(Connection {
+            frames,
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(rand::random()),
+            closed: AtomicBool::new(false),
+        });
+        tokio::spawn(read_replies(reader, conn.clone(), upstream));
+        tokio::spawn(write_frames(writer, queued, Arc::downgrade(&conn), upstream));
+        *slot = Some(conn.clone());
+        Ok((conn, true))
+    }
+}
+
+impl Connection {
+    async fn exchange(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < 2 {
+            return Err(Error::Io(std::io::Error::other("query too short")));
+        }
+        let len = u16::try_from(packet.len()).map_err(|_| std::io::Error::other("query too large for TCP"))?;
+        let mut pending = self.register()?;
+
+        let mut framed = Vec::with_capacity(packet.len() + 2);
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(&pending.id.to_be_bytes());
+        framed.extend_from_slice(&packet[2..]);
+        let aborted = || Error::Io(std::io::ErrorKind::ConnectionAborted.into());
+        self.frames.send(framed).await.map_err(|_| aborted())?;
+
+        // dropped without a reply when the connection closes
+        let rx = pending.rx.as_mut().expect("taken only on drop");
+        let mut reply = rx.await.map_err(|_| aborted())?;
+        reply[..2].copy_from_slice(&packet[..2]);
+        Ok(reply)
+    }
+
+    /// An ID not in flight on this connection, with the receiver for its
+    /// reply; fails when all 65536 are.
+    fn register(&self) -> Result<Pending<'_>> {
+        let (tx, rx) = oneshot::channel();
+        let mut pending = self.pending.lock();
+        if pending.len() > usize::from(u16::MAX) {
+            return Err(Error::Io(std::io::Error::other("every query ID is in flight")));
+        }
+        let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        while pending.contains_key(&id) {
+            id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.insert(id, tx);
+        Ok(Pending { conn: self, id, rx: Some(rx) })
+    }
+
+    /// Fails everything in flight; later queries open a new connection.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.pending.lock().clear();
+    }
+}
+
+/// A query waiting for its reply. Dropping it, e.g. on timeout, frees the
+/// ID unless the reply already did.
+struct Pending<'a> {
+    conn: &'a Connection,
+    id: u16,
+    rx: Option<oneshot::Receiver<Vec<u8>>>,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        // with the receiver gone, only our own sender is closed; a later
+        // query given the same ID keeps its entry
+        drop(self.rx.take());
+        let mut pending = self.conn.pending.lock();
+        if pending.get(&self.id).is_some_and(oneshot::Sender::is_closed) {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// Writes queued frames until the connection is dropped or fails.
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut queued: mpsc::Receiver<Vec<u8>>,
+    conn: Weak<Connection>,
+    upstream: SocketAddr,
+) {
+    while let Some(frame) = queued.recv().await {
+        if let Err(e) = writer.write_all(&frame).await {
+            log::debug!("Writing to {} failed: {}", upstream, e);
+            if let Some(conn) = conn.upgrade() {
+                conn.close();
+            }
+            return;
+        }
+    }
+}
+
+/// Hands each reply to the query with its ID until the upstream closes.
+async fn read_replies(mut reader: OwnedReadHalf, conn: Arc<Connection>, upstream: SocketAddr) {
+    loop {
+        let reply = async {
+            let len = reader.read_u16().await?;
+            let mut reply = vec![0u8; usize::from(len)];
+            reader.read_exact(&mut reply).await?;
+            Ok::<_, std::io::Error>(reply)
+        }
+        .await;
+        match reply {
+            Ok(reply) if reply.len() >= 2 => {
+                let id = u16::from_be_bytes([reply[0], reply[1]]);
+                match conn.pending.lock().remove(&id) {
+                    Some(tx) => {
+                        let _ = tx.send(reply);
+                    }
+                    None => log::debug!("Dropping reply {} from {} with no query waiting", id, upstream),
+                }
+            }
+            Ok(_) => log::debug!("Dropping truncated reply from {}", upstream),
+            Err(e) => {
+                log::debug!("TCP connection to {} closed: {}", upstream, e);
+                conn.close();
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! OTLP export of query spans and metrics. Nothing is recorded until
+//! `Telemetry::init` has run.
+
+use std::{
+    net::SocketAddr,
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, Histogram},
+    trace::{Span, SpanKind, Tracer},
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, metrics::SdkMeterProvider, trace::SdkTracerProvider};
+use hickory_proto::rr::RecordType;
+
+use crate::{
+    Error, Result,
+    events::{AnswerSource, QueryOutcome},
+};
+
+struct Instruments {
+    queries: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+/// Installed OTLP providers. Call `shutdown` to flush pending spans and
+/// metrics before exit.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Exports to an OTLP/HTTP collector, e.g. `http://localhost:4318`, and
+    /// installs the global tracer and meter providers.
+    pub fn init(endpoint: &str) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::builder().with_service_name("felix").build();
+
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()
+            .map_err(|e| Error::Telemetry(e.to_string()))?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .build()
+            .map_err(|e| Error::Telemetry(e.to_string()))?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(metrics)
+            .with_resource(resource)
+            .build();
+
+        global::set_tracer_provider(tracer_provider.clone());
+        global::set_meter_provider(meter_provider.clone());
+
+        let meter = global::meter("felix");
+        let _ = INSTRUMENTS.set(Instruments {
+            queries: meter
+                .u64_counter("felix.dns.queries")
+                .with_description("DNS queries answered, by outcome and answer source")
+                .build(),
+            duration: meter
+                .f64_histogram("felix.dns.query.duration")
+                .with_unit("s")
+                .with_description("Time from receiving a query to sending the answer")
+                .build(),
+        });
+
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    pub fn shutdown(self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            log::warn!("Flushing spans failed: {}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            log::warn!("Flushing metrics failed: {}", e);
+        }
+    }
+}
+
+/// Records one answered query as a `dns.query` span plus counter and
+/// duration histogram.
+pub(crate) fn record_query(
+    qname: &str,
+    qtype: RecordType,
+    client: SocketAddr,
+    outcome: QueryOutcome,
+    source: AnswerSource,
+    started: SystemTime,
+) {
+    let Some(instruments) = INSTRUMENTS.get() else {
+        return;
+    };
+    let outcome = outcome.as_str().to_string();
+    let source = source.to_string();
+
+    let elapsed = started.elapsed().unwrap_or_default().as_secs_f64();
+    let attrs = [
+        KeyValue::new("dns.question.type", qtype.to_string()),
+        KeyValue::new("felix.outcome", outcome.clone()),
+        KeyValue::new("felix.answer.source", source.clone()),
+    ];
+    instruments.queries.add(1, &attrs);
+    instruments.duration.record(elapsed, &attrs);
+
+    let tracer = global::tracer("felix");
+    let mut span = tracer
+        .span_builder("dns.query")
+        .with_kind(SpanKind::Server)
+        .with_start_time(started)
+        .with_attributes([
+            KeyValue::new("dns.question.name", qname.trim_end_matches('.').to_string()),
+            KeyValue::new("dns.question.type", qtype.to_string()),
+            KeyValue::new("client.address", client.ip().to_string()),
+            KeyValue::new("felix.outcome", outcome),
+            KeyValue::new("felix.answer.source", source),
+        ])
+        .start(&tracer);
+    span.end();
+}
@@ -0,0 +1,649 @@
+//! Helpers for testing code that forwards through felix.
+//!
+//! `MockUpstream` is a UDP DNS server on localhost answering from a
+//! script, so forwarding, caching and failover can be exercised without a
+//! real resolver:
+//!
+//! ```ignore
+//! let upstream = MockUpstream::start(MockReply::a(Ipv4Addr::new(192, 0, 2, 1))).await?;
+//! upstream.on("slow.example", MockReply::servfail().delayed(Duration::from_secs(3)));
+//! upstream.once("flaky.example", MockReply::truncated());
+//! let state = ResolverState::new(upstream.addr());
+//! ```
+//!
+//! `SimClock` runs such tests on virtual time, so TTLs, timeouts and
+//! delays of minutes take no real time at all.
+//!
+//! `UpstreamFaults` goes the other way: set on a `ResolverState` with
+//! `set_upstream_faults`, it randomly delays, drops or corrupts real
+//! upstream answers, so applications resolving through felix can be
+//! tested against a misbehaving network.
+//!
+//! `PoisonTest` checks a running felix against cache poisoning: it acts
+//! as felix's upstream and races forged answers against the genuine ones,
+//! over UDP, TCP and cache refreshes, then reports whether any forged
+//! address reached the client:
+//!
+//! ```ignore
+//! let harness = PoisonTest::start().await?;
+//! // felix forwarding to harness.addr()
+//! for result in harness.run(felix_addr).await? {
+//!     assert!(result.rejected, "{:?} got through: {:?}", result.spoof, result.answers);
+//! }
+//! ```
+//!
+//! `ResolverState::snapshot` and `restore` reset a shared state between
+//! test cases, keeping its servers running:
+//!
+//! ```ignore
+//! let clean = state.snapshot().await?;
+//! state.add_domain("case.dev", Ipv4Addr::LOCALHOST).await?;
+//! state.restore(&clean).await?;
+//! ```
+//!
+//! Built for felix's own tests and, with the `testing` feature, exported
+//! for embedders.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::oneshot,
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{Error, Result, cache::CacheConfig, proto, rng::XorShift, server_handler::UPSTREAM_TIMEOUT};
+
+pub use crate::resolver_state::StateSnapshot;
+
+/// How the mock answers one query.
+#[derive(Debug, Clone)]
+pub struct MockReply {
+    kind: ReplyKind,
+    ttl: u32,
+    delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum ReplyKind {
+    Records(Vec<RData>),
+    Code(ResponseCode),
+    Truncated,
+    Silent,
+}
+
+impl MockReply {
+    fn new(kind: ReplyKind) -> Self {
+        Self { kind, ttl: 60, delay: Duration::ZERO }
+    }
+
+    /// One A record.
+    pub fn a(ip: Ipv4Addr) -> Self {
+        Self::records(vec![RData::A(ip.into())])
+    }
+
+    /// The records among `data` of the asked type; NOERROR with no
+    /// answers if none match.
+    pub fn records(data: Vec<RData>) -> Self {
+        Self::new(ReplyKind::Records(data))
+    }
+
+    pub fn nxdomain() -> Self {
+        Self::new(ReplyKind::Code(ResponseCode::NXDomain))
+    }
+
+    pub fn servfail() -> Self {
+        Self::new(ReplyKind::Code(ResponseCode::ServFail))
+    }
+
+    pub fn refused() -> Self {
+        Self::new(ReplyKind::Code(ResponseCode::Refused))
+    }
+
+    /// An empty reply with the TC bit set, as sent when the answer does
+    /// not fit in a datagram.
+    pub fn truncated() -> Self {
+        Self::new(ReplyKind::Truncated)
+    }
+
+    /// No reply at all, like a resolver that is down.
+    pub fn silent() -> Self {
+        Self::new(ReplyKind::Silent)
+    }
+
+    /// TTL of the answer records, 60 by default.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Waits this long before replying. Other queries are answered in the
+    /// meantime.
+    pub fn delayed(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// A query the mock received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockQuery {
+    /// Lowercased, without the trailing dot.
+    pub name: String,
+    pub qtype: RecordType,
+    /// The message id felix chose for the query.
+    pub id: u16,
+}
+
+struct Script {
+    default: MockReply,
+    names: HashMap<String, MockReply>,
+    once: HashMap<String, VecDeque<MockReply>>,
+}
+
+impl Script {
+    fn next(&mut self, name: &str) -> MockReply {
+        if let Some(reply) = self.once.get_mut(name).and_then(VecDeque::pop_front) {
+            return reply;
+        }
+        self.names.get(name).unwrap_or(&self.default).clone()
+    }
+}
+
+/// A scripted upstream resolver on 127.0.0.1. Runs until `shutdown` or the
+/// end of the runtime.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    script: Arc<Mutex<Script>>,
+    queries: Arc<Mutex<Vec<MockQuery>>>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Binds a free port; names without a script get `default`.
+    pub async fn start(default: MockReply) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let addr = socket.local_addr()?;
+        let script = Arc::new(Mutex::new(Script { default, names: HashMap::new(), once: HashMap::new() }));
+        let queries = Arc::new(Mutex::new(Vec::new()));
+
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn({
+            let script = script.clone();
+            let queries = queries.clone();
+            async move {
+                let mut buf = [0u8; 1500];
+                loop {
+                    let (n, peer) = tokio::select! {
+                        _ = &mut shutdown_rx => break,
+                        recv = socket.recv_from(&mut buf) => match recv {
+                            Ok(r) => r,
+                            Err(_) => continue,
+                        },
+                    };
+                    let Ok(request) = proto::decode(&buf[..n]) else { continue };
+                    let Some(query) = request.queries().first() else { continue };
+                    let name = key(&query.name().to_utf8());
+                    let qtype = query.query_type();
+                    let reply = script.lock().next(&name);
+                    queries.lock().push(MockQuery { name, qtype, id: request.id() });
+
+                    let resp = match reply.kind {
+                        ReplyKind::Silent => continue,
+                        ReplyKind::Code(code) => proto::build_response_code(&request, code),
+                        ReplyKind::Truncated => {
+                            let mut resp = proto::build_reply(&request);
+                            resp.set_truncated(true);
+                            resp
+                        }
+                        ReplyKind::Records(data) => {
+                            let mut resp = proto::build_reply(&request);
+                            resp.set_recursion_available(true);
+                            for data in data.into_iter().filter(|d| qtype == RecordType::ANY || d.record_type() == qtype) {
+                                resp.add_answer(Record::from_rdata(query.name().clone(), reply.ttl, data));
+                            }
+                            resp
+                        }
+                    };
+                    let Ok(bytes) = proto::encode(&resp) else { continue };
+                    let socket = socket.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(reply.delay).await;
+                        let _ = socket.send_to(&bytes, peer).await;
+                    });
+                }
+            }
+        });
+
+        Ok(Self { addr, script, queries, shutdown_tx: Some(shutdown_tx), task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Answers queries for `name` with `reply` from now on.
+    pub fn on(&self, name: &str, reply: MockReply) {
+        self.script.lock().names.insert(key(name), reply);
+    }
+
+    /// Answers only the next query for `name` with `reply`. Queued replies
+    /// are used in order before falling back to `on` or the default.
+    pub fn once(&self, name: &str, reply: MockReply) {
+        self.script.lock().once.entry(key(name)).or_default().push_back(reply);
+    }
+
+    /// Every query received so far, oldest first.
+    pub fn queries(&self) -> Vec<MockQuery> {
+        self.queries.lock().clone()
+    }
+
+    /// How many queries for `name` have arrived.
+    pub fn query_count(&self, name: &str) -> usize {
+        let name = key(name);
+        self.queries.lock().iter().filter(|q| q.name == name).count()
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// A forged answer `PoisonTest` races against the genuine one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spoof {
+    /// From the upstream's address, for the right question, but with
+    /// another message id.
+    WrongId,
+    /// With the right id and question, but from another port than the
+    /// upstream's.
+    WrongPort,
+    /// From the upstream with the right id, but for another question.
+    WrongQuestion,
+    /// The genuine answer plus a record for a name outside the question,
+    /// planted to be cached and served for later queries.
+    OutOfBailiwick,
+    /// `WrongId` against the refresh felix sends for a popular answer
+    /// about to expire, checked by asking once more after it. Assumes the
+    /// default `CacheConfig::prefetch_hits`.
+    Prefetch,
+    /// `WrongId` on the TCP connection felix retries on after a
+    /// truncated UDP answer; needs `FailoverConfig::tcp`.
+    Tcp,
+}
+
+impl Spoof {
+    pub const ALL: [Spoof; 6] =
+        [Spoof::WrongId, Spoof::WrongPort, Spoof::WrongQuestion, Spoof::OutOfBailiwick, Spoof::Prefetch, Spoof::Tcp];
+
+    /// First label of the query names used for this spoof.
+    fn label(self) -> &'static str {
+        match self {
+            Spoof::WrongId => "wrong-id",
+            Spoof::WrongPort => "wrong-port",
+            Spoof::WrongQuestion => "wrong-question",
+            Spoof::OutOfBailiwick => "out-of-bailiwick",
+            Spoof::Prefetch => "prefetch",
+            Spoof::Tcp => "tcp",
+        }
+    }
+}
+
+/// What one spoof achieved, see `PoisonTest::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpoofResult {
+    pub spoof: Spoof,
+    /// Whether the client only ever saw the genuine address.
+    pub rejected: bool,
+    /// A addresses of the answers the client got, in order.
+    pub answers: Vec<Ipv4Addr>,
+}
+
+/// Address of every genuine answer.
+pub const GENUINE_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+/// Address of every forged answer.
+pub const FORGED_ADDR: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 66);
+/// Head start forged answers get over the genuine one.
+const GENUINE_DELAY: Duration = Duration::from_millis(100);
+/// Zone every test name is under.
+const POISON_ZONE: &str = "poison.example.";
+
+/// An upstream on 127.0.0.1, over UDP and TCP on one port, that answers
+/// every query with `GENUINE_ADDR`, preceded or accompanied by a forged
+/// answer chosen by the query name. Runs until `shutdown` or the end of
+/// the runtime.
+pub struct PoisonTest {
+    addr: SocketAddr,
+    runs: AtomicU64,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl PoisonTest {
+    pub async fn start() -> Result<Self> {
+        let (socket, listener) = bind_udp_and_tcp().await?;
+        let socket = Arc::new(socket);
+        // forged answers from another port
+        let side = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let addr = socket.local_addr()?;
+
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (n, peer) = tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => {
+                        if let Ok((stream, peer)) = accepted {
+                            tokio::spawn(async move {
+                                if let Err(e) = answer_tcp(stream).await {
+                                    log::debug!("Poison test connection from {} closed: {}", peer, e);
+                                }
+                            });
+                        }
+                        continue;
+                    }
+                    recv = socket.recv_from(&mut buf) => match recv {
+                        Ok(r) => r,
+                        Err(_) => continue,
+                    },
+                };
+                let Ok(request) = proto::decode(&buf[..n]) else { continue };
+                let (socket, side) = (socket.clone(), side.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = answer_spoofed(&request, peer, &socket, &side).await {
+                        log::debug!("Poison test answer to {} failed: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, runs: AtomicU64::new(0), shutdown_tx: Some(shutdown_tx), task })
+    }
+
+    /// Where felix has to forward for the test to work.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends felix at `server` one query per `Spoof`, each for a name
+    /// never asked before so its cache cannot answer, and reports which
+    /// forged answers reached the client. For `OutOfBailiwick` the
+    /// planted name is asked for too. Fails if an answer does not carry
+    /// the id of the client's query.
+    pub async fn run(&self, server: SocketAddr) -> Result<Vec<SpoofResult>> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let run = format!("r{}-{:x}", self.runs.fetch_add(1, Ordering::Relaxed), nanos);
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+
+        let mut results = Vec::new();
+        for spoof in Spoof::ALL {
+            let zone = format!("{}.{}", run, POISON_ZONE);
+            let name = format!("{}.{}", spoof.label(), zone);
+            let mut asked = 1;
+            let mut answers = ask_a(&client, server, &name).await?;
+            match spoof {
+                Spoof::OutOfBailiwick => {
+                    answers.extend(ask_a(&client, server, &format!("victim.{}", zone)).await?);
+                    asked += 1;
+                }
+                Spoof::Prefetch => {
+                    // enough cache hits to start the refresh, then one
+                    // answered from what it stored
+                    let hits = CacheConfig::default().prefetch_hits as usize;
+                    for _ in 0..hits {
+                        answers.extend(ask_a(&client, server, &name).await?);
+                    }
+                    tokio::time::sleep(GENUINE_DELAY * 3).await;
+                    answers.extend(ask_a(&client, server, &name).await?);
+                    asked += hits + 1;
+                }
+                _ => {}
+            }
+            let rejected = answers.len() == asked && answers.iter().all(|a| *a == GENUINE_ADDR);
+            results.push(SpoofResult { spoof, rejected, answers });
+        }
+        Ok(results)
+    }
+
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+/// A UDP socket and a TCP listener on the same free port of 127.0.0.1.
+async fn bind_udp_and_tcp() -> Result<(UdpSocket, TcpListener)> {
+    let mut attempts = 0;
+    loop {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        match TcpListener::bind(socket.local_addr()?).await {
+            Ok(listener) => return Ok((socket, listener)),
+            // the port is free for UDP only; try another
+            Err(_) if attempts < 10 => attempts += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Lowercased first label of the question's name.
+fn first_label(query: &Query) -> String {
+    query.name().iter().next().map(|l| String::from_utf8_lossy(l).to_ascii_lowercase()).unwrap_or_default()
+}
+
+/// A forged answer to `request` under the next message id.
+fn with_wrong_id(request: &Message) -> Message {
+    let mut forged = proto::build_a_response(request, FORGED_ADDR, 3600);
+    forged.set_id(request.id().wrapping_add(1));
+    forged
+}
+
+/// Answers one query the way its first label asks for.
+async fn answer_spoofed(request: &Message, peer: SocketAddr, socket: &UdpSocket, side: &UdpSocket) -> Result<()> {
+    let Some(query) = request.queries().first() else { return Ok(()) };
+    let name = query.name().clone();
+    let label = first_label(query);
+    let mut genuine = proto::build_a_response(request, GENUINE_ADDR, 60);
+
+    if label == Spoof::OutOfBailiwick.label() {
+        let victim = Name::from_ascii("victim")?.append_domain(&name.base_name())?;
+        genuine.add_answer(Record::from_rdata(victim, 3600, RData::A(FORGED_ADDR.into())));
+        socket.send_to(&proto::encode(&genuine)?, peer).await?;
+        return Ok(());
+    }
+    if label == Spoof::Tcp.label() {
+        // the forged answer waits on the TCP connection, see `answer_tcp`
+        let mut truncated = proto::build_reply(request);
+        truncated.set_truncated(true);
+        socket.send_to(&proto::encode(&truncated)?, peer).await?;
+        return Ok(());
+    }
+
+    let forged = match label.as_str() {
+        l if l == Spoof::WrongId.label() => Some((socket, with_wrong_id(request))),
+        l if l == Spoof::Prefetch.label() => {
+            // short-lived, so felix refreshes it after a few cache hits
+            genuine = proto::build_a_response(request, GENUINE_ADDR, 1);
+            Some((socket, with_wrong_id(request)))
+        }
+        l if l == Spoof::WrongPort.label() => Some((side, proto::build_a_response(request, FORGED_ADDR, 3600))),
+        l if l == Spoof::WrongQuestion.label() => {
+            let other = Name::from_ascii("other")?.append_domain(&name.base_name())?;
+            let mut forged = proto::build_a_response(request, FORGED_ADDR, 3600);
+            forged.take_queries();
+            forged.add_query(Query::query(other, query.query_type()));
+            Some((socket, forged))
+        }
+        _ => None,
+    };
+    if let Some((from, forged)) = forged {
+        from.send_to(&proto::encode(&forged)?, peer).await?;
+        tokio::time::sleep(GENUINE_DELAY).await;
+    }
+    socket.send_to(&proto::encode(&genuine)?, peer).await?;
+    Ok(())
+}
+
+/// Answers the queries on one TCP connection from felix, each with the
+/// genuine answer, and a `Spoof::Tcp` name with a forged one first.
+async fn answer_tcp(mut stream: TcpStream) -> Result<()> {
+    loop {
+        let len = stream.read_u16().await?;
+        let mut packet = vec![0u8; usize::from(len)];
+        stream.read_exact(&mut packet).await?;
+        let request = proto::decode(&packet)?;
+        let Some(query) = request.queries().first() else { continue };
+
+        let mut answers = Vec::new();
+        if first_label(query) == Spoof::Tcp.label() {
+            answers.push(with_wrong_id(&request));
+        }
+        answers.push(proto::build_a_response(&request, GENUINE_ADDR, 60));
+        for answer in answers {
+            let encoded = proto::encode(&answer)?;
+            let len = u16::try_from(encoded.len()).map_err(|_| std::io::Error::other("answer too large for TCP"))?;
+            stream.write_u16(len).await?;
+            stream.write_all(&encoded).await?;
+        }
+    }
+}
+
+/// The A addresses felix at `server` answers `name` with.
+async fn ask_a(client: &UdpSocket, server: SocketAddr, name: &str) -> Result<Vec<Ipv4Addr>> {
+    let query = proto::build_query(name, RecordType::A)?;
+    client.send_to(&proto::encode(&query)?, server).await?;
+    let mut buf = [0u8; 1500];
+    let wait = UPSTREAM_TIMEOUT + Duration::from_secs(1);
+    let n = tokio::time::timeout(wait, client.recv(&mut buf)).await.map_err(|_| Error::UpstreamTimeout(server))??;
+    let reply = proto::decode(&buf[..n])?;
+    if reply.id() != query.id() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("answer for {} has id {} instead of {}", name, reply.id(), query.id()),
+        )));
+    }
+    Ok(reply.answers().iter().filter_map(|r| r.data().as_a().map(|a| a.0)).collect())
+}
+
+/// Virtual time on tokio's paused clock. Upstream timeouts, cache TTLs,
+/// prefetch windows and `MockUpstream` delays all follow it, and whenever
+/// every task is waiting the clock jumps to the next timer instead of
+/// sleeping. Needs a current-thread runtime, e.g. `#[tokio::test]`.
+pub struct SimClock {
+    start: Instant,
+}
+
+impl SimClock {
+    /// Freezes the runtime's clock until it is dropped. Panics on a
+    /// multi-thread runtime or if the clock is already paused.
+    pub fn pause() -> Self {
+        tokio::time::pause();
+        Self { start: Instant::now() }
+    }
+
+    /// Moves time forward, firing every timer due on the way.
+    pub async fn advance(&self, by: Duration) {
+        tokio::time::advance(by).await;
+    }
+
+    /// Virtual time passed since `pause`.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Drop for SimClock {
+    fn drop(&mut self) {
+        tokio::time::resume();
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Chaos for forwarded answers, see `ResolverState::set_upstream_faults`.
+/// Each probability is between 0.0 and 1.0 and rolled independently per
+/// answer; the same seed gives the same sequence of faults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamFaults {
+    /// Chance an answer is held back by `delay_by`.
+    pub delay: f64,
+    pub delay_by: Duration,
+    /// Chance an answer is lost, so the query times out as if the
+    /// upstream never replied and the client gets SERVFAIL.
+    pub drop: f64,
+    /// Chance a few bytes of the answer are flipped before felix parses
+    /// it, so the client gets a garbled answer or SERVFAIL.
+    pub corrupt: f64,
+    pub seed: u64,
+}
+
+impl Default for UpstreamFaults {
+    fn default() -> Self {
+        Self { delay: 0.0, delay_by: Duration::from_millis(500), drop: 0.0, corrupt: 0.0, seed: 0x5eed }
+    }
+}
+
+/// `UpstreamFaults` with its random state.
+pub(crate) struct FaultInjector {
+    faults: UpstreamFaults,
+    rng: XorShift,
+}
+
+impl FaultInjector {
+    pub fn new(faults: UpstreamFaults) -> Self {
+        let rng = XorShift::new(faults.seed);
+        Self { faults, rng }
+    }
+
+    fn roll(&self, chance: f64) -> bool {
+        chance > 0.0 && ((self.rng.next() >> 11) as f64 / (1u64 << 53) as f64) < chance
+    }
+
+    /// Applies the faults to one upstream answer.
+    pub async fn apply(&self, upstream: SocketAddr, reply: Result<Vec<u8>>) -> Result<Vec<u8>> {
+        let mut reply = reply?;
+        if self.roll(self.faults.drop) {
+            log::debug!("Dropping answer from {} (fault injection)", upstream);
+            tokio::time::sleep(UPSTREAM_TIMEOUT).await;
+            return Err(Error::UpstreamTimeout(upstream));
+        }
+        if self.roll(self.faults.delay) {
+            log::debug!("Delaying answer from {} by {:?} (fault injection)", upstream, self.faults.delay_by);
+            tokio::time::sleep(self.faults.delay_by).await;
+        }
+        if self.roll(self.faults.corrupt) && !reply.is_empty() {
+            log::debug!("Corrupting answer from {} (fault injection)", upstream);
+            // past the id, so the client still matches it to its query
+            for _ in 0..4 {
+                let at = 2 + (self.rng.next() as usize) % reply.len().saturating_sub(2).max(1);
+                if let Some(byte) = reply.get_mut(at) {
+                    *byte ^= (self.rng.next() as u8) | 1;
+                }
+            }
+        }
+        Ok(reply)
+    }
+}
@@ -0,0 +1,169 @@
+//! Local certificate authority for the HTTPS proxy, mkcert style: the CA is
+//! generated once and kept on disk, and a certificate is minted for each
+//! proxied name the first time a client asks for it. Install the CA into
+//! the system trust store (`felix ca install`) for browsers to accept them.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use parking_lot::Mutex;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair, KeyUsagePurpose,
+    date_time_ymd,
+};
+use rustls::{
+    ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::{Error, ResolverState, Result};
+
+pub const CA_CERT_FILE: &str = "rootCA.pem";
+const CA_KEY_FILE: &str = "rootCA-key.pem";
+const CA_NAME: &str = "felix local development CA";
+/// Stays under the 825 days Apple platforms accept for TLS certificates.
+const LEAF_DAYS: i64 = 820;
+
+pub struct LocalCa {
+    issuer: Issuer<'static, KeyPair>,
+    cert: CertificateDer<'static>,
+    cert_path: PathBuf,
+}
+
+impl LocalCa {
+    /// Loads the CA kept in `dir`, creating it (and `dir`) on first use.
+    pub fn load_or_create(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        let (cert_pem, key) = if cert_path.exists() && key_path.exists() {
+            let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?)?;
+            (fs::read_to_string(&cert_path)?, key)
+        } else {
+            fs::create_dir_all(dir)?;
+            let key = KeyPair::generate()?;
+            let cert = ca_params().self_signed(&key)?;
+            write_private(&key_path, &key.serialize_pem())?;
+            fs::write(&cert_path, cert.pem())?;
+            log::info!("Created local CA {}", cert_path.display());
+            (cert.pem(), key)
+        };
+
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|e| Error::Tls(format!("reading {}: {}", cert_path.display(), e)))?;
+        // the issuer only needs the CA's name and key usages, which
+        // `ca_params` reproduces
+        Ok(Self { issuer: Issuer::new(ca_params(), key), cert, cert_path })
+    }
+
+    /// The CA certificate file, to install into trust stores.
+    pub fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    /// Mints a certificate for `name`, chained to this CA.
+    pub fn issue(&self, name: &str) -> Result<CertifiedKey> {
+        let mut params = CertificateParams::new(vec![name.to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.distinguished_name.push(DnType::OrganizationName, "felix");
+        set_validity(&mut params, LEAF_DAYS);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+
+        let key = KeyPair::generate()?;
+        let cert = params.signed_by(&key, &self.issuer)?;
+        let key = rustls::crypto::ring::sign::any_supported_type(&PrivateKeyDer::Pkcs8(key.serialize_der().into()))
+            .map_err(|e| Error::Tls(e.to_string()))?;
+        Ok(CertifiedKey::new(vec![cert.der().clone(), self.cert.clone()], key))
+    }
+
+    /// TLS config answering each handshake with a certificate for its SNI
+    /// name, for names that have a proxy port mapped.
+    pub(crate) fn server_config(self: Arc<Self>, state: ResolverState) -> Result<Arc<ServerConfig>> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(CertResolver { ca: self, state, issued: Mutex::new(HashMap::new()) }));
+        // the proxy speaks HTTP/1.1 only
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.distinguished_name.push(DnType::OrganizationName, "felix");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    set_validity(&mut params, 10 * 365);
+    params
+}
+
+/// Valid from yesterday, so clocks a little behind still accept it.
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let date = |d: NaiveDate| date_time_ymd(d.year(), d.month() as u8, d.day() as u8);
+    let today = Utc::now().date_naive();
+    params.not_before = date(today - Duration::days(1));
+    params.not_after = date(today + Duration::days(days));
+}
+
+/// Writes the CA key readable by the owner only.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt};
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+        file.write_all(contents.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    fs::write(path, contents)?;
+    Ok(())
+}
+
+struct CertResolver {
+    ca: Arc<LocalCa>,
+    state: ResolverState,
+    issued: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver").field("ca", &self.ca.cert_path).finish()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name()?.trim_end_matches('.').to_ascii_lowercase();
+        // only mint for names felix routes, not whatever SNI a client sends
+        self.state.domain_port(&name)?;
+
+        let mut issued = self.issued.lock();
+        if let Some(key) = issued.get(&name) {
+            return Some(key.clone());
+        }
+        match self.ca.issue(&name) {
+            Ok(key) => {
+                log::info!("Issued certificate for {}", name);
+                let key = Arc::new(key);
+                issued.insert(name, key.clone());
+                Some(key)
+            }
+            Err(e) => {
+                log::warn!("Issuing certificate for {} failed: {}", name, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Explains how an A query for a name would be answered. The query runs
+//! through the state's own pipeline with a `Tracer` in its context: every
+//! stage shows up in order, stages with finer checks (policy rules,
+//! mapping keys, the cache, the upstream) record those, and the trace
+//! says which one decided and how long each took. Backs `GET /resolve`
+//! and `felix resolve --trace`.
+//!
+//! Nothing is changed along the way: the client checks (`acl`, `quota`)
+//! are left out, and an upstream answer is fetched but not cached.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{RData, RecordType},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, ResolverState, Result,
+    events::QueryOutcome,
+    name::NormalizedName,
+    pipeline::{BlockResponse, Pipeline, QueryContext, Response},
+    policy::{PolicyAction, PolicyRule},
+    proto,
+    store::{Wildcards, candidate_keys, wildcard_base},
+};
+
+/// The client a traced query comes from, as hooks see it.
+const TRACE_CLIENT: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceCheck {
+    /// A pipeline stage without finer checks; the subject is its name.
+    Stage,
+    /// Resolution is disabled and everything is forwarded.
+    Bypass,
+    Policy,
+    TypeBlock,
+    Exact,
+    Wildcard,
+    /// Choosing between upstream mappings, forward rules and the default.
+    UpstreamSelection,
+    Cache,
+    Upstream,
+}
+
+impl fmt::Display for TraceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TraceCheck::Stage => "stage",
+            TraceCheck::Bypass => "bypass",
+            TraceCheck::Policy => "policy",
+            TraceCheck::TypeBlock => "type-block",
+            TraceCheck::Exact => "exact",
+            TraceCheck::Wildcard => "wildcard",
+            TraceCheck::UpstreamSelection => "upstream-selection",
+            TraceCheck::Cache => "cache",
+            TraceCheck::Upstream => "upstream",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One check made while resolving.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceStep {
+    pub check: TraceCheck,
+    /// What was looked at: a rule suffix, a lookup key, an upstream.
+    pub subject: String,
+    pub matched: bool,
+    pub detail: Option<String>,
+    /// Wildcard levels count out from the name: `*.app.dev` and
+    /// `**.app.dev` are level 1 for `api.app.dev`, `**.dev` is level 2 and
+    /// level 0 is the apex wildcard.
+    pub level: Option<usize>,
+    pub elapsed_us: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResolveTrace {
+    pub name: String,
+    pub steps: Vec<TraceStep>,
+    /// The pipeline stage that answered, if any did.
+    pub stage: Option<String>,
+    /// The check that produced the answer; `None` when nothing answered,
+    /// e.g. the upstream could not be reached.
+    pub decided_by: Option<TraceCheck>,
+    /// The answer in short: addresses, a response code, or a block.
+    pub answer: String,
+    pub elapsed_us: u64,
+}
+
+/// Steps recorded while a traced query runs through the pipeline.
+pub(crate) struct Tracer {
+    started: Instant,
+    last: Instant,
+    steps: Vec<TraceStep>,
+    answered_by: Option<&'static str>,
+}
+
+impl Tracer {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self { started: now, last: now, steps: Vec::new(), answered_by: None }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub(crate) fn step(&mut self, check: TraceCheck, subject: impl Into<String>, matched: bool, detail: Option<String>) {
+        self.step_at(check, subject, matched, detail, None);
+    }
+
+    fn step_at(
+        &mut self,
+        check: TraceCheck,
+        subject: impl Into<String>,
+        matched: bool,
+        detail: Option<String>,
+        level: Option<usize>,
+    ) {
+        let now = Instant::now();
+        let elapsed_us = now.duration_since(self.last).as_micros() as u64;
+        self.last = now;
+        self.steps.push(TraceStep { check, subject: subject.into(), matched, detail, level, elapsed_us });
+    }
+
+    /// Called by `Pipeline::run` after each stage; `recorded` is `len()`
+    /// from before it ran, so a stage that said nothing still shows up.
+    pub(crate) fn stage_done(&mut self, stage: &'static str, recorded: usize, answered: bool) {
+        if self.steps.len() == recorded {
+            self.step(TraceCheck::Stage, stage, answered, None);
+        }
+        if answered {
+            self.answered_by = Some(stage);
+        }
+    }
+
+    /// The policy rules checked, up to the one that applies (`found`).
+    pub(crate) fn policy(&mut self, rules: &[PolicyRule], found: Option<usize>) {
+        let checked = found.map_or(rules.len(), |i| i + 1);
+        for (i, rule) in rules[..checked].iter().enumerate() {
+            let action = match rule.action {
+                PolicyAction::Block(response) => format!("block ({:?})", response),
+                PolicyAction::Allow => "allow".to_string(),
+            };
+            self.step(TraceCheck::Policy, rule.suffix.clone(), found == Some(i), Some(action));
+        }
+    }
+
+    pub(crate) fn type_block(&mut self, suffix: Option<&str>, response: BlockResponse) {
+        self.step(TraceCheck::TypeBlock, suffix.unwrap_or("."), true, Some(format!("block ({:?})", response)));
+    }
+
+    /// The mapping keys tried for `name`, in lookup order, up to `found`.
+    pub(crate) fn mapping(&mut self, name: &str, wildcards: Wildcards, found: Option<(&str, Ipv4Addr)>) {
+        let name = NormalizedName::new(name).into_string();
+        let depth = name.split('.').count();
+        for key in candidate_keys(&name, wildcards) {
+            let ip = found.filter(|(k, _)| *k == key).map(|(_, ip)| ip);
+            let (check, level) = match wildcard_base(&key) {
+                None => (TraceCheck::Exact, None),
+                Some(base) => (TraceCheck::Wildcard, Some(depth - base.split('.').count())),
+            };
+            self.step_at(check, key, ip.is_some(), ip.map(|ip| ip.to_string()), level);
+            if ip.is_some() {
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn answer(
+        &mut self,
+        check: TraceCheck,
+        subject: impl Into<String>,
+        answer: std::result::Result<&Message, &Error>,
+    ) {
+        match answer {
+            Ok(msg) => self.step(check, subject, true, Some(summarize(msg))),
+            Err(e) => self.step(check, subject, false, Some(e.to_string())),
+        }
+    }
+
+    fn finish(self, name: String, decided_by: Option<TraceCheck>, answer: impl Into<String>) -> ResolveTrace {
+        ResolveTrace {
+            name,
+            steps: self.steps,
+            stage: self.answered_by.map(str::to_string),
+            decided_by,
+            answer: answer.into(),
+            elapsed_us: self.started.elapsed().as_micros() as u64,
+        }
+    }
+}
+
+pub(crate) async fn trace(state: &ResolverState, qname: &str) -> Result<ResolveTrace> {
+    let name = NormalizedName::new(qname).into_string();
+    let mut t = Tracer::new();
+
+    let enabled = state.enabled();
+    t.step(TraceCheck::Bypass, "enabled", !enabled, None);
+    let (upstream, source) = state.upstream_route(&name);
+    t.step(TraceCheck::UpstreamSelection, upstream.to_string(), true, Some(source.to_string()));
+
+    let mut pipeline = if enabled { Pipeline::clone(&state.pipeline()) } else { Pipeline::passthrough() };
+    // they are about who asks, not how the name is answered
+    pipeline.remove("acl");
+    pipeline.remove("quota");
+
+    let request = proto::build_query(&name, RecordType::A)?;
+    let qname = NormalizedName::from_wire(request.queries()[0].name()).fqdn();
+    let mut ctx = QueryContext {
+        upstream,
+        view: None,
+        search: Arc::from([]),
+        state: state.clone(),
+        client: TRACE_CLIENT,
+        raw: proto::encode(&request)?.into(),
+        request,
+        lookup_name: qname.clone(),
+        qname,
+        qtype: RecordType::A,
+        trace: Some(t),
+    };
+    let answer = pipeline.run(&mut ctx).await?;
+    let t = ctx.trace.take().expect("the context was created with a tracer");
+
+    let summary = match &answer.response {
+        Response::Message(msg) => summarize(msg),
+        Response::Raw(bytes) => proto::decode(bytes).map_or_else(|_| "unparsable answer".to_string(), |m| summarize(&m)),
+    };
+    let decided_by = match answer.outcome {
+        QueryOutcome::ServFail => None,
+        _ => t.steps.last().map(|s| s.check),
+    };
+    Ok(t.finish(name, decided_by, summary))
+}
+
+/// Addresses in the answer, or its response code when there are none.
+pub(crate) fn summarize(msg: &Message) -> String {
+    let addresses: Vec<String> = msg
+        .answers()
+        .iter()
+        .filter_map(|r| match r.data() {
+            RData::A(a) => Some(a.0.to_string()),
+            RData::AAAA(a) => Some(a.0.to_string()),
+            _ => None,
+        })
+        .collect();
+    if addresses.is_empty() || msg.response_code() != ResponseCode::NoError {
+        return msg.response_code().to_string();
+    }
+    addresses.join(", ")
+}
@@ -0,0 +1,148 @@
+//! DNS views: named mapping sets for queries arriving on particular
+//! listener addresses, e.g. LAN addresses for a listener on the LAN
+//! interface while the loopback listener keeps the default mappings.
+//!
+//! A query on a bound listener is answered from its view's mappings only;
+//! names the view does not map are forwarded as usual, even when the
+//! default mappings have them. Typed records, policy and the cache are
+//! shared by all views. Views live in memory and are not persisted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use crate::{DomainMap, Error, Result, store::Wildcards};
+
+/// The view bound to one listener. The listener takes it once when it
+/// starts and reads it per query; binding updates it in place.
+pub(crate) type ViewSlot = Arc<RwLock<Option<Arc<str>>>>;
+
+#[derive(Default)]
+pub(crate) struct Views {
+    maps: HashMap<String, DomainMap>,
+    /// Listener address → its view, kept when unbound so a running
+    /// listener sees a later binding.
+    bindings: HashMap<SocketAddr, ViewSlot>,
+}
+
+// a copy must not share slots with the original, see `restore`
+impl Clone for Views {
+    fn clone(&self) -> Self {
+        let bindings = self.bindings.iter().map(|(addr, slot)| (*addr, Arc::new(RwLock::new(slot.read().clone())))).collect();
+        Self { maps: self.maps.clone(), bindings }
+    }
+}
+
+/// A view as listed by the management API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ViewSummary {
+    pub name: String,
+    pub domains: usize,
+    /// Listener addresses answering from this view, sorted.
+    pub listeners: Vec<SocketAddr>,
+}
+
+impl Views {
+    pub(crate) fn set(&mut self, view: &str, domain: &str, ip: Ipv4Addr, wildcards: Wildcards) {
+        self.map_mut(view, wildcards).set(domain.to_string(), ip);
+    }
+
+    pub(crate) fn remove(&mut self, view: &str, domain: &str) {
+        if let Some(map) = self.maps.get_mut(view) {
+            map.remove(domain);
+        }
+    }
+
+    /// Drops the view's mappings and unbinds its listeners.
+    pub(crate) fn remove_view(&mut self, view: &str) {
+        self.maps.remove(view);
+        for slot in self.bindings.values() {
+            let mut bound = slot.write();
+            if bound.as_deref() == Some(view) {
+                *bound = None;
+            }
+        }
+    }
+
+    /// Binds `listener` to `view`, creating it empty if needed; `None`
+    /// returns the listener to the default mappings.
+    pub(crate) fn bind(&mut self, listener: SocketAddr, view: Option<&str>, wildcards: Wildcards) {
+        if let Some(view) = view {
+            self.map_mut(view, wildcards);
+        }
+        *self.slot(listener).write() = view.map(Arc::from);
+    }
+
+    /// The slot `listener` reads its view from, created unbound.
+    pub(crate) fn slot(&mut self, listener: SocketAddr) -> ViewSlot {
+        self.bindings.entry(listener).or_default().clone()
+    }
+
+    /// Takes over `snapshot`'s mappings and bindings, keeping the slots
+    /// running listeners hold.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn restore(&mut self, snapshot: &Views) {
+        self.maps = snapshot.maps.clone();
+        for (addr, slot) in &snapshot.bindings {
+            *self.slot(*addr).write() = slot.read().clone();
+        }
+        for (addr, slot) in &self.bindings {
+            if !snapshot.bindings.contains_key(addr) {
+                *slot.write() = None;
+            }
+        }
+    }
+
+    pub(crate) fn resolve_match(&self, view: &str, qname: &str) -> Option<(String, Ipv4Addr)> {
+        self.maps.get(view)?.resolve_match(qname)
+    }
+
+    /// `None` when there is no such view.
+    pub(crate) fn domains(&self, view: &str) -> Option<Vec<(String, Ipv4Addr)>> {
+        let mut domains = self.maps.get(view)?.list();
+        domains.sort();
+        Some(domains)
+    }
+
+    /// Every view, sorted by name.
+    pub(crate) fn summaries(&self) -> Vec<ViewSummary> {
+        let mut listeners: BTreeMap<&str, Vec<SocketAddr>> = BTreeMap::new();
+        let bound: Vec<(SocketAddr, Arc<str>)> =
+            self.bindings.iter().filter_map(|(addr, slot)| Some((*addr, slot.read().clone()?))).collect();
+        for (addr, view) in &bound {
+            listeners.entry(view).or_default().push(*addr);
+        }
+        let mut views: Vec<ViewSummary> = self
+            .maps
+            .iter()
+            .map(|(name, map)| {
+                let mut bound = listeners.remove(name.as_str()).unwrap_or_default();
+                bound.sort();
+                ViewSummary { name: name.clone(), domains: map.list().len(), listeners: bound }
+            })
+            .collect();
+        views.sort_by(|a, b| a.name.cmp(&b.name));
+        views
+    }
+
+    fn map_mut(&mut self, view: &str, wildcards: Wildcards) -> &mut DomainMap {
+        self.maps.entry(view.to_string()).or_insert_with(|| {
+            let mut map = DomainMap::new();
+            map.set_wildcards(wildcards);
+            map
+        })
+    }
+}
+
+/// View names go in URL paths, so they are kept to `[a-z0-9-]`.
+pub(crate) fn check_view_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') {
+        return Err(Error::InvalidView(name.to_string()));
+    }
+    Ok(())
+}
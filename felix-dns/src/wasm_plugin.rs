@@ -0,0 +1,120 @@
+//! Sandboxed resolution plugins compiled to WebAssembly.
+//!
+//! A plugin is a core wasm module with no imports that exports:
+//!
+//! - `memory`
+//! - `felix_alloc(len: i32) -> i32`: returns a buffer the host writes the query into
+//! - `felix_on_query(ptr: i32, len: i32) -> i64`: takes the query as JSON
+//!   `{"name", "qtype", "client"}` and returns `(out_ptr << 32) | out_len`
+//!   pointing at a JSON decision `{"name"?, "upstream"?, "answer"?, "refuse"?}`,
+//!   or 0 for no decision
+//!
+//! Every call runs in a fresh instance with bounded fuel and memory, so a
+//! plugin cannot keep state between queries or stall the resolver.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{Error, Result, scripting::QueryDecision};
+
+const FUEL_PER_CALL: u64 = 10_000_000;
+const MAX_MEMORY_BYTES: usize = 16 << 20;
+
+#[derive(Serialize)]
+struct PluginQuery<'a> {
+    name: &'a str,
+    qtype: &'a str,
+    client: String,
+}
+
+#[derive(Deserialize, Default)]
+struct PluginDecision {
+    name: Option<String>,
+    upstream: Option<SocketAddr>,
+    answer: Option<Ipv4Addr>,
+    #[serde(default)]
+    refuse: bool,
+}
+
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    pre: InstancePre<StoreLimits>,
+}
+
+impl WasmPlugin {
+    /// Loads a plugin from a `.wasm` (or `.wat`) file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        Self::from_bytes(path.display().to_string(), &bytes)
+    }
+
+    pub fn from_bytes(name: impl Into<String>, bytes: &[u8]) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(plugin_error)?;
+        let module = Module::new(&engine, bytes).map_err(plugin_error)?;
+        let pre = Linker::new(&engine).instantiate_pre(&module).map_err(plugin_error)?;
+
+        Ok(Self { name: name.into(), engine, pre })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the plugin; failures and traps are logged and treated as "no decision".
+    pub fn on_query(&self, name: &str, qtype: &str, client: SocketAddr) -> QueryDecision {
+        match self.call(name, qtype, client) {
+            Ok(d) => QueryDecision {
+                name: d.name,
+                upstream: d.upstream,
+                answer: d.answer,
+                refuse: d.refuse,
+            },
+            Err(e) => {
+                log::warn!("Plugin {} failed: {}", self.name, e);
+                QueryDecision::default()
+            }
+        }
+    }
+
+    fn call(&self, name: &str, qtype: &str, client: SocketAddr) -> wasmtime::Result<PluginDecision> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|l| l);
+        store.set_fuel(FUEL_PER_CALL)?;
+
+        let instance = self.pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "felix_alloc")?;
+        let on_query = instance.get_typed_func::<(i32, i32), i64>(&mut store, "felix_on_query")?;
+
+        let input = serde_json::to_vec(&PluginQuery { name, qtype, client: client.to_string() })?;
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, &input)?;
+
+        let packed = on_query.call(&mut store, (ptr, len))? as u64;
+        if packed == 0 {
+            return Ok(PluginDecision::default());
+        }
+
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let mut out = vec![0u8; out_len];
+        memory.read(&store, out_ptr, &mut out)?;
+        Ok(serde_json::from_slice(&out)?)
+    }
+}
+
+fn plugin_error(e: wasmtime::Error) -> Error {
+    Error::Plugin(e.to_string())
+}
@@ -0,0 +1,150 @@
+//! Webhook notifications: every `UpstreamEvent` (see `alerts`) and every
+//! `DomainChange` is POSTed as JSON to the URLs configured for it, so a
+//! team notices their shared felix losing its upstream in chat, and
+//! dependent systems can purge their caches when a mapping moves.
+//!
+//! The payload carries a one-line summary as `text`, which Slack and
+//! Mattermost incoming webhooks display as is, and the event itself:
+//!
+//! ```json
+//! {"text": "Upstream 10.0.0.2:53 is down after 3 failed queries",
+//!  "event": {"type": "upstream_down", "upstream": "10.0.0.2:53", "failures": 3}}
+//! {"text": "api.dev now points to 127.0.0.1",
+//!  "event": {"type": "set", "domain": "api.dev", "ip": "127.0.0.1"}}
+//! ```
+//!
+//! Each URL gets its payloads in order, one request at a time, from a
+//! queue of up to `QUEUED_PAYLOADS`; a slow endpoint loses the payloads
+//! past that instead of piling up requests. Deliveries are not retried;
+//! failures are logged.
+
+use std::{collections::HashMap, time::Duration};
+
+use serde::Serialize;
+use tokio::{
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+
+use crate::{Error, ResolverState, Result, events::DomainChange};
+
+/// Payloads waiting for one URL before new ones are dropped.
+const QUEUED_PAYLOADS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    /// Receive upstream alerts.
+    pub alert_urls: Vec<String>,
+    /// Receive mapping changes.
+    pub change_urls: Vec<String>,
+    /// Per delivery.
+    pub timeout: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self { alert_urls: Vec::new(), change_urls: Vec::new(), timeout: Duration::from_secs(5) }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a, T> {
+    text: String,
+    event: &'a T,
+}
+
+pub struct WebhookHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl WebhookHandle {
+    /// Stops sending; payloads already queued are still delivered.
+    pub async fn shutdown(mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        let _ = self.task.await;
+    }
+}
+
+pub async fn start_webhooks(config: WebhookConfig, state: ResolverState) -> Result<WebhookHandle> {
+    let client = reqwest::Client::builder().timeout(config.timeout).build().map_err(|e| Error::Webhook(e.to_string()))?;
+    let mut alerts = state.subscribe_alerts();
+    let mut changes = state.subscribe_changes();
+    let mut queues = HashMap::new();
+    for url in config.alert_urls.iter().chain(&config.change_urls) {
+        queues.entry(url.clone()).or_insert_with(|| spawn_queue(client.clone(), url.clone()));
+    }
+    log::info!(
+        "Sending alerts to {} and mapping changes to {} webhook(s)",
+        config.alert_urls.len(),
+        config.change_urls.len()
+    );
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_rx => break,
+                event = alerts.recv() => match event {
+                    Ok(event) => deliver(&queues, &config.alert_urls, &Payload { text: event.summary(), event: &event }),
+                    Err(RecvError::Lagged(n)) => log::warn!("Webhooks fell behind, dropped {} alerts", n),
+                    Err(RecvError::Closed) => break,
+                },
+                change = changes.recv() => match change {
+                    Ok(change) => deliver(&queues, &config.change_urls, &Payload { text: change_summary(&change), event: &change }),
+                    Err(RecvError::Lagged(n)) => log::warn!("Webhooks fell behind, dropped {} changes", n),
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    });
+
+    Ok(WebhookHandle { shutdown_tx: Some(shutdown_tx), task })
+}
+
+fn change_summary(change: &DomainChange) -> String {
+    match change {
+        DomainChange::Set { domain, ip } => format!("{} now points to {}", domain, ip),
+        DomainChange::Removed { domain } => format!("{} was removed", domain),
+    }
+}
+
+/// POSTs the bodies queued for `url` in order until the sender is
+/// dropped and the queue is empty.
+fn spawn_queue(client: reqwest::Client, url: String) -> mpsc::Sender<String> {
+    let (tx, mut rx) = mpsc::channel::<String>(QUEUED_PAYLOADS);
+    tokio::spawn(async move {
+        while let Some(body) = rx.recv().await {
+            let request = client.post(&url).header("content-type", "application/json").body(body);
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => log::debug!("Delivered webhook to {}", url),
+                Err(e) => log::warn!("Webhook to {} failed: {}", url, e),
+            }
+        }
+    });
+    tx
+}
+
+/// Queues `payload` for every URL.
+fn deliver<T: Serialize>(queues: &HashMap<String, mpsc::Sender<String>>, urls: &[String], payload: &Payload<'_, T>) {
+    if urls.is_empty() {
+        return;
+    }
+    let body = match serde_json::to_string(payload) {
+        Ok(body) => body,
+        Err(e) => return log::warn!("Failed to encode webhook payload: {}", e),
+    };
+    for (url, queue) in urls.iter().filter_map(|url| Some((url, queues.get(url)?))) {
+        match queue.try_send(body.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::warn!("Webhook queue for {} is full, dropped a payload", url),
+            Err(TrySendError::Closed(_)) => log::warn!("Webhook queue for {} is gone, dropped a payload", url),
+        }
+    }
+}
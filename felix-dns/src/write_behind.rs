@@ -0,0 +1,92 @@
+//! Write-behind queue for the persistent layer: mutations are staged in memory
+//! and flushed in batched transactions on an interval.
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use crate::{Result, store::PersistentStore};
+
+pub struct WriteBehind {
+    store: PersistentStore,
+    /// Latest pending operation per normalized domain; `None` is a removal.
+    pending: Mutex<HashMap<String, Option<Ipv4Addr>>>,
+    /// Serializes flushes so an older batch never lands after a newer one.
+    flushing: tokio::sync::Mutex<()>,
+}
+
+impl WriteBehind {
+    /// Starts the flush loop, which stops once the returned queue is dropped.
+    pub(crate) fn start(store: PersistentStore, interval: Duration) -> Arc<Self> {
+        let queue = Arc::new(Self {
+            store,
+            pending: Mutex::new(HashMap::new()),
+            flushing: tokio::sync::Mutex::new(()),
+        });
+
+        let weak: Weak<Self> = Arc::downgrade(&queue);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(queue) = weak.upgrade() else { break };
+                if let Err(e) = queue.flush().await {
+                    log::warn!("Write-behind flush failed, will retry: {}", e);
+                }
+            }
+        });
+
+        queue
+    }
+
+    pub(crate) fn set(&self, domain: String, ip: Ipv4Addr) {
+        self.pending.lock().insert(domain, Some(ip));
+    }
+
+    pub(crate) fn remove(&self, domain: String) {
+        self.pending.lock().insert(domain, None);
+    }
+
+    /// The staged operation for `domain`, if one is waiting.
+    pub(crate) fn staged(&self, domain: &str) -> Option<Option<Ipv4Addr>> {
+        self.pending.lock().get(domain).copied()
+    }
+
+    /// Pending operations, for merging into listings.
+    pub(crate) fn snapshot(&self) -> HashMap<String, Option<Ipv4Addr>> {
+        self.pending.lock().clone()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Writes everything staged so far in one transaction. Entries stay
+    /// visible to reads until committed; on failure they are simply kept.
+    pub async fn flush(&self) -> Result<()> {
+        let _guard = self.flushing.lock().await;
+        let batch: Vec<(String, Option<Ipv4Addr>)> =
+            self.pending.lock().iter().map(|(d, ip)| (d.clone(), *ip)).collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        log::debug!("Flushing {} staged mapping changes", batch.len());
+        self.store.apply_batch(&batch).await?;
+
+        // keep anything that changed again while the batch was in flight
+        let mut pending = self.pending.lock();
+        for (domain, ip) in batch {
+            if pending.get(&domain) == Some(&ip) {
+                pending.remove(&domain);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+#ifndef FELIX_H
+#define FELIX_H
+
+/* Generated by cbindgen from felix-ffi/src/lib.rs; do not edit. */
+
+#include <stdint.h>
+
+typedef struct Felix Felix;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a stopped server listening for DNS on `listen` (e.g.
+// `"127.0.0.1:5353"`) and forwarding unmapped names to `upstream`.
+// Mappings are kept in the SQLite database at `db_path`, or only in memory
+// when it is null. Returns null on failure.
+//
+// # Safety
+//
+// Non-null arguments must be NUL-terminated strings.
+Felix *felix_new(const char *listen, const char *upstream, const char *db_path);
+
+// Maps `domain` (e.g. `"app.dev"` or `"*.app.dev"`) to the IPv4 address
+// `ip`, replacing any earlier mapping. Works whether or not the server is
+// running.
+//
+// # Safety
+//
+// `felix` must be a live handle; the strings must be NUL-terminated.
+int felix_add_domain(Felix *felix, const char *domain, const char *ip);
+
+// Removes the mapping for `domain`; removing an unmapped domain succeeds.
+//
+// # Safety
+//
+// `felix` must be a live handle; `domain` must be NUL-terminated.
+int felix_remove_domain(Felix *felix, const char *domain);
+
+// Binds the listener and starts answering. A no-op when already running.
+//
+// # Safety
+//
+// `felix` must be a live handle.
+int felix_start(Felix *felix);
+
+// Stops answering and releases the port; `felix_start` binds it again.
+//
+// # Safety
+//
+// `felix` must be a live handle.
+int felix_stop(Felix *felix);
+
+// The port the DNS listener is bound to, useful after asking for port 0;
+// 0 while stopped.
+//
+// # Safety
+//
+// `felix` must be null or a live handle.
+uint16_t felix_port(const Felix *felix);
+
+// Stops the server if running and frees the handle. Null is ignored.
+//
+// # Safety
+//
+// `felix` must be null or a live handle, which is invalid afterwards.
+void felix_free(Felix *felix);
+
+// Why the last failing call on this thread failed, or null if none has.
+// Valid until the next failing call on the same thread.
+const char *felix_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FELIX_H */
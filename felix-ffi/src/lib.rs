@@ -0,0 +1,295 @@
+//! C API for embedding felix in desktop apps and other non-Rust programs.
+//!
+//! A `Felix` handle owns a server and the Tokio runtime it runs on. Calls
+//! returning `int` give 0 on success and -1 on failure, with the reason
+//! available from `felix_last_error` on the calling thread. A handle may be
+//! used from any thread. `include/felix.h` declares everything here.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    net::{Ipv4Addr, SocketAddr},
+    ptr,
+    sync::Mutex,
+};
+
+use felix_dns::FelixServer;
+use tokio::runtime::Runtime;
+
+pub struct Felix {
+    runtime: Runtime,
+    server: Mutex<FelixServer>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: impl std::fmt::Display) {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning its error into -1 and `felix_last_error`.
+fn status(f: impl FnOnce() -> Result<(), String>) -> c_int {
+    match f() {
+        Ok(()) => 0,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// # Safety
+///
+/// `s` must be null or a NUL-terminated string valid for the call.
+unsafe fn arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is null", name));
+    }
+    // SAFETY: non-null and NUL-terminated per the caller's contract
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| format!("{} is not UTF-8", name))
+}
+
+/// # Safety
+///
+/// `felix` must be null or a live handle from `felix_new`.
+unsafe fn handle<'a>(felix: *const Felix) -> Result<&'a Felix, String> {
+    // SAFETY: null or live per the caller's contract
+    unsafe { felix.as_ref() }.ok_or_else(|| "felix handle is null".to_string())
+}
+
+fn parse<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {} {:?}", name, value))
+}
+
+/// Creates a stopped server listening for DNS on `listen` (e.g.
+/// `"127.0.0.1:5353"`) and forwarding unmapped names to `upstream`.
+/// Mappings are kept in the SQLite database at `db_path`, or only in memory
+/// when it is null. Returns null on failure.
+///
+/// # Safety
+///
+/// Non-null arguments must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_new(listen: *const c_char, upstream: *const c_char, db_path: *const c_char) -> *mut Felix {
+    let created = (|| -> Result<Felix, String> {
+        // SAFETY: forwarded from the caller
+        let listen: SocketAddr = parse(unsafe { arg(listen, "listen") }?, "listen address")?;
+        let upstream: SocketAddr = parse(unsafe { arg(upstream, "upstream") }?, "upstream address")?;
+        let mut builder = FelixServer::builder().listen(listen).upstream(upstream);
+        builder = if db_path.is_null() {
+            builder.in_memory()
+        } else {
+            // SAFETY: forwarded from the caller
+            sqlite(builder, unsafe { arg(db_path, "db_path") }?)?
+        };
+
+        let runtime = Runtime::new().map_err(|e| format!("starting runtime: {}", e))?;
+        let server = runtime.block_on(builder.build()).map_err(|e| e.to_string())?;
+        Ok(Felix { runtime, server: Mutex::new(server) })
+    })();
+    match created {
+        Ok(felix) => Box::into_raw(Box::new(felix)),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite(builder: felix_dns::server::FelixServerBuilder, path: &str) -> Result<felix_dns::server::FelixServerBuilder, String> {
+    Ok(builder.sqlite(path))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite(_builder: felix_dns::server::FelixServerBuilder, _path: &str) -> Result<felix_dns::server::FelixServerBuilder, String> {
+    Err("built without SQLite support; pass a null db_path".to_string())
+}
+
+/// Maps `domain` (e.g. `"app.dev"` or `"*.app.dev"`) to the IPv4 address
+/// `ip`, replacing any earlier mapping. Works whether or not the server is
+/// running.
+///
+/// # Safety
+///
+/// `felix` must be a live handle; the strings must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_add_domain(felix: *mut Felix, domain: *const c_char, ip: *const c_char) -> c_int {
+    status(|| {
+        // SAFETY: forwarded from the caller
+        let (felix, domain, ip) = unsafe { (handle(felix)?, arg(domain, "domain")?, arg(ip, "ip")?) };
+        let ip: Ipv4Addr = parse(ip, "IPv4 address")?;
+        let state = felix.server.lock().map_err(|e| e.to_string())?.state().clone();
+        felix.runtime.block_on(state.add_domain(domain, ip)).map_err(|e| e.to_string())
+    })
+}
+
+/// Removes the mapping for `domain`; removing an unmapped domain succeeds.
+///
+/// # Safety
+///
+/// `felix` must be a live handle; `domain` must be NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_remove_domain(felix: *mut Felix, domain: *const c_char) -> c_int {
+    status(|| {
+        // SAFETY: forwarded from the caller
+        let (felix, domain) = unsafe { (handle(felix)?, arg(domain, "domain")?) };
+        let state = felix.server.lock().map_err(|e| e.to_string())?.state().clone();
+        felix.runtime.block_on(state.remove_domain(domain)).map_err(|e| e.to_string())
+    })
+}
+
+/// Binds the listener and starts answering. A no-op when already running.
+///
+/// # Safety
+///
+/// `felix` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_start(felix: *mut Felix) -> c_int {
+    status(|| {
+        // SAFETY: forwarded from the caller
+        let felix = unsafe { handle(felix) }?;
+        let mut server = felix.server.lock().map_err(|e| e.to_string())?;
+        felix.runtime.block_on(server.start()).map_err(|e| e.to_string())
+    })
+}
+
+/// Stops answering and releases the port; `felix_start` binds it again.
+///
+/// # Safety
+///
+/// `felix` must be a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_stop(felix: *mut Felix) -> c_int {
+    status(|| {
+        // SAFETY: forwarded from the caller
+        let felix = unsafe { handle(felix) }?;
+        let mut server = felix.server.lock().map_err(|e| e.to_string())?;
+        felix.runtime.block_on(server.stop());
+        Ok(())
+    })
+}
+
+/// The port the DNS listener is bound to, useful after asking for port 0;
+/// 0 while stopped.
+///
+/// # Safety
+///
+/// `felix` must be null or a live handle.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_port(felix: *const Felix) -> u16 {
+    // SAFETY: forwarded from the caller
+    let Ok(felix) = (unsafe { handle(felix) }) else { return 0 };
+    let Ok(server) = felix.server.lock() else { return 0 };
+    server.local_addrs().first().map_or(0, SocketAddr::port)
+}
+
+/// Stops the server if running and frees the handle. Null is ignored.
+///
+/// # Safety
+///
+/// `felix` must be null or a live handle, which is invalid afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn felix_free(felix: *mut Felix) {
+    if felix.is_null() {
+        return;
+    }
+    // SAFETY: a live handle from felix_new, not used again by the caller
+    let felix = unsafe { Box::from_raw(felix) };
+    let Felix { runtime, server } = *felix;
+    match server.into_inner() {
+        Ok(server) => runtime.block_on(server.shutdown()),
+        Err(e) => log::warn!("Dropping felix server without shutdown: {}", e),
+    }
+}
+
+/// Why the last failing call on this thread failed, or null if none has.
+/// Valid until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn felix_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(felix_last_error()) }.to_str().unwrap().to_string()
+    }
+
+    /// A hand-built A query for `name`, to keep the test free of DNS crates.
+    fn query(name: &str) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+        packet
+    }
+
+    #[test]
+    fn test_embedding_lifecycle() {
+        unsafe {
+            let felix = felix_new(c("127.0.0.1:0").as_ptr(), c("127.0.0.1:9").as_ptr(), ptr::null());
+            assert!(!felix.is_null());
+            assert_eq!(felix_add_domain(felix, c("app.dev").as_ptr(), c("10.1.2.3").as_ptr()), 0);
+            assert_eq!(felix_add_domain(felix, c("bad.dev").as_ptr(), c("not-an-ip").as_ptr()), -1);
+            assert!(last_error().contains("not-an-ip"));
+
+            assert_eq!(felix_port(felix), 0);
+            assert_eq!(felix_start(felix), 0);
+            let port = felix_port(felix);
+            assert_ne!(port, 0);
+
+            let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+            client.send_to(&query("app.dev"), ("127.0.0.1", port)).unwrap();
+            let mut buf = [0u8; 512];
+            let n = client.recv(&mut buf).unwrap();
+            // one answer, ending in the mapped address
+            assert_eq!(&buf[6..8], &[0, 1]);
+            assert_eq!(&buf[n - 4..n], &[10, 1, 2, 3]);
+
+            assert_eq!(felix_stop(felix), 0);
+            assert_eq!(felix_port(felix), 0);
+            assert_eq!(felix_start(felix), 0);
+            assert_ne!(felix_port(felix), 0);
+            assert_eq!(felix_remove_domain(felix, c("app.dev").as_ptr()), 0);
+            felix_free(felix);
+        }
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        unsafe {
+            assert!(felix_new(ptr::null(), c("127.0.0.1:9").as_ptr(), ptr::null()).is_null());
+            assert_eq!(last_error(), "listen is null");
+            assert!(felix_new(c("localhost").as_ptr(), c("127.0.0.1:9").as_ptr(), ptr::null()).is_null());
+            assert_eq!(last_error(), "invalid listen address \"localhost\"");
+            assert_eq!(felix_start(ptr::null_mut()), -1);
+            felix_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_header_declares_every_export() {
+        let header = include_str!("../include/felix.h");
+        let exports: Vec<&str> = include_str!("lib.rs")
+            .lines()
+            .filter_map(|line| line.split_once("extern \"C\" fn ")?.1.split('(').next())
+            .collect();
+        assert_eq!(exports.len(), 8);
+        for name in exports {
+            assert!(header.contains(&format!("{}(", name)), "{} missing from felix.h", name);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Embeds the mappings of the TOML file named by `FELIX_EMBEDDED_DOMAINS`
+//! into the binary, see `src/embedded.rs`. Only a `[domains]` table of
+//! `"name" = "IPv4"` entries is understood, which keeps the build free of
+//! a TOML parser; anything else fails the build.
+
+use std::{env, fs, path::PathBuf};
+
+#[path = "src/embedded_toml.rs"]
+mod embedded_toml;
+
+const VAR: &str = "FELIX_EMBEDDED_DOMAINS";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={}", VAR);
+    println!("cargo:rerun-if-changed=src/embedded_toml.rs");
+    let domains = match env::var_os(VAR) {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            println!("cargo:rerun-if-changed={}", path.display());
+            let text = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: reading {}: {}", VAR, path.display(), e));
+            embedded_toml::parse(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+        }
+        None => Vec::new(),
+    };
+
+    let mut out = String::from("pub const EMBEDDED_DOMAINS: &[(&str, std::net::Ipv4Addr)] = &[\n");
+    for (domain, ip) in domains {
+        let [a, b, c, d] = ip.octets();
+        out.push_str(&format!("    ({:?}, std::net::Ipv4Addr::new({}, {}, {}, {})),\n", domain, a, b, c, d));
+    }
+    out.push_str("];\n");
+    let dest = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("embedded_domains.rs");
+    fs::write(dest, out).unwrap();
+}
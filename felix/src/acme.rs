@@ -0,0 +1,38 @@
+use anyhow::{Context, Result};
+use clap::Subcommand;
+
+use crate::api::{ApiArgs, check};
+
+#[derive(Subcommand)]
+pub enum AcmeCommand {
+    /// Publish a DNS-01 challenge value for DOMAIN at _acme-challenge.DOMAIN
+    Set {
+        domain: String,
+        value: String,
+        #[command(flatten)]
+        api: ApiArgs,
+    },
+    /// Remove VALUE, or every challenge value, for DOMAIN
+    Clear {
+        domain: String,
+        value: Option<String>,
+        #[command(flatten)]
+        api: ApiArgs,
+    },
+}
+
+pub async fn run(cmd: AcmeCommand) -> Result<()> {
+    let (path, domain, value, api) = match cmd {
+        AcmeCommand::Set { domain, value, api } => ("/acme/present", domain, Some(value), api),
+        AcmeCommand::Clear { domain, value, api } => ("/acme/cleanup", domain, value, api),
+    };
+    let resp = reqwest::Client::new()
+        .post(api.url(path))
+        .bearer_auth(&api.token)
+        .json(&serde_json::json!({ "fqdn": domain, "value": value }))
+        .send()
+        .await
+        .with_context(|| format!("connecting to {}", api.api))?;
+    check(resp).await?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//! Shared bits of the subcommands that talk to a running instance's
+//! management API.
+
+use std::net::SocketAddr;
+
+use anyhow::{Result, bail};
+use clap::Args;
+use felix_client::FelixClient;
+
+#[derive(Args)]
+pub struct ApiArgs {
+    /// Management API address of the running instance
+    #[arg(long, default_value = "127.0.0.1:5380")]
+    pub api: SocketAddr,
+    /// Management token
+    #[arg(long, env = "FELIX_TOKEN")]
+    pub token: String,
+}
+
+impl ApiArgs {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.api, path)
+    }
+
+    pub fn client(&self) -> FelixClient {
+        FelixClient::new(self.api, &self.token)
+    }
+}
+
+/// Turns a non-2xx response into an error carrying its body.
+pub async fn check(resp: reqwest::Response) -> Result<reqwest::Response> {
+    if !resp.status().is_success() {
+        bail!("{}: {}", resp.status(), resp.text().await.unwrap_or_default());
+    }
+    Ok(resp)
+}
@@ -0,0 +1,170 @@
+//! `felix apply`: makes a running instance's mappings match a file, for
+//! keeping team DNS state in version control.
+//!
+//! ```yaml
+//! domains:
+//!   app.dev: 127.0.0.1
+//!   "*.app.dev": 127.0.0.1
+//! ```
+
+use std::{collections::BTreeMap, net::Ipv4Addr, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use felix_dns::name::NormalizedName;
+use serde::Deserialize;
+
+use crate::api::ApiArgs;
+
+#[derive(Args)]
+pub struct ApplyArgs {
+    /// YAML file listing every mapping the instance should have
+    file: PathBuf,
+    #[command(flatten)]
+    api: ApiArgs,
+    /// Print the changes without making them
+    #[arg(long)]
+    dry_run: bool,
+    /// Allow mappings to public addresses when the instance runs with --safe-ips
+    #[arg(long)]
+    allow_public: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DesiredState {
+    #[serde(default)]
+    domains: BTreeMap<String, Ipv4Addr>,
+}
+
+enum Change {
+    Add(String, Ipv4Addr),
+    Update(String, Ipv4Addr, Ipv4Addr),
+    Remove(String, Ipv4Addr),
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Add(domain, ip) => write!(f, "+ {} {}", domain, ip),
+            Change::Update(domain, from, to) => write!(f, "~ {} {} -> {}", domain, from, to),
+            Change::Remove(domain, ip) => write!(f, "- {} {}", domain, ip),
+        }
+    }
+}
+
+/// What turns `current` into `desired`, by domain. Both are keyed the way
+/// the store keeps names, see `normalize`.
+fn diff(current: &BTreeMap<String, Ipv4Addr>, desired: &BTreeMap<String, Ipv4Addr>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (domain, ip) in desired {
+        match current.get(domain) {
+            None => changes.push(Change::Add(domain.clone(), *ip)),
+            Some(old) if old != ip => changes.push(Change::Update(domain.clone(), *old, *ip)),
+            Some(_) => {}
+        }
+    }
+    for (domain, ip) in current {
+        if !desired.contains_key(domain) {
+            changes.push(Change::Remove(domain.clone(), *ip));
+        }
+    }
+    changes
+}
+
+/// The file's mappings keyed the way the store keeps them: lowercase,
+/// punycode, without a trailing dot. Names that end up the same, such as
+/// `App.dev` and `app.dev.`, are an error rather than one silently winning.
+fn normalize(domains: &BTreeMap<String, Ipv4Addr>) -> Result<BTreeMap<String, Ipv4Addr>> {
+    let mut normalized = BTreeMap::new();
+    let mut spelled: BTreeMap<String, &str> = BTreeMap::new();
+    for (domain, ip) in domains {
+        let key = NormalizedName::new(domain).into_string();
+        if let Some(other) = spelled.insert(key.clone(), domain) {
+            bail!("{:?} and {:?} are the same domain, {}", other, domain, key);
+        }
+        normalized.insert(key, *ip);
+    }
+    Ok(normalized)
+}
+
+/// Prints the diff between the file and the instance, then applies it
+/// unless `--dry-run` is given. Unchanged mappings are left alone.
+pub async fn run(args: ApplyArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&args.file).with_context(|| format!("reading {}", args.file.display()))?;
+    let state: DesiredState =
+        serde_yml::from_str(&text).with_context(|| format!("parsing {}", args.file.display()))?;
+    let desired = normalize(&state.domains).with_context(|| format!("in {}", args.file.display()))?;
+
+    let client = args.api.client();
+    let current: BTreeMap<String, Ipv4Addr> = client
+        .list_domains()
+        .await
+        .with_context(|| format!("connecting to {}", args.api.api))?
+        .into_iter()
+        .map(|d| (d.domain, d.ip))
+        .collect();
+
+    let changes = diff(&current, &desired);
+    if changes.is_empty() {
+        println!("No changes: {} mapping(s) up to date", desired.len());
+        return Ok(());
+    }
+    for change in &changes {
+        println!("{}", change);
+    }
+    if args.dry_run {
+        println!("{} change(s) not applied (dry run)", changes.len());
+        return Ok(());
+    }
+
+    for change in &changes {
+        let warnings = match change {
+            Change::Add(domain, ip) | Change::Update(domain, _, ip) if args.allow_public => {
+                client.add_domain_allow_public(domain, *ip).await
+            }
+            Change::Add(domain, ip) | Change::Update(domain, _, ip) => client.add_domain(domain, *ip).await,
+            Change::Remove(domain, _) => client.remove_domain(domain).await.map(|()| Vec::new()),
+        }
+        .with_context(|| format!("applying {}", change))?;
+        for warning in warnings {
+            println!("  warning: {}", warning);
+        }
+    }
+    println!("{} change(s) applied", changes.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(entries: &[(&str, [u8; 4])]) -> BTreeMap<String, Ipv4Addr> {
+        entries.iter().map(|(d, ip)| (d.to_string(), Ipv4Addr::from(*ip))).collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let current = map(&[("app.dev", [127, 0, 0, 1]), ("api.dev", [127, 0, 0, 1]), ("old.dev", [10, 0, 0, 1])]);
+        let desired = map(&[("app.dev", [127, 0, 0, 1]), ("api.dev", [127, 0, 0, 2]), ("new.dev", [10, 0, 0, 2])]);
+        let changes: Vec<String> = diff(&current, &desired).iter().map(ToString::to_string).collect();
+        assert_eq!(changes, vec!["~ api.dev 127.0.0.1 -> 127.0.0.2", "+ new.dev 10.0.0.2", "- old.dev 10.0.0.1"]);
+        assert!(diff(&desired, &desired).is_empty());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let desired = normalize(&map(&[("App.dev.", [127, 0, 0, 1]), ("Bücher.dev", [127, 0, 0, 1])])).unwrap();
+        assert_eq!(desired.keys().collect::<Vec<_>>(), vec!["app.dev", "xn--bcher-kva.dev"]);
+
+        let err = normalize(&map(&[("App.dev", [127, 0, 0, 1]), ("app.dev.", [127, 0, 0, 2])])).unwrap_err();
+        assert!(err.to_string().contains("same domain"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_desired_state() {
+        let state: DesiredState = serde_yml::from_str("domains:\n  app.dev: 127.0.0.1\n  \"*.app.dev\": 10.0.0.1\n").unwrap();
+        assert_eq!(state.domains, map(&[("app.dev", [127, 0, 0, 1]), ("*.app.dev", [10, 0, 0, 1])]));
+        assert!(serde_yml::from_str::<DesiredState>("mappings: {}\n").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! The local CA the HTTPS proxy issues certificates from, and installing it
+//! into the system trust store.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use clap::{Args, Subcommand};
+use felix_dns::LocalCa;
+
+#[derive(Args)]
+pub struct CaDirArgs {
+    /// Directory holding the local CA, created on first use [default:
+    /// $XDG_DATA_HOME/felix or ~/.local/share/felix]
+    #[arg(long, env = "FELIX_CA_DIR")]
+    ca_dir: Option<PathBuf>,
+}
+
+impl CaDirArgs {
+    pub fn load(&self) -> Result<LocalCa> {
+        let dir = match &self.ca_dir {
+            Some(dir) => dir.clone(),
+            None => default_dir()?,
+        };
+        LocalCa::load_or_create(&dir).with_context(|| format!("loading CA from {}", dir.display()))
+    }
+}
+
+fn default_dir() -> Result<PathBuf> {
+    if let Some(data) = std::env::var_os("XDG_DATA_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(data).join("felix"));
+    }
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .context("cannot locate the home directory, pass --ca-dir")?;
+    Ok(PathBuf::from(home).join(".local/share/felix"))
+}
+
+#[derive(Subcommand)]
+pub enum CaCommand {
+    /// Print the CA certificate path, creating the CA if needed
+    Path(CaDirArgs),
+    /// Add the CA to the system trust store (usually needs root)
+    Install(CaDirArgs),
+}
+
+pub fn run(cmd: CaCommand) -> Result<()> {
+    match cmd {
+        CaCommand::Path(args) => println!("{}", args.load()?.cert_path().display()),
+        CaCommand::Install(args) => {
+            let ca = args.load()?;
+            install(ca.cert_path())?;
+            println!("Installed {}", ca.cert_path().display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn install(cert: &Path) -> Result<()> {
+    // Debian/Ubuntu/Alpine, then Fedora/RHEL/Arch
+    let stores = [
+        ("/usr/local/share/ca-certificates", "update-ca-certificates"),
+        ("/etc/pki/ca-trust/source/anchors", "update-ca-trust"),
+    ];
+    let Some((dir, update)) = stores.iter().find(|(dir, _)| Path::new(dir).is_dir()) else {
+        bail!("no known system trust store found; add {} manually", cert.display());
+    };
+    let dest = Path::new(dir).join("felix-rootCA.crt");
+    std::fs::copy(cert, &dest).with_context(|| format!("copying CA to {}", dest.display()))?;
+    run_tool(&mut Command::new(update))
+}
+
+#[cfg(target_os = "macos")]
+fn install(cert: &Path) -> Result<()> {
+    run_tool(
+        Command::new("security")
+            .args(["add-trusted-cert", "-d", "-r", "trustRoot", "-k", "/Library/Keychains/System.keychain"])
+            .arg(cert),
+    )
+}
+
+#[cfg(windows)]
+fn install(cert: &Path) -> Result<()> {
+    run_tool(Command::new("certutil").args(["-addstore", "-f", "ROOT"]).arg(cert))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn install(cert: &Path) -> Result<()> {
+    bail!("installing into the trust store is not supported here; add {} manually", cert.display())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+fn run_tool(cmd: &mut Command) -> Result<()> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd.status().with_context(|| format!("running {}", program))?;
+    if !status.success() {
+        bail!("{} failed ({})", program, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use anyhow::{Context, Result};
+use clap::Subcommand;
+
+use crate::api::ApiArgs;
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// List cached answers with their remaining TTLs (read-only token is enough)
+    List(ApiArgs),
+    /// Purge the whole cache, or only NAME (needs an admin token)
+    Flush {
+        name: Option<String>,
+        #[command(flatten)]
+        api: ApiArgs,
+    },
+}
+
+pub async fn run(cmd: CacheCommand) -> Result<()> {
+    match cmd {
+        CacheCommand::List(api) => {
+            let entries = api.client().cache_entries().await.context("listing cache")?;
+            for e in entries {
+                println!("{:<6} {:>6}s {:>5} {:<9} {}", e.qtype, e.ttl, e.hits, e.rcode, e.name);
+                for answer in e.answers {
+                    println!("{:31}{}", "", answer);
+                }
+            }
+        }
+        CacheCommand::Flush { name, api } => {
+            let flushed = api.client().flush_cache(name.as_deref()).await.context("flushing cache")?;
+            println!("Flushed {} entries", flushed);
+        }
+    }
+    Ok(())
+}
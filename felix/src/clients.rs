@@ -0,0 +1,22 @@
+use anyhow::{Context, Result};
+
+use crate::api::ApiArgs;
+
+/// Prints query counts by client, busiest today first.
+pub async fn run(api: ApiArgs) -> Result<()> {
+    let clients = api.client().clients().await.with_context(|| format!("connecting to {}", api.api))?;
+    println!("{:<39} {:>8} {:>8} {:>8} {:>10} {:>6}", "CLIENT", "TODAY", "REFUSED", "QUOTA", "TOTAL", "IDLE");
+    for c in clients {
+        let quota = c.quota.map_or("-".to_string(), |q| q.to_string());
+        println!(
+            "{:<39} {:>8} {:>8} {:>8} {:>10} {:>5}s",
+            c.client.to_string(),
+            c.queries_today,
+            c.refused_today,
+            quota,
+            c.queries_total,
+            c.idle
+        );
+    }
+    Ok(())
+}
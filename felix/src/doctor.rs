@@ -0,0 +1,268 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use clap::Args;
+use felix_dns::{RecordType, proto};
+use serde::Deserialize;
+
+use crate::api::ApiArgs;
+
+/// How long to wait for a DNS or HTTP answer before calling a check failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    #[command(flatten)]
+    api: ApiArgs,
+    /// DNS listen address of the running instance
+    #[arg(long, default_value = "127.0.0.1:5353")]
+    dns: SocketAddr,
+    /// SQLite database of the running instance, to check it is writable
+    #[arg(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+}
+
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one check, with what to do about a problem.
+struct Finding {
+    status: Status,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(message: impl Into<String>) -> Self {
+        Self { status: Status::Ok, message: message.into(), fix: None }
+    }
+
+    fn warn(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { status: Status::Warn, message: message.into(), fix: Some(fix.into()) }
+    }
+
+    fn fail(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self { status: Status::Fail, message: message.into(), fix: Some(fix.into()) }
+    }
+}
+
+/// Checks a running instance for common problems and prints how to fix
+/// them. Fails when any check fails; warnings alone do not.
+pub async fn run(args: DoctorArgs) -> Result<()> {
+    let mut findings = vec![check_listener(args.dns).await];
+    findings.push(check_system_resolver(&args.api, args.dns).await);
+    findings.extend(check_readiness(&args.api).await);
+    if let Some(db) = &args.db {
+        findings.push(check_db_writable(db));
+    }
+    findings.extend(check_clashing_resolvers());
+    findings.extend(check_conflicts(&args.api).await);
+
+    let mut failed = 0;
+    for f in &findings {
+        let label = match f.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => {
+                failed += 1;
+                "FAIL"
+            }
+        };
+        println!("[{:>4}] {}", label, f.message);
+        if let Some(fix) = &f.fix {
+            println!("       fix: {}", fix);
+        }
+    }
+    if failed > 0 {
+        bail!("{} check(s) failed", failed);
+    }
+    Ok(())
+}
+
+/// The DNS listener answers at all; any reply will do.
+async fn check_listener(dns: SocketAddr) -> Finding {
+    let fix = format!("start felix with --listen {} or pass its DNS address with --dns", dns);
+    match query(dns, "localhost.").await {
+        Ok(_) => Finding::ok(format!("DNS listener answers on {}", dns)),
+        Err(e) => Finding::fail(format!("no DNS answer from {}: {}", dns, e), fix),
+    }
+}
+
+async fn query(dns: SocketAddr, name: &str) -> Result<()> {
+    let bind: SocketAddr = if dns.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+    socket.send_to(&proto::encode(&proto::build_query(name, RecordType::A)?)?, dns).await?;
+    let mut buf = [0u8; 512];
+    tokio::time::timeout(PROBE_TIMEOUT, socket.recv(&mut buf)).await??;
+    Ok(())
+}
+
+/// The operating system resolves an exact mapping to felix's address,
+/// i.e. applications actually reach felix.
+async fn check_system_resolver(api: &ApiArgs, dns: SocketAddr) -> Finding {
+    let Ok(domains) = api.client().list_domains().await else {
+        // reported by the conflicts check
+        return Finding::warn("could not check the system resolver", "make the management API reachable first");
+    };
+    let Some(probe) = domains.into_iter().find(|d| !d.domain.starts_with('*')) else {
+        return Finding::warn("no exact mapping to check the system resolver with", "map a name, e.g. felix-doctor.test");
+    };
+    let resolved = tokio::time::timeout(PROBE_TIMEOUT, tokio::net::lookup_host((probe.domain.as_str(), 0))).await;
+    let addrs: Vec<IpAddr> = match resolved {
+        Ok(Ok(addrs)) => addrs.map(|a| a.ip()).collect(),
+        _ => Vec::new(),
+    };
+    if addrs.contains(&IpAddr::V4(probe.ip)) {
+        return Finding::ok(format!("the system resolver answers {} from felix", probe.domain));
+    }
+    let message = format!("the system resolver does not answer {} with {} (got {:?})", probe.domain, probe.ip, addrs);
+    Finding::fail(message, system_resolver_fix(dns))
+}
+
+fn system_resolver_fix(dns: SocketAddr) -> String {
+    if dns.port() == 53 {
+        return format!("make {} the first nameserver of the system, e.g. in /etc/resolv.conf", dns.ip());
+    }
+    if cfg!(target_os = "macos") {
+        format!("create /etc/resolver/<tld> with `nameserver {}` and `port {}` for each dev TLD", dns.ip(), dns.port())
+    } else if cfg!(target_os = "linux") {
+        "run felix serve with --systemd-resolved split=test (or upstream), or listen on port 53".to_string()
+    } else {
+        "listen on port 53 and make felix the system's DNS server".to_string()
+    }
+}
+
+#[derive(Deserialize)]
+struct Readiness {
+    store: ReadyCheck,
+    upstream: ReadyCheck,
+}
+
+#[derive(Deserialize)]
+struct ReadyCheck {
+    ok: bool,
+    error: Option<String>,
+}
+
+/// The upstream answers and the store responds, as seen by the instance.
+async fn check_readiness(api: &ApiArgs) -> Vec<Finding> {
+    let http = reqwest::Client::builder().timeout(PROBE_TIMEOUT).build().unwrap_or_default();
+    let ready: Readiness = match http.get(api.url("/readyz")).send().await {
+        Ok(resp) => match resp.json().await {
+            Ok(ready) => ready,
+            Err(e) => return vec![Finding::fail(format!("unreadable /readyz from {}: {}", api.api, e), "upgrade felix")],
+        },
+        Err(e) => {
+            return vec![Finding::fail(
+                format!("management API at {} is unreachable: {}", api.api, e),
+                "start felix with --management and pass its address with --api",
+            )];
+        }
+    };
+    let upstream = match ready.upstream {
+        ReadyCheck { ok: true, .. } => Finding::ok("upstream answers"),
+        ReadyCheck { error, .. } => Finding::fail(
+            format!("upstream does not answer: {}", error.unwrap_or_default()),
+            "check network access or pick another with --upstream, e.g. --upstream 1.1.1.1",
+        ),
+    };
+    let store = match ready.store {
+        ReadyCheck { ok: true, .. } => Finding::ok("store responds"),
+        ReadyCheck { error, .. } => Finding::fail(
+            format!("store does not respond: {}", error.unwrap_or_default()),
+            "check the database file and restart felix",
+        ),
+    };
+    vec![upstream, store]
+}
+
+/// SQLite needs to write both the database and, for its journal, the
+/// directory holding it.
+fn check_db_writable(db: &Path) -> Finding {
+    let fix = format!("give the user running felix write access to {} and its directory", db.display());
+    if let Err(e) = std::fs::OpenOptions::new().append(true).open(db) {
+        return Finding::fail(format!("cannot write {}: {}", db.display(), e), fix);
+    }
+    let dir = match db.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".felix-doctor-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Finding::ok(format!("{} is writable", db.display()))
+        }
+        Err(e) => Finding::fail(format!("cannot create files next to {}: {}", db.display(), e), fix),
+    }
+}
+
+/// Other local resolvers that commonly hold port 53.
+#[cfg(target_os = "linux")]
+fn check_clashing_resolvers() -> Vec<Finding> {
+    let running: Vec<String> = std::fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .collect();
+    let mut findings = Vec::new();
+    if running.iter().any(|c| c == "systemd-resolve") {
+        findings.push(Finding::warn(
+            "systemd-resolved is running; its stub holds 127.0.0.53:53",
+            "listen on 127.0.0.1:53 rather than 0.0.0.0:53, or run felix serve with --systemd-resolved takeover",
+        ));
+    }
+    if running.iter().any(|c| c == "dnsmasq") {
+        findings.push(Finding::warn(
+            "dnsmasq is running and may hold port 53",
+            "stop it with `systemctl disable --now dnsmasq`, or give it bind-interfaces and a listen-address away from felix",
+        ));
+    }
+    findings
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_clashing_resolvers() -> Vec<Finding> {
+    Vec::new()
+}
+
+/// Exact entries and nested wildcards overriding wider wildcards.
+async fn check_conflicts(api: &ApiArgs) -> Vec<Finding> {
+    let conflicts = match api.client().conflicts().await {
+        Ok(conflicts) => conflicts,
+        Err(e) => {
+            return vec![Finding::fail(
+                format!("could not read mappings from {}: {}", api.api, e),
+                "start felix with --management and pass its address with --api and a token with --token",
+            )];
+        }
+    };
+    if conflicts.is_empty() {
+        return vec![Finding::ok("no mappings overlap")];
+    }
+    conflicts
+        .into_iter()
+        .map(|c| {
+            let message = if c.ip == c.wildcard_ip {
+                format!("{} repeats {} ({})", c.domain, c.wildcard, c.ip)
+            } else {
+                format!("{} ({}) overrides {} ({})", c.domain, c.ip, c.wildcard, c.wildcard_ip)
+            };
+            let fix = if c.ip == c.wildcard_ip {
+                format!("remove {}; {} already answers it", c.domain, c.wildcard)
+            } else {
+                format!("remove {} if {} should answer it", c.domain, c.wildcard)
+            };
+            Finding::warn(message, fix)
+        })
+        .collect()
+}
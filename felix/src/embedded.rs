@@ -0,0 +1,19 @@
+//! Mappings built into the binary, so an organization can ship a felix
+//! with its standard dev domains preloaded:
+//!
+//! ```toml
+//! # domains.toml
+//! [domains]
+//! "intranet.corp.test" = "10.1.0.10"
+//! "*.apps.corp.test" = "10.1.0.20"
+//! ```
+//!
+//! ```sh
+//! FELIX_EMBEDDED_DOMAINS=$PWD/domains.toml cargo build --release
+//! ```
+//!
+//! `serve` seeds them as ephemeral mappings, so they never reach `--db`,
+//! for names the store does not map yet, unless given
+//! `--no-embedded-domains`.
+
+include!(concat!(env!("OUT_DIR"), "/embedded_domains.rs"));
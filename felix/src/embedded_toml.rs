@@ -0,0 +1,77 @@
+//! The `[domains]` table parser behind `build.rs`, which includes this
+//! file; the binary only compiles it for the tests below.
+
+use std::net::Ipv4Addr;
+
+/// `"name" = "IPv4"` entries of a `[domains]` table, in file order.
+pub fn parse(text: &str) -> Result<Vec<(String, Ipv4Addr)>, String> {
+    let mut domains = Vec::new();
+    let mut in_domains = false;
+    for (n, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let at = |msg: &str| format!("line {}: {}", n + 1, msg);
+        if let Some(table) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if table.trim() != "domains" {
+                return Err(at(&format!("unknown table [{}], expected [domains]", table)));
+            }
+            in_domains = true;
+            continue;
+        }
+        if !in_domains {
+            return Err(at("entries must follow [domains]"));
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| at("expected \"name\" = \"IP\""))?;
+        let domain = unquote(key.trim()).to_string();
+        let ip = unquote(value.trim()).parse().map_err(|_| at(&format!("invalid IPv4 address {}", value.trim())))?;
+        domains.push((domain, ip));
+    }
+    Ok(domains)
+}
+
+/// Drops a `#` comment outside quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "# shipped defaults\n[domains]\n\"intranet.corp.test\" = \"10.1.0.10\" # the wiki\n\n\"*.apps.corp.test\"=\"10.1.0.20\"\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                ("intranet.corp.test".to_string(), Ipv4Addr::new(10, 1, 0, 10)),
+                ("*.apps.corp.test".to_string(), Ipv4Addr::new(10, 1, 0, 20)),
+            ]
+        );
+        assert_eq!(parse("[domains]\n\"hash#tag.test\" = \"10.0.0.1\"\n").unwrap()[0].0, "hash#tag.test");
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |text: &str| parse(text).unwrap_err();
+        assert_eq!(err("[services]\n"), "line 1: unknown table [services], expected [domains]");
+        assert_eq!(err("\"a.test\" = \"10.0.0.1\"\n"), "line 1: entries must follow [domains]");
+        assert_eq!(err("[domains]\na.test\n"), "line 2: expected \"name\" = \"IP\"");
+        assert_eq!(err("[domains]\n\"a.test\" = \"::1\"\n"), "line 2: invalid IPv4 address \"::1\"");
+    }
+}
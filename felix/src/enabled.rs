@@ -0,0 +1,12 @@
+use anyhow::{Context, Result};
+
+use crate::api::ApiArgs;
+
+/// Turns local resolution on or off; off, every query goes to the upstream.
+pub async fn run(api: ApiArgs, enabled: bool) -> Result<()> {
+    api.client()
+        .set_enabled(enabled)
+        .await
+        .with_context(|| format!("connecting to {}", api.api))?;
+    Ok(())
+}
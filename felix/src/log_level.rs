@@ -0,0 +1,27 @@
+use anyhow::{Context, Result};
+use clap::Args;
+
+use crate::api::ApiArgs;
+
+#[derive(Args)]
+pub struct LogLevelArgs {
+    /// New filter in RUST_LOG syntax, e.g. `info,felix_dns::server_handler=debug`;
+    /// prints the current one when omitted
+    filter: Option<String>,
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+/// Shows or changes the log filter of a running instance.
+pub async fn run(args: LogLevelArgs) -> Result<()> {
+    let client = args.api.client();
+    let context = || format!("connecting to {}", args.api.api);
+    match &args.filter {
+        Some(filter) => client.set_log_filter(filter).await.with_context(context)?,
+        None => match client.log_filter().await.with_context(context)? {
+            Some(filter) => println!("{}", filter),
+            None => println!("(the log filter is managed by the host application)"),
+        },
+    }
+    Ok(())
+}
@@ -1,91 +1,133 @@
-use std::net::{Ipv4Addr};
-use felix_dns::{ResolverState};
+mod acme;
+mod apply;
+mod api;
+#[cfg(feature = "tls")]
+mod ca;
+mod cache;
+mod clients;
+mod demo;
+mod doctor;
+mod embedded;
+#[cfg(test)]
+mod embedded_toml;
+mod enabled;
+mod log_level;
+mod resolve;
+mod serve;
+mod stats;
+mod tail;
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    
-    println!("🚀 Felix DNS Demo với SQLite storage");
-    
-    println!("\n📁 Demo 1: In-memory storage");
-    demo_in_memory().await;
-    
-    println!("\n💾 Demo 2: SQLite storage");
-    demo_sqlite().await;
-    
-    println!("\n✅ Hoàn thành!");
+use clap::{Parser, Subcommand};
+use felix_dns::log_filter;
+
+#[derive(Parser)]
+#[command(name = "felix", about = "Local development DNS server")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Runtime worker threads [default: one per core]
+    #[arg(long, global = true, value_name = "N")]
+    worker_threads: Option<usize>,
+    /// Most threads for blocking work such as redb transactions [default: 512]
+    #[arg(long, global = true, value_name = "N")]
+    max_blocking_threads: Option<usize>,
+    /// Log only warnings and errors, ignoring RUST_LOG
+    #[arg(long, short, global = true)]
+    quiet: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Set or clear ACME DNS-01 challenge records on a running instance
+    #[command(subcommand)]
+    Acme(acme::AcmeCommand),
+    /// Make the mappings of a running instance match a YAML file
+    Apply(apply::ApplyArgs),
+    /// Manage the local CA used for HTTPS proxy certificates
+    #[cfg(feature = "tls")]
+    #[command(subcommand)]
+    Ca(ca::CaCommand),
+    /// Inspect or purge the cache of a running instance
+    #[command(subcommand)]
+    Cache(cache::CacheCommand),
+    /// Show per-client query counts and quotas of a running instance
+    Clients(api::ApiArgs),
+    /// Run the in-memory and SQLite storage demo
+    Demo,
+    /// Check a running instance for common problems
+    Doctor(doctor::DoctorArgs),
+    /// Pass every query of a running instance straight to its upstream
+    Disable(api::ApiArgs),
+    /// Resume local resolution after `disable`
+    Enable(api::ApiArgs),
+    /// Show or change the log filter of a running instance
+    LogLevel(log_level::LogLevelArgs),
+    /// Look a name up on a running instance, optionally explaining how it was answered
+    Resolve(resolve::ResolveArgs),
+    /// Run the DNS server and management API
+    Serve(Box<serve::ServeArgs>),
+    /// Show which names and query types a running instance answers most
+    Stats(stats::StatsArgs),
+    /// Follow queries handled by a running instance
+    Tail(tail::TailArgs),
 }
 
-async fn demo_in_memory() {
-    let state = ResolverState::new("8.8.8.8:53".parse().unwrap());
-    state.add_domain_sync("inmemory.dev", Ipv4Addr::new(192, 168, 1, 1));
-    
-    if let Ok(Some(ip)) = state.resolve("inmemory.dev").await {
-        println!("   ✓ Resolved inmemory.dev -> {}", ip);
+fn main() {
+    let cli = Cli::parse();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(n) = cli.worker_threads {
+        runtime.worker_threads(n);
     }
-    
-    if let Ok(domains) = state.list_domains().await {
-        println!("   ✓ Total domains in memory: {}", domains.len());
+    if let Some(n) = cli.max_blocking_threads {
+        runtime.max_blocking_threads(n);
     }
+    let runtime = match runtime.build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("error: starting runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run(cli));
 }
 
-async fn demo_sqlite() {
-    use std::fs;
-    let db_path = "./felix_demo.db";
-    
-    let _ = fs::remove_file(db_path);
-    
-    println!("   📂 Creating SQLite database at: {}", db_path);
-    let state = ResolverState::new_with_sqlite("8.8.8.8:53".parse().unwrap(), db_path)
-        .await
-        .expect("Failed to create SQLite resolver state");
-    
-    let domains = vec![
-        ("sqlite.dev", Ipv4Addr::new(10, 0, 0, 1)),
-        ("*.test.local", Ipv4Addr::new(172, 16, 0, 1)),
-        ("api.example.com", Ipv4Addr::new(203, 0, 113, 1)),
-    ];
-    
-    for (domain, ip) in &domains {
-        if let Err(e) = state.add_domain(domain, *ip).await {
-            println!("   ❌ Failed to add {}: {}", domain, e);
+async fn run(cli: Cli) {
+    // `serve --syslog` installs its own logger
+    if !matches!(&cli.command, Command::Serve(args) if args.syslog.is_some()) {
+        let installed = if cli.quiet {
+            log_filter::install(Box::new(log_filter::stderr_logger()), "warn")
         } else {
-            println!("   ✓ Added {} -> {}", domain, ip);
+            log_filter::install_env_logger("error")
+        };
+        if let Err(e) = installed {
+            eprintln!("warning: {}", e);
         }
     }
-    
-    println!("\n   🔍 Testing resolution:");
-    let test_queries = vec![
-        "sqlite.dev",
-        "app.test.local", 
-        "api.example.com",
-        "unknown.domain",  
-    ];
-    
-    for query in &test_queries {
-        match state.resolve(query).await {
-            Ok(Some(ip)) => println!("   ✓ {} -> {}", query, ip),
-            Ok(None) => println!("   ❌ {} -> NOT FOUND", query),
-            Err(e) => println!("   ⚠️ {} -> ERROR: {}", query, e),
-        }
-    }
-    
-    if let Ok(all_domains) = state.list_domains().await {
-        println!("\n   📝 All domains in SQLite ({} total):", all_domains.len());
-        for (domain, ip) in all_domains {
-            println!("      {} -> {}", domain, ip);
+
+    let result = match cli.command {
+        Command::Acme(cmd) => acme::run(cmd).await,
+        Command::Apply(args) => apply::run(args).await,
+        #[cfg(feature = "tls")]
+        Command::Ca(cmd) => ca::run(cmd),
+        Command::Cache(cmd) => cache::run(cmd).await,
+        Command::Clients(api) => clients::run(api).await,
+        Command::Demo => {
+            demo::run().await;
+            Ok(())
         }
+        Command::Doctor(args) => doctor::run(args).await,
+        Command::Disable(api) => enabled::run(api, false).await,
+        Command::Enable(api) => enabled::run(api, true).await,
+        Command::LogLevel(args) => log_level::run(args).await,
+        Command::Resolve(args) => resolve::run(args).await,
+        Command::Serve(args) => serve::run(*args, cli.quiet).await,
+        Command::Stats(args) => stats::run(args).await,
+        Command::Tail(args) => tail::run(args).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
     }
-    
-    println!("\n   💾 Testing persistence - creating new resolver with same DB:");
-    let state2 = ResolverState::new_with_sqlite("8.8.8.8:53".parse().unwrap(), db_path)
-        .await
-        .expect("Failed to create second SQLite resolver state");
-    
-    if let Ok(domains_count) = state2.list_domains().await {
-        println!("   ✓ Persisted {} domains successfully!", domains_count.len());
-    }
-    
-    let _ = fs::remove_file(db_path);
-    println!("   🧹 Cleaned up demo database");
 }
@@ -0,0 +1,80 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use felix_client::FelixClient;
+use felix_dns::{RecordType, proto};
+use tokio::{net::UdpSocket, time::timeout};
+
+#[derive(Args)]
+pub struct ResolveArgs {
+    /// Name to look up
+    name: String,
+    /// Explain which rule answered, in what order rules were checked and how long each took (uses the management API)
+    #[arg(long)]
+    trace: bool,
+    /// DNS address of the running instance
+    #[arg(long, default_value = "127.0.0.1:53")]
+    server: SocketAddr,
+    /// Management API address, for --trace
+    #[arg(long, default_value = "127.0.0.1:5380")]
+    api: SocketAddr,
+    /// Management token (read-only is enough), for --trace
+    #[arg(long, env = "FELIX_TOKEN")]
+    token: Option<String>,
+}
+
+pub async fn run(args: ResolveArgs) -> Result<()> {
+    if args.trace {
+        return trace(&args).await;
+    }
+
+    let query = proto::build_query(&args.name, RecordType::A)?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.send_to(&proto::encode(&query)?, args.server).await?;
+    let mut buf = vec![0u8; 4096];
+    let len = timeout(Duration::from_secs(3), socket.recv(&mut buf))
+        .await
+        .with_context(|| format!("no answer from {}", args.server))??;
+
+    let reply = proto::decode(&buf[..len])?;
+    if reply.answers().is_empty() {
+        println!("{}", reply.response_code());
+    }
+    for record in reply.answers() {
+        println!("{}", record);
+    }
+    Ok(())
+}
+
+async fn trace(args: &ResolveArgs) -> Result<()> {
+    let Some(token) = &args.token else {
+        bail!("--trace needs a management token: pass --token or set FELIX_TOKEN");
+    };
+    let trace = FelixClient::new(args.api, token)
+        .resolve_trace(&args.name)
+        .await
+        .with_context(|| format!("connecting to {}", args.api))?;
+
+    println!("{:<3} {:<18} {:<40} {:<6} {:>9}", "#", "CHECK", "SUBJECT", "MATCH", "TIME");
+    for (i, step) in trace.steps.iter().enumerate() {
+        let check = match step.level {
+            Some(level) => format!("{} (level {})", step.check, level),
+            None => step.check.clone(),
+        };
+        let matched = if step.matched { "yes" } else { "no" };
+        print!("{:<3} {:<18} {:<40} {:<6} {:>7}us", i + 1, check, step.subject, matched, step.elapsed_us);
+        match &step.detail {
+            Some(detail) => println!("  {}", detail),
+            None => println!(),
+        }
+    }
+    println!();
+    match (&trace.stage, &trace.decided_by) {
+        (Some(stage), Some(check)) => {
+            println!("{} -> {} (by {}/{}, {}us)", trace.name, trace.answer, stage, check, trace.elapsed_us)
+        }
+        _ => println!("{} -> {} ({}us)", trace.name, trace.answer, trace.elapsed_us),
+    }
+    Ok(())
+}
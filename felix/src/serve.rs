@@ -0,0 +1,896 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use felix_dns::{
+    AlertConfig, AnswerOrder, BlockResponse, CacheConfig, ChaosAnswer, ChaosConfig, ClientNet, UpdateKey, DohBootstrap, FailoverConfig, FelixServer, ForwardRule, GossipConfig, HostsSyncConfig, ListenOptions, LlmnrConfig, LocalOnly, NegativeSoa, PolicyAction, PolicyRule, PolicyTimeZone,
+    ProjectTemplate, PublicSuffixGuard, QuotaConfig, RaftConfig, RecordType, ReservedTlds, ResolvedMode, RewriteRule, Role, Schedule, SingleLabel, ScriptHooks, StatsdConfig, SyslogTarget, TtlClamp, TypeBlockRule,
+    policy::{NaiveTime, Weekday},
+    system_dns, systemd_resolved,
+};
+
+use crate::embedded;
+
+/// The settings needed to run felix as a sidecar container can also come
+/// from `FELIX_*` environment variables, e.g. `FELIX_LISTEN=0.0.0.0:53`
+/// and `FELIX_DOMAINS="app.dev=127.0.0.1,*.api.dev=10.0.0.5"`; list
+/// variables are comma-separated. Flags win over the environment.
+#[derive(Args)]
+pub struct ServeArgs {
+    /// DNS listen address
+    #[arg(long, env = "FELIX_LISTEN", default_value = "127.0.0.1:5353")]
+    listen: SocketAddr,
+    /// Only serve queries arriving on this network interface, e.g. docker0
+    /// (Linux only)
+    #[arg(long, value_name = "IFACE")]
+    interface: Option<String>,
+    /// Receive on this many SO_REUSEPORT sockets, each with its own loop,
+    /// to spread heavy query load across cores (unix only)
+    #[arg(long, default_value_t = 1)]
+    udp_workers: usize,
+    /// Move up to this many datagrams per recvmmsg/sendmmsg call, cutting
+    /// syscalls at high query rates (Linux only)
+    #[arg(long, default_value_t = 1)]
+    udp_batch: usize,
+    /// Look up single-label names under DOMAIN, like resolv.conf's search
+    /// directive (api -> api.mycompany.dev); may be repeated, tried in order
+    #[arg(long = "search", env = "FELIX_SEARCH", value_delimiter = ',', value_name = "DOMAIN")]
+    search: Vec<String>,
+    /// Append the search and domain directives of /etc/resolv.conf to the
+    /// search list
+    #[arg(long, env = "FELIX_SEARCH_FROM_RESOLV_CONF")]
+    search_from_resolv_conf: bool,
+    /// Queries for names without a dot: expand them with the search list
+    /// (forwarded as asked without one), forward them, refuse them, or
+    /// answer them from the mappings only so they never leave the machine
+    #[arg(long, env = "FELIX_SINGLE_LABEL", value_name = "expand|forward|refuse|mappings", default_value = "expand", value_parser = parse_single_label)]
+    single_label: SingleLabel,
+    /// Upstream resolver for names without a local mapping [default: the one
+    /// saved in --db, else 8.8.8.8:53]. Saved to --db when given. May be
+    /// repeated: the others are asked in order when the upstream fails
+    #[arg(long, env = "FELIX_UPSTREAM", value_delimiter = ',')]
+    upstream: Vec<SocketAddr>,
+    /// Without --upstream or a saved one, forward to the system's configured
+    /// resolver (resolv.conf, scutil or the Windows DNS client) instead of
+    /// 8.8.8.8:53
+    #[arg(long, env = "FELIX_SYSTEM_UPSTREAM")]
+    system_upstream: bool,
+    /// Have systemd-resolved send the system's queries to felix while it
+    /// runs: all of them (upstream), only those for some domains
+    /// (split=test,dev) or by taking over its 127.0.0.53:53 stub
+    /// (takeover). Needs root.
+    #[arg(long, value_name = "upstream|split=DOMAINS|takeover", value_parser = parse_resolved_mode)]
+    systemd_resolved: Option<ResolvedMode>,
+    /// Forward SUFFIX and its subdomains to ADDR instead, may be repeated.
+    /// Saved to --db when given.
+    #[arg(long = "forward", env = "FELIX_FORWARD", value_delimiter = ',', value_name = "SUFFIX=ADDR", value_parser = parse_forward)]
+    forward_rules: Vec<ForwardRule>,
+    /// Forward a name, or `*.SUFFIX` for its subdomains, to ADDR; may be
+    /// repeated. Saved to --db when given.
+    #[arg(long = "resolve-via", env = "FELIX_RESOLVE_VIA", value_delimiter = ',', value_name = "DOMAIN=ADDR", value_parser = parse_upstream_mapping)]
+    upstream_mappings: Vec<(String, SocketAddr)>,
+    /// SQLite database path; mappings are kept in memory when omitted
+    #[cfg(feature = "sqlite")]
+    #[arg(long, env = "FELIX_DB")]
+    db: Option<String>,
+    /// Most --db connections, each with its own thread [default: 10]
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "N")]
+    db_connections: Option<u32>,
+    /// Read the --db encryption key from the OS keychain entry SERVICE
+    /// (macOS Keychain or the Secret Service via secret-tool) instead of
+    /// the FELIX_DB_KEY environment variable
+    #[cfg(feature = "sqlcipher")]
+    #[arg(long, value_name = "SERVICE")]
+    db_key_keychain: Option<String>,
+    /// Vacuum and analyze --db every SECS seconds
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "SECS")]
+    db_maintenance: Option<u64>,
+    /// redb database path, a pure-Rust alternative to --db
+    #[cfg(feature = "redb")]
+    #[arg(long, env = "FELIX_REDB")]
+    redb: Option<String>,
+    /// Batch --db writes and commit them every MS milliseconds
+    #[arg(long, value_name = "MS")]
+    write_behind: Option<u64>,
+    /// Management API listen address
+    #[arg(long, env = "FELIX_API", default_value = "127.0.0.1:5380")]
+    api: SocketAddr,
+    /// Run the HTTP reverse proxy for mappings with a port here, e.g. 127.0.0.1:80
+    #[arg(long)]
+    proxy: Option<SocketAddr>,
+    /// Run the proxy over HTTPS here too, e.g. 127.0.0.1:443, with
+    /// certificates from the local CA (see `felix ca install`)
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    https_proxy: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    #[command(flatten)]
+    ca: crate::ca::CaDirArgs,
+    /// Route proxied requests for DOMAIN (or `*.SUFFIX`) to PORT; may be
+    /// repeated. Saved to --db when given.
+    #[arg(long = "domain-port", value_name = "DOMAIN=PORT", value_parser = parse_domain_port)]
+    domain_ports: Vec<(String, u16)>,
+    /// Hold local answers for DOMAIN (or `*.SUFFIX`) back by MS
+    /// milliseconds, to test how clients cope with slow DNS; may be repeated
+    #[arg(long = "delay", value_name = "DOMAIN=MS", value_parser = parse_domain_delay)]
+    domain_delays: Vec<(String, Duration)>,
+    /// Map DOMAIN (or `*.SUFFIX`) to IP; may be repeated. Saved to --db
+    /// when given.
+    #[arg(long = "domain", env = "FELIX_DOMAINS", value_delimiter = ',', value_name = "DOMAIN=IP", value_parser = parse_domain_address)]
+    domains: Vec<(String, Ipv4Addr)>,
+    /// Also answer IP for DOMAIN, after its mapping; may be repeated
+    #[arg(long = "address", value_name = "DOMAIN=IP", value_parser = parse_domain_address)]
+    domain_addresses: Vec<(String, Ipv4Addr)>,
+    /// Order of the addresses answered for DOMAIN (or `*.SUFFIX`) when it
+    /// has several: fixed, shuffle or round-robin; may be repeated
+    #[arg(long = "answer-order", value_name = "DOMAIN=ORDER", value_parser = parse_answer_order)]
+    answer_orders: Vec<(String, AnswerOrder)>,
+    /// Skip the mappings built into this binary with FELIX_EMBEDDED_DOMAINS
+    #[arg(long)]
+    no_embedded_domains: bool,
+    /// Map the domains of project NAME to IP, `NAME.test` and `*.NAME.test`
+    /// unless --project-template says otherwise; may be repeated
+    #[arg(long = "project", value_name = "NAME=IP", value_parser = parse_domain_address)]
+    projects: Vec<(String, Ipv4Addr)>,
+    /// Domain patterns created for each --project, with {project} standing
+    /// for its name, e.g. "{project}.dev,*.{project}.dev"
+    #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+    project_template: Vec<String>,
+    /// Map DOMAIN to IP and its port to PORT, e.g. app.dev=127.0.0.1:3000;
+    /// the port is also answered in SRV and HTTPS records. May be repeated.
+    #[arg(long = "service", value_name = "DOMAIN=IP:PORT", value_parser = parse_service)]
+    services: Vec<(String, SocketAddrV4)>,
+    /// Token granting full management access
+    #[arg(long, env = "FELIX_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Token granting read-only management access (domains, search, stats, cache, events)
+    #[arg(long, env = "FELIX_READ_TOKEN")]
+    read_token: Option<String>,
+    /// Let `*.example.dev` and `**.example.dev` also answer `example.dev`
+    #[arg(long)]
+    wildcard_apex: bool,
+    /// Mirror mappings from the primary whose management API listens here
+    #[arg(long, requires = "primary_token")]
+    primary: Option<SocketAddr>,
+    /// Read-only token for the primary's management API
+    #[arg(long, env = "FELIX_PRIMARY_TOKEN")]
+    primary_token: Option<String>,
+    /// Share mappings with other felix instances on the LAN, found with
+    /// mDNS/DNS-SD. Discovery needs UDP port 5353, so --listen has to use
+    /// another port
+    #[arg(long, requires = "gossip_secret")]
+    gossip: bool,
+    /// Secret shared by every gossiping instance; packets signed with any
+    /// other are dropped
+    #[arg(long, env = "FELIX_GOSSIP_SECRET", hide_env_values = true)]
+    gossip_secret: Option<String>,
+    /// Join a Raft cluster as member ID, so domain adds and removes made
+    /// through the management API are replicated to every --raft-peer
+    #[arg(long, value_name = "ID", requires_all = ["raft_secret", "raft_peers"])]
+    raft_id: Option<u64>,
+    /// Address the Raft members talk to each other on
+    #[arg(long, default_value = "0.0.0.0:5389", requires = "raft_id")]
+    raft_bind: SocketAddr,
+    /// Another cluster member, e.g. 2=10.0.0.2:5389; may be repeated
+    #[arg(long = "raft-peer", env = "FELIX_RAFT_PEERS", value_delimiter = ',', value_name = "ID=ADDR", value_parser = parse_raft_peer)]
+    raft_peers: Vec<(u64, SocketAddr)>,
+    /// Secret shared by every cluster member
+    #[arg(long, env = "FELIX_RAFT_SECRET", hide_env_values = true)]
+    raft_secret: Option<String>,
+    /// Keep the Raft term, vote and log in this file, so the member can
+    /// rejoin after a restart
+    #[arg(long, value_name = "PATH", requires = "raft_id")]
+    raft_state: Option<PathBuf>,
+    /// Answer LLMNR queries (UDP 5355 multicast) for mapped names
+    #[arg(long)]
+    llmnr: bool,
+    /// Send query counts and latencies to this statsd agent, e.g. 127.0.0.1:8125
+    #[arg(long)]
+    statsd: Option<SocketAddr>,
+    /// Prefix of the statsd metric names
+    #[arg(long, default_value = "felix", requires = "statsd")]
+    statsd_prefix: String,
+    /// Send outcome and query type as DogStatsD tags instead of in the metric name
+    #[arg(long, requires = "statsd")]
+    statsd_tags: bool,
+    /// Failed forwards in a row after which an upstream is reported down
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    down_after: u32,
+    /// Share of SERVFAIL answers per minute, 0.0 to 1.0, reported as high
+    #[arg(long, value_name = "RATE", default_value_t = 0.5, value_parser = parse_rate)]
+    servfail_alert_rate: f64,
+    /// POST upstream alerts as JSON to this URL, e.g. a Slack incoming
+    /// webhook; may be repeated
+    #[cfg(feature = "webhooks")]
+    #[arg(long = "alert-webhook", value_name = "URL", env = "FELIX_ALERT_WEBHOOK", value_delimiter = ',', value_parser = parse_webhook_url)]
+    alert_webhooks: Vec<String>,
+    /// POST every mapping change as JSON to this URL; may be repeated
+    #[cfg(feature = "webhooks")]
+    #[arg(long = "change-webhook", value_name = "URL", env = "FELIX_CHANGE_WEBHOOK", value_delimiter = ',', value_parser = parse_webhook_url)]
+    change_webhooks: Vec<String>,
+    /// Mirror the IPv4 entries of a hosts file (default /etc/hosts) and
+    /// follow its changes
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "/etc/hosts")]
+    hosts_sync: Option<PathBuf>,
+    /// Rhai script defining on_query/on_answer hooks
+    #[arg(long)]
+    script: Option<String>,
+    /// Replace an address in forwarded answers, as FROM=TO; may be repeated
+    #[arg(long = "rewrite", value_parser = parse_rewrite)]
+    rewrites: Vec<RewriteRule>,
+    /// Raise TTLs of forwarded answers to at least this, before caching
+    #[arg(long)]
+    min_ttl: Option<u32>,
+    /// Cap TTLs of forwarded answers, before caching
+    #[arg(long)]
+    max_ttl: Option<u32>,
+    /// Bound TTLs of forwarded answers under a suffix, as SUFFIX=MIN:MAX
+    /// with either side optional; overrides --min-ttl/--max-ttl there. May
+    /// be repeated
+    #[arg(long = "ttl-clamp", value_parser = parse_ttl_clamp)]
+    ttl_clamps: Vec<TtlClamp>,
+    /// Block a query type, as
+    /// TYPE[@SUFFIX][:nodata|nxdomain|refused|sinkhole|redirect=IP]; may be
+    /// repeated
+    #[arg(long = "block-type", value_parser = parse_type_block)]
+    type_blocks: Vec<TypeBlockRule>,
+    /// Block a domain and its subdomains, as DOMAIN[:RESPONSE][@SCHEDULE]
+    /// with the responses of --block-type and a schedule like
+    /// mon-fri/09:00-17:00, sat,sun or 22:00-06:00; may be repeated
+    #[arg(long = "block", value_parser = parse_block)]
+    blocks: Vec<PolicyRule>,
+    /// Exempt a domain from --block, as DOMAIN[@SCHEDULE]; may be repeated
+    #[arg(long = "allow", value_parser = parse_allow)]
+    allows: Vec<PolicyRule>,
+    /// Time zone --block and --allow schedules are read in: "local" or a
+    /// UTC offset like +02:00
+    #[arg(long, default_value = "local", value_parser = parse_time_zone)]
+    policy_tz: PolicyTimeZone,
+    /// Answer for `version.bind` CHAOS queries, or "refuse"
+    #[arg(long, value_parser = parse_chaos)]
+    chaos_version: Option<ChaosAnswer>,
+    /// Answer for `hostname.bind` CHAOS queries, or "refuse"
+    #[arg(long, value_parser = parse_chaos)]
+    chaos_hostname: Option<ChaosAnswer>,
+    /// Forward unmapped *.localhost names instead of answering 127.0.0.1/::1
+    #[arg(long)]
+    forward_localhost: bool,
+    /// Forward unmapped .test names instead of answering NXDOMAIN
+    #[arg(long)]
+    forward_test: bool,
+    /// Seconds clients may cache locally generated NXDOMAIN and empty
+    /// answers, sent as the TTL of a synthesized SOA
+    #[arg(long, default_value_t = NegativeSoa::default().ttl)]
+    negative_ttl: u32,
+    /// Forwarded answers to cache, 0 to disable caching
+    #[arg(long, default_value_t = CacheConfig::default().max_entries)]
+    cache_size: usize,
+    /// Refresh cached answers asked for this many times before they expire,
+    /// 0 to disable prefetching
+    #[arg(long, default_value_t = CacheConfig::default().prefetch_hits)]
+    prefetch_hits: u32,
+    /// Retry over TCP when the upstream's UDP answer is truncated or does
+    /// not arrive
+    #[arg(long)]
+    tcp_fallback: bool,
+    /// DNS-over-HTTPS endpoint to try when the upstream cannot be reached
+    /// over plain DNS, e.g. https://1.1.1.1/dns-query
+    #[cfg(feature = "doh")]
+    #[arg(long, value_name = "URL", value_parser = parse_doh_url)]
+    doh_fallback: Option<String>,
+    /// How to look up the --doh-fallback host when the system resolver is
+    /// felix: resolver=ADDR for a plain DNS server, or pin=IP[,IP...]
+    #[cfg(feature = "doh")]
+    #[arg(long, value_name = "resolver=ADDR|pin=IPS", value_parser = parse_doh_bootstrap, requires = "doh_fallback")]
+    doh_bootstrap: Option<DohBootstrap>,
+    /// Queries each client may make per day before being refused
+    #[arg(long, value_name = "N")]
+    daily_quota: Option<u64>,
+    /// Daily quota for one client, as IP=N; may be repeated
+    #[arg(long = "client-quota", value_parser = parse_client_quota)]
+    client_quotas: Vec<(IpAddr, u64)>,
+    /// Only answer clients in this network, e.g. 192.168.1.0/24; may be
+    /// repeated. Without it every client is answered
+    #[arg(long = "allow-client", value_name = "CIDR")]
+    allowed_clients: Vec<ClientNet>,
+    /// Accept RFC 2136 updates of _acme-challenge TXT records signed with
+    /// this HMAC-SHA256 TSIG key, as NAME:BASE64SECRET
+    #[arg(long, env = "FELIX_ACME_UPDATE_KEY", hide_env_values = true)]
+    acme_update_key: Option<UpdateKey>,
+    /// Refuse mappings to public addresses unless the API request sets
+    /// allow_public, so a typo cannot hijack a real domain
+    #[arg(long)]
+    safe_ips: bool,
+    /// Warn about (the default) or refuse mappings under real public
+    /// suffixes like .com; .test, .localhost and .internal are always fine
+    #[arg(long, value_name = "warn|refuse", num_args = 0..=1, default_missing_value = "warn", value_parser = parse_public_suffix_guard)]
+    public_suffix_guard: Option<PublicSuffixGuard>,
+    /// Never forward: names without a local answer get NXDOMAIN (the
+    /// default) or REFUSED, e.g. for hermetic test environments
+    #[arg(long, value_name = "nxdomain|refused", num_args = 0..=1, default_missing_value = "nxdomain", value_parser = parse_local_only)]
+    local_only: Option<LocalOnly>,
+    /// Log to syslog (unix:///dev/log or udp://HOST:PORT) instead of stderr
+    #[arg(long)]
+    pub syslog: Option<SyslogTarget>,
+    /// WebAssembly resolution plugin, may be repeated
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long = "plugin")]
+    plugins: Vec<String>,
+    /// OTLP/HTTP collector to export query spans and metrics to, e.g. http://localhost:4318
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+/// `quiet` caps the syslog level at warnings; the stderr logger is set up
+/// by `main`.
+pub async fn run(args: ServeArgs, quiet: bool) -> Result<()> {
+    let mut search = args.search;
+    if args.search_from_resolv_conf {
+        let system = system_dns::system_search_domains().context("reading the search list of /etc/resolv.conf")?;
+        if system.is_empty() {
+            log::warn!("/etc/resolv.conf has no search list to import");
+        }
+        for domain in system {
+            if !search.contains(&domain) {
+                search.push(domain);
+            }
+        }
+    }
+    let listen = ListenOptions { interface: args.interface, workers: args.udp_workers, batch: args.udp_batch, search };
+    let mut builder = FelixServer::builder()
+        .listen_with(args.listen, listen)
+        .wildcard_apex(args.wildcard_apex)
+        .management(args.api);
+    if let Some(&upstream) = args.upstream.first() {
+        builder = builder.upstream(upstream);
+    }
+    if args.system_upstream {
+        builder = builder.system_upstream();
+    }
+    if !args.no_embedded_domains {
+        for (domain, ip) in embedded::EMBEDDED_DOMAINS {
+            builder = builder.ephemeral_domain(*domain, *ip);
+        }
+    }
+    for (domain, ip) in args.domains {
+        builder = builder.domain(domain, ip);
+    }
+    let resolved_stub = args.systemd_resolved.is_none() && systemd_resolved::stub_in_use();
+    if let Some(mode) = args.systemd_resolved {
+        builder = builder.systemd_resolved(mode);
+    }
+    for rule in args.forward_rules {
+        builder = builder.forward_rule(rule);
+    }
+    for (domain, upstream) in args.upstream_mappings {
+        builder = builder.upstream_mapping(domain, upstream);
+    }
+    if let Some(ms) = args.write_behind {
+        builder = builder.write_behind(Duration::from_millis(ms));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = args.db {
+        builder = builder.sqlite(path);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(n) = args.db_connections {
+        builder = builder.sqlite_connections(n);
+    }
+    #[cfg(feature = "sqlcipher")]
+    if let Some(key) = database_key(args.db_key_keychain.as_deref())? {
+        builder = builder.sqlite_key(key);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(secs) = args.db_maintenance {
+        builder = builder.sqlite_maintenance(Duration::from_secs(secs));
+    }
+    #[cfg(feature = "redb")]
+    if let Some(path) = args.redb {
+        builder = builder.redb(path);
+    }
+    if let Some(addr) = args.proxy {
+        builder = builder.http_proxy(addr);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(addr) = args.https_proxy {
+        builder = builder.https_proxy(addr, args.ca.load()?);
+    }
+    for (domain, port) in args.domain_ports {
+        builder = builder.domain_port(domain, port);
+    }
+    for (domain, delay) in args.domain_delays {
+        builder = builder.domain_delay(domain, delay);
+    }
+    for (domain, ip) in args.domain_addresses {
+        builder = builder.domain_address(domain, ip);
+    }
+    for (domain, order) in args.answer_orders {
+        builder = builder.answer_order(domain, order);
+    }
+    if !args.project_template.is_empty() {
+        builder = builder.project_template(ProjectTemplate::new(args.project_template));
+    }
+    for (project, ip) in args.projects {
+        builder = builder.project(project, ip);
+    }
+    for (domain, addr) in args.services {
+        builder = builder.service(domain, addr);
+    }
+    if let Some(token) = args.admin_token {
+        builder = builder.token(token, Role::Admin);
+    }
+    if let Some(token) = args.read_token {
+        builder = builder.token(token, Role::ReadOnly);
+    }
+    if let (Some(primary), Some(token)) = (args.primary, args.primary_token) {
+        builder = builder.replicate_from(primary, token);
+    }
+    if let Some(secret) = args.gossip_secret.filter(|_| args.gossip) {
+        builder = builder.gossip(GossipConfig { secret, ..Default::default() });
+    }
+    if let (Some(id), Some(secret)) = (args.raft_id, args.raft_secret) {
+        builder = builder.raft(RaftConfig {
+            id,
+            bind: args.raft_bind,
+            peers: args.raft_peers,
+            secret,
+            state_path: args.raft_state,
+            ..Default::default()
+        });
+    }
+    if args.llmnr {
+        builder = builder.llmnr(LlmnrConfig::default());
+    }
+    if let Some(addr) = args.statsd {
+        builder = builder.statsd(StatsdConfig { addr, prefix: args.statsd_prefix, tags: args.statsd_tags, ..Default::default() });
+    }
+    builder =
+        builder.alerts(AlertConfig { down_after: args.down_after, servfail_rate: args.servfail_alert_rate, ..Default::default() });
+    #[cfg(feature = "webhooks")]
+    if !args.alert_webhooks.is_empty() || !args.change_webhooks.is_empty() {
+        builder = builder.webhooks(felix_dns::WebhookConfig {
+            alert_urls: args.alert_webhooks,
+            change_urls: args.change_webhooks,
+            ..Default::default()
+        });
+    }
+    if let Some(path) = args.hosts_sync {
+        builder = builder.hosts_sync(HostsSyncConfig { path, ..Default::default() });
+    }
+    if let Some(path) = args.script {
+        builder = builder.script_hooks(ScriptHooks::from_file(path)?);
+    }
+    for rule in args.rewrites {
+        builder = builder.rewrite_rule(rule);
+    }
+    if args.min_ttl.is_some() || args.max_ttl.is_some() {
+        builder = builder.ttl_clamp(TtlClamp { suffix: None, min: args.min_ttl, max: args.max_ttl });
+    }
+    for clamp in args.ttl_clamps {
+        builder = builder.ttl_clamp(clamp);
+    }
+    for rule in args.type_blocks {
+        builder = builder.type_block_rule(rule);
+    }
+    // allow rules are exceptions, so they are checked first
+    for rule in args.allows.into_iter().chain(args.blocks) {
+        builder = builder.policy_rule(rule);
+    }
+    builder = builder.policy_time_zone(args.policy_tz);
+    let mut chaos = ChaosConfig::default();
+    if let Some(version) = args.chaos_version {
+        chaos.version = version;
+    }
+    if let Some(hostname) = args.chaos_hostname {
+        chaos.hostname = hostname;
+    }
+    builder = builder.chaos(chaos);
+    builder = builder.reserved_tlds(ReservedTlds { localhost: !args.forward_localhost, test_local_only: !args.forward_test });
+    builder = builder.single_label(args.single_label);
+    builder = builder.negative_soa(NegativeSoa { ttl: args.negative_ttl, ..Default::default() });
+    if let Some(key) = args.acme_update_key {
+        builder = builder.acme_update_key(key);
+    }
+    builder = builder.safe_ips(args.safe_ips);
+    if let Some(guard) = args.public_suffix_guard {
+        builder = builder.public_suffix_guard(guard);
+    }
+    if let Some(mode) = args.local_only {
+        builder = builder.local_only(mode);
+    }
+    builder = builder.cache(CacheConfig { max_entries: args.cache_size, prefetch_hits: args.prefetch_hits });
+    #[cfg(feature = "doh")]
+    let (doh, doh_bootstrap) = (args.doh_fallback, args.doh_bootstrap.unwrap_or_default());
+    #[cfg(not(feature = "doh"))]
+    let (doh, doh_bootstrap) = (None, DohBootstrap::default());
+    builder = builder.failover(FailoverConfig { tcp: args.tcp_fallback, upstreams: args.upstream, doh, doh_bootstrap });
+    builder = builder.quotas(QuotaConfig { daily: args.daily_quota, per_client: args.client_quotas.into_iter().collect() });
+    builder = builder.allowed_clients(args.allowed_clients);
+    if let Some(target) = args.syslog {
+        builder = builder.syslog(target);
+        if quiet {
+            builder = builder.log_level(log::LevelFilter::Warn);
+        }
+    }
+    #[cfg(feature = "wasm-plugins")]
+    for path in args.plugins {
+        builder = builder.wasm_plugin(felix_dns::wasm_plugin::WasmPlugin::from_file(path)?);
+    }
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = args.otlp_endpoint {
+        builder = builder.otlp_endpoint(endpoint);
+    }
+
+    let mut server = builder.build().await?;
+    server.start().await?;
+    if resolved_stub {
+        log::info!(
+            "The system resolves through systemd-resolved's stub, which does not know felix; \
+             pass --systemd-resolved upstream, split=DOMAINS or takeover to route queries here"
+        );
+    }
+
+    wait_for_exit(&mut server).await?;
+
+    server.shutdown().await;
+    Ok(())
+}
+
+/// Waits for Ctrl-C or, on unix, SIGTERM, so the shutdown that follows
+/// undoes the systemd-resolved settings; or for SIGUSR2 asking felix to
+/// restart without dropping queries: the binary at its path, e.g. just
+/// upgraded, takes the sockets over and this process then exits.
+#[cfg(unix)]
+async fn wait_for_exit(server: &mut FelixServer) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut restart = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            ctrl_c = tokio::signal::ctrl_c() => return Ok(ctrl_c?),
+            _ = terminate.recv() => return Ok(()),
+            _ = restart.recv() => match server.hand_over().await {
+                Ok(pid) => {
+                    log::info!("Now serving from pid {}, exiting", pid);
+                    return Ok(());
+                }
+                Err(e) => log::error!("Restart failed, still serving: {}", e),
+            },
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_exit(_server: &mut FelixServer) -> Result<()> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+fn parse_rewrite(s: &str) -> Result<RewriteRule> {
+    let (from, to) = s.split_once('=').context("expected FROM=TO")?;
+    let from: IpAddr = from.parse().with_context(|| format!("invalid address {:?}", from))?;
+    let to: IpAddr = to.parse().with_context(|| format!("invalid address {:?}", to))?;
+    Ok(RewriteRule::ReplaceAddress { from, to })
+}
+
+fn parse_ttl_clamp(s: &str) -> Result<TtlClamp> {
+    let (suffix, bounds) = s.split_once('=').context("expected SUFFIX=MIN:MAX")?;
+    let (min, max) = bounds.split_once(':').context("expected SUFFIX=MIN:MAX")?;
+    let ttl = |v: &str| -> Result<Option<u32>> {
+        if v.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(v.parse().with_context(|| format!("invalid TTL {:?}", v))?))
+    };
+    let clamp = TtlClamp { suffix: Some(suffix.to_string()), min: ttl(min)?, max: ttl(max)? };
+    if let (Some(min), Some(max)) = (clamp.min, clamp.max)
+        && min > max
+    {
+        bail!("minimum TTL {} is above maximum {}", min, max);
+    }
+    Ok(clamp)
+}
+
+fn parse_forward(s: &str) -> Result<ForwardRule> {
+    let (suffix, upstream) = s.split_once('=').context("expected SUFFIX=ADDR")?;
+    Ok(ForwardRule {
+        suffix: suffix.to_string(),
+        upstream: parse_upstream(upstream)?,
+    })
+}
+
+fn parse_domain_port(s: &str) -> Result<(String, u16)> {
+    let (domain, port) = s.split_once('=').context("expected DOMAIN=PORT")?;
+    let port = port.parse().with_context(|| format!("invalid port {:?}", port))?;
+    Ok((domain.to_string(), port))
+}
+
+fn parse_raft_peer(s: &str) -> Result<(u64, SocketAddr)> {
+    let (id, addr) = s.split_once('=').context("expected ID=ADDR")?;
+    let id = id.parse().with_context(|| format!("invalid member id {:?}", id))?;
+    let addr = addr.parse().with_context(|| format!("invalid address {:?}", addr))?;
+    Ok((id, addr))
+}
+
+fn parse_domain_delay(s: &str) -> Result<(String, Duration)> {
+    let (domain, ms) = s.split_once('=').context("expected DOMAIN=MS")?;
+    let ms = ms.parse().with_context(|| format!("invalid delay {:?}, expected milliseconds", ms))?;
+    Ok((domain.to_string(), Duration::from_millis(ms)))
+}
+
+fn parse_domain_address(s: &str) -> Result<(String, Ipv4Addr)> {
+    let (domain, ip) = s.split_once('=').context("expected DOMAIN=IP")?;
+    let ip = ip.parse().with_context(|| format!("invalid address {:?}", ip))?;
+    Ok((domain.to_string(), ip))
+}
+
+fn parse_answer_order(s: &str) -> Result<(String, AnswerOrder)> {
+    let (domain, order) = s.split_once('=').context("expected DOMAIN=ORDER")?;
+    let order = match order {
+        "fixed" => AnswerOrder::Fixed,
+        "shuffle" => AnswerOrder::Shuffle,
+        "round-robin" => AnswerOrder::RoundRobin,
+        _ => bail!("expected fixed, shuffle or round-robin, got {:?}", order),
+    };
+    Ok((domain.to_string(), order))
+}
+
+fn parse_service(s: &str) -> Result<(String, SocketAddrV4)> {
+    let (domain, addr) = s.split_once('=').context("expected DOMAIN=IP:PORT")?;
+    let addr = addr.parse().with_context(|| format!("invalid address {:?}", addr))?;
+    Ok((domain.to_string(), addr))
+}
+
+fn parse_upstream_mapping(s: &str) -> Result<(String, SocketAddr)> {
+    let (domain, upstream) = s.split_once('=').context("expected DOMAIN=ADDR")?;
+    Ok((domain.to_string(), parse_upstream(upstream)?))
+}
+
+/// `ADDR[:PORT]`, port 53 when omitted.
+fn parse_upstream(s: &str) -> Result<SocketAddr> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .with_context(|| format!("invalid upstream {:?}", s))
+}
+
+fn parse_local_only(s: &str) -> Result<LocalOnly> {
+    match s {
+        "nxdomain" => Ok(LocalOnly::NxDomain),
+        "refused" => Ok(LocalOnly::Refused),
+        _ => bail!("expected nxdomain or refused, got {:?}", s),
+    }
+}
+
+fn parse_single_label(s: &str) -> Result<SingleLabel> {
+    match s {
+        "expand" => Ok(SingleLabel::Expand),
+        "forward" => Ok(SingleLabel::Forward),
+        "refuse" => Ok(SingleLabel::Refuse),
+        "mappings" => Ok(SingleLabel::Mappings),
+        _ => bail!("expected expand, forward, refuse or mappings, got {:?}", s),
+    }
+}
+
+fn parse_resolved_mode(s: &str) -> Result<ResolvedMode> {
+    match s.split_once('=') {
+        None if s == "upstream" => Ok(ResolvedMode::Upstream),
+        None if s == "takeover" => Ok(ResolvedMode::TakeOver),
+        Some(("split", domains)) if !domains.is_empty() => {
+            Ok(ResolvedMode::Split(domains.split(',').map(str::to_string).collect()))
+        }
+        _ => bail!("expected upstream, split=DOMAIN[,DOMAIN...] or takeover, got {:?}", s),
+    }
+}
+
+fn parse_public_suffix_guard(s: &str) -> Result<PublicSuffixGuard> {
+    match s {
+        "warn" => Ok(PublicSuffixGuard::Warn),
+        "refuse" => Ok(PublicSuffixGuard::Refuse),
+        _ => bail!("expected warn or refuse, got {:?}", s),
+    }
+}
+
+fn parse_type_block(s: &str) -> Result<TypeBlockRule> {
+    // split at the first colon: a redirect address may be IPv6
+    let (rest, response) = match s.split_once(':') {
+        Some((rest, response)) => (rest, parse_block_response(response)?),
+        None => (s, BlockResponse::NoData),
+    };
+    let (qtype, suffix) = match rest.split_once('@') {
+        Some((qtype, suffix)) => (qtype, Some(suffix.to_string())),
+        None => (rest, None),
+    };
+    let qtype: RecordType = qtype
+        .to_ascii_uppercase()
+        .parse()
+        .with_context(|| format!("unknown record type {:?}", qtype))?;
+    Ok(TypeBlockRule { qtype, suffix, response })
+}
+
+fn parse_block_response(s: &str) -> Result<BlockResponse> {
+    Ok(match s {
+        "nodata" => BlockResponse::NoData,
+        "nxdomain" => BlockResponse::NxDomain,
+        "refused" => BlockResponse::Refused,
+        "sinkhole" => BlockResponse::Sinkhole,
+        other => match other.strip_prefix("redirect=") {
+            Some(ip) => BlockResponse::Redirect(ip.parse().with_context(|| format!("invalid redirect address {:?}", ip))?),
+            None => bail!("unknown block response {:?} (expected nodata, nxdomain, refused, sinkhole or redirect=IP)", other),
+        },
+    })
+}
+
+fn parse_block(s: &str) -> Result<PolicyRule> {
+    let (rest, schedule) = match s.split_once('@') {
+        Some((rest, schedule)) => (rest, Some(parse_schedule(schedule)?)),
+        None => (s, None),
+    };
+    let (suffix, response) = match rest.split_once(':') {
+        Some((suffix, response)) => (suffix, parse_block_response(response)?),
+        None => (rest, BlockResponse::NxDomain),
+    };
+    Ok(PolicyRule { suffix: suffix.to_string(), action: PolicyAction::Block(response), schedule })
+}
+
+fn parse_allow(s: &str) -> Result<PolicyRule> {
+    let (suffix, schedule) = match s.split_once('@') {
+        Some((suffix, schedule)) => (suffix, Some(parse_schedule(schedule)?)),
+        None => (s, None),
+    };
+    Ok(PolicyRule { suffix: suffix.to_string(), action: PolicyAction::Allow, schedule })
+}
+
+/// `[DAYS/]HH:MM-HH:MM` or `DAYS`, where DAYS is a comma-separated list of
+/// days or ranges such as `mon-fri`.
+fn parse_schedule(s: &str) -> Result<Schedule> {
+    let (days, window) = match s.split_once('/') {
+        Some((days, window)) => (parse_days(days)?, Some(window)),
+        None if s.contains(':') => (parse_days("mon-sun")?, Some(s)),
+        None => (parse_days(s)?, None),
+    };
+    let Some(window) = window else { return Ok(Schedule::days(days)) };
+    let (start, end) = window.split_once('-').with_context(|| format!("expected HH:MM-HH:MM, got {:?}", window))?;
+    let time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").with_context(|| format!("invalid time {:?}", t));
+    Ok(Schedule { days, start: time(start)?, end: time(end)? })
+}
+
+fn parse_days(s: &str) -> Result<Vec<Weekday>> {
+    let day = |d: &str| d.parse::<Weekday>().map_err(|_| anyhow::anyhow!("unknown day {:?}", d));
+    let mut days = Vec::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (mut d, to) = (day(from)?, day(to)?);
+                days.push(d);
+                while d != to {
+                    d = d.succ();
+                    days.push(d);
+                }
+            }
+            None => days.push(day(part)?),
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time_zone(s: &str) -> Result<PolicyTimeZone> {
+    if s == "local" {
+        return Ok(PolicyTimeZone::Local);
+    }
+    s.parse()
+        .map(PolicyTimeZone::Fixed)
+        .map_err(|_| anyhow::anyhow!("expected \"local\" or an offset like +02:00, got {:?}", s))
+}
+
+fn parse_client_quota(s: &str) -> Result<(IpAddr, u64)> {
+    let (ip, n) = s.split_once('=').with_context(|| format!("expected IP=N, got {:?}", s))?;
+    let ip = ip.parse().with_context(|| format!("invalid client address {:?}", ip))?;
+    let n = n.parse().with_context(|| format!("invalid quota {:?}", n))?;
+    Ok((ip, n))
+}
+
+fn parse_chaos(s: &str) -> Result<ChaosAnswer> {
+    Ok(match s {
+        "refuse" => ChaosAnswer::Refuse,
+        text => ChaosAnswer::Text(text.to_string()),
+    })
+}
+
+/// The SQLCipher key from the keychain entry `service` if given, else
+/// from `FELIX_DB_KEY`; `None` leaves the database unencrypted.
+#[cfg(feature = "sqlcipher")]
+fn database_key(service: Option<&str>) -> Result<Option<felix_dns::DatabaseKey>> {
+    let Some(service) = service else {
+        return Ok(std::env::var("FELIX_DB_KEY").ok().filter(|k| !k.is_empty()).map(felix_dns::DatabaseKey::new));
+    };
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = std::process::Command::new("security");
+        command.args(["find-generic-password", "-w", "-s", service]);
+        command
+    } else {
+        let mut command = std::process::Command::new("secret-tool");
+        command.args(["lookup", "service", service]);
+        command
+    };
+    let output = command.output().context("running the keychain tool")?;
+    if !output.status.success() {
+        bail!("no keychain entry for {:?}: {}", service, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let key = String::from_utf8(output.stdout).context("keychain entry is not UTF-8")?;
+    let key = key.trim_end_matches(['\r', '\n']);
+    if key.is_empty() {
+        bail!("keychain entry {:?} is empty", service);
+    }
+    Ok(Some(felix_dns::DatabaseKey::new(key)))
+}
+
+fn parse_rate(s: &str) -> Result<f64> {
+    let rate: f64 = s.parse().with_context(|| format!("invalid rate {:?}", s))?;
+    if !(0.0..=1.0).contains(&rate) {
+        bail!("expected a rate from 0.0 to 1.0, got {}", rate);
+    }
+    Ok(rate)
+}
+
+#[cfg(feature = "webhooks")]
+fn parse_webhook_url(s: &str) -> Result<String> {
+    let url = reqwest::Url::parse(s).with_context(|| format!("invalid URL {:?}", s))?;
+    if !matches!(url.scheme(), "https" | "http") {
+        bail!("expected an http:// or https:// URL, got {:?}", s);
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(feature = "doh")]
+fn parse_doh_bootstrap(s: &str) -> Result<DohBootstrap> {
+    match s.split_once('=') {
+        Some(("resolver", addr)) => Ok(DohBootstrap::Resolver(parse_upstream(addr)?)),
+        Some(("pin", ips)) if !ips.is_empty() => Ok(DohBootstrap::Pinned(
+            ips.split(',').map(|ip| ip.parse().with_context(|| format!("invalid address {:?}", ip))).collect::<Result<_>>()?,
+        )),
+        _ => bail!("expected resolver=ADDR or pin=IP[,IP...], got {:?}", s),
+    }
+}
+
+#[cfg(feature = "doh")]
+fn parse_doh_url(s: &str) -> Result<String> {
+    let url = reqwest::Url::parse(s).with_context(|| format!("invalid URL {:?}", s))?;
+    if url.scheme() != "https" {
+        bail!("expected an https:// URL, got {:?}", s);
+    }
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        serve: ServeArgs,
+    }
+
+    #[test]
+    fn test_upstream_list_from_env() {
+        // SAFETY: no other test reads or writes FELIX_UPSTREAM
+        unsafe { std::env::set_var("FELIX_UPSTREAM", "1.1.1.1:53,9.9.9.9:53") };
+        let cli = Cli::try_parse_from(["felix"]).unwrap();
+        unsafe { std::env::remove_var("FELIX_UPSTREAM") };
+        let expected: Vec<SocketAddr> = vec!["1.1.1.1:53".parse().unwrap(), "9.9.9.9:53".parse().unwrap()];
+        assert_eq!(cli.serve.upstream, expected);
+
+        // a flag replaces the environment's list
+        let cli = Cli::try_parse_from(["felix", "--upstream", "8.8.8.8:53"]).unwrap();
+        assert_eq!(cli.serve.upstream, vec!["8.8.8.8:53".parse::<SocketAddr>().unwrap()]);
+    }
+}
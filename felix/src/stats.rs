@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+
+use crate::api::ApiArgs;
+
+#[derive(Args)]
+pub struct StatsArgs {
+    /// Most queried names to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+    /// How far back to count, e.g. 90s, 5m, 1h or 24h; at most a day
+    #[arg(long, default_value = "1h", value_parser = parse_window)]
+    window: Duration,
+    #[command(flatten)]
+    api: ApiArgs,
+}
+
+/// Prints the queries of a running instance by type and answer source,
+/// and its most queried names.
+pub async fn run(args: StatsArgs) -> Result<()> {
+    let report = args
+        .api
+        .client()
+        .query_report(args.window, args.top)
+        .await
+        .with_context(|| format!("connecting to {}", args.api.api))?;
+    println!("{} queries in the last {}s", report.queries, report.window);
+    if report.queries == 0 {
+        return Ok(());
+    }
+
+    println!();
+    println!("{:<10} {:>8}", "TYPE", "QUERIES");
+    for t in &report.qtypes {
+        println!("{:<10} {:>8}", t.qtype, t.count);
+    }
+    println!();
+    println!("{:<30} {:>8}", "SOURCE", "QUERIES");
+    for s in &report.sources {
+        println!("{:<30} {:>8}", s.source, s.count);
+    }
+    println!();
+    println!("{:<50} {:>8} LAST ANSWERED BY", "NAME", "QUERIES");
+    for n in &report.names {
+        println!("{:<50} {:>8} {}", n.name, n.count, n.source);
+    }
+    if report.untracked > 0 {
+        println!("({} more queries for names past the tracking limit)", report.untracked);
+    }
+    Ok(())
+}
+
+fn parse_window(s: &str) -> Result<Duration> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = n.parse().with_context(|| format!("expected a duration like 5m or 1h, got {:?}", s))?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        _ => bail!("expected a duration like 5m or 1h, got {:?}", s),
+    };
+    Ok(Duration::from_secs(secs))
+}
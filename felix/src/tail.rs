@@ -0,0 +1,167 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use felix_dns::{QueryEvent, QueryOutcome};
+use futures_util::StreamExt;
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Args)]
+pub struct TailArgs {
+    /// Management API address of the running instance
+    #[arg(long, default_value = "127.0.0.1:5380")]
+    api: SocketAddr,
+    /// Management token (read-only is enough)
+    #[arg(long, env = "FELIX_TOKEN")]
+    token: String,
+    /// Only show names matching this pattern (`*` matches any run of
+    /// characters, `?` one character)
+    #[arg(long)]
+    name: Option<String>,
+    /// Only show queries from this client IP
+    #[arg(long)]
+    client: Option<IpAddr>,
+    /// Only show queries with this outcome
+    #[arg(long, value_parser = parse_outcome)]
+    outcome: Option<QueryOutcome>,
+}
+
+pub async fn run(args: TailArgs) -> Result<()> {
+    let url = format!("ws://{}/events?token={}", args.api, args.token);
+    let (mut ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .with_context(|| format!("connecting to {}", args.api))?;
+
+    while let Some(msg) = ws.next().await {
+        let text = match msg? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let ev: QueryEvent = serde_json::from_str(&text)?;
+        if args.matches(&ev) {
+            println!(
+                "{:<21} {:<6} {:<10} {:<28} {}",
+                ev.client,
+                ev.qtype,
+                outcome_label(ev.outcome),
+                ev.source.to_string(),
+                ev.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+impl TailArgs {
+    fn matches(&self, ev: &QueryEvent) -> bool {
+        if let Some(pattern) = &self.name
+            && !glob_match(&pattern.to_ascii_lowercase(), &ev.name.to_ascii_lowercase())
+        {
+            return false;
+        }
+        if self.client.is_some_and(|ip| ip != ev.client.ip()) {
+            return false;
+        }
+        if self.outcome.is_some_and(|o| o != ev.outcome) {
+            return false;
+        }
+        true
+    }
+}
+
+fn parse_outcome(s: &str) -> Result<QueryOutcome> {
+    match s {
+        "local" => Ok(QueryOutcome::Local),
+        "forwarded" => Ok(QueryOutcome::Forwarded),
+        "cached" => Ok(QueryOutcome::Cached),
+        "servfail" => Ok(QueryOutcome::ServFail),
+        "refused" => Ok(QueryOutcome::Refused),
+        "blocked" => Ok(QueryOutcome::Blocked),
+        "not-forwarded" => Ok(QueryOutcome::NotForwarded),
+        _ => bail!(
+            "unknown outcome {:?} (expected local, forwarded, cached, servfail, refused, blocked or not-forwarded)",
+            s
+        ),
+    }
+}
+
+fn outcome_label(outcome: QueryOutcome) -> &'static str {
+    match outcome {
+        QueryOutcome::Local => "local",
+        QueryOutcome::Forwarded => "forwarded",
+        QueryOutcome::Cached => "cached",
+        QueryOutcome::ServFail => "servfail",
+        QueryOutcome::Refused => "refused",
+        QueryOutcome::Blocked => "blocked",
+        QueryOutcome::NotForwarded => "not-forwarded",
+    }
+}
+
+/// Whether the whole of `text` matches `pattern`, where `*` matches any
+/// run of characters and `?` exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the last `*` seen and the text position it was tried at, to retry
+    // with one more character swallowed when the rest fails
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, text, matches) in [
+            ("app.dev", "app.dev", true),
+            ("app.dev", "api.dev", false),
+            // anchored at both ends
+            ("app", "app.dev", false),
+            ("dev", "app.dev", false),
+            ("*.dev", "app.dev", true),
+            ("*.dev", "app.dev.example", false),
+            ("app.*", "app.dev", true),
+            ("*app*", "my.app.dev", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("*", "", true),
+            ("**", "anything", true),
+            // `?` is exactly one character
+            ("ap?.dev", "app.dev", true),
+            ("ap?.dev", "ap.dev", false),
+            ("ap?.dev", "appp.dev", false),
+            ("?", "ü", true),
+            ("*?", "", false),
+            // empty pattern only matches an empty name
+            ("", "", true),
+            ("", "app.dev", false),
+        ] {
+            assert_eq!(glob_match(pattern, text), matches, "{:?} {:?}", pattern, text);
+        }
+    }
+}